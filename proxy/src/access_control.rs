@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use ipnet::IpNet;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::geoip::GeoIp;
+use crate::ip_list::IpList;
+use crate::units::timestamp;
+use crate::watched_file::WatchedFile;
+
+/// `[access_control]` section of the config file, or a route's `access_control` table,
+/// which replaces the global one for that route. Every client is allowed when absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct AccessControlConfig {
+    /// Only clients in these IP addresses and CIDR blocks are allowed.
+    pub allow: Option<Vec<String>>,
+    /// Clients in these are refused, even when also allowed.
+    pub deny: Option<Vec<String>>,
+    /// File with more `allow` entries, one per line (`#` starts a comment), re-read when it
+    /// changes.
+    pub allow_file: Option<String>,
+    /// File with more `deny` entries, like `allow_file`.
+    pub deny_file: Option<String>,
+    /// Clients located in these countries (ISO 3166 codes) are allowed too; needs `[geoip]`.
+    pub allow_countries: Option<Vec<String>>,
+    /// Clients located in these countries are refused; needs `[geoip]`.
+    pub deny_countries: Option<Vec<String>>,
+}
+
+fn read_list_file(path: &Path) -> Result<IpList, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    let entries: Vec<String> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    IpList::parse(&entries).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+/// Inline entries of a list plus those of its file.
+struct AccessList {
+    inline: IpList,
+    file: Option<WatchedFile<IpList>>,
+}
+
+impl AccessList {
+    fn new(entries: Option<&[String]>, file: Option<&str>) -> Result<Option<Self>, String> {
+        if entries.is_none() && file.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            inline: IpList::parse(entries.unwrap_or_default())?,
+            file: file
+                .map(|path| WatchedFile::open(path, read_list_file))
+                .transpose()?,
+        }))
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.inline.contains(ip)
+            || self
+                .file
+                .as_ref()
+                .is_some_and(|file| file.get().contains(ip))
+    }
+}
+
+/// Country codes, upper-cased, after checking they look like ISO 3166 codes.
+fn country_set(codes: Option<&[String]>) -> Result<Option<HashSet<String>>, String> {
+    codes
+        .map(|codes| {
+            codes
+                .iter()
+                .map(|code| {
+                    if code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+                        Ok(code.to_ascii_uppercase())
+                    } else {
+                        Err(format!("invalid country code '{code}'"))
+                    }
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+/// Country allow and deny lists, checked against the GeoIP database.
+struct CountryLists {
+    geoip: Arc<GeoIp>,
+    allow: Option<HashSet<String>>,
+    deny: Option<HashSet<String>>,
+}
+
+/// Allow and deny lists checked against the client IP.
+pub struct AccessControl {
+    allow: Option<AccessList>,
+    deny: Option<AccessList>,
+    countries: Option<CountryLists>,
+}
+
+impl AccessControl {
+    pub fn new(config: &AccessControlConfig, geoip: Option<&Arc<GeoIp>>) -> Result<Self, String> {
+        let allow_countries = country_set(config.allow_countries.as_deref())
+            .map_err(|err| format!("allow_countries: {err}"))?;
+        let deny_countries = country_set(config.deny_countries.as_deref())
+            .map_err(|err| format!("deny_countries: {err}"))?;
+        let countries = match (allow_countries, deny_countries, geoip) {
+            (None, None, _) => None,
+            (allow, deny, Some(geoip)) => Some(CountryLists {
+                geoip: geoip.clone(),
+                allow,
+                deny,
+            }),
+            (_, _, None) => {
+                return Err(
+                    "allow_countries and deny_countries need a loaded [geoip] database".to_string(),
+                );
+            }
+        };
+        Ok(Self {
+            allow: AccessList::new(config.allow.as_deref(), config.allow_file.as_deref())
+                .map_err(|err| format!("allow: {err}"))?,
+            deny: AccessList::new(config.deny.as_deref(), config.deny_file.as_deref())
+                .map_err(|err| format!("deny: {err}"))?,
+            countries,
+        })
+    }
+
+    fn has_allow_list(&self) -> bool {
+        self.allow.is_some()
+            || self
+                .countries
+                .as_ref()
+                .is_some_and(|countries| countries.allow.is_some())
+    }
+
+    /// Whether `client` may be served. Without a known address, requests are refused only
+    /// when there is an allow list; clients not found in the GeoIP database match no
+    /// country.
+    pub fn allows(&self, client: Option<IpAddr>) -> bool {
+        let Some(ip) = client else {
+            return !self.has_allow_list();
+        };
+        if self.deny.as_ref().is_some_and(|deny| deny.contains(&ip)) {
+            return false;
+        }
+        let (country_allowed, country_denied) = match &self.countries {
+            Some(countries) => {
+                let country = countries.geoip.country(ip);
+                let listed = |codes: &Option<HashSet<String>>| {
+                    codes
+                        .as_ref()
+                        .zip(country.as_ref())
+                        .is_some_and(|(codes, country)| codes.contains(country))
+                };
+                (listed(&countries.allow), listed(&countries.deny))
+            }
+            None => (false, false),
+        };
+        if country_denied {
+            return false;
+        }
+        !self.has_allow_list()
+            || self.allow.as_ref().is_some_and(|allow| allow.contains(&ip))
+            || country_allowed
+    }
+
+    pub fn refresh(&self) {
+        for list in [&self.allow, &self.deny].into_iter().flatten() {
+            if let Some(file) = &list.file {
+                file.refresh();
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for AccessControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessControl")
+            .field("allow", &self.allow.is_some())
+            .field("deny", &self.deny.is_some())
+            .field("countries", &self.countries.is_some())
+            .finish()
+    }
+}
+
+/// Clients banned through the admin API during an incident, each until a set time or until
+/// lifted; kept across config reloads. Banned clients are refused before access control.
+#[derive(Clone, Default)]
+pub struct IpBans {
+    inner: Arc<RwLock<BTreeMap<IpNet, Option<SystemTime>>>>,
+}
+
+impl IpBans {
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        let bans = self
+            .inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if bans.is_empty() {
+            return false;
+        }
+        let now = SystemTime::now();
+        bans.iter()
+            .any(|(network, until)| network.contains(ip) && until.is_none_or(|until| until > now))
+    }
+
+    /// Bans `network` for `duration`, or until lifted; returns when the ban ends.
+    pub fn ban(&self, network: IpNet, duration: Option<Duration>) -> Option<SystemTime> {
+        let until = duration.map(|duration| SystemTime::now() + duration);
+        self.inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(network.trunc(), until);
+        until
+    }
+
+    /// Lifts the ban of `network`; returns whether it was banned.
+    pub fn lift(&self, network: &IpNet) -> bool {
+        let now = SystemTime::now();
+        self.inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&network.trunc())
+            .is_some_and(|until| until.is_none_or(|until| until > now))
+    }
+
+    /// The bans in force, for the admin API; expired ones are forgotten.
+    pub fn report(&self) -> serde_json::Value {
+        let mut bans = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = SystemTime::now();
+        bans.retain(|_, until| until.is_none_or(|until| until > now));
+        let bans: Vec<_> = bans
+            .iter()
+            .map(|(network, until)| {
+                json!({ "network": network.to_string(), "until": until.map(timestamp) })
+            })
+            .collect();
+        json!({ "bans": bans })
+    }
+}
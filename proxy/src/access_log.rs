@@ -0,0 +1,386 @@
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use pingora::proxy::Session;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::proxy_protocol;
+
+/// `log` target access log lines are written to, so they can be filtered
+/// separately, e.g. `log_level = "warn,access_log=info"`.
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+/// nginx's `combined` format.
+const COMBINED_FORMAT: &str = r#"$remote_addr - - [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+/// nginx's `common` format (the classic CLF).
+const COMMON_FORMAT: &str = r#"$remote_addr - - [$time_local] "$request" $status $body_bytes_sent"#;
+
+/// `[access_log]` section of the config file. Access logging is off when the
+/// section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct AccessLogConfig {
+    /// nginx-style template such as `"$remote_addr $status $request_time"`, or
+    /// one of the presets `"combined"` (the default) and `"common"`.
+    pub format: Option<String>,
+    /// Variables to log as one JSON object per line, instead of `format`.
+    pub fields: Option<Vec<String>>,
+}
+
+/// Compiled access log format.
+#[derive(Debug, Clone)]
+pub enum AccessLog {
+    Template(Vec<Segment>),
+    Json(Vec<(String, Variable)>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Segment {
+    Literal(String),
+    Variable(Variable),
+}
+
+/// A value that can be logged, named as in nginx.
+#[derive(Debug, Clone)]
+pub enum Variable {
+    RemoteAddr,
+    RemotePort,
+    /// Client address resolved through `trusted_proxies`, as used by access control and
+    /// rate limiting.
+    ClientIp,
+    TimeLocal,
+    TimeIso8601,
+    Msec,
+    Request,
+    RequestMethod,
+    RequestUri,
+    Uri,
+    Args,
+    ServerProtocol,
+    Scheme,
+    Host,
+    Status,
+    BodyBytesSent,
+    BytesSent,
+    RequestTime,
+    UpstreamAddr,
+    Route,
+    RequestId,
+    /// Id of the API key the request was accepted with.
+    ApiKey,
+    /// Tags added by `[waf]` rules, comma-separated.
+    WafTags,
+    /// `grpc-status` of a gRPC call, from the response trailers or headers.
+    GrpcStatus,
+    /// `$http_<name>`: a request header.
+    RequestHeader(String),
+    /// `$sent_http_<name>`: a response header.
+    ResponseHeader(String),
+}
+
+impl Variable {
+    fn parse(name: &str) -> Result<Self, String> {
+        let header = |name: &str| name.replace('_', "-");
+        Ok(match name {
+            "remote_addr" => Self::RemoteAddr,
+            "remote_port" => Self::RemotePort,
+            "client_ip" => Self::ClientIp,
+            "time_local" => Self::TimeLocal,
+            "time_iso8601" => Self::TimeIso8601,
+            "msec" => Self::Msec,
+            "request" => Self::Request,
+            "request_method" => Self::RequestMethod,
+            "request_uri" => Self::RequestUri,
+            "uri" => Self::Uri,
+            "args" => Self::Args,
+            "server_protocol" => Self::ServerProtocol,
+            "scheme" => Self::Scheme,
+            "host" => Self::Host,
+            "status" => Self::Status,
+            "body_bytes_sent" => Self::BodyBytesSent,
+            "bytes_sent" => Self::BytesSent,
+            "request_time" => Self::RequestTime,
+            "upstream_addr" => Self::UpstreamAddr,
+            "route" => Self::Route,
+            "request_id" => Self::RequestId,
+            "api_key" => Self::ApiKey,
+            "waf_tags" => Self::WafTags,
+            "grpc_status" => Self::GrpcStatus,
+            _ => {
+                if let Some(name) = name.strip_prefix("sent_http_") {
+                    Self::ResponseHeader(header(name))
+                } else if let Some(name) = name.strip_prefix("http_") {
+                    Self::RequestHeader(header(name))
+                } else {
+                    return Err(format!("unknown variable ${name}"));
+                }
+            }
+        })
+    }
+
+    /// Whether the value is logged as a JSON number.
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Self::RemotePort
+                | Self::Msec
+                | Self::Status
+                | Self::BodyBytesSent
+                | Self::BytesSent
+                | Self::RequestTime
+        )
+    }
+
+    fn value(&self, request: &LoggedRequest) -> Option<String> {
+        let session = request.session;
+        let header = session.req_header();
+        let header_value = |headers: &http::HeaderMap, name: &str| {
+            let values: Vec<_> = headers
+                .get_all(name)
+                .iter()
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .collect();
+            (!values.is_empty()).then(|| values.join(", "))
+        };
+        match self {
+            Self::RemoteAddr => Some(match proxy_protocol::client_addr(session) {
+                Some(addr) => match addr.as_inet() {
+                    Some(inet) => inet.ip().to_string(),
+                    None => "unix:".to_string(),
+                },
+                None => return None,
+            }),
+            Self::RemotePort => proxy_protocol::client_addr(session)
+                .and_then(|addr| addr.as_inet().map(|inet| inet.port().to_string())),
+            Self::ClientIp => request.client_ip.map(|ip| ip.to_string()),
+            Self::TimeLocal => Some(
+                DateTime::<Utc>::from(request.logged_at)
+                    .format("%d/%b/%Y:%H:%M:%S %z")
+                    .to_string(),
+            ),
+            Self::TimeIso8601 => Some(
+                DateTime::<Utc>::from(request.logged_at)
+                    .to_rfc3339_opts(SecondsFormat::Secs, false),
+            ),
+            Self::Msec => {
+                let since_epoch = request
+                    .logged_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                Some(format!(
+                    "{}.{:03}",
+                    since_epoch.as_secs(),
+                    since_epoch.subsec_millis()
+                ))
+            }
+            Self::Request => Some(format!(
+                "{} {} {:?}",
+                header.method,
+                request_uri(session),
+                header.version
+            )),
+            Self::RequestMethod => Some(header.method.to_string()),
+            Self::RequestUri => Some(request_uri(session)),
+            Self::Uri => Some(header.uri.path().to_string()),
+            Self::Args => header.uri.query().map(str::to_string),
+            Self::ServerProtocol => Some(format!("{:?}", header.version)),
+            Self::Scheme => Some(crate::forwarded::downstream_scheme(session).to_string()),
+            Self::Host => crate::forwarded::downstream_host(session).map(str::to_string),
+            Self::Status => Some(
+                session
+                    .response_written()
+                    .map_or(0, |response| response.status.as_u16())
+                    .to_string(),
+            ),
+            Self::BodyBytesSent => {
+                Some(body_bytes_sent(session, request.upstream_body_bytes).to_string())
+            }
+            // Pingora counts HTTP/1 response headers along with the body.
+            Self::BytesSent => Some(session.body_bytes_sent().to_string()),
+            Self::RequestTime => Some(format_seconds(request.started.elapsed())),
+            Self::UpstreamAddr => request.upstream_addr.map(str::to_string),
+            Self::Route => request.route.map(str::to_string),
+            Self::RequestId => {
+                (!request.request_id.is_empty()).then(|| request.request_id.to_string())
+            }
+            Self::ApiKey => request.api_key.map(str::to_string),
+            Self::WafTags => (!request.waf_tags.is_empty()).then(|| request.waf_tags.join(",")),
+            Self::GrpcStatus => request.grpc_status.map(str::to_string),
+            Self::RequestHeader(name) => header_value(&header.headers, name),
+            Self::ResponseHeader(name) => session
+                .response_written()
+                .and_then(|response| header_value(&response.headers, name)),
+        }
+    }
+}
+
+/// What the access log needs to know about a finished request.
+pub struct LoggedRequest<'a> {
+    pub session: &'a Session,
+    pub started: Instant,
+    pub logged_at: SystemTime,
+    pub request_id: &'a str,
+    pub client_ip: Option<IpAddr>,
+    /// Upstream the request was proxied to, unset when answered by the proxy itself.
+    pub upstream_addr: Option<&'a str>,
+    /// Body bytes of a proxied response, counted as they were passed on.
+    pub upstream_body_bytes: Option<usize>,
+    pub route: Option<&'a str>,
+    pub api_key: Option<&'a str>,
+    pub waf_tags: &'a [String],
+    pub grpc_status: Option<&'a str>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<Self, String> {
+        match (&config.format, &config.fields) {
+            (Some(_), Some(_)) => Err("set either format or fields, not both".to_string()),
+            (_, Some(fields)) => {
+                if fields.is_empty() {
+                    return Err("fields must list at least one variable".to_string());
+                }
+                fields
+                    .iter()
+                    .map(|field| {
+                        let name = field.trim_start_matches('$');
+                        Ok((name.to_string(), Variable::parse(name)?))
+                    })
+                    .collect::<Result<_, String>>()
+                    .map(Self::Json)
+            }
+            (format, None) => {
+                let template = match format.as_deref() {
+                    None | Some("combined") => COMBINED_FORMAT,
+                    Some("common") => COMMON_FORMAT,
+                    Some(template) => template,
+                };
+                parse_template(template).map(Self::Template)
+            }
+        }
+    }
+
+    /// Writes the access log line for a finished request.
+    pub fn log(&self, request: &LoggedRequest) {
+        info!(target: ACCESS_LOG_TARGET, "{}", self.format(request));
+    }
+
+    fn format(&self, request: &LoggedRequest) -> String {
+        match self {
+            Self::Template(segments) => {
+                let mut line = String::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Literal(text) => line.push_str(text),
+                        Segment::Variable(variable) => match variable.value(request) {
+                            Some(value) => escape_into(&mut line, &value),
+                            None => line.push('-'),
+                        },
+                    }
+                }
+                line
+            }
+            Self::Json(fields) => {
+                // Built by hand so keys keep the configured order.
+                let mut line = String::from("{");
+                for (index, (name, variable)) in fields.iter().enumerate() {
+                    let value = match variable.value(request) {
+                        Some(value) if variable.is_numeric() => value
+                            .parse::<serde_json::Number>()
+                            .map(serde_json::Value::Number)
+                            .unwrap_or(serde_json::Value::String(value)),
+                        Some(value) => serde_json::Value::String(value),
+                        None => serde_json::Value::Null,
+                    };
+                    if index > 0 {
+                        line.push(',');
+                    }
+                    let _ = write!(line, "{}:{value}", serde_json::Value::from(name.as_str()));
+                }
+                line.push('}');
+                line
+            }
+        }
+    }
+}
+
+/// Splits a template into literals and `$name` / `${name}` variables.
+fn parse_template(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        literal.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("unterminated ${{ in format {template:?}"))?;
+            (&braced[..end], &braced[end + 1..])
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() {
+            return Err(format!("empty variable name in format {template:?}"));
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+        }
+        segments.push(Segment::Variable(Variable::parse(name)?));
+        rest = after;
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Response body size: counted for proxied responses, and taken from
+/// `Content-Length` for the ones the proxy wrote itself (static files, preflights).
+/// Response body bytes sent to the client: those counted of a proxied response, or the
+/// Content-Length of one the proxy answered.
+pub fn body_bytes_sent(session: &Session, upstream_body_bytes: Option<usize>) -> usize {
+    if let Some(bytes) = upstream_body_bytes {
+        return bytes;
+    }
+    if session.req_header().method == http::Method::HEAD {
+        return 0;
+    }
+    session
+        .response_written()
+        .and_then(|response| response.headers.get(http::header::CONTENT_LENGTH))
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .unwrap_or(0)
+}
+
+fn request_uri(session: &Session) -> String {
+    let uri = &session.req_header().uri;
+    uri.path_and_query()
+        .map_or_else(|| uri.path().to_string(), |path| path.to_string())
+}
+
+/// Seconds with millisecond resolution, as nginx logs `$request_time`.
+fn format_seconds(duration: Duration) -> String {
+    format!("{}.{:03}", duration.as_secs(), duration.subsec_millis())
+}
+
+/// Appends `value`, escaping quotes, backslashes and control characters as `\xHH`
+/// the way nginx does, so values can't break the line format.
+fn escape_into(line: &mut String, value: &str) {
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c.is_control() {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                let _ = write!(line, "\\x{byte:02X}");
+            }
+        } else {
+            line.push(c);
+        }
+    }
+}
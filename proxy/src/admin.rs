@@ -0,0 +1,849 @@
+use std::cmp::Reverse;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode, header};
+use log::{info, warn};
+use pingora::apps::http_app::ServeHttp;
+use pingora::http::RequestHeader;
+use pingora::protocols::http::ServerSession;
+use ring::digest::{SHA256, digest};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::access_control::IpBans;
+use crate::admin_tls::AdminTlsConfig;
+use crate::audit;
+use crate::build_info;
+use crate::config::{Config, ConfigFormat};
+use crate::drain::{Quiesce, UpstreamDrains};
+use crate::dump;
+use crate::endpoints::local_response;
+use crate::health::UpstreamHealth;
+use crate::ip_list::parse_network;
+use crate::log_control::{self, DEFAULT_DEBUG_LOG_LEVEL};
+use crate::maintenance::Maintenance;
+use crate::rate_limit::RateLimiter;
+use crate::reload::ConfigReloader;
+use crate::routes::RouteConfig;
+use crate::state::{ProxyState, SharedState};
+use crate::status::RequestStats;
+use crate::units;
+
+/// Largest request body read, e.g. a route.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Offenders listed by `GET /rate-limit` unless `?top=` says otherwise.
+const DEFAULT_TOP_OFFENDERS: usize = 20;
+/// Entries listed by `GET /cache/entries` unless `?limit=` says otherwise.
+const DEFAULT_CACHE_ENTRIES: usize = 20;
+
+/// `[admin]` section of the config file: an HTTP API on its own listener for inspecting and
+/// controlling the running proxy. Off when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct AdminConfig {
+    /// Address the API listens on, apart from the proxy listeners; changes need a restart.
+    pub listen_addr: String,
+    /// Bearer token of full access, sent as `Authorization: Bearer <token>`; reloadable.
+    pub token: Option<String>,
+    /// More tokens, each with a name for the audit log and its own scope.
+    #[serde(default)]
+    pub tokens: Vec<AdminTokenConfig>,
+    /// TOML file of the `[[routes]]` managed through the API, loaded with the config and
+    /// rewritten on every change; without it they last until the next restart.
+    pub routes_file: Option<String>,
+    /// Serves the API over TLS, optionally taking client certificates in place of tokens.
+    pub tls: Option<AdminTlsConfig>,
+}
+
+/// An `[[admin.tokens]]` entry.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct AdminTokenConfig {
+    /// Name the audit log records for calls made with the token.
+    pub name: String,
+    pub token: String,
+    pub scope: AdminScope,
+}
+
+/// What an admin API caller may do: `read` only inspects, `write` also changes things.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminScope {
+    Read,
+    Write,
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .token
+            .as_ref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            return Err("token must not be empty".to_string());
+        }
+        for (i, entry) in self.tokens.iter().enumerate() {
+            if entry.token.trim().is_empty() {
+                return Err(format!("token '{}' must not be empty", entry.name));
+            }
+            if self.tokens[..i]
+                .iter()
+                .any(|other| other.name == entry.name)
+            {
+                return Err(format!("token '{}' is named twice", entry.name));
+            }
+        }
+        let client_certs = self.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some());
+        if self.token.is_none() && self.tokens.is_empty() && !client_certs {
+            return Err("set token, tokens or tls.client_ca".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Who makes an admin API call, by token name or client certificate, and what they may do.
+struct Caller {
+    name: String,
+    scope: AdminScope,
+}
+
+/// What the admin API switches at runtime for the proxy to act on, kept across config reloads.
+#[derive(Clone, Default)]
+pub struct RuntimeControls {
+    pub drains: UpstreamDrains,
+    pub maintenance: Maintenance,
+    pub quiesce: Quiesce,
+    pub bans: IpBans,
+}
+
+/// Serves the admin API.
+pub struct AdminService {
+    state: SharedState,
+    reloader: ConfigReloader,
+    upstreams: UpstreamHealth,
+    stats: Arc<RequestStats>,
+    rate_limiter: Arc<RateLimiter>,
+    controls: RuntimeControls,
+    started: Instant,
+    /// Held while the routes are changed, so concurrent changes don't undo each other.
+    routes_lock: Mutex<()>,
+}
+
+impl AdminService {
+    pub fn new(
+        state: SharedState,
+        reloader: ConfigReloader,
+        upstreams: UpstreamHealth,
+        stats: Arc<RequestStats>,
+        rate_limiter: Arc<RateLimiter>,
+        controls: RuntimeControls,
+    ) -> Self {
+        Self {
+            state,
+            reloader,
+            upstreams,
+            stats,
+            rate_limiter,
+            controls,
+            started: Instant::now(),
+            routes_lock: Mutex::new(()),
+        }
+    }
+
+    /// The caller of `session` under the current config: the token it carries, else its
+    /// client certificate. Tokens are compared by digest, so the comparison takes as long
+    /// whatever the token sent.
+    fn caller(&self, session: &ServerSession) -> Option<Caller> {
+        let state = self.state.current();
+        let admin = state.config.admin.as_ref()?;
+        let bearer = session
+            .req_header()
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let Some(sent) = bearer {
+            let sent = digest(&SHA256, sent.trim().as_bytes());
+            let matches = |token: &str| digest(&SHA256, token.as_bytes()).as_ref() == sent.as_ref();
+            if admin.token.as_deref().is_some_and(matches) {
+                return Some(Caller {
+                    name: "token".to_string(),
+                    scope: AdminScope::Write,
+                });
+            }
+            return admin
+                .tokens
+                .iter()
+                .find(|entry| matches(&entry.token))
+                .map(|entry| Caller {
+                    name: entry.name.clone(),
+                    scope: entry.scope,
+                });
+        }
+        let tls = admin.tls.as_ref().filter(|tls| tls.client_ca.is_some())?;
+        let cert_digest = &session.digest()?.ssl_digest.as_ref()?.cert_digest;
+        if cert_digest.is_empty() {
+            return None;
+        }
+        let (name, scope) = tls.client(cert_digest);
+        Some(Caller { name, scope })
+    }
+
+    /// Reads the body of an authorized call and answers it.
+    async fn call(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let mut body = Vec::new();
+        if session.req_header().method == Method::PUT {
+            loop {
+                match session.read_request_body().await {
+                    Ok(Some(chunk)) if body.len() + chunk.len() <= MAX_BODY_BYTES => {
+                        body.extend_from_slice(&chunk);
+                    }
+                    Ok(Some(_)) => {
+                        return error_response(StatusCode::PAYLOAD_TOO_LARGE, "body too large");
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        return error_response(StatusCode::BAD_REQUEST, &err.to_string());
+                    }
+                }
+            }
+        }
+        self.answer(session.req_header(), &body).await
+    }
+
+    async fn answer(&self, request: &RequestHeader, body: &[u8]) -> Response<Vec<u8>> {
+        let path = request.uri.path();
+        let mutating = request.method != Method::GET && request.method != Method::HEAD;
+        match (path, mutating) {
+            ("/health", false) => json_response(
+                StatusCode::OK,
+                json!({
+                    "status": "ok",
+                    "uptime_seconds": self.started.elapsed().as_secs(),
+                }),
+            ),
+            ("/build", false) => json_response(
+                StatusCode::OK,
+                build_info::report(&self.state.current().config),
+            ),
+            ("/config", false) => {
+                match dump::dump_config(&self.state.current().config, ConfigFormat::Json) {
+                    Ok(config) => local_response(StatusCode::OK, JSON, config.into_bytes()),
+                    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err),
+                }
+            }
+            ("/upstreams", false) => {
+                let mut upstreams = self.upstreams.report(self.state.current().upstreams());
+                for upstream in &mut upstreams {
+                    let drain = self
+                        .controls
+                        .drains
+                        .get(upstream["addr"].as_str().unwrap_or_default());
+                    upstream["draining"] = drain.draining.into();
+                    upstream["in_flight"] = drain.in_flight.into();
+                }
+                json_response(StatusCode::OK, json!({ "upstreams": upstreams }))
+            }
+            ("/reload", true) => match self.reloader.reload() {
+                Ok(()) => json_response(StatusCode::OK, json!({ "reloaded": true })),
+                Err(err) => {
+                    warn!("config reload through the admin API failed: {err}");
+                    error_response(StatusCode::UNPROCESSABLE_ENTITY, &err)
+                }
+            },
+            ("/debug-log", true) => {
+                let config = &self.state.current().config;
+                let debug_filter = config
+                    .debug_log_level
+                    .as_deref()
+                    .unwrap_or(DEFAULT_DEBUG_LOG_LEVEL);
+                match log_control::toggle_debug(debug_filter) {
+                    Ok(filter) => {
+                        warn!("log filter toggled through the admin API, now {filter:?}");
+                        audit::record(
+                            "log_filter_changed",
+                            json!({ "filter": filter, "trigger": "admin" }),
+                        );
+                        json_response(StatusCode::OK, json!({ "filter": filter }))
+                    }
+                    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err),
+                }
+            }
+            ("/routes", false) => {
+                let config = &self.state.current().config;
+                let routes: Vec<_> = config
+                    .routes
+                    .iter()
+                    .map(|route| route_json(config, route))
+                    .collect();
+                json_response(StatusCode::OK, json!({ "routes": routes }))
+            }
+            ("/static/reload", true) => {
+                let Some(static_assets) = self.state.current().static_assets.clone() else {
+                    return error_response(StatusCode::NOT_FOUND, "no static_root configured");
+                };
+                match static_assets.force_reload().await {
+                    Ok(reload) => json_response(StatusCode::OK, json!(reload)),
+                    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err),
+                }
+            }
+            ("/quiesce", false) => json_response(StatusCode::OK, self.quiesce_report()),
+            ("/quiesce", true) => {
+                match request.method {
+                    Method::POST => {
+                        if self.controls.quiesce.start() {
+                            warn!("quiescing: readiness fails and connections close from now on");
+                            audit::record("quiesce_changed", json!({ "quiescing": true }));
+                        }
+                    }
+                    Method::DELETE => {
+                        if self.controls.quiesce.stop() {
+                            warn!("quiesce called off");
+                            audit::record("quiesce_changed", json!({ "quiescing": false }));
+                        }
+                    }
+                    _ => {
+                        return error_response(
+                            StatusCode::METHOD_NOT_ALLOWED,
+                            "use POST or DELETE",
+                        );
+                    }
+                }
+                json_response(StatusCode::OK, self.quiesce_report())
+            }
+            ("/rate-limit", false) => {
+                let Some(limits) = self.state.current().rate_limits.clone() else {
+                    return error_response(StatusCode::NOT_FOUND, "no [rate_limit] configured");
+                };
+                let top = match query_param(request, "top").map(|top| top.parse()) {
+                    Some(Ok(top)) => top,
+                    Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid top"),
+                    None => DEFAULT_TOP_OFFENDERS,
+                };
+                json_response(StatusCode::OK, self.rate_limiter.report(&limits, top))
+            }
+            ("/cache", false) => {
+                let state = self.state.current();
+                let Some(static_assets) = &state.static_assets else {
+                    return error_response(StatusCode::NOT_FOUND, "no static_root configured");
+                };
+                let Some(path) = query_param(request, "path") else {
+                    return error_response(StatusCode::BAD_REQUEST, "missing ?path=");
+                };
+                match static_assets.inspect(&path).await {
+                    Some(report) => json_response(StatusCode::OK, report),
+                    None => error_response(StatusCode::NOT_FOUND, "not a static asset path"),
+                }
+            }
+            ("/cache/entries", false) => self.cache_entries(request).await,
+            ("/bans", false) => json_response(StatusCode::OK, self.controls.bans.report()),
+            ("/bans", true) => self.change_ban(request),
+            ("/maintenance", false) => {
+                json_response(StatusCode::OK, self.controls.maintenance.report())
+            }
+            ("/maintenance", true) => self.switch_maintenance(request, None),
+            ("/health" | "/build" | "/config" | "/upstreams" | "/routes", true) => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "use GET")
+            }
+            ("/reload" | "/debug-log", false) => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "use POST")
+            }
+            _ => {
+                if let Some(name) = path.strip_prefix("/routes/")
+                    && !name.is_empty()
+                    && !name.contains('/')
+                {
+                    return match request.method {
+                        Method::GET | Method::HEAD => {
+                            let config = &self.state.current().config;
+                            match config.route(name) {
+                                Some(route) => {
+                                    json_response(StatusCode::OK, route_json(config, route))
+                                }
+                                None => error_response(StatusCode::NOT_FOUND, "no such route"),
+                            }
+                        }
+                        Method::PUT => self.put_route(name, body),
+                        Method::DELETE => self.delete_route(name),
+                        _ => {
+                            error_response(StatusCode::METHOD_NOT_ALLOWED, "use GET, PUT or DELETE")
+                        }
+                    };
+                }
+                if let Some(rule) = path.strip_prefix("/rate-limit/rules/")
+                    && !rule.is_empty()
+                {
+                    return self.override_rate_limit(request, rule, body);
+                }
+                if let Some(route) = path.strip_prefix("/maintenance/")
+                    && !route.is_empty()
+                {
+                    return self.switch_maintenance(request, Some(route));
+                }
+                match path
+                    .strip_prefix("/upstreams/")
+                    .and_then(|rest| rest.rsplit_once('/'))
+                {
+                    Some((upstream, action @ ("drain" | "enable"))) => {
+                        if !mutating {
+                            return error_response(StatusCode::METHOD_NOT_ALLOWED, "use POST");
+                        }
+                        self.set_draining(upstream, action == "drain")
+                    }
+                    _ => error_response(StatusCode::NOT_FOUND, "no such endpoint"),
+                }
+            }
+        }
+    }
+
+    /// Whether the proxy is quiescing, and the requests still in flight; `drained` once
+    /// quiescing with none left.
+    fn quiesce_report(&self) -> serde_json::Value {
+        let since = self.controls.quiesce.since();
+        let in_flight = self.stats.in_flight();
+        json!({
+            "quiescing": since.is_some(),
+            "since": since.map(units::timestamp),
+            "in_flight": in_flight,
+            "drained": since.is_some() && in_flight == 0,
+        })
+    }
+
+    /// The largest cached objects, or with `?sort=hits` the hottest, of both the static files
+    /// and the converted images held in memory.
+    async fn cache_entries(&self, request: &RequestHeader) -> Response<Vec<u8>> {
+        let by_hits = match query_param(request, "sort").as_deref() {
+            None | Some("bytes") => false,
+            Some("hits") => true,
+            Some(_) => return error_response(StatusCode::BAD_REQUEST, "sort by bytes or hits"),
+        };
+        let limit = match query_param(request, "limit").map(|limit| limit.parse()) {
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid limit"),
+            None => DEFAULT_CACHE_ENTRIES,
+        };
+        let state = self.state.current();
+        let mut entries = Vec::new();
+        if let Some(static_assets) = &state.static_assets {
+            let files = static_assets.cache_entries().await;
+            entries.extend(files.into_iter().map(|entry| ("static", entry)));
+        }
+        if let Some(images) = &state.images {
+            let converted = images.cache_entries().await;
+            entries.extend(converted.into_iter().map(|entry| ("images", entry)));
+        }
+        let total = entries.len();
+        if by_hits {
+            entries.sort_by_key(|(_, entry)| Reverse(entry.hits));
+        } else {
+            entries.sort_by_key(|(_, entry)| Reverse(entry.bytes));
+        }
+        entries.truncate(limit);
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(tier, entry)| {
+                let mut entry = json!(entry);
+                entry["tier"] = tier.into();
+                entry
+            })
+            .collect();
+        json_response(
+            StatusCode::OK,
+            json!({ "total": total, "entries": entries }),
+        )
+    }
+
+    /// Sets the limit of the rate limit rule `id` from the JSON `body` with PUT (for
+    /// `?duration=`, if given), or puts it back to its configured limit with DELETE.
+    fn override_rate_limit(
+        &self,
+        request: &RequestHeader,
+        id: &str,
+        body: &[u8],
+    ) -> Response<Vec<u8>> {
+        let Some(limits) = self.state.current().rate_limits.clone() else {
+            return error_response(StatusCode::NOT_FOUND, "no [rate_limit] configured");
+        };
+        if !limits.has_rule(id) {
+            return error_response(StatusCode::NOT_FOUND, "no such rate limit rule");
+        }
+        match request.method {
+            Method::PUT => {
+                let limit: RateLimitOverride = match serde_json::from_slice(body) {
+                    Ok(limit) => limit,
+                    Err(err) => {
+                        return error_response(
+                            StatusCode::BAD_REQUEST,
+                            &format!("invalid limit: {err}"),
+                        );
+                    }
+                };
+                let duration = match query_duration(request) {
+                    Ok(duration) => duration,
+                    Err(err) => return error_response(StatusCode::BAD_REQUEST, &err),
+                };
+                let until = match self.rate_limiter.set_override(
+                    id,
+                    limit.requests_per_second,
+                    limit.burst,
+                    duration,
+                ) {
+                    Ok(until) => until,
+                    Err(err) => return error_response(StatusCode::BAD_REQUEST, &err),
+                };
+                warn!(
+                    "rate limit rule {id} set to {} requests per second through the admin API",
+                    limit.requests_per_second
+                );
+                audit::record(
+                    "rate_limit_changed",
+                    json!({
+                        "rule": id,
+                        "requests_per_second": limit.requests_per_second,
+                        "burst": limit.burst,
+                        "until": until.map(units::timestamp),
+                    }),
+                );
+            }
+            Method::DELETE => {
+                if self.rate_limiter.clear_override(id) {
+                    warn!("rate limit rule {id} back to its configured limit");
+                    audit::record("rate_limit_changed", json!({ "rule": id, "reset": true }));
+                }
+            }
+            _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "use PUT or DELETE"),
+        }
+        json_response(StatusCode::OK, self.rate_limiter.report(&limits, 0))
+    }
+
+    /// Bans the client address or block in `?ip=` with POST (for `?duration=`, if given), or
+    /// lifts its ban with DELETE.
+    fn change_ban(&self, request: &RequestHeader) -> Response<Vec<u8>> {
+        let network = match query_param(request, "ip").as_deref().map(parse_network) {
+            Some(Ok(network)) => network,
+            Some(Err(err)) => return error_response(StatusCode::BAD_REQUEST, &err),
+            None => return error_response(StatusCode::BAD_REQUEST, "missing ?ip="),
+        };
+        match request.method {
+            Method::POST => {
+                let duration = match query_duration(request) {
+                    Ok(duration) => duration,
+                    Err(err) => return error_response(StatusCode::BAD_REQUEST, &err),
+                };
+                let until = self.controls.bans.ban(network, duration);
+                warn!("{network} banned through the admin API");
+                audit::record(
+                    "ip_ban_changed",
+                    json!({
+                        "network": network.to_string(),
+                        "banned": true,
+                        "until": until.map(units::timestamp),
+                    }),
+                );
+            }
+            Method::DELETE => {
+                if self.controls.bans.lift(&network) {
+                    warn!("ban of {network} lifted through the admin API");
+                    audit::record(
+                        "ip_ban_changed",
+                        json!({ "network": network.to_string(), "banned": false }),
+                    );
+                }
+            }
+            _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "use POST or DELETE"),
+        }
+        json_response(StatusCode::OK, self.controls.bans.report())
+    }
+
+    /// Switches maintenance of `route`, or of everything, on with POST (for `?duration=`, if
+    /// given) or off with DELETE.
+    fn switch_maintenance(
+        &self,
+        request: &RequestHeader,
+        route: Option<&str>,
+    ) -> Response<Vec<u8>> {
+        if let Some(route) = route
+            && self.state.current().config.route(route).is_none()
+        {
+            return error_response(StatusCode::NOT_FOUND, "no such route");
+        }
+        let scope = route.map_or("everything".to_string(), |route| format!("route {route}"));
+        match request.method {
+            Method::POST => {
+                let duration = match query_duration(request) {
+                    Ok(duration) => duration,
+                    Err(err) => return error_response(StatusCode::BAD_REQUEST, &err),
+                };
+                let until = self.controls.maintenance.enable(route, duration);
+                match duration {
+                    Some(duration) => {
+                        warn!(
+                            "maintenance mode on for {scope} for {}s",
+                            duration.as_secs()
+                        );
+                    }
+                    None => warn!("maintenance mode on for {scope}"),
+                }
+                audit::record(
+                    "maintenance_changed",
+                    json!({ "route": route, "enabled": true, "until": until.map(units::timestamp) }),
+                );
+            }
+            Method::DELETE => {
+                if self.controls.maintenance.disable(route) {
+                    warn!("maintenance mode off for {scope}");
+                    audit::record(
+                        "maintenance_changed",
+                        json!({ "route": route, "enabled": false }),
+                    );
+                }
+            }
+            _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "use POST or DELETE"),
+        }
+        json_response(StatusCode::OK, self.controls.maintenance.report())
+    }
+
+    /// Adds the route `name` from its JSON `body`, or replaces the one added before.
+    fn put_route(&self, name: &str, body: &[u8]) -> Response<Vec<u8>> {
+        let mut route: RouteConfig = match serde_json::from_slice(body) {
+            Ok(route) => route,
+            Err(err) => {
+                return error_response(StatusCode::BAD_REQUEST, &format!("invalid route: {err}"));
+            }
+        };
+        route.name = Some(name.to_string());
+        let mut created = false;
+        let changed = self.change_routes(|config| {
+            let managed = config.managed_routes.iter().any(|managed| managed == name);
+            match config
+                .routes
+                .iter_mut()
+                .find(|existing| existing.name.as_deref() == Some(name))
+            {
+                Some(_) if !managed => return Err(defined_in_config(name)),
+                Some(existing) => *existing = route,
+                None => {
+                    config.routes.push(route);
+                    config.managed_routes.push(name.to_string());
+                    created = true;
+                }
+            }
+            Ok(())
+        });
+        if let Err((status, body)) = changed {
+            return json_response(status, body);
+        }
+        info!(
+            "route '{name}' {} through the admin API",
+            if created { "added" } else { "replaced" }
+        );
+        audit::record(
+            "routes_changed",
+            json!({ "route": name, "action": if created { "add" } else { "replace" } }),
+        );
+        let config = &self.state.current().config;
+        let status = if created {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        };
+        match config.route(name) {
+            Some(route) => json_response(status, route_json(config, route)),
+            None => json_response(status, json!({ "name": name })),
+        }
+    }
+
+    /// Removes the route `name` added through the API.
+    fn delete_route(&self, name: &str) -> Response<Vec<u8>> {
+        let changed = self.change_routes(|config| {
+            let Some(index) = config
+                .managed_routes
+                .iter()
+                .position(|managed| managed == name)
+            else {
+                return Err(match config.route(name) {
+                    Some(_) => defined_in_config(name),
+                    None => (StatusCode::NOT_FOUND, json!({ "error": "no such route" })),
+                });
+            };
+            config.managed_routes.remove(index);
+            config
+                .routes
+                .retain(|route| route.name.as_deref() != Some(name));
+            Ok(())
+        });
+        if let Err((status, body)) = changed {
+            return json_response(status, body);
+        }
+        info!("route '{name}' removed through the admin API");
+        audit::record(
+            "routes_changed",
+            json!({ "route": name, "action": "remove" }),
+        );
+        json_response(StatusCode::OK, json!({ "removed": name }))
+    }
+
+    /// Applies `change` to a copy of the current config and installs the result if it is
+    /// valid, after saving the managed routes to `admin.routes_file`.
+    fn change_routes(
+        &self,
+        change: impl FnOnce(&mut Config) -> Result<(), Refusal>,
+    ) -> Result<(), Refusal> {
+        let _guard = self
+            .routes_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let current = self.state.current();
+        let mut config = current.config.clone();
+        change(&mut config)?;
+        let problems = config.validate();
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({ "error": "invalid route", "problems": problems }),
+            ));
+        }
+        let next = ProxyState::build(config, Some(&current), current.filters.clone())
+            .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, json!({ "error": err })))?;
+        next.config
+            .save_managed_routes()
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": err })))?;
+        self.state.replace(next);
+        Ok(())
+    }
+
+    /// Drains `upstream`, or takes it back into service, if the current config sends
+    /// requests to it.
+    fn set_draining(&self, upstream: &str, draining: bool) -> Response<Vec<u8>> {
+        if !self
+            .state
+            .current()
+            .upstreams()
+            .iter()
+            .any(|addr| addr == upstream)
+        {
+            return error_response(StatusCode::NOT_FOUND, "no such upstream");
+        }
+        let drain = self.controls.drains.set_draining(upstream, draining);
+        if draining {
+            warn!(
+                "upstream {upstream} is draining, {} requests in flight",
+                drain.in_flight
+            );
+        } else {
+            info!("upstream {upstream} is back in service");
+        }
+        json_response(
+            StatusCode::OK,
+            json!({ "addr": upstream, "draining": drain.draining, "in_flight": drain.in_flight }),
+        )
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminService {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let Some(caller) = self.caller(session) else {
+            let request = session.req_header();
+            info!(
+                "unauthorized admin API call {} {} from {}",
+                request.method,
+                request.uri.path(),
+                session
+                    .client_addr()
+                    .map(ToString::to_string)
+                    .unwrap_or_default()
+            );
+            let mut response = error_response(StatusCode::UNAUTHORIZED, "missing or wrong token");
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            return response;
+        };
+        let request = session.req_header();
+        let (method, uri) = (request.method.clone(), request.uri.to_string());
+        let mutating = method != Method::GET && method != Method::HEAD;
+        let response = if mutating && caller.scope == AdminScope::Read {
+            info!(
+                "admin API call {method} {} by {} refused: read-only",
+                request.uri.path(),
+                caller.name
+            );
+            error_response(StatusCode::FORBIDDEN, "read-only scope")
+        } else {
+            self.call(session).await
+        };
+        // Calls that change things, or tried to, are recorded with their outcome.
+        if mutating {
+            audit::record(
+                "admin_call",
+                json!({
+                    "endpoint": "admin",
+                    "method": method.as_str(),
+                    "uri": uri,
+                    "caller": caller.name,
+                    "scope": caller.scope,
+                    "status": response.status().as_u16(),
+                }),
+            );
+        }
+        response
+    }
+}
+
+/// A route as the API shows it, secrets redacted, and whether the API manages it.
+fn route_json(config: &Config, route: &RouteConfig) -> serde_json::Value {
+    let mut value = dump::redacted_json(route, config).unwrap_or_default();
+    let managed = route
+        .name
+        .as_ref()
+        .is_some_and(|name| config.managed_routes.contains(name));
+    value["managed"] = managed.into();
+    value
+}
+
+/// Why a change to the routes was refused: the status and JSON body of the response.
+type Refusal = (StatusCode, serde_json::Value);
+
+fn defined_in_config(name: &str) -> Refusal {
+    (
+        StatusCode::CONFLICT,
+        json!({ "error": format!("route '{name}' is defined in the config file") }),
+    )
+}
+
+const JSON: &str = "application/json";
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Vec<u8>> {
+    let mut body = serde_json::to_vec_pretty(&body).unwrap_or_default();
+    body.push(b'\n');
+    local_response(status, JSON, body)
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response<Vec<u8>> {
+    json_response(status, json!({ "error": error }))
+}
+
+/// Body of `PUT /rate-limit/rules/<id>`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitOverride {
+    requests_per_second: f64,
+    burst: Option<u32>,
+}
+
+/// The query parameter `name` of `request`.
+fn query_param(request: &RequestHeader, name: &str) -> Option<String> {
+    form_urlencoded::parse(request.uri.query()?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// The `?duration=` of `request`, e.g. `30m`.
+fn query_duration(request: &RequestHeader) -> Result<Option<Duration>, String> {
+    query_param(request, "duration")
+        .map(|duration| units::parse_seconds(&duration).map(Duration::from_secs))
+        .transpose()
+}
@@ -0,0 +1,312 @@
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::info;
+use pingora::apps::ServerApp;
+use pingora::protocols::raw_connect::ProxyDigest;
+use pingora::protocols::tls::SslDigest;
+use pingora::protocols::{
+    GetProxyDigest, GetSocketDigest, GetTimingDigest, Peek, Shutdown, SocketDigest, Ssl, Stream,
+    TimingDigest, UniqueID, UniqueIDType,
+};
+use pingora::server::ShutdownWatch;
+use ring::digest::{SHA256, digest};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+use crate::admin::{AdminScope, AdminService};
+use crate::hex;
+use crate::state::SharedState;
+
+/// Time a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `[admin.tls]`: serves the admin API over TLS, and with `client_ca` takes client
+/// certificates in place of a token. Whether the API uses TLS only changes on restart; the
+/// files are read again on reload.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct AdminTlsConfig {
+    /// PEM certificate chain the listener presents.
+    pub cert: String,
+    /// PEM private key of `cert`.
+    pub key: String,
+    /// PEM CA certificates client certificates must be issued by. Clients that present none
+    /// still need a token.
+    pub client_ca: Option<String>,
+    /// Scope of a client certificate `clients` doesn't list; `read` by default.
+    pub client_scope: Option<AdminScope>,
+    /// Client certificates with names and scopes of their own.
+    #[serde(default)]
+    pub clients: Vec<AdminClientConfig>,
+}
+
+/// An `[[admin.tls.clients]]` entry.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct AdminClientConfig {
+    /// Name the audit log records for calls made with the certificate.
+    pub name: String,
+    /// SHA-256 fingerprint of the certificate, in hex with or without colons, as
+    /// `openssl x509 -noout -fingerprint -sha256` prints it.
+    pub fingerprint: String,
+    pub scope: AdminScope,
+}
+
+impl AdminTlsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.client_ca.is_none() && !self.clients.is_empty() {
+            return Err("clients need client_ca".to_string());
+        }
+        for client in &self.clients {
+            if fingerprint(&client.fingerprint).is_none() {
+                return Err(format!(
+                    "client '{}' has no SHA-256 fingerprint: {}",
+                    client.name, client.fingerprint
+                ));
+            }
+        }
+        AdminTls::new(self).map(drop)
+    }
+
+    /// The name and scope of a client whose certificate has the SHA-256 digest `cert_digest`.
+    pub fn client(&self, cert_digest: &[u8]) -> (String, AdminScope) {
+        self.clients
+            .iter()
+            .find(|client| fingerprint(&client.fingerprint).as_deref() == Some(cert_digest))
+            .map(|client| (client.name.clone(), client.scope))
+            .unwrap_or_else(|| {
+                (
+                    format!("certificate {}", hex::encode(cert_digest)),
+                    self.client_scope.unwrap_or(AdminScope::Read),
+                )
+            })
+    }
+}
+
+/// The digest spelled by a fingerprint, if it is one.
+fn fingerprint(fingerprint: &str) -> Option<Vec<u8>> {
+    hex::decode(&fingerprint.replace(':', "")).filter(|digest| digest.len() == 32)
+}
+
+/// The TLS settings of `[admin.tls]`, loaded.
+pub struct AdminTls {
+    acceptor: TlsAcceptor,
+}
+
+impl AdminTls {
+    pub fn new(config: &AdminTlsConfig) -> Result<Self, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let certs = read_certs(&config.cert)?;
+        let key = PrivateKeyDer::from_pem_file(&config.key)
+            .map_err(|err| format!("can't read key {}: {err}", config.key))?;
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?;
+        let builder = match &config.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots
+                        .add(cert)
+                        .map_err(|err| format!("invalid CA certificate in {path}: {err}"))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|err| format!("invalid client_ca {path}: {err}"))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut server = builder
+            .with_single_cert(certs, key)
+            .map_err(|err| format!("invalid cert or key: {err}"))?;
+        server.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+        })
+    }
+}
+
+/// The certificates in the PEM file at `path`.
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("can't read certificates {path}: {err}"))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {path}"));
+    }
+    Ok(certs)
+}
+
+/// The admin API over TLS: terminates TLS with the certificate of the current config, then
+/// serves the HTTP inside as the plain listener does.
+pub struct AdminTlsService {
+    state: SharedState,
+    admin: Arc<AdminService>,
+}
+
+impl AdminTlsService {
+    pub fn new(state: SharedState, admin: AdminService) -> Self {
+        Self {
+            state,
+            admin: Arc::new(admin),
+        }
+    }
+}
+
+#[async_trait]
+impl ServerApp for AdminTlsService {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let acceptor = self.state.current().admin_tls.as_ref()?.acceptor.clone();
+        let client = stream
+            .get_socket_digest()
+            .and_then(|socket| socket.peer_addr().map(ToString::to_string))
+            .unwrap_or_default();
+        let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
+                info!("admin API TLS handshake with {client} failed: {err}");
+                return None;
+            }
+            Err(_) => {
+                info!("admin API TLS handshake with {client} timed out");
+                return None;
+            }
+        };
+        self.admin
+            .process_new(Box::new(AdminTlsStream::new(stream)), shutdown)
+            .await
+    }
+}
+
+/// A TLS connection to the admin API, with what pingora asks of a stream.
+#[derive(Debug)]
+struct AdminTlsStream {
+    inner: TlsStream<Stream>,
+    digest: Arc<SslDigest>,
+}
+
+impl AdminTlsStream {
+    fn new(inner: TlsStream<Stream>) -> Self {
+        let (_, connection) = inner.get_ref();
+        let digest = SslDigest {
+            cipher: connection
+                .negotiated_cipher_suite()
+                .and_then(|suite| suite.suite().as_str())
+                .unwrap_or_default(),
+            version: connection
+                .protocol_version()
+                .and_then(|version| version.as_str())
+                .unwrap_or_default(),
+            organization: None,
+            serial_number: None,
+            cert_digest: connection
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| digest(&SHA256, cert).as_ref().to_vec())
+                .unwrap_or_default(),
+        };
+        Self {
+            inner,
+            digest: Arc::new(digest),
+        }
+    }
+
+    fn tcp(&self) -> &Stream {
+        self.inner.get_ref().0
+    }
+}
+
+impl AsyncRead for AdminTlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AdminTlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl Shutdown for AdminTlsStream {
+    async fn shutdown(&mut self) {
+        let _ = AsyncWriteExt::shutdown(&mut self.inner).await;
+    }
+}
+
+impl UniqueID for AdminTlsStream {
+    fn id(&self) -> UniqueIDType {
+        self.tcp().id()
+    }
+}
+
+impl Ssl for AdminTlsStream {
+    fn get_ssl_digest(&self) -> Option<Arc<SslDigest>> {
+        Some(self.digest.clone())
+    }
+}
+
+impl GetTimingDigest for AdminTlsStream {
+    fn get_timing_digest(&self) -> Vec<Option<TimingDigest>> {
+        self.tcp().get_timing_digest()
+    }
+}
+
+impl GetProxyDigest for AdminTlsStream {
+    fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>> {
+        self.tcp().get_proxy_digest()
+    }
+}
+
+impl GetSocketDigest for AdminTlsStream {
+    fn get_socket_digest(&self) -> Option<Arc<SocketDigest>> {
+        self.tcp().get_socket_digest()
+    }
+}
+
+impl Peek for AdminTlsStream {}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const DEFAULT_WINDOW_SECONDS: u64 = 60;
+const DEFAULT_MIN_REQUESTS: u64 = 20;
+
+/// `[alerts]` section of the config file. Routes are not watched when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct AlertConfig {
+    /// Length of the windows requests are counted in, per route.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub window_seconds: Option<u64>,
+    /// Windows with fewer requests than this are not judged.
+    pub min_requests: Option<u64>,
+    /// Alert when more than this fraction (0 to 1) of a window's requests answered 5xx.
+    pub server_error_rate: Option<f64>,
+    /// Alert when the average request time of a window exceeds this many seconds.
+    pub average_latency_seconds: Option<f64>,
+}
+
+impl AlertConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            window_seconds: Some(self.window().as_secs()),
+            min_requests: Some(self.min_requests()),
+            ..self
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds.unwrap_or(DEFAULT_WINDOW_SECONDS))
+    }
+
+    fn min_requests(&self) -> u64 {
+        self.min_requests.unwrap_or(DEFAULT_MIN_REQUESTS)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window_seconds == Some(0) {
+            return Err("window_seconds must be at least 1".to_string());
+        }
+        if let Some(rate) = self.server_error_rate
+            && !(0.0..=1.0).contains(&rate)
+        {
+            return Err("server_error_rate must be between 0 and 1".to_string());
+        }
+        if let Some(latency) = self.average_latency_seconds
+            && !(latency.is_finite() && latency > 0.0)
+        {
+            return Err("average_latency_seconds must be a positive number".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Kind of threshold a route crossed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    ServerErrorRate,
+    AverageLatency,
+}
+
+impl AlertKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertKind::ServerErrorRate => "server_error_rate",
+            AlertKind::AverageLatency => "average_latency",
+        }
+    }
+}
+
+#[derive(Default)]
+struct RouteWindow {
+    started: Option<Instant>,
+    requests: u64,
+    server_errors: u64,
+    total_time: Duration,
+    /// Alerts that fired for the previous window, to log when they resolve.
+    firing: Vec<AlertKind>,
+}
+
+/// Judges each route's traffic per window against the `[alerts]` thresholds and logs
+/// an `alert` line for every threshold crossed.
+#[derive(Default)]
+pub struct AlertMonitor {
+    routes: Mutex<HashMap<String, RouteWindow>>,
+}
+
+impl AlertMonitor {
+    /// Counts a finished request. When this closes the route's window, returns the
+    /// alerts that window raised.
+    pub fn record(
+        &self,
+        config: &AlertConfig,
+        route: &str,
+        status: u16,
+        elapsed: Duration,
+    ) -> Vec<AlertKind> {
+        let now = Instant::now();
+        let mut routes = self
+            .routes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let window = match routes.get_mut(route) {
+            Some(window) => window,
+            None => routes.entry(route.to_string()).or_default(),
+        };
+
+        let mut raised = Vec::new();
+        let started = *window.started.get_or_insert(now);
+        if now.duration_since(started) >= config.window() {
+            // Too few requests to judge leave the alerts as they were.
+            let firing = match judge(config, route, window) {
+                Some(alerts) => {
+                    raised = alerts.clone();
+                    alerts
+                }
+                None => std::mem::take(&mut window.firing),
+            };
+            *window = RouteWindow {
+                started: Some(now),
+                firing,
+                ..Default::default()
+            };
+        }
+        window.requests += 1;
+        window.total_time += elapsed;
+        if status >= 500 {
+            window.server_errors += 1;
+        }
+        raised
+    }
+}
+
+/// Logs the alerts a closed window raises, and those of the previous window it resolves.
+/// Returns `None` when the window had too few requests to judge.
+fn judge(config: &AlertConfig, route: &str, window: &RouteWindow) -> Option<Vec<AlertKind>> {
+    if window.requests < config.min_requests() {
+        return None;
+    }
+    let window_seconds = config.window().as_secs();
+    let mut raised = Vec::new();
+    let error_rate = window.server_errors as f64 / window.requests as f64;
+    if let Some(threshold) = config.server_error_rate
+        && error_rate > threshold
+    {
+        raised.push(AlertKind::ServerErrorRate);
+        warn!(target: "alert", "{}", json!({
+            "alert": AlertKind::ServerErrorRate.as_str(),
+            "route": route,
+            "window_seconds": window_seconds,
+            "requests": window.requests,
+            "server_errors": window.server_errors,
+            "value": error_rate,
+            "threshold": threshold,
+        }));
+    }
+    let average = window.total_time.as_secs_f64() / window.requests as f64;
+    if let Some(threshold) = config.average_latency_seconds
+        && average > threshold
+    {
+        raised.push(AlertKind::AverageLatency);
+        warn!(target: "alert", "{}", json!({
+            "alert": AlertKind::AverageLatency.as_str(),
+            "route": route,
+            "window_seconds": window_seconds,
+            "requests": window.requests,
+            "value": average,
+            "threshold": threshold,
+        }));
+    }
+    for kind in &window.firing {
+        if !raised.contains(kind) {
+            info!(target: "alert", "{}", json!({
+                "alert": kind.as_str(),
+                "route": route,
+                "resolved": true,
+            }));
+        }
+    }
+    Some(raised)
+}
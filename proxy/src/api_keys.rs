@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use http::HeaderName;
+use pingora::http::RequestHeader;
+use ring::digest::{SHA256, digest};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::hex;
+use crate::watched_file::WatchedFile;
+
+const DEFAULT_HEADER: &str = "x-api-key";
+
+/// A route's `api_keys` table: requests must carry one of the keys, stored as SHA-256
+/// hashes so the config and key file do not hold the keys themselves.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct ApiKeysConfig {
+    /// Request header the key is sent in.
+    pub header: Option<String>,
+    #[serde(default)]
+    pub keys: Vec<ApiKeyEntry>,
+    /// File with more keys, one `<id> <sha256> [tier]` per line (`#` starts a comment),
+    /// re-read when it changes.
+    pub keys_file: Option<String>,
+}
+
+/// An `api_keys.keys` entry.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct ApiKeyEntry {
+    /// Names the key in access logs (`$api_key`) and rate limits.
+    pub id: String,
+    /// Hex SHA-256 of the key, e.g. from `printf %s "$KEY" | sha256sum`.
+    pub sha256: String,
+    /// `[rate_limit.tiers]` entry limiting the key.
+    pub tier: Option<String>,
+}
+
+/// The identity of an accepted key.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub tier: Option<String>,
+}
+
+type KeyTable = HashMap<Vec<u8>, ApiKey>;
+
+fn parse_entry(entry: &ApiKeyEntry, table: &mut KeyTable) -> Result<(), String> {
+    if entry.id.is_empty() || entry.id.contains(char::is_whitespace) {
+        return Err(format!("invalid key id {:?}", entry.id));
+    }
+    let hash = hex::decode(&entry.sha256)
+        .filter(|hash| hash.len() == 32)
+        .ok_or_else(|| format!("key '{}': sha256 must be 64 hex digits", entry.id))?;
+    table.insert(
+        hash,
+        ApiKey {
+            id: entry.id.clone(),
+            tier: entry.tier.clone(),
+        },
+    );
+    Ok(())
+}
+
+fn read_keys_file(path: &Path) -> Result<KeyTable, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    let mut table = KeyTable::new();
+    for (index, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+        let entry = match fields[..] {
+            [] => continue,
+            [id, sha256] | [id, sha256, _] => ApiKeyEntry {
+                id: id.to_string(),
+                sha256: sha256.to_string(),
+                tier: fields.get(2).map(|tier| tier.to_string()),
+            },
+            _ => {
+                return Err(format!(
+                    "{} line {}: expected <id> <sha256> [tier]",
+                    path.display(),
+                    index + 1
+                ));
+            }
+        };
+        parse_entry(&entry, &mut table)
+            .map_err(|err| format!("{} line {}: {err}", path.display(), index + 1))?;
+    }
+    Ok(table)
+}
+
+/// API keys accepted on a route.
+pub struct ApiKeys {
+    header: HeaderName,
+    keys: KeyTable,
+    file: Option<WatchedFile<KeyTable>>,
+}
+
+impl ApiKeys {
+    pub fn new(config: &ApiKeysConfig) -> Result<Self, String> {
+        let header = HeaderName::from_bytes(
+            config
+                .header
+                .as_deref()
+                .unwrap_or(DEFAULT_HEADER)
+                .as_bytes(),
+        )
+        .map_err(|err| format!("invalid header: {err}"))?;
+        let mut keys = KeyTable::new();
+        for entry in &config.keys {
+            parse_entry(entry, &mut keys)?;
+        }
+        let file = config
+            .keys_file
+            .as_deref()
+            .map(|path| WatchedFile::open(path, read_keys_file))
+            .transpose()?;
+        if keys.is_empty() && file.is_none() {
+            return Err("add keys or a keys_file".to_string());
+        }
+        Ok(Self { header, keys, file })
+    }
+
+    /// The key `request` carries, if it is one of ours.
+    pub fn authenticate(&self, request: &RequestHeader) -> Option<ApiKey> {
+        let key = request.headers.get(&self.header)?;
+        let hash = digest(&SHA256, key.as_bytes());
+        if let Some(key) = self.keys.get(hash.as_ref()) {
+            return Some(key.clone());
+        }
+        self.file.as_ref()?.get().get(hash.as_ref()).cloned()
+    }
+
+    /// Tiers the keys refer to, for checking against `[rate_limit.tiers]`.
+    pub fn tiers(&self) -> Vec<String> {
+        let file = self.file.as_ref().map(|file| file.get());
+        self.keys
+            .values()
+            .chain(file.iter().flat_map(|keys| keys.values()))
+            .filter_map(|key| key.tier.clone())
+            .collect()
+    }
+
+    pub fn refresh(&self) {
+        if let Some(file) = &self.file {
+            file.refresh();
+        }
+    }
+}
+
+impl std::fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeys")
+            .field("header", &self.header)
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
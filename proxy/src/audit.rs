@@ -0,0 +1,76 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+/// `log` target audit events are written to when no `path` is set.
+pub const AUDIT_TARGET: &str = "audit";
+
+static AUDIT_LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// `[audit_log]` section of the config file: admin calls, config reloads, log filter
+/// changes and upstream health transitions, one JSON object per line. Off when absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct AuditConfig {
+    /// File events are appended to; unset logs them at info level under the `audit` target.
+    pub path: Option<String>,
+}
+
+enum Sink {
+    Log,
+    File(Mutex<File>),
+}
+
+struct AuditLog {
+    sink: Sink,
+}
+
+/// Starts recording audit events; until then (or without `[audit_log]`) they are dropped.
+pub fn init(config: &AuditConfig) -> Result<(), String> {
+    let sink = match &config.path {
+        Some(path) => Sink::File(Mutex::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| format!("failed to open audit log {path}: {err}"))?,
+        )),
+        None => Sink::Log,
+    };
+    let _ = AUDIT_LOG.set(AuditLog { sink });
+    Ok(())
+}
+
+/// Records an `event` with its details, which must be a JSON object.
+pub fn record(event: &str, details: Value) {
+    let Some(audit_log) = AUDIT_LOG.get() else {
+        return;
+    };
+    let mut entry = Map::new();
+    entry.insert(
+        "time".to_string(),
+        json!(
+            DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Millis, true)
+        ),
+    );
+    entry.insert("event".to_string(), json!(event));
+    if let Value::Object(details) = details {
+        entry.extend(details);
+    }
+    let line = Value::Object(entry).to_string();
+    match &audit_log.sink {
+        Sink::Log => info!(target: AUDIT_TARGET, "{line}"),
+        Sink::File(file) => {
+            let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Err(err) = writeln!(file, "{line}") {
+                warn!("failed to write audit event {event}: {err}");
+            }
+        }
+    }
+}
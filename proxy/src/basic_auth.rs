@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{BuildHasher, RandomState};
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use pingora::http::{RequestHeader, ResponseHeader};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::bcrypt::BcryptHash;
+use crate::errors::refusal_response;
+use crate::watched_file::WatchedFile;
+
+const DEFAULT_REALM: &str = "Restricted";
+/// Credentials remembered as verified, so bcrypt only runs once per client login.
+const VERIFIED_CACHE_SIZE: usize = 1024;
+/// Highest bcrypt cost taken. Each step doubles the time a check takes, which anyone can make
+/// the proxy spend by sending wrong passwords.
+const MAX_COST: u32 = 14;
+/// Cost of the hash checked for users the file doesn't have, when it has none to copy.
+const DEFAULT_COST: u32 = 10;
+
+/// Bcrypt checks running at once, across routes: one per CPU, so that a flood of wrong
+/// passwords waits its turn instead of filling the blocking thread pool.
+static VERIFICATIONS: LazyLock<Semaphore> = LazyLock::new(|| {
+    Semaphore::new(std::thread::available_parallelism().map_or(1, |cpus| cpus.get()))
+});
+
+/// A route's `basic_auth` table: HTTP Basic authentication against an htpasswd file.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct BasicAuthConfig {
+    /// `user:hash` lines with bcrypt hashes (`htpasswd -B`) of cost 14 at most, re-read when
+    /// the file changes.
+    pub htpasswd_file: String,
+    /// Realm named in the `WWW-Authenticate` challenge.
+    pub realm: Option<String>,
+}
+
+/// Users of an htpasswd file.
+struct Htpasswd {
+    users: HashMap<String, BcryptHash>,
+    /// Checked for users the file doesn't have, at the highest cost in it, so that the time a
+    /// refusal takes doesn't tell which users exist.
+    dummy: BcryptHash,
+    /// Fingerprints of `user:password` pairs that verified.
+    verified: Mutex<HashSet<u64>>,
+    hasher: RandomState,
+}
+
+impl Htpasswd {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        Self::parse(&text, path)
+    }
+
+    fn parse(text: &str, path: &Path) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line.split_once(':').ok_or_else(|| {
+                format!("{} line {}: expected user:hash", path.display(), index + 1)
+            })?;
+            let hash = BcryptHash::parse(hash).map_err(|err| {
+                format!(
+                    "{} line {}: {err} (only bcrypt is supported, use htpasswd -B)",
+                    path.display(),
+                    index + 1
+                )
+            })?;
+            if hash.cost() > MAX_COST {
+                return Err(format!(
+                    "{} line {}: bcrypt cost {} is above the maximum of {MAX_COST}",
+                    path.display(),
+                    index + 1,
+                    hash.cost()
+                ));
+            }
+            users.insert(user.to_string(), hash);
+        }
+        let cost = users
+            .values()
+            .map(BcryptHash::cost)
+            .max()
+            .unwrap_or(DEFAULT_COST);
+        Ok(Self {
+            users,
+            dummy: BcryptHash::dummy(cost),
+            verified: Mutex::default(),
+            hasher: RandomState::new(),
+        })
+    }
+
+    /// Whether `credentials` verified before, which takes no bcrypt check.
+    fn remembers(&self, credentials: &str) -> bool {
+        let fingerprint = self.hasher.hash_one(credentials);
+        self.verified
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .contains(&fingerprint)
+    }
+
+    fn verify(&self, credentials: &str) -> bool {
+        let Some((user, password)) = credentials.split_once(':') else {
+            return false;
+        };
+        let Some(hash) = self.users.get(user) else {
+            self.dummy.verify(password.as_bytes());
+            return false;
+        };
+        if !hash.verify(password.as_bytes()) {
+            return false;
+        }
+        let fingerprint = self.hasher.hash_one(credentials);
+        let mut verified = self
+            .verified
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if verified.len() >= VERIFIED_CACHE_SIZE {
+            verified.clear();
+        }
+        verified.insert(fingerprint);
+        true
+    }
+}
+
+/// HTTP Basic authentication for a route.
+pub struct BasicAuth {
+    realm: String,
+    htpasswd: WatchedFile<Htpasswd>,
+}
+
+impl BasicAuth {
+    pub fn new(config: &BasicAuthConfig) -> Result<Self, String> {
+        let realm = config.realm.as_deref().unwrap_or(DEFAULT_REALM);
+        if realm.contains(['"', '\\']) || realm.chars().any(char::is_control) {
+            return Err("realm must not contain quotes, backslashes or control characters".into());
+        }
+        Ok(Self {
+            realm: realm.to_string(),
+            htpasswd: WatchedFile::open(&config.htpasswd_file, Htpasswd::load)?,
+        })
+    }
+
+    /// Whether the request carries the credentials of a user in the htpasswd file.
+    pub async fn authorizes(&self, req: &RequestHeader) -> bool {
+        let authorization = req
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        self.user(authorization).await.is_some()
+    }
+
+    /// The user whose credentials an `Authorization` (or `Proxy-Authorization`) value
+    /// carries, if they are in the htpasswd file. Hashes are checked on the blocking thread
+    /// pool, as bcrypt is slow on purpose, a few at a time.
+    pub async fn user(&self, authorization: Option<&str>) -> Option<String> {
+        let credentials = authorization
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())?;
+        let user = credentials.split_once(':')?.0.to_string();
+        let htpasswd = self.htpasswd.get();
+        if htpasswd.remembers(&credentials) {
+            return Some(user);
+        }
+        let _permit = VERIFICATIONS.acquire().await.ok()?;
+        tokio::task::spawn_blocking(move || htpasswd.verify(&credentials))
+            .await
+            .is_ok_and(|verified| verified)
+            .then_some(user)
+    }
+
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// The 401 response asking the client for credentials.
+    pub fn challenge(&self, request_id: &str) -> pingora::Result<(ResponseHeader, Bytes)> {
+        let (mut header, body) = refusal_response(401, request_id)?;
+        header.insert_header(
+            WWW_AUTHENTICATE,
+            format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+        )?;
+        Ok((header, body))
+    }
+
+    pub fn refresh(&self) {
+        self.htpasswd.refresh();
+    }
+}
+
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("realm", &self.realm)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "U*U" at cost 5.
+    const HASH: &str = "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+
+    #[test]
+    fn refuses_costs_above_the_maximum() {
+        let path = Path::new("users");
+        let high = HASH.replace("$05$", "$15$");
+        let err = Htpasswd::parse(&format!("alice:{HASH}\nbob:{high}\n"), path)
+            .err()
+            .unwrap();
+        assert!(err.contains("line 2") && err.contains("cost 15"), "{err}");
+        assert!(Htpasswd::parse(&format!("bob:{}", HASH.replace("$05$", "$14$")), path).is_ok());
+    }
+
+    #[test]
+    fn checks_a_dummy_hash_for_unknown_users() {
+        let htpasswd = Htpasswd::parse(&format!("alice:{HASH}"), Path::new("users")).unwrap();
+        assert_eq!(htpasswd.dummy.cost(), 5);
+        assert!(!htpasswd.verify("mallory:U*U"));
+        assert!(!htpasswd.remembers("mallory:U*U"));
+        assert!(!htpasswd.verify("alice:wrong"));
+        assert!(!htpasswd.remembers("alice:wrong"));
+        assert!(htpasswd.verify("alice:U*U"));
+        assert!(htpasswd.remembers("alice:U*U"));
+    }
+}
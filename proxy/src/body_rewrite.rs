@@ -0,0 +1,58 @@
+use http::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+    TRANSFER_ENCODING,
+};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+/// Asks the upstream for an uncompressed response, as only those can be rewritten.
+pub fn ask_uncompressed(request: &mut RequestHeader) {
+    request.remove_header(&ACCEPT_ENCODING);
+}
+
+/// The media type of `response`, lowercased and without parameters.
+pub fn media_type(response: &ResponseHeader) -> Option<String> {
+    response
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|mime| mime.trim().to_ascii_lowercase())
+}
+
+/// Whether the body of `response` can be rewritten: a complete, uncompressed response to a
+/// request other than HEAD, whose media type `accepts`. If so, its headers are adjusted for a
+/// body of a length not known in advance.
+pub fn start(
+    request: &RequestHeader,
+    response: &mut ResponseHeader,
+    accepts: impl Fn(&str) -> bool,
+) -> Result<bool> {
+    let header = |name| {
+        response
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let accepted = media_type(response).is_some_and(|mime| accepts(&mime));
+    let encoded = header(CONTENT_ENCODING).is_some_and(|coding| coding != "identity");
+    // A range can't be changed without breaking the offsets it was asked for.
+    let partial = response.status == http::StatusCode::PARTIAL_CONTENT;
+    if !accepted || encoded || partial || request.method == http::Method::HEAD {
+        return Ok(false);
+    }
+
+    // The body changes, so a strong validator no longer holds.
+    let weak_etag = header(ETAG)
+        .filter(|etag| etag.starts_with('"'))
+        .map(|etag| format!("W/{etag}"));
+    if let Some(etag) = weak_etag {
+        response.insert_header(ETAG, etag)?;
+    }
+    response.remove_header(&CONTENT_LENGTH);
+    response.remove_header(&ACCEPT_RANGES);
+    if request.version == http::Version::HTTP_11 {
+        response.insert_header(TRANSFER_ENCODING, "chunked")?;
+    }
+    Ok(true)
+}
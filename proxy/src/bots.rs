@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use http::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, USER_AGENT};
+use log::debug;
+use pingora::http::{RequestHeader, ResponseHeader};
+use regex::{Regex, RegexSet};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cookies::request_cookie;
+use crate::doh;
+use crate::hex;
+use crate::request_id::REQUEST_ID_HEADER;
+
+const DEFAULT_CHALLENGE_COOKIE: &str = "proxy_challenge";
+const DEFAULT_CHALLENGE_SECONDS: u64 = 24 * 60 * 60;
+/// How long the DNS verification of a crawler's address is trusted.
+const VERIFIED_TTL: Duration = Duration::from_secs(60 * 60);
+/// Crawler addresses remembered at once.
+const MAX_VERIFIED: usize = 10_000;
+/// Time the reverse and forward lookups of a crawler's address have together; one that
+/// takes longer is not verified.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `[bots]` section of the config file: what to do with clients by their `User-Agent`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct BotsConfig {
+    /// User agents (regexes) never refused, challenged or throttled.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// User agents refused with 403.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// User agents that must run a JavaScript challenge before they get through.
+    #[serde(default)]
+    pub challenge: Vec<String>,
+    /// User agents limited by `[[rate_limit.rules]]` with `key = "bot"`, sharing one bucket
+    /// per pattern.
+    #[serde(default)]
+    pub throttle: Vec<String>,
+    /// Search engine crawlers confirmed by reverse and forward DNS. Clients only claiming to
+    /// be one are refused; confirmed ones skip the lists above.
+    #[serde(default)]
+    pub verify: Vec<Crawler>,
+    /// DNS server reverse lookups for `verify` are sent to, an IP address with an optional
+    /// port; the first `nameserver` of `/etc/resolv.conf` by default.
+    pub resolver: Option<String>,
+    /// Cookie a passed challenge is remembered in.
+    pub challenge_cookie: Option<String>,
+    /// How long a passed challenge is remembered.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub challenge_seconds: Option<u64>,
+}
+
+impl BotsConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            challenge_cookie: Some(self.challenge_cookie().to_string()),
+            challenge_seconds: Some(self.challenge_seconds()),
+            ..self
+        }
+    }
+
+    fn challenge_cookie(&self) -> &str {
+        self.challenge_cookie
+            .as_deref()
+            .unwrap_or(DEFAULT_CHALLENGE_COOKIE)
+    }
+
+    fn challenge_seconds(&self) -> u64 {
+        self.challenge_seconds.unwrap_or(DEFAULT_CHALLENGE_SECONDS)
+    }
+}
+
+/// Search engine crawlers whose addresses resolve to their operator's domains.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Crawler {
+    Googlebot,
+    Bingbot,
+    Applebot,
+    Yandexbot,
+    Baiduspider,
+}
+
+impl Crawler {
+    /// User agents the crawler sends.
+    fn user_agent(self) -> &'static str {
+        match self {
+            Self::Googlebot => {
+                r"(?i)googlebot|adsbot-google|mediapartners-google|google-inspectiontool"
+            }
+            Self::Bingbot => r"(?i)bingbot|msnbot|adidxbot|bingpreview",
+            Self::Applebot => r"(?i)applebot",
+            Self::Yandexbot => r"(?i)yandex\w*bot",
+            Self::Baiduspider => r"(?i)baiduspider",
+        }
+    }
+
+    /// Domains the crawler's addresses have reverse DNS names under.
+    fn domains(self) -> &'static [&'static str] {
+        match self {
+            Self::Googlebot => &[".googlebot.com", ".google.com", ".googleusercontent.com"],
+            Self::Bingbot => &[".search.msn.com"],
+            Self::Applebot => &[".applebot.apple.com"],
+            Self::Yandexbot => &[".yandex.ru", ".yandex.net", ".yandex.com"],
+            Self::Baiduspider => &[".baidu.com", ".baidu.jp"],
+        }
+    }
+}
+
+/// What to do with a request, by its user agent.
+pub enum BotVerdict {
+    /// Let it through, counted against `key = "bot"` rate limit rules under `throttle`.
+    Pass {
+        throttle: Option<String>,
+    },
+    Deny,
+    Challenge,
+}
+
+/// The `[bots]` section, compiled.
+pub struct Bots {
+    allow: RegexSet,
+    deny: RegexSet,
+    challenge: RegexSet,
+    throttle: RegexSet,
+    crawlers: Vec<(Crawler, Regex)>,
+    /// Where reverse lookups go; set when crawlers are verified.
+    resolver: Option<SocketAddr>,
+    challenge_cookie: String,
+    challenge_seconds: u64,
+    /// Crawler addresses already checked, with whether they verified.
+    verified: Mutex<HashMap<IpAddr, (bool, Instant)>>,
+}
+
+impl Bots {
+    pub fn new(config: &BotsConfig) -> Result<Self, String> {
+        let set = |name: &str, patterns: &[String]| {
+            RegexSet::new(patterns).map_err(|err| format!("{name}: {err}"))
+        };
+        let resolver = match &config.resolver {
+            Some(resolver) => Some(doh::parse_resolver(resolver)?),
+            None if config.verify.is_empty() => None,
+            None => Some(doh::system_resolver().map_err(|err| format!("verify: {err}"))?),
+        };
+        Ok(Self {
+            allow: set("allow", &config.allow)?,
+            deny: set("deny", &config.deny)?,
+            challenge: set("challenge", &config.challenge)?,
+            throttle: set("throttle", &config.throttle)?,
+            crawlers: config
+                .verify
+                .iter()
+                .map(|crawler| {
+                    let pattern =
+                        Regex::new(crawler.user_agent()).expect("crawler patterns are valid");
+                    (*crawler, pattern)
+                })
+                .collect(),
+            resolver,
+            challenge_cookie: config.challenge_cookie().to_string(),
+            challenge_seconds: config.challenge_seconds(),
+            verified: Mutex::default(),
+        })
+    }
+
+    /// Whether any user agents are throttled, needing a `key = "bot"` rate limit rule.
+    pub fn throttles(&self) -> bool {
+        !self.throttle.is_empty()
+    }
+
+    /// Decides what to do with `request` from `client`.
+    pub async fn classify(&self, request: &RequestHeader, client: Option<IpAddr>) -> BotVerdict {
+        let user_agent = request
+            .headers
+            .get(USER_AGENT)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .unwrap_or_default();
+        if let Some((crawler, _)) = self
+            .crawlers
+            .iter()
+            .find(|(_, pattern)| pattern.is_match(&user_agent))
+        {
+            return match client {
+                Some(ip) if self.verify(*crawler, ip).await => BotVerdict::Pass { throttle: None },
+                _ => BotVerdict::Deny,
+            };
+        }
+        if self.allow.is_match(&user_agent) {
+            return BotVerdict::Pass { throttle: None };
+        }
+        if self.deny.is_match(&user_agent) {
+            return BotVerdict::Deny;
+        }
+        if self.challenge.is_match(&user_agent) && !self.passed_challenge(request, client) {
+            return BotVerdict::Challenge;
+        }
+        let throttle = self
+            .throttle
+            .matches(&user_agent)
+            .iter()
+            .next()
+            .map(|index| self.throttle.patterns()[index].clone());
+        BotVerdict::Pass { throttle }
+    }
+
+    /// Whether `ip` belongs to `crawler`: its reverse DNS name is under one of the crawler's
+    /// domains and resolves back to `ip`.
+    async fn verify(&self, crawler: Crawler, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        {
+            let verified = self
+                .verified
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some((ok, checked)) = verified.get(&ip)
+                && now.duration_since(*checked) < VERIFIED_TTL
+            {
+                return *ok;
+            }
+        }
+        let ok = match self.resolver {
+            Some(resolver) => tokio::time::timeout(VERIFY_TIMEOUT, confirm(resolver, crawler, ip))
+                .await
+                .unwrap_or(false),
+            None => false,
+        };
+        debug!("{ip} claiming to be {crawler:?} verified: {ok}");
+        let mut verified = self
+            .verified
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(ip, (ok, now));
+        ok
+    }
+
+    fn passed_challenge(&self, request: &RequestHeader, client: Option<IpAddr>) -> bool {
+        let Some((expires, signature)) =
+            request_cookie(request, &self.challenge_cookie).and_then(|token| token.split_once('.'))
+        else {
+            return false;
+        };
+        let Ok(expires) = expires.parse::<u64>() else {
+            return false;
+        };
+        let Some(signature) = hex::decode(signature) else {
+            return false;
+        };
+        expires > unix_now()
+            && hmac::verify(
+                challenge_key(),
+                challenge_message(client, expires).as_bytes(),
+                &signature,
+            )
+            .is_ok()
+    }
+
+    /// A page that sets the challenge cookie from JavaScript and reloads itself.
+    pub fn challenge_response(
+        &self,
+        client: Option<IpAddr>,
+        request_id: &str,
+    ) -> pingora::Result<(ResponseHeader, Bytes)> {
+        let expires = unix_now() + self.challenge_seconds;
+        let tag = hmac::sign(
+            challenge_key(),
+            challenge_message(client, expires).as_bytes(),
+        );
+        let cookie = format!(
+            "{}={expires}.{}; Path=/; Max-Age={}; SameSite=Lax",
+            self.challenge_cookie,
+            hex::encode(tag.as_ref()),
+            self.challenge_seconds
+        );
+        let body = Bytes::from(format!(
+            "<!doctype html>\n<meta charset=\"utf-8\">\n<title>Checking your browser</title>\n\
+             <noscript>Please enable JavaScript to continue.</noscript>\n\
+             <script>document.cookie = atob(\"{}\"); location.reload();</script>\n",
+            STANDARD.encode(cookie)
+        ));
+        let mut header = ResponseHeader::build(403, Some(4))?;
+        header.insert_header(CONTENT_TYPE, "text/html; charset=utf-8")?;
+        header.insert_header(CONTENT_LENGTH, body.len())?;
+        header.insert_header(CACHE_CONTROL, "no-store")?;
+        header.insert_header(REQUEST_ID_HEADER, request_id)?;
+        Ok((header, body))
+    }
+}
+
+impl std::fmt::Debug for Bots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Bots")
+            .field("allow", &self.allow.patterns())
+            .field("deny", &self.deny.patterns())
+            .field("challenge", &self.challenge.patterns())
+            .field("throttle", &self.throttle.patterns())
+            .finish()
+    }
+}
+
+/// Key challenge cookies are signed with, for the life of the process.
+fn challenge_key() -> &'static hmac::Key {
+    static KEY: OnceLock<hmac::Key> = OnceLock::new();
+    KEY.get_or_init(|| {
+        hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new() as &dyn SecureRandom)
+            .expect("the system random number generator works")
+    })
+}
+
+fn challenge_message(client: Option<IpAddr>, expires: u64) -> String {
+    let client = client.map(|ip| ip.to_string()).unwrap_or_default();
+    format!("{client}|{expires}")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Whether the reverse DNS name of `ip`, from `resolver`, is under one of the crawler's
+/// domains and resolves back to `ip`.
+async fn confirm(resolver: SocketAddr, crawler: Crawler, ip: IpAddr) -> bool {
+    let name = match doh::reverse_lookup(resolver, ip).await {
+        Ok(Some(name)) => name.trim_end_matches('.').to_ascii_lowercase(),
+        Ok(None) => return false,
+        Err(err) => {
+            debug!("reverse lookup of {ip} at {resolver} failed: {err}");
+            return false;
+        }
+    };
+    crawler
+        .domains()
+        .iter()
+        .any(|domain| name.ends_with(domain))
+        && tokio::net::lookup_host((name.as_str(), 0))
+            .await
+            .is_ok_and(|mut addrs| addrs.any(|addr| addr.ip() == ip))
+}
@@ -0,0 +1,72 @@
+use chrono::{DateTime, SecondsFormat};
+use ring::digest::{SHA256, digest};
+use serde_json::json;
+
+use crate::config::Config;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Commit the binary was built from, `unknown` when built outside a git checkout.
+pub const GIT_SHA: &str = env!("PROXY_GIT_SHA");
+const BUILD_EPOCH: &str = env!("PROXY_BUILD_EPOCH");
+/// Enabled cargo features, comma-separated.
+const FEATURES: &str = env!("PROXY_FEATURES");
+
+/// When the binary was built, as an RFC 3339 timestamp.
+pub fn build_time() -> String {
+    BUILD_EPOCH
+        .parse()
+        .ok()
+        .and_then(|epoch| DateTime::from_timestamp(epoch, 0))
+        .map_or_else(
+            || "unknown".to_string(),
+            |time| time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        )
+}
+
+pub fn features() -> Vec<&'static str> {
+    FEATURES
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect()
+}
+
+/// SHA-256 of the effective config, secrets included, so two processes report the same hash
+/// only when they run the same config.
+pub fn config_hash(config: &Config) -> String {
+    let serialized = toml::Value::try_from(config)
+        .and_then(|value| toml::to_string(&value))
+        .unwrap_or_default();
+    digest(&SHA256, serialized.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// What is running, for the admin API.
+pub fn report(config: &Config) -> serde_json::Value {
+    json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": VERSION,
+        "git_sha": GIT_SHA,
+        "build_time": build_time(),
+        "features": features(),
+        "config_hash": config_hash(config),
+    })
+}
+
+/// One line on what is running, logged at startup.
+pub fn summary(config: &Config) -> String {
+    let features = FEATURES.replace(',', ", ");
+    format!(
+        "{} {VERSION} (git {GIT_SHA}, built {}, features: {}), config hash {}",
+        env!("CARGO_PKG_NAME"),
+        build_time(),
+        if features.is_empty() {
+            "none"
+        } else {
+            &features
+        },
+        config_hash(config),
+    )
+}
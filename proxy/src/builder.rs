@@ -0,0 +1,480 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use pingora::prelude::*;
+use pingora::proxy::http_proxy_service;
+use pingora::server::configuration::ServerConf;
+use pingora::services::background::background_service;
+
+use crate::admin::{AdminService, RuntimeControls};
+use crate::admin_tls::AdminTlsService;
+use crate::audit;
+use crate::build_info;
+use crate::capture::DebugCapture;
+use crate::clients::ClientTraffic;
+use crate::config::{
+    Config, ConfigError, ConfigFormat, ConfigSource, DEFAULT_STATIC_MANIFEST_POLL_SECONDS,
+};
+use crate::dump;
+use crate::endpoints::{EndpointService, LocalEndpoints};
+use crate::error_reporting::UpstreamFailureReporter;
+use crate::filters::{Filter, Filters};
+use crate::forward_proxy::ForwardProxyService;
+use crate::health::{HealthEndpoints, UpstreamHealth, UpstreamHealthChecker};
+use crate::hooks::{Hooks, RequestStart, ResponseComplete, UpstreamSelected};
+use crate::listeners::{self, ListenAddrs};
+use crate::log_control::DebugLogToggleService;
+use crate::metrics::ProxyMetrics;
+use crate::proxy::RoseProxy;
+use crate::proxy_protocol::{self, ProxyProtocolService};
+use crate::rate_limit::RateLimiter;
+use crate::reload::{ConfigReloader, SighupReloadService};
+use crate::routes::RouteConfig;
+use crate::state::{ProxyState, SharedState};
+use crate::static_assets::{self, SelfTestMode, StaticAssets};
+use crate::statsd::StatsdExporter;
+use crate::status::{RequestStats, StatusPage};
+use crate::systemd::{self, SocketActivated, SystemdNotifier};
+use crate::tcp_proxy::TcpProxyService;
+use crate::watched_file::WatchedFileService;
+use crate::websocket::WebSocketDrain;
+
+/// Sets up a proxy server, from a config file as the `proxy` binary does, or in code:
+///
+/// ```no_run
+/// use rose_proxy::{ProxyBuilder, RouteConfig};
+///
+/// let server = ProxyBuilder::new("127.0.0.1:8000")
+///     .listen("127.0.0.1:8080")
+///     .route(RouteConfig {
+///         path_prefix: "/api/".to_string(),
+///         upstream_addr: Some("127.0.0.1:8001".to_string()),
+///         ..RouteConfig::default()
+///     })
+///     .static_files("/", "./dist")
+///     .build()
+///     .unwrap();
+/// server.run_forever();
+/// ```
+///
+/// Logging and Sentry are left to the caller, since they are global to the process.
+pub struct ProxyBuilder {
+    config: Config,
+    source: Option<ConfigSource>,
+    opt: Option<Opt>,
+    filters: Vec<(String, Arc<dyn Filter>)>,
+    hooks: Hooks,
+}
+
+impl ProxyBuilder {
+    /// A proxy sending requests to `upstream_addr`, with nothing else set.
+    pub fn new(upstream_addr: impl Into<String>) -> Self {
+        Self::from_config(Config {
+            upstream_addr: upstream_addr.into(),
+            ..Config::default()
+        })
+    }
+
+    /// A proxy running `config`.
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            source: None,
+            opt: None,
+            filters: Vec::new(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// A proxy running the config file of `source`, reloaded from it on `SIGHUP` and through
+    /// the admin API.
+    pub fn from_file(source: ConfigSource) -> Result<Self, ConfigError> {
+        let config = Config::load(&source)?;
+        Ok(Self::from_config(config).reload_from(source))
+    }
+
+    /// Reloads the config from `source` on `SIGHUP` and through the admin API; without it
+    /// the config only changes on restart.
+    pub fn reload_from(mut self, source: ConfigSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Listens for proxied requests on `addr`, in addition to the addresses already set.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        self.config.listen_addr = Some(match self.config.listen_addr.take() {
+            None => ListenAddrs::One(addr),
+            Some(ListenAddrs::One(first)) => ListenAddrs::Many(vec![first, addr]),
+            Some(ListenAddrs::Many(mut addrs)) => {
+                addrs.push(addr);
+                ListenAddrs::Many(addrs)
+            }
+        });
+        self
+    }
+
+    /// Sends requests no route takes to `addr`.
+    pub fn upstream(mut self, addr: impl Into<String>) -> Self {
+        self.config.upstream_addr = addr.into();
+        self
+    }
+
+    /// Adds a route after those already set; the longest matching `path_prefix` wins.
+    pub fn route(mut self, route: RouteConfig) -> Self {
+        self.config.routes.push(route);
+        self
+    }
+
+    /// Serves the files under `root` at `mount` instead of proxying those paths.
+    pub fn static_files(mut self, mount: impl Into<String>, root: impl Into<String>) -> Self {
+        self.config.static_mount = Some(mount.into());
+        self.config.static_root = Some(root.into());
+        self
+    }
+
+    /// Registers `filter` under `name`, for the `filters` of the config and its routes to
+    /// name. Chains that name none run it after the built-in filters, in the order filters
+    /// were registered.
+    pub fn filter(mut self, name: impl Into<String>, filter: impl Filter + 'static) -> Self {
+        self.filters.push((name.into(), Arc::new(filter)));
+        self
+    }
+
+    /// Calls `hook` as each request starts, once its request ID is known.
+    pub fn on_request_start(
+        mut self,
+        hook: impl Fn(&RequestStart) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.add_request_start(hook);
+        self
+    }
+
+    /// Calls `hook` whenever an upstream is picked for a request, again on each retry.
+    pub fn on_upstream_selected(
+        mut self,
+        hook: impl Fn(&UpstreamSelected) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.add_upstream_selected(hook);
+        self
+    }
+
+    /// Calls `hook` once the proxy is done with a request, with its status, timing and body
+    /// sizes, e.g. for metrics or billing of its own.
+    pub fn on_response_complete(
+        mut self,
+        hook: impl Fn(&ResponseComplete) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.add_response_complete(hook);
+        self
+    }
+
+    /// Pingora's server options, e.g. to daemonize or take over from a running process.
+    pub fn server_opt(mut self, opt: Opt) -> Self {
+        self.opt = Some(opt);
+        self
+    }
+
+    /// The config as it stands.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Validates the config and sets up the server with every service it configures, ready
+    /// for [`Server::run_forever`].
+    pub fn build(self) -> Result<Server, String> {
+        let Self {
+            config,
+            source,
+            opt,
+            filters,
+            hooks,
+        } = self;
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(format!(
+                "invalid configuration:\n{}",
+                ConfigError { problems }
+            ));
+        }
+        if let Some(audit_config) = &config.audit_log {
+            audit::init(audit_config)?;
+        }
+        info!("Running {}", build_info::summary(&config));
+        if let Ok(effective) = dump::dump_config(&config, ConfigFormat::Toml) {
+            debug!("Effective configuration (secrets redacted):\n{effective}");
+        }
+
+        let inherited_sockets = systemd::inherited_sockets();
+        if config.listen_addr.is_none()
+            && config.listen_unix.is_none()
+            && config.proxy_protocol.is_none()
+            && inherited_sockets.is_empty()
+        {
+            return Err(
+                "no listener configured: set listen_addr, listen_unix or proxy_protocol, or use systemd socket activation"
+                    .to_string(),
+            );
+        }
+
+        let default_conf = ServerConf::default();
+        let server_conf = ServerConf {
+            grace_period_seconds: config
+                .grace_period_seconds
+                .or(default_conf.grace_period_seconds),
+            graceful_shutdown_timeout_seconds: config
+                .graceful_shutdown_timeout_seconds
+                .or(default_conf.graceful_shutdown_timeout_seconds),
+            threads: config.threads.unwrap_or(default_conf.threads),
+            ..default_conf
+        };
+        info!("Using ServerConf: {:?}", server_conf);
+
+        let mut my_server = Server::new_with_opt_and_conf(opt, server_conf);
+
+        my_server.bootstrap();
+
+        let manifest_poll = config
+            .static_manifest_poll_seconds
+            .unwrap_or(DEFAULT_STATIC_MANIFEST_POLL_SECONDS);
+
+        // The rate limiter, client tracking and metrics outlive reloads, and so do the
+        // filters that use them.
+        let rate_limiter = Arc::new(RateLimiter::default());
+        let clients = config
+            .clients
+            .as_ref()
+            .map(|clients_config| Arc::new(ClientTraffic::new(clients_config)));
+        let metrics = config
+            .metrics
+            .as_ref()
+            .map(|metrics_config| {
+                ProxyMetrics::new(metrics_config, clients.clone())
+                    .map(Arc::new)
+                    .map_err(|err| format!("failed to set up metrics: {err}"))
+            })
+            .transpose()?;
+        let filters = Filters::new(rate_limiter.clone(), metrics.clone(), filters)?;
+
+        let state = SharedState::new(ProxyState::build(config, None, Arc::new(filters))?);
+
+        if let Some(static_assets) = &state.current().static_assets {
+            run_static_self_test(static_assets, state.current().config.static_self_test)?;
+        }
+
+        my_server.add_service(static_assets::manifest_background(
+            Arc::new(state.clone()),
+            manifest_poll,
+        ));
+        // Only a config loaded from a file has anything to reload on SIGHUP.
+        let reloader = ConfigReloader::new(source.clone(), state.clone());
+        if source.is_some() {
+            my_server.add_service(background_service(
+                "config reload",
+                SighupReloadService::new(reloader.clone()),
+            ));
+        }
+        my_server.add_service(background_service(
+            "watched file reload",
+            WatchedFileService::new(state.clone()),
+        ));
+        my_server.add_service(background_service(
+            "debug log toggle",
+            DebugLogToggleService::new(state.clone()),
+        ));
+        my_server.add_service(background_service("websocket drain", WebSocketDrain));
+
+        let startup = state.current();
+
+        // Local endpoints go on the proxy listeners unless they have their own address.
+        let stats = Arc::new(RequestStats::default());
+        let upstream_health = UpstreamHealth::default();
+        let controls = RuntimeControls::default();
+        let mut on_proxy = LocalEndpoints::default();
+        let mut separate: BTreeMap<String, LocalEndpoints> = BTreeMap::new();
+        if let Some(health_config) = &startup.config.health {
+            my_server.add_service(background_service(
+                "upstream health check",
+                UpstreamHealthChecker::new(health_config, state.clone(), upstream_health.clone()),
+            ));
+            let endpoints = match &health_config.listen_addr {
+                Some(addr) => separate.entry(addr.clone()).or_default(),
+                None => &mut on_proxy,
+            };
+            endpoints.health = Some(Arc::new(HealthEndpoints::new(
+                health_config,
+                state.clone(),
+                upstream_health.clone(),
+                controls.drains.clone(),
+                controls.quiesce.clone(),
+            )));
+        }
+        if let Some(status_config) = &startup.config.status {
+            let endpoints = match &status_config.listen_addr {
+                Some(addr) => separate.entry(addr.clone()).or_default(),
+                None => &mut on_proxy,
+            };
+            endpoints.status = Some(Arc::new(StatusPage::new(
+                status_config,
+                state.clone(),
+                stats.clone(),
+                upstream_health.clone(),
+                clients.clone(),
+            )));
+        }
+        if let Some(metrics_config) = &startup.config.metrics {
+            let endpoints = match &metrics_config.listen_addr {
+                Some(addr) => separate.entry(addr.clone()).or_default(),
+                None => &mut on_proxy,
+            };
+            endpoints.metrics = metrics.clone();
+        }
+        let mut capture = None;
+        if let Some(capture_config) = &startup.config.capture {
+            let debug_capture = Arc::new(DebugCapture::new(capture_config));
+            let endpoints = match &capture_config.listen_addr {
+                Some(addr) => separate.entry(addr.clone()).or_default(),
+                None => &mut on_proxy,
+            };
+            endpoints.capture = Some(debug_capture.clone());
+            capture = Some(debug_capture);
+        }
+        let statsd = match &startup.config.statsd {
+            Some(statsd_config) => {
+                let exporter = StatsdExporter::new(statsd_config)
+                    .map_err(|err| format!("failed to set up statsd export: {err}"))?;
+                info!("Sending statsd metrics to {}", statsd_config.addr);
+                Some(Arc::new(exporter))
+            }
+            None => None,
+        };
+        for (addr, endpoints) in separate {
+            let mut service = pingora::services::listening::Service::new(
+                format!("local endpoints on {addr}"),
+                EndpointService(endpoints),
+            );
+            service.add_tcp(&addr);
+            info!("Local endpoints listening on {addr}");
+            my_server.add_service(service);
+        }
+        if let Some(admin) = &startup.config.admin {
+            let addr = &admin.listen_addr;
+            let api = AdminService::new(
+                state.clone(),
+                reloader,
+                upstream_health.clone(),
+                stats.clone(),
+                rate_limiter.clone(),
+                controls.clone(),
+            );
+            let name = format!("admin API on {addr}");
+            if admin.tls.is_some() {
+                let mut service = pingora::services::listening::Service::new(
+                    name,
+                    AdminTlsService::new(state.clone(), api),
+                );
+                service.add_tcp(addr);
+                my_server.add_service(service);
+                info!("Admin API listening on {addr} over TLS");
+            } else {
+                let mut service = pingora::services::listening::Service::new(name, api);
+                service.add_tcp(addr);
+                my_server.add_service(service);
+                info!("Admin API listening on {addr}");
+            }
+        }
+        if let Some(forward_proxy) = &startup.config.forward_proxy {
+            let addr = &forward_proxy.listen_addr;
+            let mut service = pingora::services::listening::Service::new(
+                format!("forward proxy on {addr}"),
+                ForwardProxyService::new(state.clone()),
+            );
+            service.add_tcp(addr);
+            info!("Forward proxy listening on {addr}");
+            my_server.add_service(service);
+        }
+        if let Some(proxy_protocol) = &startup.config.proxy_protocol {
+            let addr = &proxy_protocol.listen_addr;
+            let mut service = pingora::services::listening::Service::new(
+                format!("PROXY protocol listener on {addr}"),
+                ProxyProtocolService::new(state.clone()),
+            );
+            service.add_tcp(addr);
+            info!("Proxy listening on {addr} for PROXY protocol connections");
+            my_server.add_service(service);
+        }
+        for addr in startup.tcp_routes.listen_addrs() {
+            let mut service = pingora::services::listening::Service::new(
+                format!("TCP routes on {addr}"),
+                TcpProxyService::new(addr.to_string(), state.clone(), metrics.clone()),
+            );
+            service.add_tcp(addr);
+            info!("TCP routes listening on {addr}");
+            my_server.add_service(service);
+        }
+
+        let proxy_config = RoseProxy {
+            state,
+            endpoints: on_proxy,
+            stats,
+            alerts: Arc::default(),
+            concurrency: Arc::default(),
+            metrics,
+            capture,
+            clients,
+            statsd,
+            upstream_failures: startup
+                .config
+                .sentry
+                .as_ref()
+                .map(|sentry_config| Arc::new(UpstreamFailureReporter::new(sentry_config))),
+            controls: startup.config.admin.is_some().then_some(controls),
+            hooks,
+        };
+
+        let mut proxy_service = http_proxy_service(&my_server.configuration, proxy_config);
+        if let Some(proxy) = proxy_service.app_logic_mut() {
+            let listener = startup.config.listener.clone().unwrap_or_default();
+            proxy.server_options = listener.server_options();
+            proxy.h2_options = listener.h2_options();
+        }
+
+        let mut adopted =
+            listeners::add_listeners(&mut proxy_service, &startup.config, &inherited_sockets);
+        if startup.config.proxy_protocol.is_some() {
+            // Connections taken on the PROXY protocol listener go on to the proxy through a
+            // loopback listener bound here, so that its port is known before the service starts.
+            let (addr, fd) = proxy_protocol::bind_bridge()?;
+            proxy_service.add_tcp(&addr);
+            adopted.push((addr, fd));
+        }
+
+        my_server.add_service(SocketActivated::new(proxy_service, adopted));
+        my_server.add_service(background_service("systemd notify", SystemdNotifier));
+        Ok(my_server)
+    }
+}
+
+/// Runs the static asset self-test when enabled, failing on problems in `fail` mode.
+fn run_static_self_test(
+    static_assets: &StaticAssets,
+    mode: Option<SelfTestMode>,
+) -> Result<(), String> {
+    let mode = mode.unwrap_or_default();
+    if mode == SelfTestMode::Off {
+        return Ok(());
+    }
+    let problems = static_assets.self_test();
+    if problems.is_empty() {
+        info!("static self-test passed");
+        return Ok(());
+    }
+    if mode == SelfTestMode::Fail {
+        return Err(format!("static self-test failed:\n{}", problems.join("\n")));
+    }
+    for problem in &problems {
+        warn!("static self-test: {problem}");
+    }
+    Ok(())
+}
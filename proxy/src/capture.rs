@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::{HeaderMap, Method, Response, StatusCode};
+use pingora::http::{RequestHeader, ResponseHeader};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::audit;
+use crate::dump::SECRET_HEADERS;
+use crate::endpoints::local_response;
+
+const DEFAULT_CAPTURE_PATH: &str = "/debug/capture";
+const DEFAULT_CAPACITY: usize = 100;
+const REDACTED: &str = "<redacted>";
+
+/// `[capture]` section of the config file: records requests and responses matching a
+/// filter into a ring buffer served as JSON. Off when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct CaptureConfig {
+    /// Serve the capture endpoint on this address only; unset serves it on the proxy listeners.
+    pub listen_addr: Option<String>,
+    /// GET lists the captures, POST `?enabled=true|false` toggles capturing, DELETE clears them.
+    pub path: Option<String>,
+    /// Capture from startup; otherwise capturing starts when toggled on.
+    pub enabled: Option<bool>,
+    /// Only capture requests under this path.
+    pub path_prefix: Option<String>,
+    /// Only capture requests carrying this header, given as `name` or `name: value`.
+    pub header: Option<String>,
+    /// Percentage of matching requests captured.
+    pub sample_percent: Option<f64>,
+    /// Body bytes kept per request and response; 0 captures headers only.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_body_kb: Option<usize>,
+    /// Captures kept; the oldest are dropped first.
+    pub capacity: Option<usize>,
+}
+
+impl CaptureConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            path: Some(self.path().to_string()),
+            enabled: Some(self.enabled.unwrap_or(false)),
+            sample_percent: Some(self.sample_percent()),
+            max_body_kb: Some(self.max_body_kb.unwrap_or(0)),
+            capacity: Some(self.capacity()),
+            ..self
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or(DEFAULT_CAPTURE_PATH)
+    }
+
+    fn sample_percent(&self) -> f64 {
+        self.sample_percent.unwrap_or(100.0)
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity.unwrap_or(DEFAULT_CAPACITY)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.sample_percent()) {
+            return Err("sample_percent must be between 0 and 100".to_string());
+        }
+        if self.capacity() == 0 {
+            return Err("capacity must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// One captured request and its response.
+#[derive(Serialize, Clone)]
+pub struct CapturedExchange {
+    request_id: String,
+    time: String,
+    client: Option<String>,
+    method: String,
+    uri: String,
+    version: String,
+    request_headers: Vec<(String, String)>,
+    request_body: Option<String>,
+    request_body_truncated: bool,
+    status: u16,
+    response_headers: Vec<(String, String)>,
+    response_body: Option<String>,
+    response_body_truncated: bool,
+    duration_ms: f64,
+}
+
+/// A capture being filled in while its request is handled.
+pub struct PendingCapture {
+    exchange: CapturedExchange,
+    max_body: usize,
+    request_body: Vec<u8>,
+    response_body: Vec<u8>,
+}
+
+impl PendingCapture {
+    pub fn request_body(&mut self, chunk: &Bytes) {
+        self.exchange.request_body_truncated |=
+            append_capped(&mut self.request_body, chunk, self.max_body);
+    }
+
+    pub fn response_body(&mut self, chunk: &Bytes) {
+        self.exchange.response_body_truncated |=
+            append_capped(&mut self.response_body, chunk, self.max_body);
+    }
+}
+
+/// Appends up to `max` bytes in total; returns whether anything was cut off.
+fn append_capped(buffer: &mut Vec<u8>, chunk: &[u8], max: usize) -> bool {
+    let room = max.saturating_sub(buffer.len());
+    buffer.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    chunk.len() > room
+}
+
+/// Debug capture: a "tcpdump lite" for HTTP exchanges, togglable at runtime.
+pub struct DebugCapture {
+    config: CaptureConfig,
+    enabled: AtomicBool,
+    /// Matching requests seen, to spread sampling evenly.
+    seen: AtomicU64,
+    captured: Mutex<VecDeque<CapturedExchange>>,
+}
+
+impl DebugCapture {
+    pub fn new(config: &CaptureConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(config.enabled.unwrap_or(false)),
+            config: config.clone(),
+            seen: AtomicU64::new(0),
+            captured: Mutex::default(),
+        }
+    }
+
+    /// Starts capturing `request` if capturing is on and it matches the filter.
+    pub fn start(
+        &self,
+        request: &RequestHeader,
+        request_id: &str,
+        client: Option<String>,
+    ) -> Option<PendingCapture> {
+        if !self.enabled.load(Ordering::Relaxed) || !self.matches(request) {
+            return None;
+        }
+        let percent = self.config.sample_percent();
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        if ((seen + 1.0) * percent / 100.0).floor() <= (seen * percent / 100.0).floor() {
+            return None;
+        }
+        let max_body = self.config.max_body_kb.unwrap_or(0) * 1024;
+        Some(PendingCapture {
+            exchange: CapturedExchange {
+                request_id: request_id.to_string(),
+                time: DateTime::<Utc>::from(SystemTime::now())
+                    .to_rfc3339_opts(SecondsFormat::Millis, true),
+                client,
+                method: request.method.to_string(),
+                uri: request.uri.to_string(),
+                version: format!("{:?}", request.version),
+                request_headers: captured_headers(&request.headers),
+                request_body: None,
+                request_body_truncated: false,
+                status: 0,
+                response_headers: Vec::new(),
+                response_body: None,
+                response_body_truncated: false,
+                duration_ms: 0.0,
+            },
+            max_body,
+            request_body: Vec::new(),
+            response_body: Vec::new(),
+        })
+    }
+
+    fn matches(&self, request: &RequestHeader) -> bool {
+        if let Some(prefix) = &self.config.path_prefix
+            && !request.uri.path().starts_with(prefix.as_str())
+        {
+            return false;
+        }
+        if let Some(filter) = &self.config.header {
+            let (name, value) = match filter.split_once(':') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (filter.trim(), None),
+            };
+            let mut values = request.headers.get_all(name).iter();
+            return match value {
+                Some(expected) => values.any(|actual| actual.as_bytes() == expected.as_bytes()),
+                None => values.next().is_some(),
+            };
+        }
+        true
+    }
+
+    /// Stores a finished capture, dropping the oldest when full.
+    pub fn finish(
+        &self,
+        mut pending: PendingCapture,
+        response: Option<&ResponseHeader>,
+        elapsed: Duration,
+    ) {
+        let exchange = &mut pending.exchange;
+        if let Some(response) = response {
+            exchange.status = response.status.as_u16();
+            exchange.response_headers = captured_headers(&response.headers);
+        }
+        if pending.max_body > 0 {
+            exchange.request_body =
+                Some(String::from_utf8_lossy(&pending.request_body).into_owned());
+            exchange.response_body =
+                Some(String::from_utf8_lossy(&pending.response_body).into_owned());
+        }
+        exchange.duration_ms = elapsed.as_secs_f64() * 1000.0;
+
+        let mut captured = self
+            .captured
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        while captured.len() >= self.config.capacity() {
+            captured.pop_front();
+        }
+        captured.push_back(pending.exchange);
+    }
+
+    /// Lists, toggles or clears the captures for requests to the capture path.
+    pub fn answer(&self, request: &RequestHeader) -> Option<Response<Vec<u8>>> {
+        if request.uri.path() != self.config.path() {
+            return None;
+        }
+        audit::record(
+            "admin_call",
+            json!({
+                "endpoint": "capture",
+                "method": request.method.as_str(),
+                "uri": request.uri.to_string(),
+            }),
+        );
+        if request.method == Method::POST {
+            let enabled = request.uri.query().and_then(|query| {
+                query.split('&').find_map(|pair| match pair {
+                    "enabled=true" => Some(true),
+                    "enabled=false" => Some(false),
+                    _ => None,
+                })
+            });
+            let Some(enabled) = enabled else {
+                return Some(local_response(
+                    StatusCode::BAD_REQUEST,
+                    "text/plain; charset=utf-8",
+                    b"expected ?enabled=true or ?enabled=false\n".to_vec(),
+                ));
+            };
+            self.enabled.store(enabled, Ordering::Relaxed);
+        } else if request.method == Method::DELETE {
+            self.captured
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clear();
+        } else if request.method != Method::GET {
+            return Some(local_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "text/plain; charset=utf-8",
+                b"use GET, POST or DELETE\n".to_vec(),
+            ));
+        }
+
+        let captured: Vec<_> = self
+            .captured
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .cloned()
+            .collect();
+        let body = json!({
+            "enabled": self.enabled.load(Ordering::Relaxed),
+            "captured": captured,
+        });
+        Some(local_response(
+            StatusCode::OK,
+            "application/json",
+            serde_json::to_vec_pretty(&body).unwrap_or_default(),
+        ))
+    }
+}
+
+/// Headers as name/value pairs, with credentials redacted.
+fn captured_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = name.as_str();
+            let value = if SECRET_HEADERS.contains(&name) || name == "set-cookie" {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use pingora::server::configuration::Opt;
+
+use rose_proxy::config::{ConfigFormat, ConfigSource};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/proxy/config.toml";
+
+/// Command line of the proxy binary: our own flags plus pingora's server flags.
+#[derive(Parser, Debug)]
+#[command(
+    name = "proxy",
+    version,
+    about = "Reverse proxy and static server for the Tar stack"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the proxy config file.
+    #[arg(long, env = "PROXY_CONFIG", default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+
+    /// Config file syntax; detected from the file extension when omitted.
+    #[arg(long, value_enum)]
+    pub format: Option<ConfigFormat>,
+
+    /// Config profile (`[profiles.<name>]`) to overlay on the base config.
+    #[arg(long, env = "PROXY_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Print the effective config (defaults applied, secrets redacted) and exit.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "toml"
+    )]
+    pub dump_config: Option<ConfigFormat>,
+
+    /// Try to take over listening sockets from a running old server.
+    #[arg(short, long)]
+    pub upgrade: bool,
+
+    /// Run in the background.
+    #[arg(short, long)]
+    pub daemon: bool,
+
+    /// Test the configuration and exit.
+    #[arg(short, long)]
+    pub test: bool,
+
+    /// Path to the pingora server configuration file (YAML).
+    #[arg(short = 'c', long)]
+    pub conf: Option<String>,
+
+    /// Accepted and ignored so `cargo test` style invocations don't fail.
+    #[arg(long, hide = true)]
+    pub nocapture: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Write a commented starter config with every option and its default.
+    Init {
+        /// Where to write the config; printed to stdout when omitted.
+        path: Option<PathBuf>,
+
+        /// Overwrite the file if it already exists.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the JSON Schema of the config file format.
+    Schema,
+}
+
+impl Cli {
+    /// Where to load the config from.
+    pub fn config_source(&self) -> ConfigSource {
+        ConfigSource {
+            path: self.config.clone(),
+            format: self.format,
+            profile: self.profile.clone(),
+        }
+    }
+
+    /// The pingora server options carried by this command line.
+    pub fn server_opt(&self) -> Opt {
+        Opt {
+            upgrade: self.upgrade,
+            daemon: self.daemon,
+            nocapture: self.nocapture,
+            test: self.test,
+            conf: self.conf.clone(),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_WINDOW_SECONDS: u64 = 60;
+const DEFAULT_TOP: usize = 10;
+const DEFAULT_MAX_TRACKED: usize = 10_000;
+/// Slots each client's window is split into; traffic ages out one slot at a time.
+const SLOTS: usize = 12;
+
+/// `[clients]` section of the config file: per client IP traffic accounting, reported as
+/// the top talkers on the status page and in the metrics. Off when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct ClientsConfig {
+    /// Sliding window traffic is summed over.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub window_seconds: Option<u64>,
+    /// Clients reported, busiest (by bytes) first.
+    pub top: Option<usize>,
+    /// Client IPs tracked at once; idle ones are dropped first, new ones ignored beyond this.
+    pub max_tracked: Option<usize>,
+}
+
+impl ClientsConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            window_seconds: Some(self.window().as_secs()),
+            top: Some(self.top()),
+            max_tracked: Some(self.max_tracked()),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds.unwrap_or(DEFAULT_WINDOW_SECONDS))
+    }
+
+    fn top(&self) -> usize {
+        self.top.unwrap_or(DEFAULT_TOP)
+    }
+
+    fn max_tracked(&self) -> usize {
+        self.max_tracked.unwrap_or(DEFAULT_MAX_TRACKED)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window().as_secs() < SLOTS as u64 {
+            return Err(format!("window_seconds must be at least {SLOTS}"));
+        }
+        if self.max_tracked() == 0 {
+            return Err("max_tracked must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Slot {
+    requests: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+#[derive(Default)]
+struct ClientWindow {
+    /// Requests currently being handled for the client.
+    in_flight: u64,
+    slots: [Slot; SLOTS],
+    /// Slot number (since start) the newest slot belongs to.
+    newest: u64,
+    /// Client ports seen, with the slot number they were last seen in.
+    connections: HashMap<u16, u64>,
+}
+
+impl ClientWindow {
+    /// Clears the slots that aged out of the window, up to slot number `slot`.
+    fn advance(&mut self, slot: u64) {
+        let elapsed = slot.saturating_sub(self.newest);
+        for step in 1..=elapsed.min(SLOTS as u64) {
+            self.slots[((self.newest + step) % SLOTS as u64) as usize] = Slot::default();
+        }
+        self.newest = self.newest.max(slot);
+        self.connections
+            .retain(|_, seen| slot.saturating_sub(*seen) < SLOTS as u64);
+    }
+
+    fn total(&self) -> Slot {
+        self.slots.iter().fold(Slot::default(), |sum, slot| Slot {
+            requests: sum.requests + slot.requests,
+            bytes_received: sum.bytes_received + slot.bytes_received,
+            bytes_sent: sum.bytes_sent + slot.bytes_sent,
+        })
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight == 0 && self.connections.is_empty()
+    }
+}
+
+/// A client's traffic over the window.
+#[derive(Serialize, Clone)]
+pub struct ClientUsage {
+    pub client: String,
+    /// Requests being handled right now, standing in for its concurrent connections.
+    pub in_flight: u64,
+    /// Connections it made requests on within the window.
+    pub connections: usize,
+    pub requests: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Connections, requests and bytes per client IP over a sliding window.
+pub struct ClientTraffic {
+    started: Instant,
+    slot_length: Duration,
+    top: usize,
+    max_tracked: usize,
+    clients: Mutex<HashMap<IpAddr, ClientWindow>>,
+}
+
+impl ClientTraffic {
+    pub fn new(config: &ClientsConfig) -> Self {
+        Self {
+            started: Instant::now(),
+            slot_length: config.window() / SLOTS as u32,
+            top: config.top(),
+            max_tracked: config.max_tracked(),
+            clients: Mutex::default(),
+        }
+    }
+
+    fn slot(&self) -> u64 {
+        (self.started.elapsed().as_millis() / self.slot_length.as_millis().max(1)) as u64
+    }
+
+    /// Counts a request from `client` as in flight; returns whether it is tracked and
+    /// [`ClientTraffic::request_finished`] must follow.
+    pub fn request_started(&self, client: SocketAddr) -> bool {
+        let slot = self.slot();
+        let mut clients = self
+            .clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if clients.len() >= self.max_tracked && !clients.contains_key(&client.ip()) {
+            clients.retain(|_, window| {
+                window.advance(slot);
+                !window.is_idle()
+            });
+            if clients.len() >= self.max_tracked {
+                return false;
+            }
+        }
+        let window = clients.entry(client.ip()).or_default();
+        window.advance(slot);
+        window.in_flight += 1;
+        window.connections.insert(client.port(), slot);
+        true
+    }
+
+    /// Adds a finished request's bytes to its client's window.
+    pub fn request_finished(&self, client: SocketAddr, bytes_received: usize, bytes_sent: usize) {
+        let slot = self.slot();
+        let mut clients = self
+            .clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(window) = clients.get_mut(&client.ip()) else {
+            return;
+        };
+        window.advance(slot);
+        window.in_flight = window.in_flight.saturating_sub(1);
+        window.connections.insert(client.port(), slot);
+        let current = &mut window.slots[(slot % SLOTS as u64) as usize];
+        current.requests += 1;
+        current.bytes_received += bytes_received as u64;
+        current.bytes_sent += bytes_sent as u64;
+    }
+
+    /// The busiest clients of the window by bytes transferred, busiest first.
+    pub fn top_talkers(&self) -> Vec<ClientUsage> {
+        let slot = self.slot();
+        let mut clients = self
+            .clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut usage: Vec<_> = clients
+            .iter_mut()
+            .filter_map(|(ip, window)| {
+                window.advance(slot);
+                let total = window.total();
+                (total.requests > 0 || window.in_flight > 0).then(|| ClientUsage {
+                    client: ip.to_string(),
+                    in_flight: window.in_flight,
+                    connections: window.connections.len(),
+                    requests: total.requests,
+                    bytes_received: total.bytes_received,
+                    bytes_sent: total.bytes_sent,
+                })
+            })
+            .collect();
+        clients.retain(|_, window| !window.is_idle());
+        usage.sort_by(|a, b| {
+            (b.bytes_received + b.bytes_sent)
+                .cmp(&(a.bytes_received + a.bytes_sent))
+                .then(b.requests.cmp(&a.requests))
+        });
+        usage.truncate(self.top);
+        usage
+    }
+}
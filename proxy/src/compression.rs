@@ -0,0 +1,126 @@
+use pingora::http::ResponseHeader;
+use pingora::modules::http::compression::ResponseCompression;
+use pingora::protocols::http::compression::Algorithm;
+use pingora::proxy::Session;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LEVEL: u32 = 6;
+const DEFAULT_MIN_SIZE_KB: usize = 1;
+const DEFAULT_ENCODINGS: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+/// A content coding responses can be compressed with.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    fn algorithm(self) -> Algorithm {
+        match self {
+            Self::Gzip => Algorithm::Gzip,
+            Self::Brotli => Algorithm::Brotli,
+            Self::Zstd => Algorithm::Zstd,
+        }
+    }
+}
+
+/// `[compression]` section of the config file: compresses text-like upstream responses the
+/// upstream sent uncompressed, with the first coding the client lists in `Accept-Encoding`,
+/// and decompresses ones in a coding the client doesn't accept. Off when absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct CompressionConfig {
+    /// Codings used; a client preferring another one gets the response as it is. Empty to
+    /// only decompress.
+    pub encodings: Option<Vec<Encoding>>,
+    /// 1 (fastest) to 9 (smallest), for every coding.
+    pub level: Option<u32>,
+    /// Responses declaring a smaller `Content-Length` are sent as they are.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub min_size_kb: Option<usize>,
+    /// Decompresses gzip and brotli responses for clients that don't accept the coding.
+    pub decompress: Option<bool>,
+}
+
+impl CompressionConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            encodings: Some(self.encodings.unwrap_or_else(|| DEFAULT_ENCODINGS.to_vec())),
+            level: Some(self.level.unwrap_or(DEFAULT_LEVEL)),
+            min_size_kb: Some(self.min_size_kb.unwrap_or(DEFAULT_MIN_SIZE_KB)),
+            decompress: Some(self.decompress.unwrap_or(true)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.level {
+            Some(level) if !(1..=9).contains(&level) => {
+                Err(format!("level {level} is not between 1 and 9"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Enables compression of the response to `session`, which Pingora's compression module
+    /// applies as the response is written.
+    pub fn enable(&self, session: &mut Session) {
+        let Some(compression) = session
+            .downstream_modules_ctx
+            .get_mut::<ResponseCompression>()
+        else {
+            return;
+        };
+        let level = self.level.unwrap_or(DEFAULT_LEVEL);
+        for encoding in self.encodings.as_deref().unwrap_or(&DEFAULT_ENCODINGS) {
+            compression.adjust_algorithm_level(encoding.algorithm(), level);
+        }
+        compression.adjust_decompression(self.decompress.unwrap_or(true));
+        // The module skipped `Accept-Encoding` when the request arrived, as it was off then.
+        compression.request_filter(session.downstream_session.req_header());
+    }
+
+    /// Turns compression back off for `response` if it is too small, or a stream whose
+    /// events must not wait for a compressor to fill its buffer, as are those of `streaming`
+    /// routes, and decompression if it is a range of the compressed body, which can't be
+    /// decoded on its own.
+    pub fn check_response(
+        &self,
+        session: &mut Session,
+        response: &ResponseHeader,
+        streaming: bool,
+    ) {
+        if response.status.is_informational() {
+            return;
+        }
+        let Some(compression) = session
+            .downstream_modules_ctx
+            .get_mut::<ResponseCompression>()
+        else {
+            return;
+        };
+        let min_size = self.min_size_kb.unwrap_or(DEFAULT_MIN_SIZE_KB) * 1024;
+        let header = |name| {
+            response
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let too_small = header(http::header::CONTENT_LENGTH)
+            .and_then(|length| length.parse::<usize>().ok())
+            .is_some_and(|length| length < min_size);
+        let streamed = streaming
+            || header(http::header::CONTENT_TYPE)
+                .is_some_and(|content_type| content_type.starts_with("text/event-stream"));
+        if too_small || streamed {
+            compression.adjust_level(0);
+        }
+        if response.status == http::StatusCode::PARTIAL_CONTENT {
+            compression.adjust_decompression(false);
+        }
+    }
+}
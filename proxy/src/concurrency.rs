@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A cap on requests in flight at once.
+struct Gate {
+    limit: usize,
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+/// Decrements a gate's queue length however the wait ends.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Requests in flight per client IP.
+type ClientCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// A client's slot in its in-flight requests, freed when dropped.
+pub struct ClientPermit {
+    counts: ClientCounts,
+    client: IpAddr,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let mut counts = self
+            .counts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(count) = counts.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// In-flight request caps, kept across config reloads. A gate whose limit changed is replaced;
+/// requests holding its permits finish without counting against the new one.
+#[derive(Default)]
+pub struct ConcurrencyLimiter {
+    /// Gates by route name; `None` is the global `limits.max_concurrent_requests` one.
+    gates: Mutex<HashMap<Option<String>, Arc<Gate>>>,
+    clients: ClientCounts,
+}
+
+impl ConcurrencyLimiter {
+    /// Takes one of `limit` slots of the gate of `route`, waiting up to `timeout` in a queue of at
+    /// most `queue` requests when they are all taken. `None` means the request is to be shed.
+    /// The slot is freed when the permit is dropped.
+    pub async fn acquire(
+        &self,
+        route: Option<&str>,
+        limit: usize,
+        queue: usize,
+        timeout: Duration,
+    ) -> Option<OwnedSemaphorePermit> {
+        let gate = self.gate(route, limit);
+        if let Ok(permit) = gate.permits.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        if gate.queued.fetch_add(1, Ordering::Relaxed) >= queue {
+            gate.queued.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        let _queued = Queued(&gate.queued);
+        tokio::time::timeout(timeout, gate.permits.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// Counts a request from `client` unless it already has `limit` in flight. Refused
+    /// requests don't wait: a client over its cap is better turned away at once.
+    pub fn enter_client(&self, client: IpAddr, limit: usize) -> Option<ClientPermit> {
+        let mut counts = self
+            .clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count = counts.entry(client).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(ClientPermit {
+            counts: self.clients.clone(),
+            client,
+        })
+    }
+
+    fn gate(&self, route: Option<&str>, limit: usize) -> Arc<Gate> {
+        let mut gates = self
+            .gates
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match gates.get(&route.map(str::to_string)) {
+            Some(gate) if gate.limit == limit => gate.clone(),
+            _ => {
+                let gate = Arc::new(Gate {
+                    limit,
+                    permits: Arc::new(Semaphore::new(limit)),
+                    queued: AtomicUsize::new(0),
+                });
+                gates.insert(route.map(str::to_string), gate.clone());
+                gate
+            }
+        }
+    }
+}
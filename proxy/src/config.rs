@@ -0,0 +1,1795 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use toml::Value;
+
+use crate::access_control::{AccessControl, AccessControlConfig};
+use crate::access_log::{AccessLog, AccessLogConfig};
+use crate::admin::AdminConfig;
+use crate::alerts::AlertConfig;
+use crate::api_keys::ApiKeys;
+use crate::audit::AuditConfig;
+use crate::basic_auth::BasicAuth;
+use crate::bots::{Bots, BotsConfig};
+use crate::capture::CaptureConfig;
+use crate::clients::ClientsConfig;
+use crate::compression::CompressionConfig;
+use crate::cookies::CookieRules;
+use crate::cors::CorsConfig;
+use crate::csrf::CsrfConfig;
+use crate::doh::{Doh, DohConfig};
+use crate::error_reporting::SentryConfig;
+use crate::errors::{ErrorDetail, ErrorPages, ErrorPagesConfig};
+use crate::esi::Esi;
+use crate::forward_proxy::{ForwardProxy, ForwardProxyConfig};
+use crate::forwarded::{ClientIpHeader, TrustedProxies};
+use crate::geoip::{GeoIp, GeoIpConfig};
+use crate::headers::HeaderRules;
+use crate::health::HealthConfig;
+use crate::html_inject::HtmlInjectConfig;
+use crate::images::ImagesConfig;
+use crate::json_redact::JsonRedact;
+use crate::limits::LimitsConfig;
+use crate::listeners::{self, ListenAddrs, ListenerConfig};
+use crate::log_control::{self, DEFAULT_DEBUG_LOG_LEVEL};
+use crate::maintenance::{MaintenanceConfig, MaintenancePage};
+use crate::markdown::MarkdownPages;
+use crate::metrics::MetricsConfig;
+use crate::oidc::{Oidc, OidcConfig};
+use crate::parent_proxy::{ParentProxy, ParentProxyConfig};
+use crate::proxy_protocol::{ProxyProtocol, ProxyProtocolConfig};
+use crate::rate_limit::{RateLimitConfig, RateLimits};
+use crate::rewrites::RewriteConfig;
+use crate::routes::RouteConfig;
+use crate::security_headers::SecurityPreset;
+use crate::signed_urls::SignedUrls;
+use crate::static_assets::SelfTestMode;
+use crate::statsd::StatsdConfig;
+use crate::status::StatusConfig;
+use crate::substitutions::{Substitutions, SubstitutionsConfig};
+use crate::syslog::SyslogConfig;
+use crate::tcp_proxy::{TcpRouteConfig, TcpRoutes};
+use crate::waf::{Waf, WafConfig};
+use crate::wasm::WasmPluginConfig;
+use crate::websocket::WebSocketConfig;
+
+/// Prefix of environment variables overriding config fields, e.g. `PROXY__UPSTREAM_ADDR`.
+const ENV_OVERRIDE_PREFIX: &str = "PROXY__";
+/// Separator between nested keys in override variables, e.g. `PROXY__CORS__MAX_AGE_SECONDS`.
+const ENV_OVERRIDE_SEPARATOR: &str = "__";
+/// Top-level key listing drop-in files to merge into the config.
+const INCLUDE_KEY: &str = "include";
+/// Arrays that included files extend instead of replace.
+const APPENDED_ARRAYS: &[&[&str]] = &[&["routes"]];
+/// Fields holding secrets: redacted in `--dump-config` and loadable from a
+/// file through a `<field>_file` key, e.g. `value_file = "/run/secrets/api"`.
+pub const SECRET_FIELDS: &[&str] = &[
+    "password",
+    "secret",
+    "token",
+    "api_key",
+    "private_key",
+    "dsn",
+    "client_secret",
+    "cookie_secret",
+    "previous_secret",
+];
+/// Header rule values may carry credentials too, e.g. an upstream `Authorization`.
+const FILE_BACKED_FIELDS: &[&str] = &["value"];
+const SECRET_FILE_SUFFIX: &str = "_file";
+/// Top-level table of named overlays, e.g. `[profiles.staging]`.
+const PROFILES_KEY: &str = "profiles";
+/// Stop collecting type errors after this many; they are usually follow-ups by then.
+const MAX_PROBLEMS: usize = 32;
+
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const DEFAULT_VIA_TOKEN: &str = "rose-proxy";
+pub const DEFAULT_STATIC_MOUNT: &str = "/";
+pub const DEFAULT_STATIC_INDEX: &str = "index.html";
+pub const DEFAULT_STATIC_CACHE_SECONDS: u64 = 60;
+pub const DEFAULT_STATIC_IMMUTABLE_CACHE_SECONDS: u64 = 60 * 60 * 24 * 365; // 1 year
+pub const DEFAULT_STATIC_KEEPALIVE_SECONDS: u64 = 60;
+pub const DEFAULT_STATIC_MEMORY_CACHE_MAX_OBJECT_KB: usize = 256;
+pub const DEFAULT_STATIC_MEMORY_CACHE_PROMOTE_HITS: u32 = 3;
+pub const DEFAULT_STATIC_MANIFEST_POLL_SECONDS: u64 = 5;
+
+/// Syntax of the config file.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension, defaulting to TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml") => {
+                ConfigFormat::Yaml
+            }
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    /// Parses `contents`, returning the error message and its line on failure.
+    fn parse(self, contents: &str) -> Result<Value, (String, Option<usize>)> {
+        match self {
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|err| {
+                let line = err
+                    .span()
+                    .map(|span| contents[..span.start].lines().count().max(1));
+                (err.message().to_string(), line)
+            }),
+            ConfigFormat::Yaml => serde_yaml_ng::from_str(contents)
+                .map_err(|err| {
+                    let line = err.location().map(|location| location.line());
+                    (err.to_string(), line)
+                })
+                .and_then(|value| without_nulls(value, "").map_err(|message| (message, None))),
+            ConfigFormat::Json => serde_json::from_str(contents)
+                .map_err(|err| {
+                    let line = Some(err.line()).filter(|line| *line > 0);
+                    (err.to_string(), line)
+                })
+                .and_then(|value| without_nulls(value, "").map_err(|message| (message, None))),
+        }
+    }
+}
+
+/// Converts a YAML or JSON document to TOML, which has no null: null entries of a map are
+/// dropped as if left out, while null items of a list are refused.
+fn without_nulls(value: serde_json::Value, path: &str) -> Result<Value, String> {
+    use serde_json::Value as Json;
+    Ok(match value {
+        // An empty document.
+        Json::Null if path.is_empty() => Value::Table(Default::default()),
+        Json::Null => return Err(format!("`{path}`: null is only allowed as a map value")),
+        Json::Bool(value) => Value::Boolean(value),
+        Json::Number(number) => match number.as_i64() {
+            Some(number) => Value::Integer(number),
+            None if number.is_u64() => {
+                return Err(format!("`{path}`: {number} is too large"));
+            }
+            None => Value::Float(number.as_f64().unwrap_or_default()),
+        },
+        Json::String(value) => Value::String(value),
+        Json::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| without_nulls(item, &format!("{path}[{index}]")))
+                .collect::<Result<_, _>>()?,
+        ),
+        Json::Object(entries) => Value::Table(
+            entries
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| {
+                    let path = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{path}.{key}")
+                    };
+                    Ok((key, without_nulls(value, &path)?))
+                })
+                .collect::<Result<_, String>>()?,
+        ),
+    })
+}
+
+/// JSON Schema of the config file, for editor completion and CI validation.
+pub fn json_schema() -> String {
+    let mut schema = schemars::schema_for!(Config);
+    // `include` and `profiles` are consumed while loading, before the file becomes a `Config`.
+    if let Some(properties) = schema
+        .get_mut("properties")
+        .and_then(|properties| properties.as_object_mut())
+    {
+        properties.insert(
+            INCLUDE_KEY.to_string(),
+            serde_json::json!({
+                "description": "Glob patterns of drop-in files merged on top of this one.",
+                "type": "array",
+                "items": { "type": "string" },
+            }),
+        );
+        properties.insert(
+            PROFILES_KEY.to_string(),
+            serde_json::json!({
+                "description": "Named overlays merged on top of the base config when selected with --profile.",
+                "type": "object",
+                "additionalProperties": { "type": "object" },
+            }),
+        );
+    }
+    serde_json::to_string_pretty(&schema).expect("schema serializes to JSON") + "\n"
+}
+
+/// A single problem found while loading the config.
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    /// Dotted path of the offending field, e.g. `routes[0].path_prefix`.
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    fn in_file(file: &Path, line: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            file: Some(file.to_path_buf()),
+            line,
+            field: None,
+            message: message.into(),
+        }
+    }
+
+    fn field(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            file: None,
+            line: None,
+            field: Some(field.into()),
+            message: message.into(),
+        }
+    }
+
+    /// Fills in the file and line where the field is defined, when it can be found.
+    fn located(mut self, sources: &Sources) -> Self {
+        if let Some(field) = &self.field
+            && let Some((file, line)) = sources.locate(field)
+        {
+            self.file = Some(file.to_path_buf());
+            self.line = Some(line);
+        }
+        self
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{line}: ", file.display())?,
+            (Some(file), None) => write!(f, "{}: ", file.display())?,
+            _ => {}
+        }
+        if let Some(field) = &self.field {
+            write!(f, "`{field}`: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Every problem found in the config, reported together.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl From<ConfigProblem> for ConfigError {
+    fn from(problem: ConfigProblem) -> Self {
+        Self {
+            problems: vec![problem],
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, problem) in self.problems.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{problem}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Contents of every file read for the config, used to point problems at lines.
+#[derive(Default)]
+struct Sources {
+    files: Vec<(PathBuf, String)>,
+}
+
+impl Sources {
+    /// Best-effort location of `field`: the line declaring its last key, found by
+    /// walking the keys in order through the last file that declares all of them.
+    fn locate(&self, field: &str) -> Option<(&Path, usize)> {
+        let keys: Vec<&str> = field
+            .split('.')
+            .map(|key| key.split('[').next().unwrap_or(key))
+            .filter(|key| !key.is_empty())
+            .collect();
+        if keys.is_empty() {
+            return None;
+        }
+
+        self.files.iter().rev().find_map(|(path, contents)| {
+            let mut line = 0;
+            for key in &keys {
+                line += contents
+                    .lines()
+                    .skip(line)
+                    .position(|text| declares(text, key))?
+                    + 1;
+            }
+            Some((path.as_path(), line))
+        })
+    }
+}
+
+/// Checks the `listen_addr` and paths of a section served by the proxy itself.
+fn check_local_endpoint(
+    problems: &mut Vec<ConfigProblem>,
+    section: &str,
+    listen_addr: Option<&str>,
+    paths: &[(&str, &str)],
+) {
+    if let Some(addr) = listen_addr
+        && addr.parse::<std::net::SocketAddr>().is_err()
+    {
+        problems.push(ConfigProblem::field(
+            format!("{section}.listen_addr"),
+            format!("{addr:?} is not an IP:port address"),
+        ));
+    }
+    for (field, path) in paths {
+        if !path.starts_with('/') {
+            problems.push(ConfigProblem::field(
+                format!("{section}.{field}"),
+                format!("{path:?} must start with '/'"),
+            ));
+        }
+    }
+}
+
+/// Whether `line` declares `key` as a TOML key or table header, a YAML key, or a JSON member.
+fn declares(line: &str, key: &str) -> bool {
+    let text = line
+        .trim_start()
+        .trim_start_matches("- ")
+        .trim_start_matches('[')
+        .trim_start_matches('"');
+    let Some(rest) = text.strip_prefix(key) else {
+        return false;
+    };
+    let rest = rest.trim_start_matches(['"', ']']).trim_start();
+    rest.is_empty() || rest.starts_with('=') || rest.starts_with(':')
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct Config {
+    pub upstream_addr: String,
+    pub upstream_proxy_protocol: Option<ProxyProtocol>,
+    /// HTTP proxy that requests to upstreams go through, unless their route has its own.
+    pub parent_proxy: Option<ParentProxyConfig>,
+    pub listen_addr: Option<ListenAddrs>,
+    pub listen_unix: Option<String>,
+    pub listen_unix_mode: Option<String>,
+    pub listener: Option<ListenerConfig>,
+    pub threads: Option<usize>,
+    pub log_level: Option<String>,
+    /// Filter `SIGUSR2` switches to, and back from, while the proxy runs.
+    pub debug_log_level: Option<String>,
+    pub syslog: Option<SyslogConfig>,
+    pub sentry: Option<SentryConfig>,
+    pub access_log: Option<AccessLogConfig>,
+    pub audit_log: Option<AuditConfig>,
+    pub health: Option<HealthConfig>,
+    pub status: Option<StatusConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub alerts: Option<AlertConfig>,
+    pub capture: Option<CaptureConfig>,
+    pub admin: Option<AdminConfig>,
+    /// Page served while maintenance mode is switched on through the admin API.
+    pub maintenance: Option<MaintenanceConfig>,
+    /// Bodies of the errors the proxy answers itself, for clients asking for HTML or JSON.
+    pub error_pages: Option<ErrorPagesConfig>,
+    pub clients: Option<ClientsConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub access_control: Option<AccessControlConfig>,
+    pub geoip: Option<GeoIpConfig>,
+    pub oidc: Option<OidcConfig>,
+    pub limits: Option<LimitsConfig>,
+    pub waf: Option<WafConfig>,
+    pub bots: Option<BotsConfig>,
+    pub csrf: Option<CsrfConfig>,
+    pub websocket: Option<WebSocketConfig>,
+    pub compression: Option<CompressionConfig>,
+    /// Snippets inserted into HTML pages, unless their route has its own.
+    pub html_inject: Option<HtmlInjectConfig>,
+    /// Find-and-replace rules for response bodies, unless their route has its own.
+    pub substitutions: Option<SubstitutionsConfig>,
+    pub images: Option<ImagesConfig>,
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    pub doh: Option<DohConfig>,
+    pub statsd: Option<StatsdConfig>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub grace_period_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub graceful_shutdown_timeout_seconds: Option<u64>,
+    pub static_root: Option<String>,
+    pub static_mount: Option<String>,
+    pub static_index_file: Option<String>,
+    pub static_manifest: Option<String>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub static_default_cache_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub static_immutable_cache_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub static_keepalive_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub static_manifest_poll_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "crate::units::opt_megabytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub static_memory_cache_mb: Option<usize>,
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub static_memory_cache_max_object_kb: Option<usize>,
+    pub static_memory_cache_promote_hits: Option<u32>,
+    pub static_self_test: Option<SelfTestMode>,
+    /// Serves `.md` files under the static mount as HTML pages.
+    pub static_markdown: Option<bool>,
+    /// HTML file the pages are made from, with `{{title}}` and `{{content}}` placeholders.
+    pub static_markdown_template: Option<String>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub client_ip_header: Option<ClientIpHeader>,
+    /// Believes the forwarding headers of peers on `listen_unix`, such as a local TLS
+    /// terminator; off by default.
+    pub trust_unix_peers: Option<bool>,
+    pub cors: Option<CorsConfig>,
+    pub headers: Option<HeaderRules>,
+    /// Filters requests pass through, in order; the built-in ones followed by those
+    /// registered in code by default.
+    pub filters: Option<Vec<String>>,
+    /// WebAssembly modules run as filters, named by their `name`.
+    #[serde(default)]
+    pub wasm_plugins: Vec<WasmPluginConfig>,
+    pub security_headers: Option<SecurityPreset>,
+    pub server_header: Option<String>,
+    pub cookies: Option<CookieRules>,
+    pub rewrite_location: Option<bool>,
+    pub error_detail: Option<ErrorDetail>,
+    pub via_token: Option<String>,
+    #[serde(default)]
+    pub security_header_overrides: BTreeMap<String, String>,
+    /// Path and query rewrites, applied in order before requests are routed.
+    #[serde(default)]
+    pub rewrites: Vec<RewriteConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub tcp_routes: Vec<TcpRouteConfig>,
+    /// Values read through `*_file` keys, so they can be redacted wherever shown.
+    #[serde(skip)]
+    pub file_secrets: FileSecrets,
+    /// Names of the `routes` managed through the admin API: read from `admin.routes_file`,
+    /// or added since startup when there is none.
+    #[serde(skip)]
+    pub managed_routes: Vec<String>,
+}
+
+/// Secrets read from files; `Debug` shows only how many there are.
+#[derive(Clone, Default)]
+pub struct FileSecrets(pub Vec<String>);
+
+impl fmt::Debug for FileSecrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FileSecrets({} redacted)", self.0.len())
+    }
+}
+
+/// Where the config comes from: the file, its syntax, and the selected profile.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// Taken from the file extension when unset.
+    pub format: Option<ConfigFormat>,
+    /// Name of the `[profiles.<name>]` overlay to apply, if any.
+    pub profile: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file, merges its `include` files and the selected profile,
+    /// expands `${VAR}` references, applies `PROXY__*` overrides, and validates
+    /// the result.
+    ///
+    /// Type errors and invalid values are all collected into one [`ConfigError`].
+    pub fn load(source: &ConfigSource) -> Result<Self, ConfigError> {
+        let path = source.path.as_path();
+        let mut sources = Sources::default();
+        let mut value = read_value(path, source.format, &mut sources)?;
+        apply_includes(&mut value, path, &mut sources)?;
+        apply_profile(&mut value, path, source.profile.as_deref())?;
+
+        interpolate(&mut value).map_err(|message| ConfigProblem::in_file(path, None, message))?;
+        apply_env_overrides(&mut value, env::vars()).map_err(|message| ConfigProblem {
+            file: None,
+            line: None,
+            field: None,
+            message,
+        })?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        let managed_routes = apply_routes_file(&mut value, base, &mut sources)?;
+        let mut file_secrets = Vec::new();
+        read_secret_files(&mut value, base, "", &mut file_secrets)
+            .map_err(|(field, message)| ConfigProblem::field(field, message).located(&sources))?;
+
+        let (mut config, mut problems) = deserialize(value, &sources);
+        if let Some(config) = &mut config {
+            config.file_secrets = FileSecrets(file_secrets);
+            config.managed_routes = managed_routes;
+        }
+        if let Some(config) = &config {
+            let reported: Vec<Option<String>> = problems
+                .iter()
+                .map(|problem| problem.field.clone())
+                .collect();
+            problems.extend(
+                config
+                    .validate()
+                    .into_iter()
+                    .filter(|problem| !reported.contains(&problem.field))
+                    .map(|problem| problem.located(&sources)),
+            );
+        }
+        match config {
+            Some(config) if problems.is_empty() => Ok(config),
+            _ => Err(ConfigError { problems }),
+        }
+    }
+
+    /// Carries over the routes `previous` had from the admin API, for a reloaded config that
+    /// keeps them in no `admin.routes_file`.
+    pub fn keep_managed_routes(&mut self, previous: &Config) -> Result<(), ConfigError> {
+        if self
+            .admin
+            .as_ref()
+            .is_none_or(|admin| admin.routes_file.is_some())
+        {
+            return Ok(());
+        }
+        for name in &previous.managed_routes {
+            if let Some(route) = previous.route(name) {
+                self.routes.push(route.clone());
+                self.managed_routes.push(name.clone());
+            }
+        }
+        let problems = self.validate();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+    /// The route named `name`.
+    pub fn route(&self, name: &str) -> Option<&RouteConfig> {
+        self.routes
+            .iter()
+            .find(|route| route.name.as_deref() == Some(name))
+    }
+
+    /// Writes the managed routes to `admin.routes_file`, if set, replacing it at once.
+    pub fn save_managed_routes(&self) -> Result<(), String> {
+        #[derive(Serialize)]
+        struct RoutesFile<'a> {
+            routes: Vec<&'a RouteConfig>,
+        }
+
+        let Some(path) = self
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.routes_file.as_deref())
+        else {
+            return Ok(());
+        };
+        let routes = self
+            .managed_routes
+            .iter()
+            .filter_map(|name| self.route(name))
+            .collect();
+        let text = toml::to_string_pretty(&RoutesFile { routes })
+            .map_err(|err| format!("failed to serialize routes: {err}"))?;
+        let temporary = format!("{path}.tmp");
+        fs::write(&temporary, text)
+            .and_then(|()| fs::rename(&temporary, path))
+            .map_err(|err| format!("failed to write {path}: {err}"))
+    }
+
+    /// This config as the proxy actually runs it, with every default filled in.
+    ///
+    /// Fields whose absence means something (e.g. `server_header`) stay unset.
+    pub fn with_defaults(&self) -> Self {
+        let mut config = self.clone();
+        config
+            .log_level
+            .get_or_insert_with(|| DEFAULT_LOG_LEVEL.to_string());
+        config
+            .debug_log_level
+            .get_or_insert_with(|| DEFAULT_DEBUG_LOG_LEVEL.to_string());
+        if config.static_root.is_some() {
+            config
+                .static_mount
+                .get_or_insert_with(|| DEFAULT_STATIC_MOUNT.to_string());
+            config
+                .static_index_file
+                .get_or_insert_with(|| DEFAULT_STATIC_INDEX.to_string());
+            config
+                .static_default_cache_seconds
+                .get_or_insert(DEFAULT_STATIC_CACHE_SECONDS);
+            config
+                .static_immutable_cache_seconds
+                .get_or_insert(DEFAULT_STATIC_IMMUTABLE_CACHE_SECONDS);
+            config
+                .static_keepalive_seconds
+                .get_or_insert(DEFAULT_STATIC_KEEPALIVE_SECONDS);
+            config
+                .static_manifest_poll_seconds
+                .get_or_insert(DEFAULT_STATIC_MANIFEST_POLL_SECONDS);
+            config.static_memory_cache_mb.get_or_insert(0);
+            config
+                .static_memory_cache_max_object_kb
+                .get_or_insert(DEFAULT_STATIC_MEMORY_CACHE_MAX_OBJECT_KB);
+            config
+                .static_memory_cache_promote_hits
+                .get_or_insert(DEFAULT_STATIC_MEMORY_CACHE_PROMOTE_HITS);
+            config.static_self_test.get_or_insert_with(Default::default);
+            config.static_markdown.get_or_insert(false);
+        }
+        config.trusted_proxies.get_or_insert_with(Vec::new);
+        config.trust_unix_peers.get_or_insert(false);
+        config.error_detail.get_or_insert_with(Default::default);
+        config.cors = Some(config.cors.take().unwrap_or_default().with_defaults());
+        config.syslog = config.syslog.take().map(SyslogConfig::with_defaults);
+        config.sentry = config.sentry.take().map(SentryConfig::with_defaults);
+        config.health = config.health.take().map(HealthConfig::with_defaults);
+        config.status = config.status.take().map(StatusConfig::with_defaults);
+        config.metrics = config.metrics.take().map(MetricsConfig::with_defaults);
+        config.alerts = config.alerts.take().map(AlertConfig::with_defaults);
+        config.capture = config.capture.take().map(CaptureConfig::with_defaults);
+        config.clients = config.clients.take().map(ClientsConfig::with_defaults);
+        config.rate_limit = config.rate_limit.take().map(RateLimitConfig::with_defaults);
+        config.oidc = config.oidc.take().map(OidcConfig::with_defaults);
+        config.waf = config.waf.take().map(WafConfig::with_defaults);
+        config.bots = config.bots.take().map(BotsConfig::with_defaults);
+        config.csrf = config.csrf.take().map(CsrfConfig::with_defaults);
+        config.websocket = Some(config.websocket.take().unwrap_or_default().with_defaults());
+        config.compression = config
+            .compression
+            .take()
+            .map(CompressionConfig::with_defaults);
+        config.substitutions = config
+            .substitutions
+            .take()
+            .map(SubstitutionsConfig::with_defaults);
+        config.images = config.images.take().map(ImagesConfig::with_defaults);
+        config.forward_proxy = config
+            .forward_proxy
+            .take()
+            .map(ForwardProxyConfig::with_defaults);
+        config.doh = config.doh.take().map(DohConfig::with_defaults);
+        config.tcp_routes = std::mem::take(&mut config.tcp_routes)
+            .into_iter()
+            .map(TcpRouteConfig::with_defaults)
+            .collect();
+        config.statsd = config.statsd.take().map(StatsdConfig::with_defaults);
+        config.headers.get_or_insert_with(Default::default);
+        config.security_headers.get_or_insert_with(Default::default);
+        config.cookies.get_or_insert_with(Default::default);
+        let rewrite_location = *config.rewrite_location.get_or_insert(true);
+        let proxy_protocol = *config
+            .upstream_proxy_protocol
+            .get_or_insert_with(Default::default);
+        config
+            .via_token
+            .get_or_insert_with(|| DEFAULT_VIA_TOKEN.to_string());
+        let listener = config.listener.get_or_insert_with(Default::default);
+        listener.reuseport.get_or_insert(false);
+        listener.h2c.get_or_insert(false);
+        config.threads.get_or_insert(1);
+        for route in &mut config.routes {
+            route
+                .upstream_addr
+                .get_or_insert_with(|| self.upstream_addr.clone());
+            route.rewrite_location.get_or_insert(rewrite_location);
+            route.upstream_proxy_protocol.get_or_insert(proxy_protocol);
+        }
+        config
+    }
+
+    /// Checks values that parse but can't work, e.g. a missing `listen_addr`.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+
+        if let Some(listen) = &self.listen_addr
+            && listen.addrs().is_empty()
+        {
+            problems.push(ConfigProblem::field(
+                "listen_addr",
+                "must list at least one address",
+            ));
+        }
+        if self.threads == Some(0) {
+            problems.push(ConfigProblem::field("threads", "must be at least 1"));
+        }
+        for (field, filter) in [
+            ("log_level", &self.log_level),
+            ("debug_log_level", &self.debug_log_level),
+        ] {
+            if let Some(filter) = filter
+                && let Err(message) = log_control::parse_filter(filter)
+            {
+                problems.push(ConfigProblem::field(field, message));
+            }
+        }
+        if let Some(mode) = &self.listen_unix_mode {
+            match listeners::parse_mode(mode) {
+                Err(message) => problems.push(ConfigProblem::field("listen_unix_mode", message)),
+                Ok(mode) if mode & 0o002 != 0 && self.trust_unix_peers == Some(true) => {
+                    problems.push(ConfigProblem::field(
+                        "listen_unix_mode",
+                        "lets any local user connect, who could then pick their client IP \
+                         through trust_unix_peers",
+                    ));
+                }
+                Ok(_) => {}
+            }
+        }
+        if let Some(access_log) = &self.access_log
+            && let Err(message) = AccessLog::new(access_log)
+        {
+            problems.push(ConfigProblem::field("access_log", message));
+        }
+        if let Err(message) = check_upstream(&self.upstream_addr) {
+            problems.push(ConfigProblem::field("upstream_addr", message));
+        }
+        if let Some(parent_proxy) = &self.parent_proxy {
+            if let Err(message) = ParentProxy::new(parent_proxy) {
+                problems.push(ConfigProblem::field("parent_proxy", message));
+            }
+            if self
+                .upstream_proxy_protocol
+                .is_some_and(|protocol| protocol != ProxyProtocol::Off)
+            {
+                problems.push(ConfigProblem::field(
+                    "upstream_proxy_protocol",
+                    "can't be sent through parent_proxy",
+                ));
+            }
+        }
+        for (field, message) in self.headers.iter().flat_map(HeaderRules::problems) {
+            problems.push(ConfigProblem::field(format!("headers.{field}"), message));
+        }
+        for (index, rewrite) in self.rewrites.iter().enumerate() {
+            if let Err(message) = rewrite.validate() {
+                problems.push(ConfigProblem::field(format!("rewrites[{index}]"), message));
+            }
+        }
+        for (index, entry) in self.trusted_proxies.iter().flatten().enumerate() {
+            if let Err(message) = TrustedProxies::parse(std::slice::from_ref(entry)) {
+                problems.push(ConfigProblem::field(
+                    format!("trusted_proxies[{index}]"),
+                    message,
+                ));
+            }
+        }
+        if let Some(root) = &self.static_root
+            && !Path::new(root).is_dir()
+        {
+            problems.push(ConfigProblem::field(
+                "static_root",
+                format!("{root} is not a directory"),
+            ));
+        }
+        if let Some(manifest) = &self.static_manifest
+            && !Path::new(manifest).is_file()
+        {
+            problems.push(ConfigProblem::field(
+                "static_manifest",
+                format!("{manifest} does not exist"),
+            ));
+        }
+        if self.static_markdown == Some(true)
+            && let Err(message) = MarkdownPages::new(self.static_markdown_template.as_deref())
+        {
+            problems.push(ConfigProblem::field("static_markdown_template", message));
+        }
+        for name in self.security_header_overrides.keys() {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(ConfigProblem::field(
+                    "security_header_overrides",
+                    format!("invalid header name {name:?}"),
+                ));
+            }
+        }
+        if let Some(health) = &self.health {
+            check_local_endpoint(
+                &mut problems,
+                "health",
+                health.listen_addr.as_deref(),
+                &[
+                    ("liveness_path", health.liveness_path()),
+                    ("readiness_path", health.readiness_path()),
+                ],
+            );
+            if health.check_interval_seconds == Some(0) {
+                problems.push(ConfigProblem::field(
+                    "health.check_interval_seconds",
+                    "must be at least 1",
+                ));
+            }
+        }
+        if let Some(websocket) = &self.websocket {
+            for (field, seconds) in [
+                (
+                    "websocket.idle_timeout_seconds",
+                    websocket.idle_timeout_seconds,
+                ),
+                (
+                    "websocket.max_lifetime_seconds",
+                    websocket.max_lifetime_seconds,
+                ),
+            ] {
+                if seconds == Some(0) {
+                    problems.push(ConfigProblem::field(field, "must be at least 1"));
+                }
+            }
+        }
+        if let Some(compression) = &self.compression
+            && let Err(message) = compression.validate()
+        {
+            problems.push(ConfigProblem::field("compression", message));
+        }
+        if let Some(substitutions) = &self.substitutions
+            && let Err(message) = Substitutions::new(substitutions)
+        {
+            problems.push(ConfigProblem::field("substitutions", message));
+        }
+        if let Some(images) = &self.images
+            && let Err(message) = images.validate()
+        {
+            problems.push(ConfigProblem::field("images", message));
+        }
+        if let Some(forward_proxy) = &self.forward_proxy {
+            check_local_endpoint(
+                &mut problems,
+                "forward_proxy",
+                Some(&forward_proxy.listen_addr),
+                &[],
+            );
+            if let Err(message) = ForwardProxy::new(forward_proxy) {
+                problems.push(ConfigProblem::field("forward_proxy", message));
+            }
+        }
+        if let Some(proxy_protocol) = &self.proxy_protocol {
+            check_local_endpoint(
+                &mut problems,
+                "proxy_protocol",
+                Some(&proxy_protocol.listen_addr),
+                &[],
+            );
+            if let Err(message) = proxy_protocol.validate() {
+                problems.push(ConfigProblem::field("proxy_protocol", message));
+            }
+        }
+        if let Some(doh) = &self.doh
+            && let Err(message) = Doh::new(doh)
+        {
+            problems.push(ConfigProblem::field("doh", message));
+        }
+        if let Some(syslog) = &self.syslog
+            && let Err(message) = syslog.validate_addr()
+        {
+            problems.push(ConfigProblem::field("syslog.addr", message));
+        }
+        if let Some(sentry) = &self.sentry {
+            if let Err(err) = sentry.dsn.parse::<sentry::types::Dsn>() {
+                problems.push(ConfigProblem::field("sentry.dsn", err.to_string()));
+            }
+            if !(0.0..=1.0).contains(&sentry.sample_rate()) {
+                problems.push(ConfigProblem::field(
+                    "sentry.sample_rate",
+                    "must be between 0 and 1",
+                ));
+            }
+        }
+        if let Some(status) = &self.status {
+            check_local_endpoint(
+                &mut problems,
+                "status",
+                status.listen_addr.as_deref(),
+                &[("path", status.path())],
+            );
+        }
+        if let Some(metrics) = &self.metrics {
+            check_local_endpoint(
+                &mut problems,
+                "metrics",
+                metrics.listen_addr.as_deref(),
+                &[("path", metrics.path())],
+            );
+            if let Err(message) = metrics.validate_buckets() {
+                problems.push(ConfigProblem::field("metrics.latency_buckets", message));
+            }
+        }
+        if let Some(capture) = &self.capture {
+            check_local_endpoint(
+                &mut problems,
+                "capture",
+                capture.listen_addr.as_deref(),
+                &[("path", capture.path())],
+            );
+            if let Err(message) = capture.validate() {
+                problems.push(ConfigProblem::field("capture", message));
+            }
+        }
+        if let Some(admin) = &self.admin {
+            check_local_endpoint(&mut problems, "admin", Some(&admin.listen_addr), &[]);
+            if let Err(message) = admin.validate() {
+                problems.push(ConfigProblem::field("admin", message));
+            }
+            if let Some(tls) = &admin.tls
+                && let Err(message) = tls.validate()
+            {
+                problems.push(ConfigProblem::field("admin.tls", message));
+            }
+            let shared = [
+                self.health
+                    .as_ref()
+                    .and_then(|health| health.listen_addr.as_ref()),
+                self.status
+                    .as_ref()
+                    .and_then(|status| status.listen_addr.as_ref()),
+                self.metrics
+                    .as_ref()
+                    .and_then(|metrics| metrics.listen_addr.as_ref()),
+                self.capture
+                    .as_ref()
+                    .and_then(|capture| capture.listen_addr.as_ref()),
+            ];
+            if shared.contains(&Some(&admin.listen_addr))
+                || self
+                    .listen_addr
+                    .as_ref()
+                    .is_some_and(|addrs| addrs.addrs().contains(&admin.listen_addr))
+            {
+                problems.push(ConfigProblem::field(
+                    "admin.listen_addr",
+                    "must not be shared with another listener",
+                ));
+            }
+        }
+        if let Some(maintenance) = &self.maintenance
+            && let Err(message) = MaintenancePage::new(Some(maintenance))
+        {
+            problems.push(ConfigProblem::field("maintenance.page", message));
+        }
+        if let Some(error_pages) = &self.error_pages
+            && let Err(message) = ErrorPages::new(Some(error_pages))
+        {
+            problems.push(ConfigProblem::field("error_pages", message));
+        }
+        if let Some(alerts) = &self.alerts
+            && let Err(message) = alerts.validate()
+        {
+            problems.push(ConfigProblem::field("alerts", message));
+        }
+        if let Some(clients) = &self.clients
+            && let Err(message) = clients.validate()
+        {
+            problems.push(ConfigProblem::field("clients", message));
+        }
+        if let Some(rate_limit) = &self.rate_limit
+            && let Err(message) = RateLimits::new(rate_limit)
+        {
+            problems.push(ConfigProblem::field("rate_limit", message));
+        }
+        let geoip = match self.geoip.as_ref().map(GeoIp::open).transpose() {
+            Ok(geoip) => geoip.map(Arc::new),
+            Err(message) => {
+                problems.push(ConfigProblem::field("geoip.database", message));
+                None
+            }
+        };
+        if let Some(access_control) = &self.access_control
+            && let Err(message) = AccessControl::new(access_control, geoip.as_ref())
+        {
+            problems.push(ConfigProblem::field("access_control", message));
+        }
+        for (index, route) in self.routes.iter().enumerate() {
+            for (field, message) in route.headers.problems() {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].headers.{field}"),
+                    message,
+                ));
+            }
+            if let Some(access_control) = &route.access_control
+                && let Err(message) = AccessControl::new(access_control, geoip.as_ref())
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].access_control"),
+                    message,
+                ));
+            }
+            if let Some(basic_auth) = &route.basic_auth
+                && let Err(message) = BasicAuth::new(basic_auth)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].basic_auth"),
+                    message,
+                ));
+            }
+            if let Some(signed_urls) = &route.signed_urls
+                && let Err(message) = SignedUrls::new(signed_urls)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].signed_urls"),
+                    message,
+                ));
+            }
+            if let Some(api_keys) = &route.api_keys {
+                match ApiKeys::new(api_keys) {
+                    Ok(api_keys) => {
+                        let rate_limits = self
+                            .rate_limit
+                            .as_ref()
+                            .and_then(|rate_limit| RateLimits::new(rate_limit).ok());
+                        for tier in api_keys.tiers() {
+                            if !rate_limits
+                                .as_ref()
+                                .is_some_and(|rate_limits| rate_limits.has_tier(&tier))
+                            {
+                                problems.push(ConfigProblem::field(
+                                    format!("routes[{index}].api_keys"),
+                                    format!("tier '{tier}' is not in [rate_limit.tiers]"),
+                                ));
+                            }
+                        }
+                    }
+                    Err(message) => problems.push(ConfigProblem::field(
+                        format!("routes[{index}].api_keys"),
+                        message,
+                    )),
+                }
+            }
+            if route.grpc
+                && !self
+                    .listener
+                    .as_ref()
+                    .and_then(|listener| listener.h2c)
+                    .unwrap_or(false)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].grpc"),
+                    "needs h2c = true in [listener], as gRPC clients speak HTTP/2",
+                ));
+            }
+            if (route.grpc || route.grpc_web)
+                && route
+                    .upstream_proxy_protocol
+                    .or(self.upstream_proxy_protocol)
+                    .is_some_and(|protocol| protocol != ProxyProtocol::Off)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].upstream_proxy_protocol"),
+                    "is not sent to HTTP/2 upstreams, which gRPC routes use",
+                ));
+            }
+            if let Some(substitutions) = &route.substitutions
+                && let Err(message) = Substitutions::new(substitutions)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].substitutions"),
+                    message,
+                ));
+            }
+            if let Some(redact) = &route.json_redact
+                && let Err(message) = JsonRedact::new(redact)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].json_redact"),
+                    message,
+                ));
+            }
+            if let Some(esi) = &route.esi
+                && let Err(message) = Esi::new(esi, self.listen_addr.as_ref())
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].esi"),
+                    message,
+                ));
+            }
+            if let Some(parent_proxy) = &route.parent_proxy
+                && let Err(message) = ParentProxy::new(parent_proxy)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].parent_proxy"),
+                    message,
+                ));
+            }
+            if route.parent_proxy.is_some() || self.parent_proxy.is_some() {
+                if route.grpc || route.grpc_web {
+                    problems.push(ConfigProblem::field(
+                        format!("routes[{index}].parent_proxy"),
+                        "takes HTTP/1 requests, not the HTTP/2 of gRPC routes",
+                    ));
+                }
+                if route
+                    .upstream_proxy_protocol
+                    .is_some_and(|protocol| protocol != ProxyProtocol::Off)
+                {
+                    problems.push(ConfigProblem::field(
+                        format!("routes[{index}].upstream_proxy_protocol"),
+                        "can't be sent through parent_proxy",
+                    ));
+                }
+            }
+            if route.oidc && self.oidc.is_none() {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].oidc"),
+                    "needs an [oidc] section",
+                ));
+            }
+        }
+        if let Some(oidc) = &self.oidc
+            && let Err(message) = Oidc::new(oidc)
+        {
+            problems.push(ConfigProblem::field("oidc", message));
+        }
+        if let Some(waf) = &self.waf
+            && let Err(message) = Waf::new(waf)
+        {
+            problems.push(ConfigProblem::field("waf", message));
+        }
+        if let Some(bots) = &self.bots {
+            match Bots::new(bots) {
+                Ok(bots) => {
+                    if bots.throttles()
+                        && !self
+                            .rate_limit
+                            .as_ref()
+                            .and_then(|rate_limit| RateLimits::new(rate_limit).ok())
+                            .is_some_and(|rate_limits| rate_limits.limits_bots())
+                    {
+                        problems.push(ConfigProblem::field(
+                            "bots.throttle",
+                            "needs a [[rate_limit.rules]] entry with key = \"bot\"",
+                        ));
+                    }
+                }
+                Err(message) => problems.push(ConfigProblem::field("bots", message)),
+            }
+        }
+        if let Some(statsd) = &self.statsd
+            && statsd.addr.trim().is_empty()
+        {
+            problems.push(ConfigProblem::field("statsd.addr", "must not be empty"));
+        }
+        for (index, route) in self.routes.iter().enumerate() {
+            if !route.path_prefix.starts_with('/') {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].path_prefix"),
+                    format!("{:?} must start with '/'", route.path_prefix),
+                ));
+            }
+            if let Some(upstream) = &route.upstream_addr
+                && let Err(message) = check_upstream(upstream)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("routes[{index}].upstream_addr"),
+                    message,
+                ));
+            }
+        }
+        for name in &self.managed_routes {
+            let named = self
+                .routes
+                .iter()
+                .filter(|route| route.name.as_deref() == Some(name.as_str()))
+                .count();
+            if named > 1 {
+                problems.push(ConfigProblem::field(
+                    "admin.routes_file",
+                    format!("route '{name}' is named more than once among the routes"),
+                ));
+            }
+        }
+        for (index, route) in self.tcp_routes.iter().enumerate() {
+            check_local_endpoint(
+                &mut problems,
+                &format!("tcp_routes[{index}]"),
+                Some(&route.listen_addr),
+                &[],
+            );
+            if let Err(message) = check_upstream(&route.upstream_addr) {
+                problems.push(ConfigProblem::field(
+                    format!("tcp_routes[{index}].upstream_addr"),
+                    message,
+                ));
+            }
+        }
+        if let Err(message) = TcpRoutes::new(&self.tcp_routes) {
+            problems.push(ConfigProblem::field("tcp_routes", message));
+        }
+        for (index, plugin) in self.wasm_plugins.iter().enumerate() {
+            if plugin.name.trim().is_empty() {
+                problems.push(ConfigProblem::field(
+                    format!("wasm_plugins[{index}].name"),
+                    "must not be empty",
+                ));
+            } else if self.wasm_plugins[..index]
+                .iter()
+                .any(|other| other.name == plugin.name)
+            {
+                problems.push(ConfigProblem::field(
+                    format!("wasm_plugins[{index}].name"),
+                    format!("plugin '{}' is named more than once", plugin.name),
+                ));
+            }
+            if plugin.fuel == Some(0) {
+                problems.push(ConfigProblem::field(
+                    format!("wasm_plugins[{index}].fuel"),
+                    "must be at least 1",
+                ));
+            }
+        }
+        if !self.wasm_plugins.is_empty() && !cfg!(feature = "wasm") {
+            problems.push(ConfigProblem::field(
+                "wasm_plugins",
+                "this proxy was built without the wasm feature",
+            ));
+        }
+        problems
+    }
+}
+
+/// Upstreams are dialled as `host:port`.
+fn check_upstream(addr: &str) -> Result<(), String> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(format!("{addr:?} is not a host:port address")),
+    }
+}
+
+/// Deserializes `value`, collecting every type error instead of stopping at the first.
+///
+/// After each error the offending value is dropped and deserialization retried,
+/// so the returned config (if any) is what remains once every bad value is gone.
+fn deserialize(mut value: Value, sources: &Sources) -> (Option<Config>, Vec<ConfigProblem>) {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+    loop {
+        let err = match serde_path_to_error::deserialize::<_, Config>(value.clone()) {
+            Ok(config) => return (Some(config), problems),
+            Err(err) => err,
+        };
+        let message = err.inner().message().trim().to_string();
+        let path = err.path().to_string();
+
+        // Dropping a bad required value makes it missing; that was already reported.
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.strip_suffix('`'))
+            .map(|name| match path.as_str() {
+                "." => name.to_string(),
+                parent => format!("{parent}.{name}"),
+            });
+        let already_reported = missing.is_some_and(|field| {
+            problems
+                .iter()
+                .any(|problem| problem.field.as_ref() == Some(&field))
+        });
+
+        if !already_reported {
+            problems.push(if path == "." {
+                ConfigProblem {
+                    file: sources.files.first().map(|(file, _)| file.clone()),
+                    line: None,
+                    field: None,
+                    message,
+                }
+            } else {
+                ConfigProblem::field(path, message).located(sources)
+            });
+        }
+        if problems.len() >= MAX_PROBLEMS || !remove_path(&mut value, err.path()) {
+            return (None, problems);
+        }
+    }
+}
+
+/// Removes the value at `path`, returning whether anything was removed.
+fn remove_path(value: &mut Value, path: &serde_path_to_error::Path) -> bool {
+    let segments: Vec<_> = path.iter().collect();
+    let Some((last, parents)) = segments.split_last() else {
+        return false;
+    };
+    let mut target = value;
+    for segment in parents {
+        let next = match (target, segment) {
+            (Value::Table(table), serde_path_to_error::Segment::Map { key }) => table.get_mut(key),
+            (Value::Array(items), serde_path_to_error::Segment::Seq { index }) => {
+                items.get_mut(*index)
+            }
+            _ => None,
+        };
+        let Some(next) = next else {
+            return false;
+        };
+        target = next;
+    }
+    match (target, last) {
+        (Value::Table(table), serde_path_to_error::Segment::Map { key }) => {
+            table.remove(key).is_some()
+        }
+        (Value::Array(items), serde_path_to_error::Segment::Seq { index })
+            if *index < items.len() =>
+        {
+            items.remove(*index);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn read_value(
+    path: &Path,
+    format: Option<ConfigFormat>,
+    sources: &mut Sources,
+) -> Result<Value, ConfigProblem> {
+    let format = format.unwrap_or_else(|| ConfigFormat::from_path(path));
+    let contents = fs::read_to_string(path)
+        .map_err(|err| ConfigProblem::in_file(path, None, format!("failed to read: {err}")))?;
+    let value = format
+        .parse(&contents)
+        .map_err(|(message, line)| ConfigProblem::in_file(path, line, message))?;
+    sources.files.push((path.to_path_buf(), contents));
+    Ok(value)
+}
+
+/// Merges the files matched by the top-level `include` patterns into `root`.
+///
+/// Patterns are resolved relative to the including file's directory and the
+/// matches are merged in path order, each on top of the result so far:
+/// tables merge key by key, `routes` entries are appended, and any other
+/// value replaces the earlier one. Included files may not include further files.
+fn apply_includes(
+    root: &mut Value,
+    path: &Path,
+    sources: &mut Sources,
+) -> Result<(), ConfigProblem> {
+    let Some(patterns) = root
+        .as_table_mut()
+        .and_then(|table| table.remove(INCLUDE_KEY))
+    else {
+        return Ok(());
+    };
+    let patterns: Vec<String> = patterns.try_into().map_err(|_| ConfigProblem {
+        file: Some(path.to_path_buf()),
+        line: None,
+        field: Some(INCLUDE_KEY.to_string()),
+        message: "must be a list of paths".to_string(),
+    })?;
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut files = Vec::new();
+    for pattern in &patterns {
+        let pattern = base.join(pattern);
+        let pattern = pattern.to_string_lossy();
+        let matches = glob::glob(&pattern).map_err(|err| {
+            ConfigProblem::in_file(
+                path,
+                None,
+                format!("invalid include pattern {pattern}: {err}"),
+            )
+        })?;
+        for entry in matches {
+            files.push(entry.map_err(|err| {
+                ConfigProblem::in_file(path, None, format!("failed to read include: {err}"))
+            })?);
+        }
+    }
+    files.sort();
+    files.dedup();
+
+    for file in files {
+        let included = read_value(&file, None, sources)?;
+        if included.get(INCLUDE_KEY).is_some() {
+            return Err(ConfigProblem::in_file(
+                &file,
+                None,
+                format!("included files may not use `{INCLUDE_KEY}` themselves"),
+            ));
+        }
+        merge(root, included, &[]);
+    }
+    Ok(())
+}
+
+/// Appends the routes of `admin.routes_file`, resolved against `base`, to `root`, returning
+/// their names. A missing file has no routes yet: it is written when the first is added.
+fn apply_routes_file(
+    root: &mut Value,
+    base: &Path,
+    sources: &mut Sources,
+) -> Result<Vec<String>, ConfigProblem> {
+    let Some(Value::String(routes_file)) = root
+        .get_mut("admin")
+        .and_then(|admin| admin.get_mut("routes_file"))
+    else {
+        return Ok(Vec::new());
+    };
+    let path = base.join(&*routes_file);
+    *routes_file = path.to_string_lossy().into_owned();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let routes = match read_value(&path, Some(ConfigFormat::Toml), sources)?
+        .as_table_mut()
+        .and_then(|table| table.remove("routes"))
+    {
+        Some(Value::Array(routes)) => routes,
+        None => Vec::new(),
+        Some(_) => {
+            return Err(ConfigProblem::in_file(
+                &path,
+                None,
+                "`routes` must be a list",
+            ));
+        }
+    };
+    let mut names = Vec::new();
+    for route in &routes {
+        let Some(name) = route.get("name").and_then(Value::as_str) else {
+            return Err(ConfigProblem::in_file(
+                &path,
+                None,
+                "every route needs a name",
+            ));
+        };
+        names.push(name.to_string());
+    }
+    let Some(table) = root.as_table_mut() else {
+        return Ok(names);
+    };
+    match table
+        .entry("routes")
+        .or_insert_with(|| Value::Array(Vec::new()))
+    {
+        Value::Array(existing) => existing.extend(routes),
+        _ => return Err(ConfigProblem::field("routes", "must be a list")),
+    }
+    Ok(names)
+}
+
+/// Removes the `profiles` table and merges the selected profile on top of the rest,
+/// with the same semantics as included files.
+fn apply_profile(
+    root: &mut Value,
+    path: &Path,
+    profile: Option<&str>,
+) -> Result<(), ConfigProblem> {
+    let mut profiles = match root
+        .as_table_mut()
+        .and_then(|table| table.remove(PROFILES_KEY))
+    {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err(ConfigProblem {
+                file: Some(path.to_path_buf()),
+                line: None,
+                field: Some(PROFILES_KEY.to_string()),
+                message: "must be a table of named profiles".to_string(),
+            });
+        }
+        None => Default::default(),
+    };
+    let Some(name) = profile else {
+        return Ok(());
+    };
+    let Some(overlay) = profiles.remove(name) else {
+        let available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        let message = if available.is_empty() {
+            format!("profile {name:?} selected but no [{PROFILES_KEY}] are defined")
+        } else {
+            format!(
+                "profile {name:?} is not defined (available: {})",
+                available.join(", ")
+            )
+        };
+        return Err(ConfigProblem::in_file(path, None, message));
+    };
+    if !overlay.is_table() {
+        return Err(ConfigProblem::in_file(
+            path,
+            None,
+            format!("profile {name:?} must be a table"),
+        ));
+    }
+    merge(root, overlay, &[]);
+    Ok(())
+}
+
+fn merge(base: &mut Value, overlay: Value, path: &[&str]) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                let mut nested = path.to_vec();
+                nested.push(&key);
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value, &nested),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(overlay)) if APPENDED_ARRAYS.contains(&path) => {
+            base.extend(overlay);
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Replaces every `<field>_file` key of a secret field with `<field>` set to the
+/// file's contents (without the trailing newline), so Docker and Kubernetes
+/// secret mounts can be used. Relative paths are resolved against `base`.
+fn read_secret_files(
+    value: &mut Value,
+    base: &Path,
+    path: &str,
+    secrets: &mut Vec<String>,
+) -> Result<(), (String, String)> {
+    match value {
+        Value::Table(table) => {
+            let file_keys: Vec<String> = table
+                .keys()
+                .filter(|key| {
+                    key.strip_suffix(SECRET_FILE_SUFFIX).is_some_and(|field| {
+                        SECRET_FIELDS.contains(&field) || FILE_BACKED_FIELDS.contains(&field)
+                    })
+                })
+                .cloned()
+                .collect();
+            for file_key in file_keys {
+                let field = file_key[..file_key.len() - SECRET_FILE_SUFFIX.len()].to_string();
+                let field_path = join_path(path, &file_key);
+                if table.contains_key(&field) {
+                    return Err((
+                        field_path,
+                        format!("set either `{field}` or `{file_key}`, not both"),
+                    ));
+                }
+                let Some(Value::String(file)) = table.remove(&file_key) else {
+                    return Err((field_path, "must be a file path".to_string()));
+                };
+                let contents = fs::read_to_string(base.join(&file))
+                    .map_err(|err| (field_path, format!("failed to read {file}: {err}")))?;
+                let secret = contents.strip_suffix('\n').unwrap_or(&contents);
+                let secret = secret.strip_suffix('\r').unwrap_or(secret);
+                secrets.push(secret.to_string());
+                table.insert(field, Value::String(secret.to_string()));
+            }
+            for (key, item) in table.iter_mut() {
+                read_secret_files(item, base, &join_path(path, key), secrets)?;
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                read_secret_files(item, base, &format!("{path}[{index}]"), secrets)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` in every string value; `$$` is a literal `$`.
+fn interpolate(value: &mut Value) -> Result<(), String> {
+    match value {
+        Value::String(text) => {
+            *text = expand(text)?;
+        }
+        Value::Array(items) => {
+            for item in items {
+                interpolate(item)?;
+            }
+        }
+        Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                interpolate(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand(text: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(stripped) = after.strip_prefix('$') {
+            result.push('$');
+            rest = stripped;
+        } else if let Some(body) = after.strip_prefix('{') {
+            let end = body
+                .find('}')
+                .ok_or_else(|| format!("unterminated ${{...}} in config value {text:?}"))?;
+            let expression = &body[..end];
+            let (name, default) = match expression.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expression, None),
+            };
+            match (env::var(name), default) {
+                (Ok(value), _) => result.push_str(&value),
+                (Err(_), Some(default)) => result.push_str(default),
+                (Err(_), None) => {
+                    return Err(format!(
+                        "environment variable {name} referenced in config is not set"
+                    ));
+                }
+            }
+            rest = &body[end + 1..];
+        } else {
+            result.push('$');
+            rest = after;
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Applies `PROXY__SECTION__FIELD=value` variables on top of the parsed file.
+///
+/// Values are read as TOML literals when they parse as one (numbers, booleans,
+/// arrays) and as plain strings otherwise. Numeric segments index into arrays,
+/// e.g. `PROXY__ROUTES__0__UPSTREAM_ADDR`.
+fn apply_env_overrides(
+    root: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), String> {
+    let mut overrides: Vec<(String, String)> = vars
+        .filter(|(key, _)| key.starts_with(ENV_OVERRIDE_PREFIX))
+        .collect();
+    overrides.sort();
+
+    for (key, raw) in overrides {
+        let path: Vec<String> = key[ENV_OVERRIDE_PREFIX.len()..]
+            .split(ENV_OVERRIDE_SEPARATOR)
+            .map(str::to_ascii_lowercase)
+            .collect();
+        if path.iter().any(String::is_empty) {
+            return Err(format!("malformed config override variable {key}"));
+        }
+
+        let mut target = &mut *root;
+        for segment in &path[..path.len() - 1] {
+            target = descend(target, segment)
+                .ok_or_else(|| format!("{key} does not match a config section"))?;
+        }
+        let last = &path[path.len() - 1];
+        let value = parse_override(&raw);
+        match target {
+            Value::Table(table) => {
+                table.insert(last.clone(), value);
+            }
+            Value::Array(items) => {
+                let slot = last
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| items.get_mut(index))
+                    .ok_or_else(|| format!("{key} does not match a config entry"))?;
+                *slot = value;
+            }
+            _ => return Err(format!("{key} does not match a config section")),
+        }
+    }
+    Ok(())
+}
+
+fn descend<'a>(value: &'a mut Value, segment: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Table(table) => Some(
+            table
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Table(Default::default())),
+        ),
+        Value::Array(items) => segment
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| items.get_mut(index)),
+        _ => None,
+    }
+}
+
+fn parse_override(raw: &str) -> Value {
+    toml::from_str::<toml::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(format: ConfigFormat, contents: &str) -> Result<Config, String> {
+        let value = format.parse(contents).map_err(|(message, _)| message)?;
+        value
+            .try_into()
+            .map_err(|err: toml::de::Error| err.to_string())
+    }
+
+    #[test]
+    fn yaml_nulls_are_left_out() {
+        let config = load(
+            ConfigFormat::Yaml,
+            "upstream_addr: 127.0.0.1:8080\nstatic_root: ~\nserver_header: null\n\
+             routes:\n  - name: api\n    path_prefix: /api/\n    upstream_addr:\n",
+        )
+        .unwrap();
+        assert_eq!(config.static_root, None);
+        assert_eq!(config.server_header, None);
+        assert_eq!(config.routes[0].upstream_addr, None);
+    }
+
+    #[test]
+    fn json_nulls_are_left_out() {
+        let config = load(
+            ConfigFormat::Json,
+            r#"{"upstream_addr": "127.0.0.1:8080", "static_root": null,
+                "routes": [{"name": "api", "path_prefix": "/api/", "upstream_addr": null}]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.static_root, None);
+        assert_eq!(config.routes[0].upstream_addr, None);
+    }
+
+    #[test]
+    fn null_list_items_are_refused() {
+        let err = load(
+            ConfigFormat::Yaml,
+            "trusted_proxies:\n  - 10.0.0.0/8\n  - ~\n",
+        )
+        .unwrap_err();
+        assert!(err.contains("`trusted_proxies[1]`"), "{err}");
+        let err = load(ConfigFormat::Json, r#"{"routes": [null]}"#).unwrap_err();
+        assert!(err.contains("`routes[0]`"), "{err}");
+    }
+
+    #[test]
+    fn empty_yaml_is_an_empty_table() {
+        assert_eq!(
+            ConfigFormat::Yaml.parse(""),
+            Ok(Value::Table(Default::default()))
+        );
+    }
+}
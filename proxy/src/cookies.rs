@@ -0,0 +1,153 @@
+use http::HeaderValue;
+use http::header::{COOKIE, SET_COOKIE};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ProxyError;
+
+/// A `from` -> `to` replacement used for cookie domains and paths.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct CookieRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// `[cookies]` / `[routes.cookies]` section: rewrites applied to upstream `Set-Cookie` headers.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct CookieRules {
+    /// Replaces a matching `Domain` attribute (like nginx `proxy_cookie_domain`).
+    #[serde(default)]
+    pub domain: Vec<CookieRewrite>,
+    /// Replaces a matching `Path` prefix (like nginx `proxy_cookie_path`).
+    #[serde(default)]
+    pub path: Vec<CookieRewrite>,
+    /// Adds the `Secure` attribute when missing.
+    #[serde(default)]
+    pub secure: bool,
+    /// Sets `SameSite`, replacing whatever the upstream sent.
+    pub same_site: Option<SameSite>,
+}
+
+impl CookieRules {
+    fn is_noop(&self) -> bool {
+        self.domain.is_empty() && self.path.is_empty() && !self.secure && self.same_site.is_none()
+    }
+
+    pub fn apply(&self, response: &mut ResponseHeader) -> Result<()> {
+        if self.is_noop() || !response.headers.contains_key(SET_COOKIE) {
+            return Ok(());
+        }
+
+        let rewritten: Vec<String> = response
+            .headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| match value.to_str() {
+                Ok(cookie) => self.rewrite(cookie),
+                Err(_) => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            })
+            .collect();
+
+        response.remove_header(&SET_COOKIE);
+        for cookie in rewritten {
+            let value = HeaderValue::from_str(&cookie).or_else(|err| {
+                ProxyError::Internal
+                    .because("rewritten Set-Cookie is not a valid header value", err)
+            })?;
+            response.append_header(SET_COOKIE, value)?;
+        }
+        Ok(())
+    }
+
+    fn rewrite(&self, cookie: &str) -> String {
+        let mut parts = cookie.split(';').map(str::trim);
+        let pair = parts.next().unwrap_or_default();
+
+        let mut attributes: Vec<String> = Vec::new();
+        let mut has_secure = false;
+        for attribute in parts.filter(|part| !part.is_empty()) {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim())),
+                None => (attribute, None),
+            };
+
+            if key.eq_ignore_ascii_case("domain")
+                && let Some(domain) = value
+            {
+                attributes.push(format!("Domain={}", self.rewrite_domain(domain)));
+            } else if key.eq_ignore_ascii_case("path")
+                && let Some(path) = value
+            {
+                attributes.push(format!("Path={}", self.rewrite_path(path)));
+            } else if key.eq_ignore_ascii_case("samesite") && self.same_site.is_some() {
+                // replaced below
+            } else {
+                has_secure |= key.eq_ignore_ascii_case("secure");
+                attributes.push(attribute.to_string());
+            }
+        }
+
+        if self.secure && !has_secure {
+            attributes.push("Secure".to_string());
+        }
+        if let Some(same_site) = self.same_site {
+            attributes.push(format!("SameSite={}", same_site.as_str()));
+        }
+
+        let mut result = pair.to_string();
+        for attribute in attributes {
+            result.push_str("; ");
+            result.push_str(&attribute);
+        }
+        result
+    }
+
+    fn rewrite_domain(&self, domain: &str) -> String {
+        let bare = domain.trim_start_matches('.');
+        self.domain
+            .iter()
+            .find(|rule| rule.from.trim_start_matches('.').eq_ignore_ascii_case(bare))
+            .map(|rule| rule.to.clone())
+            .unwrap_or_else(|| domain.to_string())
+    }
+
+    fn rewrite_path(&self, path: &str) -> String {
+        self.path
+            .iter()
+            .find(|rule| path.starts_with(&rule.from))
+            .map(|rule| format!("{}{}", rule.to, &path[rule.from.len()..]))
+            .unwrap_or_else(|| path.to_string())
+    }
+}
+
+/// Value of the `name` cookie sent with `request`, if any.
+pub fn request_cookie<'a>(request: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    request
+        .headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value)
+}
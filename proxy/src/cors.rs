@@ -0,0 +1,166 @@
+use http::header::{
+    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_ALLOWED_METHODS: &[&str] = &["GET", "POST", "PUT", "DELETE", "OPTIONS", "PATCH"];
+const DEFAULT_MAX_AGE_SECONDS: u64 = 60 * 60 * 24; // 1 day
+
+/// `[cors]` section of the config file. Every field is optional and falls back
+/// to the permissive defaults the proxy has always used.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests. Unset or `["*"]` reflects any origin.
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    /// Request headers allowed in preflights. Unset reflects `Access-Control-Request-Headers`.
+    pub allowed_headers: Option<Vec<String>>,
+    pub exposed_headers: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub max_age_seconds: Option<u64>,
+    pub allow_credentials: Option<bool>,
+    /// Answer every `OPTIONS` request at the proxy instead of forwarding the
+    /// ones that aren't CORS preflights (the pre-`[cors]` behavior).
+    pub intercept_plain_options: Option<bool>,
+}
+
+/// Resolved CORS policy shared by the proxy and static asset paths.
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    allowed_origins: Option<Vec<String>>,
+    allowed_methods: String,
+    allowed_headers: Option<String>,
+    exposed_headers: Option<String>,
+    max_age: String,
+    allow_credentials: bool,
+    intercept_plain_options: bool,
+}
+
+impl CorsConfig {
+    /// This config with every unset field filled in with its default.
+    pub fn with_defaults(self) -> Self {
+        Self {
+            allowed_origins: self.allowed_origins.or_else(|| Some(vec!["*".to_string()])),
+            allowed_methods: self.allowed_methods.or_else(|| {
+                Some(
+                    DEFAULT_ALLOWED_METHODS
+                        .iter()
+                        .map(|m| m.to_string())
+                        .collect(),
+                )
+            }),
+            allowed_headers: self.allowed_headers,
+            exposed_headers: self.exposed_headers.or_else(|| Some(Vec::new())),
+            max_age_seconds: self.max_age_seconds.or(Some(DEFAULT_MAX_AGE_SECONDS)),
+            allow_credentials: self.allow_credentials.or(Some(true)),
+            intercept_plain_options: self.intercept_plain_options.or(Some(false)),
+        }
+    }
+}
+
+impl CorsPolicy {
+    pub fn new(config: &CorsConfig) -> Self {
+        let allowed_origins = config
+            .allowed_origins
+            .clone()
+            .filter(|origins| !origins.iter().any(|origin| origin == "*"));
+        let allowed_methods = match &config.allowed_methods {
+            Some(methods) => methods.join(", "),
+            None => DEFAULT_ALLOWED_METHODS.join(", "),
+        };
+
+        Self {
+            allowed_origins,
+            allowed_methods,
+            allowed_headers: config.allowed_headers.as_ref().map(|h| h.join(", ")),
+            exposed_headers: config
+                .exposed_headers
+                .as_ref()
+                .filter(|h| !h.is_empty())
+                .map(|h| h.join(", ")),
+            max_age: config
+                .max_age_seconds
+                .unwrap_or(DEFAULT_MAX_AGE_SECONDS)
+                .to_string(),
+            allow_credentials: config.allow_credentials.unwrap_or(true),
+            intercept_plain_options: config.intercept_plain_options.unwrap_or(false),
+        }
+    }
+
+    /// Whether `request` is a CORS preflight: `OPTIONS` with both `Origin`
+    /// and `Access-Control-Request-Method` (just `Origin` when plain `OPTIONS`
+    /// requests are intercepted, as before).
+    pub fn is_preflight(&self, request: &RequestHeader) -> bool {
+        request.method == http::Method::OPTIONS
+            && request.headers.contains_key(ORIGIN)
+            && (request.headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+                || self.intercept_plain_options)
+    }
+
+    /// Whether non-preflight `OPTIONS` requests are answered locally with `200`.
+    pub fn intercepts_plain_options(&self) -> bool {
+        self.intercept_plain_options
+    }
+
+    /// Adds CORS headers to a regular (non-preflight) response.
+    pub fn apply(&self, request: &RequestHeader, response: &mut ResponseHeader) -> Result<()> {
+        if !self.apply_origin(request, response)? {
+            return Ok(());
+        }
+        response.insert_header(ACCESS_CONTROL_ALLOW_METHODS, self.allowed_methods.as_str())?;
+        if let Some(exposed) = &self.exposed_headers {
+            response.insert_header(ACCESS_CONTROL_EXPOSE_HEADERS, exposed.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Builds the `204` answer to a CORS preflight request.
+    pub fn preflight_response(&self, request: &RequestHeader) -> Result<ResponseHeader> {
+        let mut response = ResponseHeader::build(204, None)?;
+        if !self.apply_origin(request, &mut response)? {
+            return Ok(response);
+        }
+
+        response.insert_header(ACCESS_CONTROL_ALLOW_METHODS, self.allowed_methods.as_str())?;
+        match &self.allowed_headers {
+            Some(allowed) => {
+                response.insert_header(ACCESS_CONTROL_ALLOW_HEADERS, allowed.as_str())?;
+            }
+            None => {
+                if let Some(requested) = request.headers.get(ACCESS_CONTROL_REQUEST_HEADERS) {
+                    response.insert_header(ACCESS_CONTROL_ALLOW_HEADERS, requested)?;
+                    response.append_header(VARY, "Access-Control-Request-Headers")?;
+                }
+            }
+        }
+        response.insert_header(ACCESS_CONTROL_MAX_AGE, self.max_age.as_str())?;
+        Ok(response)
+    }
+
+    /// Sets the origin-dependent headers, returning `false` when the origin is not allowed.
+    fn apply_origin(&self, request: &RequestHeader, response: &mut ResponseHeader) -> Result<bool> {
+        let Some(origin_value) = request.headers.get(ORIGIN) else {
+            return Ok(false);
+        };
+
+        if let Some(allowed) = &self.allowed_origins {
+            let origin = origin_value.to_str().unwrap_or_default();
+            if !allowed.iter().any(|candidate| candidate == origin) {
+                return Ok(false);
+            }
+        }
+
+        response.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, origin_value)?;
+        response.append_header(VARY, "Origin")?;
+        if self.allow_credentials {
+            response.insert_header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,75 @@
+use http::Method;
+use pingora::http::RequestHeader;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+
+/// `[csrf]` section of the config file: refuses cross-site requests that could change state,
+/// judged by the `Sec-Fetch-Site` and `Origin` headers browsers send.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct CsrfConfig {
+    /// Other origins allowed to send such requests, e.g. `"https://app.example.com"`.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Allows requests from other subdomains of the same site.
+    pub allow_same_site: Option<bool>,
+}
+
+impl CsrfConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            allow_same_site: Some(self.allow_same_site.unwrap_or(false)),
+            ..self
+        }
+    }
+
+    /// Why `request`, addressed to `host`, is refused, if it is. Safe methods and requests
+    /// without either header, which browsers don't send that way, pass.
+    pub fn check(&self, request: &RequestHeader, host: Option<&str>) -> Option<String> {
+        if is_safe(&request.method) {
+            return None;
+        }
+        let header = |name| {
+            request
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let origin = header(http::header::ORIGIN.as_str());
+        let allowed_origin = origin.is_some_and(|origin| {
+            self.allowed_origins
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        });
+        if let Some(site) = header(SEC_FETCH_SITE) {
+            return match site {
+                "same-origin" | "none" => None,
+                "same-site" if self.allow_same_site.unwrap_or(false) => None,
+                _ if allowed_origin => None,
+                _ => Some(format!("Sec-Fetch-Site: {site}")),
+            };
+        }
+        let origin = origin?;
+        let origin_host = origin.split_once("://").map(|(_, authority)| authority);
+        let same_origin = origin_host.zip(host).is_some_and(|(origin, host)| {
+            without_default_port(origin).eq_ignore_ascii_case(without_default_port(host))
+        });
+        (!same_origin && !allowed_origin).then(|| format!("Origin: {origin}"))
+    }
+}
+
+/// Methods that don't change state, by RFC 9110.
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn without_default_port(authority: &str) -> &str {
+    authority
+        .strip_suffix(":443")
+        .or_else(|| authority.strip_suffix(":80"))
+        .unwrap_or(authority)
+}
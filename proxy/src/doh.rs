@@ -0,0 +1,552 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bytes::Bytes;
+use http::Method;
+use http::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use log::debug;
+use pingora::Result;
+use pingora::http::ResponseHeader;
+use pingora::proxy::Session;
+use ring::rand::{SecureRandom, SystemRandom};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::errors::ProxyError;
+use crate::request_id::REQUEST_ID_HEADER;
+
+const DEFAULT_PATH: &str = "/dns-query";
+const DEFAULT_TIMEOUT_SECONDS: u64 = 5;
+const DNS_PORT: u16 = 53;
+const DNS_MESSAGE: &str = "application/dns-message";
+/// Size of a DNS message header.
+const HEADER_LEN: usize = 12;
+/// Largest DNS message, bounded by the two-byte length prefix used over TCP.
+const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+/// Resource record type of the EDNS pseudo-record, whose TTL field is not a TTL.
+const OPT: u16 = 41;
+/// Resource record type of a reverse DNS name.
+const PTR: u16 = 12;
+/// The Internet class of resource records.
+const IN: u16 = 1;
+/// Compression pointers followed in one name, more than any real message needs.
+const MAX_POINTERS: usize = 16;
+/// Lists the system's resolvers.
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// `[doh]` section of the config file: a DNS-over-HTTPS endpoint (RFC 8484) on the proxy
+/// listeners that forwards queries to a resolver. Off when absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct DohConfig {
+    /// Path queries are accepted on.
+    pub path: Option<String>,
+    /// Resolver queries are forwarded to, an IP address with an optional port (53 by default).
+    pub resolver: String,
+    /// How long the resolver has to answer before the request fails with 504.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub timeout_seconds: Option<u64>,
+}
+
+impl DohConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            path: Some(self.path.unwrap_or_else(|| DEFAULT_PATH.to_string())),
+            timeout_seconds: Some(self.timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECONDS)),
+            resolver: self.resolver,
+        }
+    }
+}
+
+/// A compiled `[doh]` section.
+#[derive(Debug)]
+pub struct Doh {
+    path: String,
+    resolver: SocketAddr,
+    timeout: Duration,
+}
+
+impl Doh {
+    pub fn new(config: &DohConfig) -> Result<Self, String> {
+        let path = config.path.as_deref().unwrap_or(DEFAULT_PATH);
+        if !path.starts_with('/') {
+            return Err(format!("path {path:?} must start with '/'"));
+        }
+        let resolver = parse_resolver(&config.resolver)?;
+        let timeout = config.timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+        if timeout == 0 {
+            return Err("timeout_seconds must be at least 1".to_string());
+        }
+        Ok(Self {
+            path: path.to_string(),
+            resolver,
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+
+    /// Answers `session` if it is for the DoH path; errors carry the status to respond with.
+    pub async fn try_serve(&self, session: &mut Session, request_id: &str) -> Result<bool> {
+        if session.req_header().uri.path() != self.path {
+            return Ok(false);
+        }
+        let query = self.read_query(session).await?;
+        let answer = match tokio::time::timeout(self.timeout, exchange(self.resolver, &query)).await
+        {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(err)) => {
+                debug!(
+                    "request {request_id}: resolver {} failed: {err}",
+                    self.resolver
+                );
+                return ProxyError::BadUpstreamResponse.fail("DNS resolver failed");
+            }
+            Err(_) => {
+                debug!("request {request_id}: resolver {} timed out", self.resolver);
+                return ProxyError::UpstreamTimeout.fail("DNS resolver timed out");
+            }
+        };
+
+        let mut header = ResponseHeader::build(200, Some(4))?;
+        header.insert_header(CONTENT_TYPE, DNS_MESSAGE)?;
+        header.insert_header(CONTENT_LENGTH, answer.len())?;
+        header.insert_header(
+            CACHE_CONTROL,
+            format!("max-age={}", min_ttl(&answer).unwrap_or(0)),
+        )?;
+        header.insert_header(REQUEST_ID_HEADER, request_id)?;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(answer)), true)
+            .await?;
+        Ok(true)
+    }
+
+    /// The DNS query of a GET `?dns=` parameter or a POST body.
+    async fn read_query(&self, session: &mut Session) -> Result<Vec<u8>> {
+        let request = session.req_header();
+        let query = if request.method == Method::GET {
+            let Some(encoded) = request.uri.query().and_then(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|(name, _)| name == "dns")
+                    .map(|(_, value)| value.into_owned())
+            }) else {
+                return ProxyError::BadRequest.fail("missing dns parameter");
+            };
+            URL_SAFE_NO_PAD
+                .decode(encoded.trim_end_matches('='))
+                .or_else(|err| {
+                    ProxyError::BadRequest.because("dns parameter is not base64url", err)
+                })?
+        } else if request.method == Method::POST {
+            let content_type = request
+                .headers
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.split(';').next().unwrap_or("").trim());
+            if !content_type.is_some_and(|value| value.eq_ignore_ascii_case(DNS_MESSAGE)) {
+                return ProxyError::UnsupportedMediaType.fail("not a DNS message");
+            }
+            let mut body = Vec::new();
+            while let Some(chunk) = session.read_request_body().await? {
+                body.extend_from_slice(&chunk);
+                if body.len() > MAX_MESSAGE_LEN {
+                    session.set_keepalive(None);
+                    return ProxyError::PayloadTooLarge.fail("DNS message too long");
+                }
+            }
+            body
+        } else {
+            return ProxyError::MethodNotAllowed.fail("DoH takes GET and POST");
+        };
+        if query.len() < HEADER_LEN || query.len() > MAX_MESSAGE_LEN {
+            return ProxyError::BadRequest.fail("not a DNS message");
+        }
+        Ok(query)
+    }
+}
+
+/// Sends `query` to `resolver` over UDP, and again over TCP if the answer was truncated.
+pub async fn exchange(resolver: SocketAddr, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let local: SocketAddr = if resolver.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(resolver).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let answer = loop {
+        let len = socket.recv(&mut buf).await?;
+        // Stray datagrams that aren't answers to this query are dropped.
+        if len >= HEADER_LEN && buf[..2] == query[..2] && buf[2] & 0x80 != 0 {
+            break &buf[..len];
+        }
+    };
+    if answer[2] & 0x02 == 0 {
+        return Ok(answer.to_vec());
+    }
+
+    let mut stream = TcpStream::connect(resolver).await?;
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+    let len = stream.read_u16().await? as usize;
+    let mut answer = vec![0; len];
+    stream.read_exact(&mut answer).await?;
+    if len < HEADER_LEN || answer[..2] != query[..2] {
+        return Err(std::io::Error::other("answer doesn't match the query"));
+    }
+    Ok(answer)
+}
+
+/// A resolver given as an IP address with an optional port (53 by default).
+pub fn parse_resolver(resolver: &str) -> Result<SocketAddr, String> {
+    resolver
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            resolver
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        })
+        .map_err(|_| format!("resolver {resolver:?} is not an IP address or ip:port"))
+}
+
+/// The first `nameserver` of `/etc/resolv.conf`.
+pub fn system_resolver() -> Result<SocketAddr, String> {
+    let text = std::fs::read_to_string(RESOLV_CONF)
+        .map_err(|err| format!("failed to read {RESOLV_CONF}: {err}"))?;
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|rest| rest.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .next()
+        .ok_or_else(|| format!("no nameserver in {RESOLV_CONF}"))
+}
+
+/// The name `ip` has in reverse DNS, asked of `resolver`; `None` when it has none.
+pub async fn reverse_lookup(resolver: SocketAddr, ip: IpAddr) -> std::io::Result<Option<String>> {
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| std::io::Error::other("no random query id"))?;
+    let answer = exchange(resolver, &ptr_query(ip, u16::from_be_bytes(id))).await?;
+    Ok(ptr_answer(&answer))
+}
+
+/// A recursive query for the PTR record of `ip`.
+fn ptr_query(ip: IpAddr, id: u16) -> Vec<u8> {
+    let labels: Vec<String> = match ip {
+        IpAddr::V4(ip) => ip.octets().iter().rev().map(u8::to_string).collect(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [byte & 0x0f, byte >> 4])
+            .map(|nibble| format!("{nibble:x}"))
+            .collect(),
+    };
+    let zone: &[&str] = match ip {
+        IpAddr::V4(_) => &["in-addr", "arpa"],
+        IpAddr::V6(_) => &["ip6", "arpa"],
+    };
+    let mut query = id.to_be_bytes().to_vec();
+    // Recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in labels
+        .iter()
+        .map(String::as_str)
+        .chain(zone.iter().copied())
+    {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&PTR.to_be_bytes());
+    query.extend_from_slice(&IN.to_be_bytes());
+    query
+}
+
+/// The name in the first PTR record of the answer `message`.
+fn ptr_answer(message: &[u8]) -> Option<String> {
+    let count = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]) as usize;
+    // A response without an error.
+    if message.len() < HEADER_LEN || message[2] & 0x80 == 0 || message[3] & 0x0f != 0 {
+        return None;
+    }
+    let mut pos = HEADER_LEN;
+    for _ in 0..count(4) {
+        pos = skip_name(message, pos)? + 4;
+    }
+    for _ in 0..count(6) {
+        pos = skip_name(message, pos)?;
+        let fixed = message.get(pos..pos + 10)?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdata_len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+        if kind == PTR {
+            return read_name(message, pos);
+        }
+        pos += rdata_len;
+    }
+    None
+}
+
+/// The (possibly compressed) domain name starting at `pos`, e.g. `crawl.googlebot.com`.
+fn read_name(message: &[u8], mut pos: usize) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(labels.join(".")),
+            len if len & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                pos = usize::from(u16::from_be_bytes([len & 0x3f, *message.get(pos + 1)?]));
+            }
+            len => {
+                let label = message.get(pos + 1..pos + 1 + len as usize)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len as usize;
+            }
+        }
+    }
+}
+
+/// The lowest TTL of the records in `message`, which bounds how long it may be cached.
+fn min_ttl(message: &[u8]) -> Option<u32> {
+    let count = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]) as usize;
+    if message.len() < HEADER_LEN {
+        return None;
+    }
+    let mut pos = HEADER_LEN;
+    for _ in 0..count(4) {
+        pos = skip_name(message, pos)? + 4;
+    }
+    let mut ttl = None::<u32>;
+    for _ in 0..count(6) + count(8) + count(10) {
+        pos = skip_name(message, pos)?;
+        let fixed = message.get(pos..pos + 10)?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let record_ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let rdata_len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if kind != OPT {
+            ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        }
+        pos += 10 + rdata_len;
+    }
+    (pos <= message.len()).then_some(ttl).flatten()
+}
+
+/// Position just past the (possibly compressed) domain name starting at `pos`.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An answer to `query` with `records`, each a name offset, type and rdata.
+    fn answer(query: &[u8], records: &[(u16, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut message = query.to_vec();
+        message[2] |= 0x80;
+        message[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for (name, kind, rdata) in records {
+            message.extend(record(*name, *kind, 300, rdata));
+        }
+        message
+    }
+
+    fn record(name: u16, kind: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut record = (0xc000 | name).to_be_bytes().to_vec();
+        record.extend_from_slice(&kind.to_be_bytes());
+        record.extend_from_slice(&IN.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    fn name(labels: &[&str]) -> Vec<u8> {
+        let mut name = Vec::new();
+        for label in labels {
+            name.push(label.len() as u8);
+            name.extend_from_slice(label.as_bytes());
+        }
+        name.push(0);
+        name
+    }
+
+    #[test]
+    fn ptr_queries_name_the_reverse_zone() {
+        let query = ptr_query("66.249.66.1".parse().unwrap(), 0x1234);
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(
+            read_name(&query, HEADER_LEN).as_deref(),
+            Some("1.66.249.66.in-addr.arpa")
+        );
+        assert_eq!(&query[query.len() - 4..], &[0, 12, 0, 1]);
+
+        let query = ptr_query("2001:db8::567:89ab".parse().unwrap(), 1);
+        assert_eq!(
+            read_name(&query, HEADER_LEN).as_deref(),
+            Some("b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa")
+        );
+    }
+
+    #[test]
+    fn reads_the_ptr_record() {
+        let query = ptr_query("66.249.66.1".parse().unwrap(), 7);
+        let host = name(&["crawl-66-249-66-1", "googlebot", "com"]);
+        // A CNAME first, then the PTR record.
+        let message = answer(
+            &query,
+            &[(12, 5, name(&["alias", "arpa"])), (12, PTR, host)],
+        );
+        assert_eq!(
+            ptr_answer(&message).as_deref(),
+            Some("crawl-66-249-66-1.googlebot.com")
+        );
+
+        // A name ending in a pointer to the question's zone.
+        let mut compressed = name(&["host"]);
+        compressed.pop();
+        compressed.extend_from_slice(&(0xc000u16 | (HEADER_LEN as u16 + 12)).to_be_bytes());
+        let message = answer(&query, &[(12, PTR, compressed)]);
+        assert_eq!(ptr_answer(&message).as_deref(), Some("host.in-addr.arpa"));
+    }
+
+    #[test]
+    fn ptr_answers_without_a_name() {
+        let query = ptr_query("192.0.2.1".parse().unwrap(), 7);
+        // Not a response, no records, NXDOMAIN.
+        assert_eq!(ptr_answer(&query), None);
+        assert_eq!(ptr_answer(&answer(&query, &[])), None);
+        let mut nxdomain = answer(&query, &[(12, PTR, name(&["host"]))]);
+        nxdomain[3] |= 3;
+        assert_eq!(ptr_answer(&nxdomain), None);
+        // Truncated, and a pointer loop.
+        let message = answer(&query, &[(12, PTR, name(&["host"]))]);
+        assert_eq!(ptr_answer(&message[..message.len() - 3]), None);
+        let looped = answer(&query, &[(12, PTR, vec![0xc0, 0x0c])]);
+        let at = looped.len() - 2;
+        let mut looped = looped;
+        looped[at + 1] = at as u8;
+        assert_eq!(ptr_answer(&looped), None);
+    }
+
+    #[test]
+    fn resolvers_default_to_port_53() {
+        assert_eq!(
+            parse_resolver("192.0.2.53"),
+            Ok("192.0.2.53:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_resolver("[2001:db8::53]:5353"),
+            Ok("[2001:db8::53]:5353".parse().unwrap())
+        );
+        assert!(parse_resolver("dns.example").is_err());
+    }
+
+    #[test]
+    fn caches_answers_for_their_lowest_ttl() {
+        let query = ptr_query("192.0.2.1".parse().unwrap(), 7);
+        let mut message = query.clone();
+        message[2] |= 0x80;
+        // Two answers, one authority record and the EDNS record.
+        message[6..12].copy_from_slice(&[0, 2, 0, 1, 0, 1]);
+        message.extend(record(12, PTR, 600, &[0]));
+        message.extend(record(12, PTR, 120, &[0]));
+        message.extend(record(12, 6, 3600, &[0; 4]));
+        message.extend(record(0, OPT, 0, &[]));
+        assert_eq!(min_ttl(&message), Some(120));
+
+        assert_eq!(min_ttl(&answer(&query, &[])), None);
+        assert_eq!(min_ttl(&message[..message.len() - 1]), None);
+        assert_eq!(min_ttl(&query[..HEADER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn refuses_invalid_configs() {
+        let doh = |config: &str| Doh::new(&toml::from_str(config).unwrap());
+        let defaults = doh("resolver = \"192.0.2.53\"").unwrap();
+        assert_eq!(defaults.path, DEFAULT_PATH);
+        assert_eq!(defaults.resolver, "192.0.2.53:53".parse().unwrap());
+        assert_eq!(
+            defaults.timeout,
+            Duration::from_secs(DEFAULT_TIMEOUT_SECONDS)
+        );
+        for (config, error) in [
+            (
+                "resolver = \"192.0.2.53\"\npath = \"dns\"",
+                "must start with '/'",
+            ),
+            ("resolver = \"dns.example\"", "is not an IP address"),
+            (
+                "resolver = \"192.0.2.53\"\ntimeout_seconds = 0",
+                "at least 1",
+            ),
+        ] {
+            let err = doh(config).err().unwrap();
+            assert!(err.contains(error), "{err}");
+        }
+    }
+
+    #[test]
+    fn retries_truncated_answers_over_tcp() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let resolver = udp.local_addr().unwrap();
+            let tcp = tokio::net::TcpListener::bind(resolver).await.unwrap();
+            let query = ptr_query("192.0.2.1".parse().unwrap(), 0x4242);
+            let full = answer(&query, &[(12, PTR, vec![4, b'h', b'o', b's', b't', 0])]);
+            let server = {
+                let (query, full) = (query.clone(), full.clone());
+                async move {
+                    let mut buf = [0; 512];
+                    let (_, client) = udp.recv_from(&mut buf).await.unwrap();
+                    // A stray datagram, then the truncated answer.
+                    let mut stray = full.clone();
+                    stray[0] ^= 0xff;
+                    udp.send_to(&stray, client).await.unwrap();
+                    let mut truncated = answer(&query, &[]);
+                    truncated[2] |= 0x02;
+                    udp.send_to(&truncated, client).await.unwrap();
+
+                    let (mut stream, _) = tcp.accept().await.unwrap();
+                    let len = stream.read_u16().await.unwrap() as usize;
+                    let mut received = vec![0; len];
+                    stream.read_exact(&mut received).await.unwrap();
+                    assert_eq!(received, query);
+                    stream.write_u16(full.len() as u16).await.unwrap();
+                    stream.write_all(&full).await.unwrap();
+                }
+            };
+            let (answer, ()) = tokio::join!(exchange(resolver, &query), server);
+            let answer = answer.unwrap();
+            assert_eq!(answer, full);
+            assert_eq!(ptr_answer(&answer).as_deref(), Some("host"));
+        });
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+/// Upstreams drained through the admin API and the requests in flight to each, kept across
+/// config reloads. A drained upstream gets no new requests; those already sent finish.
+#[derive(Clone, Default)]
+pub struct UpstreamDrains {
+    inner: Arc<Mutex<HashMap<String, DrainState>>>,
+}
+
+/// Whether an upstream is draining, and how many requests to it are in flight.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct DrainState {
+    pub draining: bool,
+    pub in_flight: usize,
+}
+
+/// A request's slot in the in-flight count of its upstream, freed when dropped.
+pub struct UpstreamPermit {
+    drains: UpstreamDrains,
+    upstream: String,
+}
+
+impl Drop for UpstreamPermit {
+    fn drop(&mut self) {
+        let mut upstreams = self.drains.lock();
+        if let Some(traffic) = upstreams.get_mut(&self.upstream) {
+            traffic.in_flight -= 1;
+            if traffic.in_flight == 0 && !traffic.draining {
+                upstreams.remove(&self.upstream);
+            }
+        }
+    }
+}
+
+impl UpstreamDrains {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, DrainState>> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Counts a request to `upstream` until the permit is dropped, or `None` when the
+    /// upstream is draining and the request is to be refused.
+    pub fn admit(&self, upstream: &str) -> Option<UpstreamPermit> {
+        let mut upstreams = self.lock();
+        let traffic = match upstreams.get_mut(upstream) {
+            Some(traffic) => traffic,
+            None => upstreams.entry(upstream.to_string()).or_default(),
+        };
+        if traffic.draining {
+            return None;
+        }
+        traffic.in_flight += 1;
+        Some(UpstreamPermit {
+            drains: self.clone(),
+            upstream: upstream.to_string(),
+        })
+    }
+
+    /// Starts or stops draining `upstream`; returns its state now.
+    pub fn set_draining(&self, upstream: &str, draining: bool) -> DrainState {
+        let mut upstreams = self.lock();
+        let traffic = upstreams.entry(upstream.to_string()).or_default();
+        traffic.draining = draining;
+        let state = *traffic;
+        if !draining && state.in_flight == 0 {
+            upstreams.remove(upstream);
+        }
+        state
+    }
+
+    pub fn get(&self, upstream: &str) -> DrainState {
+        self.lock().get(upstream).copied().unwrap_or_default()
+    }
+
+    pub fn is_draining(&self, upstream: &str) -> bool {
+        self.lock()
+            .get(upstream)
+            .is_some_and(|traffic| traffic.draining)
+    }
+}
+
+/// Quiesce started through the admin API ahead of a restart: readiness fails and connections
+/// close after their current response, so load balancers move clients elsewhere.
+#[derive(Clone, Default)]
+pub struct Quiesce {
+    since: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl Quiesce {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<SystemTime>> {
+        self.since
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Starts quiescing, unless already; returns whether it was started now.
+    pub fn start(&self) -> bool {
+        let mut since = self.lock();
+        let started = since.is_none();
+        since.get_or_insert_with(SystemTime::now);
+        started
+    }
+
+    /// Stops quiescing; returns whether it was on.
+    pub fn stop(&self) -> bool {
+        self.lock().take().is_some()
+    }
+
+    /// When quiescing started, if it is on.
+    pub fn since(&self) -> Option<SystemTime> {
+        *self.lock()
+    }
+}
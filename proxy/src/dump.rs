@@ -0,0 +1,62 @@
+use toml::Value;
+
+use crate::config::{Config, ConfigFormat, SECRET_FIELDS};
+
+const REDACTED: &str = "<redacted>";
+
+/// Header rule targets whose values are credentials.
+pub const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+/// Renders the effective config (defaults applied, secrets redacted) in `format`.
+pub fn dump_config(config: &Config, format: ConfigFormat) -> Result<String, String> {
+    let mut value = Value::try_from(config.with_defaults())
+        .map_err(|err| format!("failed to serialize config: {err}"))?;
+    redact(&mut value, &config.file_secrets.0);
+
+    match format {
+        ConfigFormat::Toml => toml::to_string_pretty(&value).map_err(|err| err.to_string()),
+        ConfigFormat::Yaml => serde_yaml_ng::to_string(&value).map_err(|err| err.to_string()),
+        ConfigFormat::Json => serde_json::to_string_pretty(&value)
+            .map(|json| json + "\n")
+            .map_err(|err| err.to_string()),
+    }
+}
+
+/// `value` as JSON, with secrets redacted as in [`dump_config`].
+pub fn redacted_json(
+    value: &impl serde::Serialize,
+    config: &Config,
+) -> Result<serde_json::Value, String> {
+    let mut value = Value::try_from(value).map_err(|err| format!("failed to serialize: {err}"))?;
+    redact(&mut value, &config.file_secrets.0);
+    serde_json::to_value(value).map_err(|err| err.to_string())
+}
+
+/// Redacts secret fields, credential headers, and any value read from a secret file.
+fn redact(value: &mut Value, file_secrets: &[String]) {
+    match value {
+        Value::String(text) if file_secrets.contains(text) => {
+            *text = REDACTED.to_string();
+        }
+        Value::Table(table) => {
+            let secret_header = table
+                .get("name")
+                .and_then(Value::as_str)
+                .is_some_and(|name| SECRET_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+            for (key, item) in table.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) || (secret_header && key == "value") {
+                    *item = Value::String(REDACTED.to_string());
+                } else {
+                    redact(item, file_secrets);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact(item, file_secrets)),
+        _ => {}
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::{Response, StatusCode, header};
+use pingora::apps::http_app::ServeHttp;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use pingora::protocols::http::ServerSession;
+
+use crate::capture::DebugCapture;
+use crate::health::HealthEndpoints;
+use crate::metrics::ProxyMetrics;
+use crate::status::StatusPage;
+
+/// Endpoints the proxy answers itself instead of forwarding, grouped by the
+/// listener they are served on.
+#[derive(Clone, Default)]
+pub struct LocalEndpoints {
+    pub health: Option<Arc<HealthEndpoints>>,
+    pub status: Option<Arc<StatusPage>>,
+    pub metrics: Option<Arc<ProxyMetrics>>,
+    pub capture: Option<Arc<DebugCapture>>,
+}
+
+impl LocalEndpoints {
+    pub fn is_empty(&self) -> bool {
+        self.health.is_none()
+            && self.status.is_none()
+            && self.metrics.is_none()
+            && self.capture.is_none()
+    }
+
+    /// The response for one of these endpoints, or `None` when `request` is for none of them.
+    pub async fn answer(&self, request: &RequestHeader) -> Option<Response<Vec<u8>>> {
+        if let Some(health) = &self.health
+            && let Some(response) = health.answer(request.uri.path())
+        {
+            return Some(response);
+        }
+        if let Some(status) = &self.status
+            && let Some(response) = status.answer(request).await
+        {
+            return Some(response);
+        }
+        if let Some(metrics) = &self.metrics
+            && let Some(response) = metrics.answer(request)
+        {
+            return Some(response);
+        }
+        if let Some(capture) = &self.capture
+            && let Some(response) = capture.answer(request)
+        {
+            return Some(response);
+        }
+        None
+    }
+
+    /// Answers `session` if it is for one of these endpoints; returns whether it did.
+    pub async fn try_serve(&self, session: &mut Session) -> Result<bool> {
+        let Some(response) = self.answer(session.req_header()).await else {
+            return Ok(false);
+        };
+        let (parts, body) = response.into_parts();
+        let header: ResponseHeader = parts.into();
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session.write_response_body(Some(body.into()), true).await?;
+        Ok(true)
+    }
+}
+
+/// Serves only local endpoints, for those configured with their own `listen_addr`.
+pub struct EndpointService(pub LocalEndpoints);
+
+#[async_trait]
+impl ServeHttp for EndpointService {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        match self.0.answer(session.req_header()).await {
+            Some(response) => response,
+            None => local_response(
+                StatusCode::NOT_FOUND,
+                "text/plain; charset=utf-8",
+                b"not found\n".to_vec(),
+            ),
+        }
+    }
+}
+
+/// An uncacheable response generated by the proxy.
+pub fn local_response(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(body)
+        .expect("local response parts are valid")
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use sentry::protocol::{Event, Level, Request};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_SAMPLE_RATE: f32 = 1.0;
+const DEFAULT_UPSTREAM_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_UPSTREAM_FAILURE_WINDOW_SECONDS: u64 = 60;
+
+/// `[sentry]` section of the config file. Nothing is reported when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct SentryConfig {
+    /// Project DSN; may be given as `dsn_file` to read it from a file.
+    pub dsn: String,
+    pub environment: Option<String>,
+    /// Fraction of events sent, between 0 and 1.
+    pub sample_rate: Option<f32>,
+    /// Failures of one upstream within the window that trigger a report.
+    pub upstream_failure_threshold: Option<u32>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub upstream_failure_window_seconds: Option<u64>,
+}
+
+impl SentryConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            sample_rate: Some(self.sample_rate()),
+            upstream_failure_threshold: Some(self.upstream_failure_threshold()),
+            upstream_failure_window_seconds: Some(self.upstream_failure_window().as_secs()),
+            ..self
+        }
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE)
+    }
+
+    pub fn upstream_failure_threshold(&self) -> u32 {
+        self.upstream_failure_threshold
+            .unwrap_or(DEFAULT_UPSTREAM_FAILURE_THRESHOLD)
+    }
+
+    pub fn upstream_failure_window(&self) -> Duration {
+        Duration::from_secs(
+            self.upstream_failure_window_seconds
+                .unwrap_or(DEFAULT_UPSTREAM_FAILURE_WINDOW_SECONDS),
+        )
+    }
+
+    /// Starts the client and its panic handler. Reporting stops when the guard is dropped.
+    pub fn init(&self) -> Result<sentry::ClientInitGuard, String> {
+        let dsn = self
+            .dsn
+            .parse()
+            .map_err(|err| format!("invalid sentry dsn: {err}"))?;
+        Ok(sentry::init(sentry::ClientOptions {
+            dsn: Some(dsn),
+            environment: self.environment.clone().map(Into::into),
+            release: sentry::release_name!(),
+            sample_rate: self.sample_rate(),
+            ..Default::default()
+        }))
+    }
+}
+
+/// One failed attempt to proxy a request, with the context sent along in a report.
+pub struct UpstreamFailure<'a> {
+    pub upstream: &'a str,
+    pub route: &'a str,
+    pub request_id: &'a str,
+    pub method: &'a str,
+    pub url: String,
+    pub status: u16,
+    pub error: String,
+}
+
+/// Reports an upstream to Sentry once it fails `threshold` times within the window.
+pub struct UpstreamFailureReporter {
+    threshold: u32,
+    window: Duration,
+    /// Failures counted per upstream, with the start of their window.
+    failures: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl UpstreamFailureReporter {
+    pub fn new(config: &SentryConfig) -> Self {
+        Self {
+            threshold: config.upstream_failure_threshold().max(1),
+            window: config.upstream_failure_window(),
+            failures: Mutex::default(),
+        }
+    }
+
+    pub fn record(&self, failure: &UpstreamFailure) {
+        let count = {
+            let mut failures = self
+                .failures
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            let entry = failures
+                .entry(failure.upstream.to_string())
+                .or_insert((now, 0));
+            if now.duration_since(entry.0) > self.window {
+                *entry = (now, 0);
+            }
+            entry.1 += 1;
+            let count = entry.1;
+            if count >= self.threshold {
+                failures.remove(failure.upstream);
+            }
+            count
+        };
+        if count < self.threshold {
+            return;
+        }
+
+        let mut event = Event {
+            level: Level::Error,
+            message: Some(format!(
+                "upstream {} failed {count} times within {:?}",
+                failure.upstream, self.window
+            )),
+            request: Some(Request {
+                url: failure.url.parse().ok(),
+                method: Some(failure.method.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        event
+            .tags
+            .insert("upstream".into(), failure.upstream.into());
+        event.tags.insert("route".into(), failure.route.into());
+        event
+            .tags
+            .insert("status".into(), failure.status.to_string());
+        event
+            .extra
+            .insert("request_id".into(), failure.request_id.into());
+        event
+            .extra
+            .insert("last_error".into(), failure.error.clone().into());
+        sentry::capture_event(event);
+    }
+}
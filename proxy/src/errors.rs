@@ -0,0 +1,275 @@
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use http::StatusCode;
+use http::header::{ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::{BError, Error, ErrorSource, ErrorType, ImmutStr};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::units::timestamp;
+
+const DEFAULT_HTML_PAGE: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+    <title>{{status}} {{reason}}</title></head><body><h1>{{status}} {{reason}}</h1>\
+    <p>Request ID: {{request_id}}<br>{{timestamp}}</p></body></html>\n";
+const DEFAULT_JSON_PAGE: &str = "{\"status\": {{status}}, \"error\": \"{{reason}}\", \
+    \"request_id\": \"{{request_id}}\", \"timestamp\": \"{{timestamp}}\"}\n";
+
+/// Why the proxy failed a request, each answered with its own status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyError {
+    /// The request is malformed, or can't be turned into a valid one.
+    BadRequest,
+    /// The method is not one the endpoint takes.
+    MethodNotAllowed,
+    /// The request body came too slowly.
+    RequestTimeout,
+    /// The request body is over a limit.
+    PayloadTooLarge,
+    /// The request body is of a type the endpoint doesn't take.
+    UnsupportedMediaType,
+    /// The proxy itself failed, e.g. on a header rule making an invalid value.
+    Internal,
+    /// The upstream's response can't be passed on, e.g. for being over a limit.
+    BadUpstreamResponse,
+    /// The upstream took too long to answer.
+    UpstreamTimeout,
+}
+
+impl ProxyError {
+    const ALL: [Self; 8] = [
+        Self::BadRequest,
+        Self::MethodNotAllowed,
+        Self::RequestTimeout,
+        Self::PayloadTooLarge,
+        Self::UnsupportedMediaType,
+        Self::Internal,
+        Self::BadUpstreamResponse,
+        Self::UpstreamTimeout,
+    ];
+
+    pub fn status(self) -> u16 {
+        match self {
+            Self::BadRequest => 400,
+            Self::MethodNotAllowed => 405,
+            Self::RequestTimeout => 408,
+            Self::PayloadTooLarge => 413,
+            Self::UnsupportedMediaType => 415,
+            Self::Internal => 500,
+            Self::BadUpstreamResponse => 502,
+            Self::UpstreamTimeout => 504,
+        }
+    }
+
+    /// The name errors of this kind are logged with.
+    fn name(self) -> &'static str {
+        match self {
+            Self::BadRequest => "BadRequest",
+            Self::MethodNotAllowed => "MethodNotAllowed",
+            Self::RequestTimeout => "RequestTimeout",
+            Self::PayloadTooLarge => "PayloadTooLarge",
+            Self::UnsupportedMediaType => "UnsupportedMediaType",
+            Self::Internal => "Internal",
+            Self::BadUpstreamResponse => "BadUpstreamResponse",
+            Self::UpstreamTimeout => "UpstreamTimeout",
+        }
+    }
+
+    /// The kind of `error`, when the proxy failed it with one.
+    pub fn of(error: &Error) -> Option<Self> {
+        let ErrorType::Custom(name) = error.etype() else {
+            return None;
+        };
+        Self::ALL.into_iter().find(|kind| kind.name() == *name)
+    }
+
+    /// An error of this kind, explained by `context`.
+    pub fn error(self, context: impl Into<ImmutStr>) -> BError {
+        Error::explain(ErrorType::Custom(self.name()), context)
+    }
+
+    /// Fails with this kind of error, explained by `context`.
+    pub fn fail<T>(self, context: impl Into<ImmutStr>) -> pingora::Result<T> {
+        Err(self.error(context))
+    }
+
+    /// Fails with this kind of error, explained by `context` and caused by `cause`.
+    pub fn because<T>(
+        self,
+        context: impl Into<ImmutStr>,
+        cause: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> pingora::Result<T> {
+        Error::e_because(ErrorType::Custom(self.name()), context, cause)
+    }
+}
+
+/// How much of an error the proxy itself generated is shown to the client.
+/// The full error chain is always logged with the request ID.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorDetail {
+    /// A plain-text status line and the request ID.
+    #[default]
+    Generic,
+    /// An RFC 9457 `application/problem+json` body including the error chain. Meant for
+    /// debug environments, as it reveals upstream addresses and internals.
+    Problem,
+}
+
+/// Status answered for a request that failed with `error`; 0 when the client is gone.
+pub fn error_status(error: &Error) -> u16 {
+    if let Some(kind) = ProxyError::of(error) {
+        return kind.status();
+    }
+    match error.etype() {
+        ErrorType::HTTPStatus(code) => *code,
+        _ => match error.esource() {
+            ErrorSource::Upstream => 502,
+            ErrorSource::Downstream => match error.etype() {
+                ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                ErrorType::ReadTimedout => 408,
+                _ => 400,
+            },
+            ErrorSource::Internal | ErrorSource::Unset => 500,
+        },
+    }
+}
+
+/// A plain-text response for a request the proxy refused with `status`.
+pub fn refusal_response(status: u16, request_id: &str) -> pingora::Result<(ResponseHeader, Bytes)> {
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Error");
+    let body = Bytes::from(format!("{status} {reason}\n"));
+    let mut header = ResponseHeader::build(status, Some(4))?;
+    header.insert_header(CONTENT_TYPE, "text/plain; charset=utf-8")?;
+    header.insert_header(CONTENT_LENGTH, body.len())?;
+    header.insert_header(CACHE_CONTROL, "no-store")?;
+    header.insert_header(REQUEST_ID_HEADER, request_id)?;
+    Ok((header, body))
+}
+
+/// `[error_pages]` section of the config file: the bodies of errors the proxy generates
+/// itself for clients asking for HTML or JSON. `{{status}}`, `{{reason}}`, `{{request_id}}`
+/// and `{{timestamp}}` are replaced with those of the error.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct ErrorPagesConfig {
+    /// HTML page for clients accepting `text/html`; a short built-in page by default.
+    pub html: Option<String>,
+    /// JSON body for clients accepting `application/json`; a built-in object by default.
+    pub json: Option<String>,
+}
+
+/// The templates of error bodies.
+#[derive(Debug)]
+pub struct ErrorPages {
+    html: String,
+    json: String,
+}
+
+impl ErrorPages {
+    pub fn new(config: Option<&ErrorPagesConfig>) -> Result<Self, String> {
+        let read = |path: Option<&str>, default: &str| match path {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|err| format!("can't read page {path}: {err}")),
+            None => Ok(default.to_string()),
+        };
+        Ok(Self {
+            html: read(
+                config.and_then(|config| config.html.as_deref()),
+                DEFAULT_HTML_PAGE,
+            )?,
+            json: read(
+                config.and_then(|config| config.json.as_deref()),
+                DEFAULT_JSON_PAGE,
+            )?,
+        })
+    }
+}
+
+/// Body formats of generic errors, by what the client accepts.
+enum ErrorFormat {
+    Text,
+    Html,
+    Json,
+}
+
+impl ErrorFormat {
+    /// HTML or JSON, whichever the `Accept` header lists first; plain text otherwise.
+    fn negotiate(request: &RequestHeader) -> Self {
+        let accept = request
+            .headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or("");
+        let html = accept.find("text/html");
+        let json = accept.find("application/json").or(accept.find("+json"));
+        match (html, json) {
+            (Some(html), Some(json)) if json < html => Self::Json,
+            (Some(_), _) => Self::Html,
+            (None, Some(_)) => Self::Json,
+            (None, None) => Self::Text,
+        }
+    }
+}
+
+/// Fills in the placeholders of `template`. Request IDs are plain tokens, so none of the
+/// values need escaping in HTML or JSON.
+fn render(template: &str, status: u16, reason: &str, request_id: &str) -> Vec<u8> {
+    template
+        .replace("{{status}}", &status.to_string())
+        .replace("{{reason}}", reason)
+        .replace("{{request_id}}", request_id)
+        .replace("{{timestamp}}", &timestamp(SystemTime::now()))
+        .into_bytes()
+}
+
+/// The response sent for `request`, which failed with `error`.
+pub fn error_response(
+    status: u16,
+    request: &RequestHeader,
+    request_id: &str,
+    error: &Error,
+    detail: ErrorDetail,
+    pages: &ErrorPages,
+) -> pingora::Result<(ResponseHeader, Bytes)> {
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Error");
+    let (content_type, body) = match (detail, ErrorFormat::negotiate(request)) {
+        (ErrorDetail::Generic, ErrorFormat::Text) => (
+            "text/plain; charset=utf-8",
+            format!("{status} {reason}\nrequest id: {request_id}\n").into_bytes(),
+        ),
+        (ErrorDetail::Generic, ErrorFormat::Html) => (
+            "text/html; charset=utf-8",
+            render(&pages.html, status, reason, request_id),
+        ),
+        (ErrorDetail::Generic, ErrorFormat::Json) => (
+            "application/json",
+            render(&pages.json, status, reason, request_id),
+        ),
+        (ErrorDetail::Problem, _) => (
+            "application/problem+json",
+            serde_json::to_vec(&json!({
+                "type": "about:blank",
+                "title": reason,
+                "status": status,
+                "detail": error.to_string(),
+                "request_id": request_id,
+            }))
+            .unwrap_or_default(),
+        ),
+    };
+    let mut header = ResponseHeader::build(status, Some(4))?;
+    header.insert_header(CONTENT_TYPE, content_type)?;
+    header.insert_header(CONTENT_LENGTH, body.len())?;
+    header.insert_header(CACHE_CONTROL, "no-store")?;
+    header.insert_header(REQUEST_ID_HEADER, request_id)?;
+    Ok((header, Bytes::from(body)))
+}
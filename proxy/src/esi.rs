@@ -0,0 +1,549 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http::header::{
+    ACCEPT_LANGUAGE, AUTHORIZATION, CACHE_CONTROL, COOKIE, ETAG, HOST, LAST_MODIFIED, USER_AGENT,
+};
+use log::warn;
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::body_rewrite;
+use crate::listeners::ListenAddrs;
+use crate::request_id::REQUEST_ID_HEADER;
+
+const DEFAULT_TIMEOUT_SECONDS: u64 = 5;
+const DEFAULT_MAX_INCLUDES: usize = 32;
+/// Header telling the proxy how deeply nested a fragment request is.
+const DEPTH_HEADER: &str = "x-esi-depth";
+/// Fragments of fragments are processed up to this depth; deeper ones are sent as they are.
+const MAX_DEPTH: u8 = 3;
+/// Longest `<esi:include>` tag held back for its end; longer ones are passed on as text.
+const MAX_TAG_LEN: usize = 4096;
+const INCLUDE_TAG: &[u8] = b"<esi:include";
+const INCLUDE_END_TAG: &[u8] = b"</esi:include>";
+/// Request headers a fragment request carries over from the page's, so fragments can be
+/// personalized.
+const FORWARDED_HEADERS: [http::HeaderName; 4] =
+    [COOKIE, AUTHORIZATION, ACCEPT_LANGUAGE, USER_AGENT];
+
+/// `esi` of a route: `<esi:include src="..."/>` tags in its HTML responses are replaced by
+/// the fragments they name, requested through the proxy like any client request.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct EsiConfig {
+    /// host:port of a proxy listener fragments are requested from; the first `listen_addr`
+    /// by default, over loopback when it listens on every address.
+    pub fetch_addr: Option<String>,
+    /// How long a fragment may take (5 seconds by default); one that fails or takes longer
+    /// is left out, or replaced by its `alt`.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub timeout_seconds: Option<u64>,
+    /// Most includes processed on a page (32 by default); later ones are left out.
+    pub max_includes: Option<usize>,
+}
+
+/// A route's compiled `esi`.
+#[derive(Debug)]
+pub struct Esi {
+    fetch_addr: String,
+    max_includes: usize,
+    agent: ureq::Agent,
+}
+
+impl Esi {
+    pub fn new(config: &EsiConfig, listen_addr: Option<&ListenAddrs>) -> Result<Self, String> {
+        let fetch_addr = match &config.fetch_addr {
+            Some(addr) => addr.clone(),
+            None => {
+                let addr = listen_addr
+                    .and_then(|addrs| addrs.addrs().first())
+                    .ok_or("needs fetch_addr without a listen_addr")?;
+                match addr.parse::<SocketAddr>() {
+                    Ok(mut parsed) if parsed.ip().is_unspecified() => {
+                        parsed.set_ip(match parsed {
+                            SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+                        });
+                        parsed.to_string()
+                    }
+                    _ => addr.clone(),
+                }
+            }
+        };
+        if config.max_includes == Some(0) {
+            return Err("max_includes must be at least 1".to_string());
+        }
+        let timeout = Duration::from_secs(
+            config
+                .timeout_seconds
+                .unwrap_or(DEFAULT_TIMEOUT_SECONDS)
+                .max(1),
+        );
+        Ok(Self {
+            fetch_addr,
+            max_includes: config.max_includes.unwrap_or(DEFAULT_MAX_INCLUDES),
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(timeout))
+                .http_status_as_error(false)
+                .max_redirects(0)
+                .proxy(None)
+                .build()
+                .into(),
+        })
+    }
+
+    pub fn apply_request(&self, request: &mut RequestHeader) {
+        body_rewrite::ask_uncompressed(request);
+    }
+
+    /// The processor for `response`, with its headers adjusted for a body assembled for this
+    /// client, if it is an uncompressed HTML page.
+    pub fn start(
+        self: &Arc<Self>,
+        request: &RequestHeader,
+        response: &mut ResponseHeader,
+        request_id: &str,
+    ) -> Result<Option<EsiProcessor>> {
+        let depth = request
+            .headers
+            .get(DEPTH_HEADER)
+            .and_then(|value| value.to_str().ok()?.parse::<u8>().ok())
+            .unwrap_or(0);
+        if depth >= MAX_DEPTH
+            || !body_rewrite::start(request, response, |mime| mime == "text/html")?
+        {
+            return Ok(None);
+        }
+        // The page may be long-cached upstream, but what it is assembled into is not shared.
+        response.remove_header(&ETAG);
+        response.remove_header(&LAST_MODIFIED);
+        response.insert_header(CACHE_CONTROL, "private, no-cache")?;
+
+        let mut headers: Vec<(&'static str, String)> = FORWARDED_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = request.headers.get(name)?.to_str().ok()?;
+                Some((name.as_str(), value.to_string()))
+            })
+            .collect();
+        headers.push((REQUEST_ID_HEADER, request_id.to_string()));
+        headers.push((DEPTH_HEADER, (depth + 1).to_string()));
+        let host = request
+            .headers
+            .get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| request.uri.authority().map(|authority| authority.as_str()))
+            .map(str::to_string);
+        Ok(Some(EsiProcessor {
+            esi: self.clone(),
+            headers,
+            host,
+            page_path: request.uri.path().to_string(),
+            request_id: request_id.to_string(),
+            includes: 0,
+            pending: BytesMut::new(),
+        }))
+    }
+
+    /// The body of the fragment at `path`, requested from the proxy for `host`.
+    fn fetch(
+        &self,
+        path: &str,
+        host: Option<&str>,
+        headers: &[(&str, String)],
+    ) -> Result<Vec<u8>, String> {
+        let mut request = self.agent.get(format!("http://{}{path}", self.fetch_addr));
+        if let Some(host) = host {
+            request = request.header(HOST, host);
+        }
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let mut response = request.call().map_err(|err| err.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("answered {status}"));
+        }
+        response
+            .body_mut()
+            .read_to_vec()
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Streams a page through, replacing each include with its fragment as the tag passes. Bytes
+/// that may be the start of a tag split across chunks are held back until the next one.
+pub struct EsiProcessor {
+    esi: Arc<Esi>,
+    /// Headers of the page request that fragment requests carry.
+    headers: Vec<(&'static str, String)>,
+    host: Option<String>,
+    /// Path relative `src` attributes are resolved against.
+    page_path: String,
+    request_id: String,
+    includes: usize,
+    pending: BytesMut,
+}
+
+impl EsiProcessor {
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if let Some(chunk) = body.take() {
+            self.pending.extend_from_slice(&chunk);
+        }
+        let mut out = BytesMut::with_capacity(self.pending.len());
+        while let Some(at) = find_tag(&self.pending) {
+            out.extend_from_slice(&self.pending.split_to(at));
+            if self.pending.starts_with(INCLUDE_END_TAG) {
+                let _ = self.pending.split_to(INCLUDE_END_TAG.len());
+                continue;
+            }
+            if !self.pending.starts_with(INCLUDE_TAG) {
+                // The start of a tag at the end of what has arrived.
+                break;
+            }
+            match self.pending.get(INCLUDE_TAG.len()) {
+                Some(byte) if byte.is_ascii_whitespace() || *byte == b'/' => {}
+                None if !end_of_stream => break,
+                _ => {
+                    out.extend_from_slice(&self.pending.split_to(1));
+                    continue;
+                }
+            }
+            match find(&self.pending, b">") {
+                Some(end) => {
+                    let tag = self.pending.split_to(end + 1);
+                    out.extend_from_slice(&self.include(&String::from_utf8_lossy(&tag)));
+                }
+                None if self.pending.len() <= MAX_TAG_LEN && !end_of_stream => break,
+                None => out.extend_from_slice(&self.pending.split_to(1)),
+            }
+        }
+        if end_of_stream || find_tag(&self.pending).is_none() {
+            out.extend_from_slice(&self.pending.split());
+        }
+        if !out.is_empty() || end_of_stream {
+            *body = Some(out.freeze());
+        }
+    }
+
+    /// The fragment an include tag stands for, or nothing if neither `src` nor `alt` could be
+    /// fetched. Headers are sent by now, so a failed include can't fail the page.
+    fn include(&mut self, tag: &str) -> Vec<u8> {
+        self.includes += 1;
+        if self.includes > self.esi.max_includes {
+            warn!(
+                "request {}: more than {} ESI includes, leaving out the rest",
+                self.request_id, self.esi.max_includes
+            );
+            return Vec::new();
+        }
+        for src in ["src", "alt"]
+            .into_iter()
+            .filter_map(|name| attribute(tag, name))
+        {
+            let (host, path) = self.resolve(&src);
+            // Fragments come back through the proxy on this thread, so let the runtime move
+            // other tasks off it while it waits.
+            let fetched = tokio::task::block_in_place(|| {
+                self.esi.fetch(&path, host.as_deref(), &self.headers)
+            });
+            match fetched {
+                Ok(fragment) => return fragment,
+                Err(err) => warn!(
+                    "request {}: ESI include {src} failed: {err}",
+                    self.request_id
+                ),
+            }
+        }
+        Vec::new()
+    }
+
+    /// The host and path of a `src`: an absolute URL names its own host, a relative path is
+    /// resolved against the page's.
+    fn resolve(&self, src: &str) -> (Option<String>, String) {
+        let absolute = src
+            .strip_prefix("http://")
+            .or_else(|| src.strip_prefix("https://"));
+        if let Some(rest) = absolute {
+            let (host, path) = rest.find('/').map_or((rest, "/"), |at| rest.split_at(at));
+            return (Some(host.to_string()), path.to_string());
+        }
+        let path = if src.starts_with('/') {
+            src.to_string()
+        } else {
+            let dir = &self.page_path[..self.page_path.rfind('/').map_or(0, |at| at + 1)];
+            format!("{}{src}", if dir.is_empty() { "/" } else { dir })
+        };
+        (self.host.clone(), path)
+    }
+}
+
+/// The value of attribute `name` of `tag`, quoted with either kind of quote.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(at) = rest.find(name) {
+        let before = rest[..at].chars().next_back();
+        rest = &rest[at + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        let end = value.find(quote)?;
+        return Some(value[..end].replace("&amp;", "&"));
+    }
+    None
+}
+
+/// Position of the first ESI tag, or of what may be the start of one at the end.
+fn find_tag(text: &[u8]) -> Option<usize> {
+    (0..text.len()).find(|&at| {
+        let rest = &text[at..];
+        [INCLUDE_TAG, INCLUDE_END_TAG]
+            .iter()
+            .any(|tag| rest.starts_with(tag) || tag.starts_with(rest))
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn esi(config: &str, listen_addr: Option<&str>) -> Result<Esi, String> {
+        let listen_addr = listen_addr.map(|addr| ListenAddrs::One(addr.to_string()));
+        Esi::new(&toml::from_str(config).unwrap(), listen_addr.as_ref())
+    }
+
+    fn page_request(path: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            request.append_header(name.to_string(), *value).unwrap();
+        }
+        request
+    }
+
+    fn html_response() -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("content-type", "text/html; charset=utf-8")
+            .unwrap();
+        response.insert_header("etag", "\"v1\"").unwrap();
+        response
+    }
+
+    /// Serves fragments: paths ending in `frag` answer with their path, others with 404.
+    /// Returns its address and the request heads it received.
+    fn fragment_server() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                log.lock().unwrap().push(head.to_ascii_lowercase());
+                let (status, body) = match path.ends_with("frag") {
+                    true => ("200 OK", format!("FRAG:{path}")),
+                    false => ("404 Not Found", String::new()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+        (addr, received)
+    }
+
+    /// The page `chunks` are assembled into, processed on a runtime that allows the
+    /// blocking fragment requests.
+    fn assemble(mut processor: EsiProcessor, chunks: Vec<&'static str>) -> String {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let task = runtime.spawn(async move {
+            let mut page = Vec::new();
+            let last = chunks.len() - 1;
+            for (index, chunk) in chunks.into_iter().enumerate() {
+                let mut body = Some(Bytes::from_static(chunk.as_bytes()));
+                processor.response_body(&mut body, index == last);
+                page.extend_from_slice(&body.unwrap_or_default());
+            }
+            String::from_utf8(page).unwrap()
+        });
+        runtime.block_on(task).unwrap()
+    }
+
+    #[test]
+    fn reads_attributes() {
+        let tag = r#"<esi:include src="/a?x=1&amp;y=2" alt='/b' data-src="/c"/>"#;
+        assert_eq!(attribute(tag, "src").as_deref(), Some("/a?x=1&y=2"));
+        assert_eq!(attribute(tag, "alt").as_deref(), Some("/b"));
+        assert_eq!(
+            attribute("<esi:include src = '/d' />", "src").as_deref(),
+            Some("/d")
+        );
+        assert_eq!(attribute("<esi:include src=/e />", "src"), None);
+        assert_eq!(attribute("<esi:include data-src=\"/c\"/>", "src"), None);
+    }
+
+    #[test]
+    fn finds_tags_split_across_chunks() {
+        assert_eq!(find_tag(b"abc<esi:include src='/x'/>"), Some(3));
+        assert_eq!(find_tag(b"abc</esi:include>"), Some(3));
+        assert_eq!(find_tag(b"abc<es"), Some(3));
+        assert_eq!(find_tag(b"abc<"), Some(3));
+        assert_eq!(find_tag(b"abc<p>"), None);
+    }
+
+    #[test]
+    fn fetches_from_the_listener_by_default() {
+        let loopback = esi("", Some("0.0.0.0:8080")).unwrap();
+        assert_eq!(loopback.fetch_addr, "127.0.0.1:8080");
+        let loopback = esi("", Some("[::]:8080")).unwrap();
+        assert_eq!(loopback.fetch_addr, "[::1]:8080");
+        let named = esi("", Some("10.0.0.1:80")).unwrap();
+        assert_eq!(named.fetch_addr, "10.0.0.1:80");
+        assert_eq!(named.max_includes, DEFAULT_MAX_INCLUDES);
+        let configured = esi("fetch_addr = \"127.0.0.1:9\"", None).unwrap();
+        assert_eq!(configured.fetch_addr, "127.0.0.1:9");
+
+        let err = esi("", None).err().unwrap();
+        assert!(err.contains("needs fetch_addr"), "{err}");
+        let err = esi("max_includes = 0", Some("127.0.0.1:80")).err().unwrap();
+        assert!(err.contains("at least 1"), "{err}");
+    }
+
+    #[test]
+    fn processes_html_pages_only() {
+        let esi = Arc::new(esi("", Some("127.0.0.1:80")).unwrap());
+        let request = page_request("/", &[]);
+        let mut response = html_response();
+        assert!(esi.start(&request, &mut response, "1").unwrap().is_some());
+        assert!(response.headers.get(ETAG).is_none());
+        assert_eq!(response.headers[CACHE_CONTROL], "private, no-cache");
+
+        let mut json = ResponseHeader::build(200, None).unwrap();
+        json.insert_header("content-type", "application/json")
+            .unwrap();
+        assert!(esi.start(&request, &mut json, "1").unwrap().is_none());
+        let nested = page_request("/", &[(DEPTH_HEADER, "3")]);
+        assert!(
+            esi.start(&nested, &mut html_response(), "1")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn resolves_sources_against_the_page() {
+        let esi = Arc::new(esi("", Some("127.0.0.1:80")).unwrap());
+        let request = page_request("/shop/cart.html", &[("host", "example.com")]);
+        let processor = esi
+            .start(&request, &mut html_response(), "1")
+            .unwrap()
+            .unwrap();
+        let host = Some("example.com".to_string());
+        assert_eq!(
+            processor.resolve("/nav"),
+            (host.clone(), "/nav".to_string())
+        );
+        assert_eq!(processor.resolve("nav"), (host, "/shop/nav".to_string()));
+        assert_eq!(
+            processor.resolve("https://cdn.example/a/b"),
+            (Some("cdn.example".to_string()), "/a/b".to_string())
+        );
+        assert_eq!(
+            processor.resolve("http://cdn.example"),
+            (Some("cdn.example".to_string()), "/".to_string())
+        );
+    }
+
+    #[test]
+    fn replaces_includes_with_fragments() {
+        let (addr, received) = fragment_server();
+        let esi = Arc::new(esi(&format!("fetch_addr = \"{addr}\""), None).unwrap());
+        let request = page_request(
+            "/pages/index.html",
+            &[
+                ("host", "example.com"),
+                ("cookie", "session=1"),
+                ("x-other", "no"),
+            ],
+        );
+        let processor = esi
+            .start(&request, &mut html_response(), "req-1")
+            .unwrap()
+            .unwrap();
+        // Tags split at every point across chunks.
+        let page = assemble(
+            processor,
+            vec![
+                "<p><esi:inc",
+                "lude src=\"/frag\"/></p><",
+                "esi:include src=\"/missing\" alt=\"frag\"></esi:inc",
+                "lude><esi:include src=\"/missing\"/><esi:includes>x<esi:",
+            ],
+        );
+        assert_eq!(
+            page,
+            "<p>FRAG:/frag</p>FRAG:/pages/frag<esi:includes>x<esi:"
+        );
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 4);
+        assert!(received[0].starts_with("get /frag "));
+        for header in [
+            "host: example.com",
+            "cookie: session=1",
+            "x-request-id: req-1",
+        ] {
+            assert!(received[0].contains(header), "{header}");
+        }
+        assert!(received[0].contains(&format!("{DEPTH_HEADER}: 1")));
+        assert!(!received[0].contains("x-other"));
+    }
+
+    #[test]
+    fn leaves_out_includes_over_the_limit() {
+        let (addr, received) = fragment_server();
+        let config = format!("fetch_addr = \"{addr}\"\nmax_includes = 1");
+        let esi = Arc::new(esi(&config, None).unwrap());
+        let processor = esi
+            .start(&page_request("/", &[]), &mut html_response(), "1")
+            .unwrap()
+            .unwrap();
+        let page = assemble(
+            processor,
+            vec!["<esi:include src='/a/frag'/>|<esi:include src='/b/frag'/>"],
+        );
+        assert_eq!(page, "FRAG:/a/frag|");
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
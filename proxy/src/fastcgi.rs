@@ -0,0 +1,728 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION, TRANSFER_ENCODING};
+use log::warn;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+
+use crate::errors::ProxyError;
+
+const DEFAULT_INDEX: &str = "index.php";
+const DEFAULT_EXTENSION: &str = ".php";
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_READ_TIMEOUT_SECONDS: u64 = 60;
+/// Longest header block accepted from the application.
+const MAX_HEADER_LEN: usize = 64 * 1024;
+/// Largest record content, as its length is 16 bits.
+const MAX_RECORD_LEN: usize = 0xFFFF;
+
+const VERSION: u8 = 1;
+/// The one request sent per connection.
+const REQUEST_ID: u16 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+
+/// `[routes.fastcgi]`: serves the route from a FastCGI application server, e.g. PHP-FPM,
+/// instead of proxying it over HTTP.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct FastCgiConfig {
+    /// The application server, as `host:port` or the path of a Unix socket.
+    pub addr: String,
+    /// Directory holding the scripts on the application server, passed as DOCUMENT_ROOT.
+    pub root: String,
+    /// Script run for every request, relative to `root`, e.g. a framework's front controller
+    /// `"index.php"`. Otherwise the request path names the script, and paths naming none get
+    /// 404.
+    pub script: Option<String>,
+    /// Script of paths ending in `/` (`"index.php"` by default).
+    pub index: Option<String>,
+    /// Ends script names in request paths; what follows is PATH_INFO (`".php"` by default).
+    pub extension: Option<String>,
+    /// Added or replaced parameters. `$NAME` in a value stands for the parameter NAME the
+    /// proxy would send, e.g. `SCRIPT_FILENAME = "/srv/app/public$SCRIPT_NAME"`.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub connect_timeout_seconds: Option<u64>,
+    /// Longest wait for the next part of the response.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub read_timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug)]
+enum Addr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => f.write_str(addr),
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A compiled `[routes.fastcgi]`.
+#[derive(Debug)]
+pub struct FastCgi {
+    addr: Addr,
+    root: String,
+    script: Option<String>,
+    index: String,
+    extension: String,
+    params: BTreeMap<String, String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+/// The script a request runs, relative to the root.
+pub struct Script {
+    name: String,
+    path_info: Option<String>,
+}
+
+/// What the proxy knows of a request beyond its header.
+pub struct CgiRequest<'a> {
+    /// Path of the route's mount point the script path is relative to, without a trailing
+    /// slash.
+    pub prefix: &'a str,
+    pub client: Option<SocketAddr>,
+    pub server: Option<SocketAddr>,
+    pub host: Option<&'a str>,
+    pub https: bool,
+    pub max_body: Option<usize>,
+}
+
+impl FastCgi {
+    pub fn new(config: &FastCgiConfig) -> Result<Self, String> {
+        let addr = if config.addr.starts_with('/') {
+            Addr::Unix(PathBuf::from(&config.addr))
+        } else if config.addr.contains(':') {
+            Addr::Tcp(config.addr.clone())
+        } else {
+            return Err(format!(
+                "addr {:?} is neither a host:port address nor a socket path",
+                config.addr
+            ));
+        };
+        if !config.root.starts_with('/') {
+            return Err(format!("root {:?} must be an absolute path", config.root));
+        }
+        if let Some(script) = &config.script
+            && (script.is_empty() || script.split('/').any(|segment| segment == ".."))
+        {
+            return Err(format!("script {script:?} must be a path within root"));
+        }
+        let extension = config.extension.as_deref().unwrap_or(DEFAULT_EXTENSION);
+        if !extension.starts_with('.') || extension.len() < 2 || extension.contains('/') {
+            return Err(format!("extension {extension:?} must be like \".php\""));
+        }
+        Ok(Self {
+            addr,
+            root: config.root.trim_end_matches('/').to_string(),
+            script: config
+                .script
+                .as_ref()
+                .map(|script| format!("/{}", script.trim_start_matches('/'))),
+            index: config.index.clone().unwrap_or(DEFAULT_INDEX.to_string()),
+            extension: extension.to_string(),
+            params: config.params.clone(),
+            connect_timeout: Duration::from_secs(
+                config
+                    .connect_timeout_seconds
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            ),
+            read_timeout: Duration::from_secs(
+                config
+                    .read_timeout_seconds
+                    .unwrap_or(DEFAULT_READ_TIMEOUT_SECONDS),
+            ),
+        })
+    }
+
+    /// The script for `path`, relative to the route's mount point, or `None` if it names
+    /// none or leaves the root.
+    pub fn script(&self, path: &str) -> Option<Script> {
+        if let Some(script) = &self.script {
+            return Some(Script {
+                name: script.clone(),
+                path_info: None,
+            });
+        }
+        let path = percent_decode(path)?;
+        if path.contains('\0')
+            || path
+                .split('/')
+                .any(|segment| segment == ".." || segment == ".")
+        {
+            return None;
+        }
+        let path = if path.ends_with('/') {
+            format!("{path}{}", self.index)
+        } else {
+            path
+        };
+        let end = path.match_indices(&self.extension).find_map(|(at, _)| {
+            let end = at + self.extension.len();
+            (end == path.len() || path[end..].starts_with('/')).then_some(end)
+        })?;
+        Some(Script {
+            name: path[..end].to_string(),
+            path_info: (end < path.len()).then(|| path[end..].to_string()),
+        })
+    }
+
+    /// Runs `script` for the request of `session` and sends its response, after `decorate`
+    /// applied the proxy's response rules to the header.
+    pub async fn serve(
+        &self,
+        session: &mut Session,
+        script: &Script,
+        request: CgiRequest<'_>,
+        decorate: impl FnOnce(&mut ResponseHeader) -> Result<()>,
+    ) -> Result<()> {
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_request_body().await? {
+            body.extend_from_slice(&chunk);
+            if request.max_body.is_some_and(|max| body.len() > max) {
+                session.set_keepalive(None);
+                return ProxyError::PayloadTooLarge.fail("request body over the limit for FastCGI");
+            }
+        }
+        let params = self.params(session, script, &request, body.len());
+
+        let mut message = record(BEGIN_REQUEST, &{
+            let mut begin = RESPONDER.to_be_bytes().to_vec();
+            // Flags: the server closes the connection after the request.
+            begin.extend_from_slice(&[0; 6]);
+            begin
+        });
+        let mut encoded = Vec::new();
+        for (name, value) in &params {
+            encode_len(&mut encoded, name.len());
+            encode_len(&mut encoded, value.len());
+            encoded.extend_from_slice(name.as_bytes());
+            encoded.extend_from_slice(value.as_bytes());
+        }
+        for part in encoded.chunks(MAX_RECORD_LEN) {
+            message.extend_from_slice(&record(PARAMS, part));
+        }
+        message.extend_from_slice(&record(PARAMS, &[]));
+        for part in body.chunks(MAX_RECORD_LEN) {
+            message.extend_from_slice(&record(STDIN, part));
+        }
+        message.extend_from_slice(&record(STDIN, &[]));
+
+        let mut connection = self.connect().await?;
+        connection
+            .write_all(&message)
+            .await
+            .or_err(ErrorType::WriteError, "sending the request to FastCGI")
+            .map_err(|err| err.into_up())?;
+        let mut connection = BufReader::new(connection);
+
+        let mut head = Vec::new();
+        let (mut header, rest) = loop {
+            match self.read_record(&mut connection).await? {
+                Output::Stdout(data) => head.extend_from_slice(&data),
+                Output::End => {
+                    return Error::e_explain(
+                        ErrorType::InvalidHTTPHeader,
+                        "FastCGI response without headers",
+                    )
+                    .map_err(|err| err.into_up());
+                }
+            }
+            if let Some(parsed) = parse_head(&head)? {
+                break parsed;
+            }
+            if head.len() > MAX_HEADER_LEN {
+                return Error::e_explain(
+                    ErrorType::InvalidHTTPHeader,
+                    "FastCGI response headers too long",
+                )
+                .map_err(|err| err.into_up());
+            }
+        };
+        if header.headers.get(CONTENT_LENGTH).is_none()
+            && session.req_header().version == http::Version::HTTP_11
+        {
+            header.insert_header(TRANSFER_ENCODING, "chunked")?;
+        }
+        decorate(&mut header)?;
+        let send_body = session.req_header().method != http::Method::HEAD;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        if send_body && !rest.is_empty() {
+            session.write_response_body(Some(rest), false).await?;
+        }
+        loop {
+            match self.read_record(&mut connection).await? {
+                Output::Stdout(data) if send_body => {
+                    session.write_response_body(Some(data), false).await?;
+                }
+                Output::Stdout(_) => {}
+                Output::End => break,
+            }
+        }
+        session.finish_body().await?;
+        Ok(())
+    }
+
+    /// The CGI parameters of a request, with those of the config applied.
+    fn params(
+        &self,
+        session: &Session,
+        script: &Script,
+        request: &CgiRequest<'_>,
+        content_length: usize,
+    ) -> Vec<(String, String)> {
+        let header = session.req_header();
+        let mut params: BTreeMap<String, String> = BTreeMap::new();
+        let mut set = |name: &str, value: String| {
+            params.insert(name.to_string(), value);
+        };
+        set("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+        set("SERVER_SOFTWARE", env!("CARGO_PKG_NAME").to_string());
+        set(
+            "SERVER_PROTOCOL",
+            match header.version {
+                http::Version::HTTP_10 => "HTTP/1.0",
+                http::Version::HTTP_2 => "HTTP/2.0",
+                _ => "HTTP/1.1",
+            }
+            .to_string(),
+        );
+        set("REQUEST_METHOD", header.method.to_string());
+        set(
+            "REQUEST_URI",
+            header
+                .uri
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_string(),
+        );
+        set("DOCUMENT_URI", header.uri.path().to_string());
+        set(
+            "QUERY_STRING",
+            header.uri.query().unwrap_or_default().to_string(),
+        );
+        set("DOCUMENT_ROOT", self.root.clone());
+        set("SCRIPT_NAME", format!("{}{}", request.prefix, script.name));
+        set("SCRIPT_FILENAME", format!("{}{}", self.root, script.name));
+        if let Some(path_info) = &script.path_info {
+            set("PATH_INFO", path_info.clone());
+            set("PATH_TRANSLATED", format!("{}{path_info}", self.root));
+        }
+        // PHP refuses to run without it when built with cgi.force_redirect.
+        set("REDIRECT_STATUS", "200".to_string());
+        if let Some(client) = request.client {
+            set("REMOTE_ADDR", client.ip().to_string());
+            set("REMOTE_PORT", client.port().to_string());
+        }
+        if let Some(server) = request.server {
+            set("SERVER_ADDR", server.ip().to_string());
+            set("SERVER_PORT", server.port().to_string());
+        }
+        if let Some(host) = request.host {
+            let name = match host.rsplit_once(':') {
+                Some((name, port)) if port.parse::<u16>().is_ok() => name,
+                _ => host,
+            };
+            set("SERVER_NAME", name.to_string());
+        }
+        set(
+            "REQUEST_SCHEME",
+            if request.https { "https" } else { "http" }.to_string(),
+        );
+        if request.https {
+            set("HTTPS", "on".to_string());
+        }
+        if let Some(content_type) = header.headers.get(CONTENT_TYPE)
+            && let Ok(content_type) = content_type.to_str()
+        {
+            set("CONTENT_TYPE", content_type.to_string());
+        }
+        set("CONTENT_LENGTH", content_length.to_string());
+        for name in header.headers.keys() {
+            // Proxy: would set HTTP_PROXY ("httpoxy"). Names with underscores could pass for
+            // others once mapped.
+            if name == CONTENT_TYPE
+                || name == CONTENT_LENGTH
+                || name.as_str() == "proxy"
+                || name.as_str().contains('_')
+            {
+                continue;
+            }
+            let separator = if name == http::header::COOKIE {
+                "; "
+            } else {
+                ", "
+            };
+            let value = header
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(separator);
+            set(
+                &format!(
+                    "HTTP_{}",
+                    name.as_str().to_ascii_uppercase().replace('-', "_")
+                ),
+                value,
+            );
+        }
+
+        let configured: Vec<(String, String)> = self
+            .params
+            .iter()
+            .map(|(name, value)| (name.clone(), substitute(value, &params)))
+            .collect();
+        params.extend(configured);
+        params.into_iter().collect()
+    }
+
+    async fn connect(&self) -> Result<Box<dyn Connection>> {
+        let connect = async {
+            match &self.addr {
+                Addr::Tcp(addr) => TcpStream::connect(addr.as_str())
+                    .await
+                    .map(|stream| Box::new(stream) as Box<dyn Connection>),
+                Addr::Unix(path) => UnixStream::connect(path)
+                    .await
+                    .map(|stream| Box::new(stream) as Box<dyn Connection>),
+            }
+        };
+        match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(connected) => connected
+                .or_err_with(ErrorType::ConnectError, || {
+                    format!("connecting to FastCGI server {}", self.addr)
+                })
+                .map_err(|err| err.into_up()),
+            Err(_) => Error::e_explain(
+                ErrorType::ConnectTimedout,
+                format!("connecting to FastCGI server {}", self.addr),
+            )
+            .map_err(|err| err.into_up()),
+        }
+    }
+
+    /// Reads the next record of the response, logging what the application wrote to stderr.
+    async fn read_record(&self, connection: &mut BufReader<Box<dyn Connection>>) -> Result<Output> {
+        loop {
+            let read = async {
+                let mut head = [0; 8];
+                connection.read_exact(&mut head).await?;
+                let [_, kind, _, _, high, low, padding, _] = head;
+                let mut content = vec![0; usize::from(u16::from_be_bytes([high, low]))];
+                connection.read_exact(&mut content).await?;
+                let mut padding = vec![0; usize::from(padding)];
+                connection.read_exact(&mut padding).await?;
+                Ok::<_, std::io::Error>((kind, content))
+            };
+            let (kind, content) = tokio::time::timeout(self.read_timeout, read)
+                .await
+                .or_err(ErrorType::ReadTimedout, "waiting for FastCGI")
+                .map_err(|err| err.into_up())?
+                .or_err(ErrorType::ReadError, "reading from FastCGI")
+                .map_err(|err| err.into_up())?;
+            match kind {
+                STDOUT if !content.is_empty() => return Ok(Output::Stdout(content.into())),
+                STDERR if !content.is_empty() => {
+                    warn!(
+                        "FastCGI {}: {}",
+                        self.addr,
+                        String::from_utf8_lossy(&content).trim_end()
+                    );
+                }
+                END_REQUEST => return Ok(Output::End),
+                _ => {}
+            }
+        }
+    }
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+enum Output {
+    Stdout(Bytes),
+    End,
+}
+
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+    let mut record = vec![VERSION, kind];
+    record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    // No padding.
+    record.extend_from_slice(&[0, 0]);
+    record.extend_from_slice(content);
+    record
+}
+
+/// Name and value lengths take one byte below 128, four otherwise.
+fn encode_len(buf: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        buf.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// The response header in the CGI headers of `head`, with the body bytes after them, once
+/// they are complete.
+fn parse_head(head: &[u8]) -> Result<Option<(ResponseHeader, Bytes)>> {
+    let Some((end, separator)) = [&b"\r\n\r\n"[..], b"\n\n"]
+        .iter()
+        .filter_map(|separator| {
+            head.windows(separator.len())
+                .position(|window| window == *separator)
+                .map(|end| (end, separator.len()))
+        })
+        .min()
+    else {
+        return Ok(None);
+    };
+    let text = String::from_utf8_lossy(&head[..end]);
+    let mut status = None;
+    let mut headers = Vec::new();
+    for line in text.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            return Error::e_explain(
+                ErrorType::InvalidHTTPHeader,
+                format!("invalid FastCGI response header {line:?}"),
+            )
+            .map_err(|err| err.into_up());
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("status") {
+            status = value
+                .split(' ')
+                .next()
+                .and_then(|code| code.parse::<u16>().ok());
+        } else {
+            headers.push((name.trim().to_string(), value.to_string()));
+        }
+    }
+    let redirect = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case(LOCATION.as_str()));
+    let status = status.unwrap_or(if redirect { 302 } else { 200 });
+    let mut header = ResponseHeader::build(status, Some(headers.len() + 2))?;
+    for (name, value) in headers {
+        header.append_header(name, value)?;
+    }
+    Ok(Some((
+        header,
+        Bytes::copy_from_slice(&head[end + separator..]),
+    )))
+}
+
+/// Replaces `$NAME` in `value` with the parameter NAME, or nothing if there is none.
+fn substitute(value: &str, params: &BTreeMap<String, String>) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let len = after
+            .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+            .unwrap_or(after.len());
+        if len == 0 {
+            result.push('$');
+        } else if let Some(param) = params.get(&after[..len]) {
+            result.push_str(param);
+        }
+        rest = &after[len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fastcgi(config: &str) -> Result<FastCgi, String> {
+        FastCgi::new(&toml::from_str(config).unwrap())
+    }
+
+    fn php() -> FastCgi {
+        fastcgi("addr = \"127.0.0.1:9000\"\nroot = \"/srv/app/\"").unwrap()
+    }
+
+    /// The script name and path info `path` runs, if any.
+    fn script(fastcgi: &FastCgi, path: &str) -> Option<(String, Option<String>)> {
+        fastcgi
+            .script(path)
+            .map(|script| (script.name, script.path_info))
+    }
+
+    #[test]
+    fn finds_scripts_in_paths() {
+        let php = php();
+        let found = |name: &str, path_info: Option<&str>| {
+            Some((name.to_string(), path_info.map(str::to_string)))
+        };
+        assert_eq!(
+            script(&php, "/blog/post.php"),
+            found("/blog/post.php", None)
+        );
+        assert_eq!(
+            script(&php, "/post.php/2024/hello"),
+            found("/post.php", Some("/2024/hello"))
+        );
+        assert_eq!(script(&php, "/admin/"), found("/admin/index.php", None));
+        assert_eq!(script(&php, "/my%20page.php"), found("/my page.php", None));
+        // `.php` only ends a name before a slash.
+        assert_eq!(script(&php, "/a.phpx/b.php"), found("/a.phpx/b.php", None));
+        for path in [
+            "/style.css",
+            "/a.phpx",
+            "/../etc/passwd.php",
+            "/a/%2e%2e/b.php",
+            "/./a.php",
+            "/a%00.php",
+            "/%ff.php",
+        ] {
+            assert_eq!(script(&php, path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn runs_the_front_controller_for_every_path() {
+        let front = fastcgi(
+            "addr = \"/run/php-fpm.sock\"\nroot = \"/srv/app\"\nscript = \"public/index.php\"",
+        )
+        .unwrap();
+        assert!(matches!(front.addr, Addr::Unix(_)));
+        assert_eq!(front.root, "/srv/app");
+        for path in ["/", "/style.css", "/../x"] {
+            assert_eq!(
+                script(&front, path),
+                Some(("/public/index.php".to_string(), None))
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_configs() {
+        for (config, error) in [
+            ("addr = \"php\"\nroot = \"/srv\"", "neither a host:port"),
+            (
+                "addr = \"php:9000\"\nroot = \"srv\"",
+                "must be an absolute path",
+            ),
+            (
+                "addr = \"php:9000\"\nroot = \"/srv\"\nscript = \"../x.php\"",
+                "must be a path within root",
+            ),
+            (
+                "addr = \"php:9000\"\nroot = \"/srv\"\nextension = \"php\"",
+                "must be like",
+            ),
+        ] {
+            let err = fastcgi(config).err().unwrap();
+            assert!(err.contains(error), "{err}");
+        }
+    }
+
+    #[test]
+    fn parses_cgi_headers() {
+        assert!(
+            parse_head(b"Content-Type: text/html\r\n")
+                .unwrap()
+                .is_none()
+        );
+
+        let (header, rest) = parse_head(b"Status: 404 Not Found\r\nX-A: 1\r\n\r\nbody")
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.status, 404);
+        assert_eq!(header.headers["x-a"], "1");
+        assert_eq!(rest, "body");
+
+        let (header, rest) = parse_head(b"Location: /next\n\n").unwrap().unwrap();
+        assert_eq!(header.status, 302);
+        assert!(rest.is_empty());
+
+        let (header, _) = parse_head(b"Content-Type: text/plain\n\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.status, 200);
+
+        assert!(parse_head(b"not a header\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn substitutes_params() {
+        let params = BTreeMap::from([
+            ("SCRIPT_NAME".to_string(), "/index.php".to_string()),
+            ("HTTPS".to_string(), "on".to_string()),
+        ]);
+        assert_eq!(
+            substitute("/srv/public$SCRIPT_NAME", &params),
+            "/srv/public/index.php"
+        );
+        assert_eq!(substitute("$HTTPS/$MISSING/$", &params), "on//$");
+        assert_eq!(substitute("$HTTPSx", &params), "onx");
+        assert_eq!(substitute("$https", &params), "$https");
+    }
+
+    #[test]
+    fn encodes_records() {
+        let mut lengths = Vec::new();
+        encode_len(&mut lengths, 5);
+        encode_len(&mut lengths, 300);
+        assert_eq!(lengths, [5, 0x80, 0, 1, 44]);
+        assert_eq!(
+            record(STDIN, b"ab"),
+            [VERSION, STDIN, 0, 1, 0, 2, 0, 0, b'a', b'b']
+        );
+    }
+}
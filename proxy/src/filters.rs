@@ -0,0 +1,424 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::CONTENT_LENGTH;
+use log::debug;
+use pingora::http::{Method, RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+
+use crate::api_keys::ApiKey;
+use crate::errors::refusal_response;
+use crate::metrics::ProxyMetrics;
+use crate::rate_limit::{RateLimiter, too_many_requests};
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::routes::Route;
+use crate::state::ProxyState;
+use crate::wasm::WasmPlugin;
+
+/// A step of the proxy every request to a route passes through, in the order of the route's
+/// `filters`. Each hook does nothing unless overridden.
+///
+/// Register filters with [`ProxyBuilder::filter`](crate::ProxyBuilder::filter); the config
+/// names them to place them in chains.
+#[async_trait]
+pub trait Filter: Send + Sync {
+    /// Runs once the route and client are known, before the request goes upstream or is
+    /// served locally. Returning a response sends it instead, and skips the filters after.
+    async fn request_filter(
+        &self,
+        _session: &mut Session,
+        _ctx: &mut FilterCtx,
+    ) -> Result<Option<(ResponseHeader, Bytes)>> {
+        Ok(None)
+    }
+
+    /// Sees each chunk of the request body on its way upstream.
+    fn request_body_filter(
+        &self,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Changes the request sent upstream.
+    fn upstream_request_filter(
+        &self,
+        _request: &mut RequestHeader,
+        _ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Changes the response header sent to the client.
+    fn response_filter(
+        &self,
+        _request: &RequestHeader,
+        _response: &mut ResponseHeader,
+        _ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sees each chunk of the response body on its way to the client.
+    fn response_body_filter(
+        &self,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What the filters of a request know about it, and share with each other.
+pub struct FilterCtx {
+    pub request_id: String,
+    /// Name of the route the request took, if any.
+    pub route: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    /// Key the request was accepted with, on routes with `api_keys`.
+    pub api_key: Option<ApiKey>,
+    /// Name of the verified bot that sent the request.
+    pub bot: Option<String>,
+    /// Values filters leave for later hooks, of their own or other filters.
+    pub extensions: http::Extensions,
+    state: Arc<ProxyState>,
+    route_config: Option<Arc<Route>>,
+}
+
+impl FilterCtx {
+    pub fn new(
+        request_id: String,
+        state: Arc<ProxyState>,
+        route: Option<Arc<Route>>,
+        client_ip: Option<IpAddr>,
+        bot: Option<String>,
+    ) -> Self {
+        Self {
+            request_id,
+            route: route.as_ref().map(|route| route.name.clone()),
+            client_ip,
+            api_key: None,
+            bot,
+            extensions: http::Extensions::new(),
+            state,
+            route_config: route,
+        }
+    }
+}
+
+/// The filters a chain can name: the built-in ones and those registered in code.
+#[derive(Clone)]
+pub struct Filters {
+    filters: Vec<(String, Arc<dyn Filter>)>,
+}
+
+impl Filters {
+    pub fn new(
+        rate_limiter: Arc<RateLimiter>,
+        metrics: Option<Arc<ProxyMetrics>>,
+        custom: Vec<(String, Arc<dyn Filter>)>,
+    ) -> Result<Self, String> {
+        // The built-in filters, in the order chains that don't name their filters run them.
+        let mut filters: Vec<(String, Arc<dyn Filter>)> = vec![
+            ("api_keys".to_string(), Arc::new(ApiKeysFilter)),
+            (
+                "rate_limit".to_string(),
+                Arc::new(RateLimitFilter {
+                    limiter: rate_limiter,
+                    metrics,
+                }),
+            ),
+            ("basic_auth".to_string(), Arc::new(BasicAuthFilter)),
+            ("cors".to_string(), Arc::new(CorsFilter)),
+            ("headers".to_string(), Arc::new(HeadersFilter)),
+        ];
+        for (name, filter) in custom {
+            if filters.iter().any(|(known, _)| *known == name) {
+                return Err(format!("filter '{name}' is registered twice"));
+            }
+            filters.push((name, filter));
+        }
+        Ok(Self { filters })
+    }
+
+    /// These filters followed by `plugins`, which run after them in chains that don't name
+    /// their filters.
+    pub fn with_plugins(&self, plugins: &[Arc<WasmPlugin>]) -> Result<Self, String> {
+        let mut filters = self.clone();
+        for plugin in plugins {
+            let name = &plugin.config().name;
+            if filters.filters.iter().any(|(known, _)| known == name) {
+                return Err(format!("filter '{name}' is registered twice"));
+            }
+            filters.filters.push((name.clone(), plugin.clone()));
+        }
+        Ok(filters)
+    }
+
+    /// The chain running the filters `names`, or every filter when `None`.
+    pub fn chain(&self, names: Option<&[String]>) -> Result<FilterChain, String> {
+        let Some(names) = names else {
+            return Ok(FilterChain {
+                filters: self.filters.clone().into(),
+            });
+        };
+        let mut filters = Vec::with_capacity(names.len());
+        for name in names {
+            if names.iter().filter(|other| *other == name).count() > 1 {
+                return Err(format!("filter '{name}' is listed twice"));
+            }
+            let Some(filter) = self.filters.iter().find(|(known, _)| known == name) else {
+                return Err(format!("unknown filter '{name}'"));
+            };
+            filters.push(filter.clone());
+        }
+        Ok(FilterChain {
+            filters: filters.into(),
+        })
+    }
+}
+
+/// Filters run in order on the requests of a route.
+#[derive(Clone)]
+pub struct FilterChain {
+    filters: Arc<[(String, Arc<dyn Filter>)]>,
+}
+
+impl fmt::Debug for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.filters.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+impl FilterChain {
+    /// The first response a filter answers the request with.
+    pub async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut FilterCtx,
+    ) -> Result<Option<(ResponseHeader, Bytes)>> {
+        for (_, filter) in self.filters.iter() {
+            if let Some(response) = filter.request_filter(session, ctx).await? {
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn request_body_filter(
+        &self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        for (_, filter) in self.filters.iter() {
+            filter.request_body_filter(body, end_of_stream, ctx)?;
+        }
+        Ok(())
+    }
+
+    pub fn upstream_request_filter(
+        &self,
+        request: &mut RequestHeader,
+        ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        for (_, filter) in self.filters.iter() {
+            filter.upstream_request_filter(request, ctx)?;
+        }
+        Ok(())
+    }
+
+    pub fn response_filter(
+        &self,
+        request: &RequestHeader,
+        response: &mut ResponseHeader,
+        ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        for (_, filter) in self.filters.iter() {
+            filter.response_filter(request, response, ctx)?;
+        }
+        Ok(())
+    }
+
+    pub fn response_body_filter(
+        &self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        for (_, filter) in self.filters.iter() {
+            filter.response_body_filter(body, end_of_stream, ctx)?;
+        }
+        Ok(())
+    }
+}
+
+/// Refuses requests to routes with `api_keys` that carry no valid key.
+struct ApiKeysFilter;
+
+#[async_trait]
+impl Filter for ApiKeysFilter {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut FilterCtx,
+    ) -> Result<Option<(ResponseHeader, Bytes)>> {
+        let Some(route) = &ctx.route_config else {
+            return Ok(None);
+        };
+        let Some(api_keys) = &route.api_keys else {
+            return Ok(None);
+        };
+        ctx.api_key = api_keys.authenticate(session.req_header());
+        if ctx.api_key.is_some() {
+            return Ok(None);
+        }
+        debug!(
+            "request {} to route '{}' has no valid API key",
+            ctx.request_id, route.name
+        );
+        refusal_response(401, &ctx.request_id).map(Some)
+    }
+}
+
+/// Applies `[rate_limit]`.
+struct RateLimitFilter {
+    limiter: Arc<RateLimiter>,
+    metrics: Option<Arc<ProxyMetrics>>,
+}
+
+#[async_trait]
+impl Filter for RateLimitFilter {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut FilterCtx,
+    ) -> Result<Option<(ResponseHeader, Bytes)>> {
+        let Some(rate_limits) = &ctx.state.rate_limits else {
+            return Ok(None);
+        };
+        let Err(retry_after) = self
+            .limiter
+            .check(
+                rate_limits,
+                session.req_header(),
+                ctx.client_ip,
+                ctx.route.as_deref(),
+                ctx.api_key.as_ref(),
+                ctx.bot.as_deref(),
+            )
+            .await
+        else {
+            return Ok(None);
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.rate_limited();
+        }
+        too_many_requests(retry_after, &ctx.request_id).map(Some)
+    }
+}
+
+/// Asks for credentials on routes with `basic_auth`.
+struct BasicAuthFilter;
+
+#[async_trait]
+impl Filter for BasicAuthFilter {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut FilterCtx,
+    ) -> Result<Option<(ResponseHeader, Bytes)>> {
+        let Some(route) = &ctx.route_config else {
+            return Ok(None);
+        };
+        let Some(basic_auth) = &route.basic_auth else {
+            return Ok(None);
+        };
+        if basic_auth.authorizes(session.req_header()).await {
+            return Ok(None);
+        }
+        debug!(
+            "request {} to route '{}' has no valid credentials",
+            ctx.request_id, route.name
+        );
+        basic_auth.challenge(&ctx.request_id).map(Some)
+    }
+}
+
+/// Answers preflight requests and adds the CORS headers of `[cors]` to responses.
+struct CorsFilter;
+
+#[async_trait]
+impl Filter for CorsFilter {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut FilterCtx,
+    ) -> Result<Option<(ResponseHeader, Bytes)>> {
+        let request = session.req_header();
+        if request.method != Method::OPTIONS {
+            return Ok(None);
+        }
+        let cors = &ctx.state.response_policy.cors;
+        if cors.is_preflight(request) {
+            let mut header = cors.preflight_response(request)?;
+            header.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+            return Ok(Some((header, Bytes::new())));
+        }
+        if cors.intercepts_plain_options() {
+            let mut header = ResponseHeader::build(200, Some(2))?;
+            header.insert_header(CONTENT_LENGTH, 0)?;
+            header.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+            return Ok(Some((header, Bytes::new())));
+        }
+        Ok(None)
+    }
+
+    fn response_filter(
+        &self,
+        request: &RequestHeader,
+        response: &mut ResponseHeader,
+        ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        ctx.state.response_policy.cors.apply(request, response)
+    }
+}
+
+/// Applies the global `[headers]` rules, then those of the route.
+struct HeadersFilter;
+
+#[async_trait]
+impl Filter for HeadersFilter {
+    fn upstream_request_filter(
+        &self,
+        request: &mut RequestHeader,
+        ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        ctx.state.headers.apply_request(request)?;
+        if let Some(route) = &ctx.route_config {
+            route.headers.apply_request(request)?;
+        }
+        Ok(())
+    }
+
+    fn response_filter(
+        &self,
+        _request: &RequestHeader,
+        response: &mut ResponseHeader,
+        ctx: &mut FilterCtx,
+    ) -> Result<()> {
+        ctx.state.headers.apply_response(response)?;
+        if let Some(route) = &ctx.route_config {
+            route.headers.apply_response(response)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use http::StatusCode;
+use http::uri::Authority;
+use ipnet::IpNet;
+use log::debug;
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::audit;
+use crate::basic_auth::{BasicAuth, BasicAuthConfig};
+use crate::ip_list::IpList;
+use crate::state::SharedState;
+use crate::tcp_proxy::relay;
+
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 5 * 60;
+/// Time a client has to send its CONNECT request.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest CONNECT request head accepted.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// `[forward_proxy]` section of the config file: an egress proxy on its own listener that
+/// opens CONNECT tunnels to allowed destinations. Off when absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct ForwardProxyConfig {
+    /// Address it listens on, e.g. `"127.0.0.1:3128"`; only changes on restart.
+    pub listen_addr: String,
+    /// Destinations tunnels may be opened to, as `host:port`: a name, `*.` and a domain for
+    /// its subdomains, an IP address or CIDR block (IPv6 in brackets), or `*` for any host.
+    /// Without a port, or with `*`, any port.
+    pub allow: Vec<String>,
+    /// Only clients in these IP addresses and CIDR blocks may use it.
+    pub allow_clients: Option<Vec<String>>,
+    /// Requires `Proxy-Authorization` credentials of a user in an htpasswd file.
+    pub basic_auth: Option<BasicAuthConfig>,
+    /// Lets host names resolve to loopback, private and link-local addresses, which are
+    /// refused otherwise. Destinations given as such addresses only need to be allowed.
+    pub allow_private_addresses: Option<bool>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub connect_timeout_seconds: Option<u64>,
+    /// How long a tunnel may carry nothing either way before it is closed.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub idle_timeout_seconds: Option<u64>,
+}
+
+impl ForwardProxyConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            allow_private_addresses: Some(self.allow_private_addresses.unwrap_or(false)),
+            connect_timeout_seconds: Some(
+                self.connect_timeout_seconds
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            ),
+            idle_timeout_seconds: Some(
+                self.idle_timeout_seconds
+                    .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+            ),
+            ..self
+        }
+    }
+}
+
+enum HostPattern {
+    Any,
+    Name(String),
+    /// `*.example.com`, kept as `.example.com`.
+    Subdomains(String),
+    Network(IpNet),
+}
+
+/// An `allow` entry.
+struct Destination {
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+impl Destination {
+    fn parse(entry: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid destination '{entry}'");
+        let (host, port) = match entry.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+                let port = match rest {
+                    "" => None,
+                    _ => Some(rest.strip_prefix(':').ok_or_else(invalid)?),
+                };
+                (host, port)
+            }
+            None => match entry.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (entry, None),
+            },
+        };
+        let port = match port {
+            None | Some("*") => None,
+            Some(port) => Some(port.parse().map_err(|_| invalid())?),
+        };
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Subdomains(format!(".{}", domain.to_ascii_lowercase()))
+        } else if let Ok(network) = host
+            .parse::<IpNet>()
+            .or_else(|_| host.parse::<IpAddr>().map(IpNet::from))
+        {
+            HostPattern::Network(network)
+        } else if !host.is_empty() && !host.contains(['*', '/', '[', ']']) {
+            HostPattern::Name(host.to_ascii_lowercase())
+        } else {
+            return Err(invalid());
+        };
+        Ok(Self { host, port })
+    }
+
+    fn allows(&self, host: &str, ip: Option<IpAddr>, port: u16) -> bool {
+        if self.port.is_some_and(|allowed| allowed != port) {
+            return false;
+        }
+        match (&self.host, ip) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Network(network), Some(ip)) => network.contains(&ip),
+            (HostPattern::Name(name), None) => host.eq_ignore_ascii_case(name),
+            (HostPattern::Subdomains(suffix), None) => {
+                host.len() > suffix.len()
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A compiled `[forward_proxy]`.
+pub struct ForwardProxy {
+    allow: Vec<Destination>,
+    allow_clients: Option<IpList>,
+    basic_auth: Option<BasicAuth>,
+    allow_private_addresses: bool,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+/// A CONNECT request that was not tunneled.
+struct Refusal {
+    status: StatusCode,
+    reason: String,
+}
+
+impl Refusal {
+    fn new(status: StatusCode, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl ForwardProxy {
+    pub fn new(config: &ForwardProxyConfig) -> Result<Self, String> {
+        if config.allow.is_empty() {
+            return Err("allow must list at least one destination".to_string());
+        }
+        Ok(Self {
+            allow: config
+                .allow
+                .iter()
+                .map(|entry| Destination::parse(entry))
+                .collect::<Result<_, _>>()?,
+            allow_clients: config
+                .allow_clients
+                .as_deref()
+                .map(IpList::parse)
+                .transpose()?,
+            basic_auth: config.basic_auth.as_ref().map(BasicAuth::new).transpose()?,
+            allow_private_addresses: config.allow_private_addresses.unwrap_or(false),
+            connect_timeout: Duration::from_secs(
+                config
+                    .connect_timeout_seconds
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            ),
+            idle_timeout: Duration::from_secs(
+                config
+                    .idle_timeout_seconds
+                    .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+            ),
+        })
+    }
+
+    pub fn refresh(&self) {
+        if let Some(basic_auth) = &self.basic_auth {
+            basic_auth.refresh();
+        }
+    }
+
+    /// Answers one CONNECT request on `stream` and relays the tunnel until either side
+    /// closes it, recording it in the audit log.
+    async fn serve(&self, mut stream: Stream) {
+        let client = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied());
+        let Some((head, early_data)) = read_head(&mut stream).await else {
+            return;
+        };
+        let mut user = None;
+        let opened = self
+            .open(&head, client.map(|client| client.ip()), &mut user)
+            .await;
+        let (target, upstream) = match opened {
+            Ok(opened) => opened,
+            Err(refusal) => {
+                debug!(
+                    "forward proxy refused {} from {client:?}: {}",
+                    head.target, refusal.reason
+                );
+                audit::record(
+                    "forward_proxy_refused",
+                    json!({
+                        "client": client.map(|client| client.ip().to_string()),
+                        "user": user,
+                        "destination": head.target,
+                        "status": refusal.status.as_u16(),
+                        "reason": refusal.reason,
+                    }),
+                );
+                let mut response = format!(
+                    "HTTP/1.1 {} {}\r\n",
+                    refusal.status.as_u16(),
+                    refusal.status.canonical_reason().unwrap_or_default()
+                );
+                if refusal.status == StatusCode::PROXY_AUTHENTICATION_REQUIRED
+                    && let Some(basic_auth) = &self.basic_auth
+                {
+                    response.push_str(&format!(
+                        "Proxy-Authenticate: Basic realm=\"{}\", charset=\"UTF-8\"\r\n",
+                        basic_auth.realm()
+                    ));
+                }
+                response.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.flush().await;
+                return;
+            }
+        };
+        let started = Instant::now();
+        let address = upstream.peer_addr().ok();
+        let relayed = async {
+            stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            stream.flush().await?;
+            let mut upstream = upstream;
+            upstream.write_all(&early_data).await?;
+            Ok::<_, std::io::Error>(relay(stream, upstream, self.idle_timeout).await)
+        }
+        .await;
+        let (sent, received, ended) = relayed.unwrap_or((0, 0, "error"));
+        audit::record(
+            "forward_proxy_tunnel",
+            json!({
+                "client": client.map(|client| client.ip().to_string()),
+                "user": user,
+                "destination": target,
+                "address": address.map(|address| address.to_string()),
+                "bytes_sent": sent + early_data.len() as u64,
+                "bytes_received": received,
+                "duration_ms": started.elapsed().as_millis() as u64,
+                "ended": ended,
+            }),
+        );
+    }
+
+    /// Checks a request and connects to its destination.
+    async fn open(
+        &self,
+        head: &ConnectHead,
+        client: Option<IpAddr>,
+        user: &mut Option<String>,
+    ) -> Result<(String, TcpStream), Refusal> {
+        if let Some(allow_clients) = &self.allow_clients
+            && !client.is_some_and(|client| allow_clients.contains(&client))
+        {
+            return Err(Refusal::new(StatusCode::FORBIDDEN, "client not allowed"));
+        }
+        if let Some(basic_auth) = &self.basic_auth {
+            *user = basic_auth.user(head.proxy_authorization.as_deref()).await;
+            if user.is_none() {
+                return Err(Refusal::new(
+                    StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                    "no valid credentials",
+                ));
+            }
+        }
+        if head.method != "CONNECT" {
+            return Err(Refusal::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("{} instead of CONNECT", head.method),
+            ));
+        }
+        let (host, port) = head
+            .target
+            .parse::<Authority>()
+            .ok()
+            .and_then(|authority| {
+                let host = authority
+                    .host()
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+                Some((host.to_string(), authority.port_u16()?))
+            })
+            .ok_or_else(|| Refusal::new(StatusCode::BAD_REQUEST, "not a host:port target"))?;
+        let ip = host.parse::<IpAddr>().ok();
+        if !self
+            .allow
+            .iter()
+            .any(|destination| destination.allows(&host, ip, port))
+        {
+            return Err(Refusal::new(
+                StatusCode::FORBIDDEN,
+                "destination not allowed",
+            ));
+        }
+        let connect = async {
+            let addresses: Vec<SocketAddr> = match ip {
+                Some(ip) => vec![SocketAddr::new(ip, port)],
+                None => tokio::net::lookup_host((host.as_str(), port))
+                    .await
+                    .map_err(|err| Refusal::new(StatusCode::BAD_GATEWAY, err.to_string()))?
+                    .filter(|address| self.allow_private_addresses || !is_private(address.ip()))
+                    .collect(),
+            };
+            if addresses.is_empty() {
+                return Err(Refusal::new(
+                    StatusCode::FORBIDDEN,
+                    "destination resolves to private addresses only",
+                ));
+            }
+            TcpStream::connect(&addresses[..])
+                .await
+                .map_err(|err| Refusal::new(StatusCode::BAD_GATEWAY, err.to_string()))
+        };
+        let upstream = tokio::time::timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| Refusal::new(StatusCode::GATEWAY_TIMEOUT, "connect timed out"))??;
+        Ok((head.target.clone(), upstream))
+    }
+}
+
+/// The parts of a request head the forward proxy looks at.
+struct ConnectHead {
+    method: String,
+    target: String,
+    proxy_authorization: Option<String>,
+}
+
+/// Reads a request head, returning it with any bytes the client sent after it.
+async fn read_head(stream: &mut Stream) -> Option<(ConnectHead, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return None;
+        }
+        let mut chunk = [0; 1024];
+        let read = tokio::time::timeout(HEAD_TIMEOUT, stream.read(&mut chunk)).await;
+        match read {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => return None,
+            Ok(Ok(read)) => buf.extend_from_slice(&chunk[..read]),
+        }
+    };
+    let head = std::str::from_utf8(&buf[..end]).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let proxy_authorization = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("proxy-authorization")
+            .then(|| value.trim().to_string())
+    });
+    let early_data = buf[end + 4..].to_vec();
+    Some((
+        ConnectHead {
+            method,
+            target,
+            proxy_authorization,
+        },
+        early_data,
+    ))
+}
+
+/// Loopback, private, shared, link-local and unspecified addresses.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || (first == 100 && second & 0xC0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || first & 0xFE00 == 0xFC00
+                    || first & 0xFFC0 == 0xFE80
+            }
+        },
+    }
+}
+
+/// Listening service of `[forward_proxy]`, with the allow lists and credentials of the
+/// current config.
+pub struct ForwardProxyService {
+    state: SharedState,
+}
+
+impl ForwardProxyService {
+    pub fn new(state: SharedState) -> Self {
+        Self { state }
+    }
+}
+
+#[async_trait]
+impl ServerApp for ForwardProxyService {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let state = self.state.current();
+        if let Some(forward_proxy) = &state.forward_proxy {
+            forward_proxy.serve(stream).await;
+        }
+        None
+    }
+}
@@ -0,0 +1,474 @@
+use std::net::IpAddr;
+
+use http::header::HOST;
+use pingora::http::RequestHeader;
+use pingora::prelude::*;
+use pingora::proxy::Session;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::ip_list::IpList;
+use crate::proxy_protocol;
+
+const FORWARDED: &str = "Forwarded";
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+const X_REAL_IP: &str = "X-Real-IP";
+
+/// Value of the `client_ip_header` config key: the header trusted proxies record the
+/// client address in. Only one is read, since clients can send the other one themselves.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpHeader {
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded`, from its `for=` parameters.
+    Forwarded,
+}
+
+/// Set of peers whose forwarding headers are believed.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    networks: IpList,
+    header: ClientIpHeader,
+    unix_peers: bool,
+}
+
+impl TrustedProxies {
+    /// Parses a list of IP addresses or CIDR blocks.
+    pub fn parse(entries: &[String]) -> std::result::Result<Self, String> {
+        IpList::parse(entries)
+            .map(|networks| Self {
+                networks,
+                header: ClientIpHeader::default(),
+                unix_peers: false,
+            })
+            .map_err(|err| format!("invalid trusted proxy entry: {err}"))
+    }
+
+    /// Reads client addresses from `header` instead of `X-Forwarded-For`.
+    pub fn reading(self, header: ClientIpHeader) -> Self {
+        Self { header, ..self }
+    }
+
+    /// Believes peers on a Unix socket too when `trust` is set.
+    pub fn trusting_unix_peers(self, trust: bool) -> Self {
+        Self {
+            unix_peers: trust,
+            ..self
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.networks.contains(ip)
+    }
+
+    /// Whether the forwarding headers of `peer`, `None` on a Unix socket, are believed.
+    fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(ip) => self.contains(&ip),
+            None => self.unix_peers,
+        }
+    }
+}
+
+/// Scheme the client used to reach the proxy.
+pub fn downstream_scheme(session: &Session) -> &'static str {
+    if session
+        .digest()
+        .and_then(|digest| digest.ssl_digest.as_ref())
+        .is_some()
+    {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+/// Host the client addressed, from `Host` or the request authority (HTTP/2).
+pub fn downstream_host(session: &Session) -> Option<&str> {
+    let request = session.req_header();
+    request
+        .headers
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| request.uri.authority().map(|authority| authority.as_str()))
+}
+
+/// Address of the client the request came from: the peer, or when the peer is a trusted
+/// proxy, the rightmost untrusted entry of the `client_ip_header`. `None` on a Unix socket,
+/// unless its peers are trusted and sent forwarding headers.
+pub fn client_ip(session: &Session, trusted: &TrustedProxies) -> Option<IpAddr> {
+    resolve_client_ip(peer_ip(session), session.req_header(), trusted)
+}
+
+/// Address of the peer, or of the client a load balancer named with the PROXY protocol.
+fn peer_ip(session: &Session) -> Option<IpAddr> {
+    proxy_protocol::client_addr(session).and_then(|addr| addr.as_inet().map(|addr| addr.ip()))
+}
+
+/// `client_ip` of a request from `peer_ip`.
+fn resolve_client_ip(
+    peer_ip: Option<IpAddr>,
+    request: &RequestHeader,
+    trusted: &TrustedProxies,
+) -> Option<IpAddr> {
+    if !trusted.trusts(peer_ip) {
+        return peer_ip;
+    }
+    let values = request
+        .headers
+        .get_all(match trusted.header {
+            ClientIpHeader::XForwardedFor => X_FORWARDED_FOR,
+            ClientIpHeader::Forwarded => FORWARDED,
+        })
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    let forwarded = match trusted.header {
+        ClientIpHeader::XForwardedFor => values
+            .filter_map(|entry| entry.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>(),
+        ClientIpHeader::Forwarded => values.filter_map(forwarded_for).collect(),
+    };
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !trusted.contains(ip))
+        .or(forwarded.first())
+        .copied()
+        .or(peer_ip)
+}
+
+/// The address in the `for=` parameter of a `Forwarded` element, such as `for=192.0.2.60` or
+/// `for="[2001:db8::17]:4711"`. Obfuscated identifiers and `unknown` yield `None`.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    let node = element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("for")
+            .then(|| value.trim().trim_matches('"'))
+    })?;
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    // An IPv4 address, possibly with a port.
+    node.split(':').next()?.parse().ok()
+}
+
+/// The `Forwarded` element describing a hop from `peer`, e.g.
+/// `for="[2001:db8::17]";proto=https;host="example.com"`; `for=unknown` on a Unix socket.
+fn forwarded_element(peer: Option<IpAddr>, scheme: &str, host: Option<&str>) -> String {
+    let mut element = match peer {
+        Some(IpAddr::V4(ip)) => format!("for={ip}"),
+        Some(IpAddr::V6(ip)) => format!("for=\"[{ip}]\""),
+        None => "for=unknown".to_string(),
+    };
+    element.push_str(";proto=");
+    element.push_str(scheme);
+    if let Some(host) = host {
+        let escaped = host.replace('\\', "\\\\").replace('"', "\\\"");
+        element.push_str(&format!(";host=\"{escaped}\""));
+    }
+    element
+}
+
+/// Adds `Forwarded`, `X-Forwarded-*` and `X-Real-IP` headers to the upstream request.
+///
+/// Inbound values are only kept (and appended to) when the immediate peer is a
+/// trusted proxy; otherwise they are replaced so clients can't spoof them.
+/// Peers on a Unix socket are trusted with `trust_unix_peers`, but have no address
+/// of their own to append.
+pub fn apply_forwarded_headers(
+    session: &Session,
+    trusted: &TrustedProxies,
+    upstream_request: &mut RequestHeader,
+) -> Result<()> {
+    forward(
+        peer_ip(session),
+        session.req_header(),
+        downstream_scheme(session),
+        downstream_host(session),
+        trusted,
+        upstream_request,
+    )
+}
+
+/// `apply_forwarded_headers` for a `downstream` request from `peer_ip`.
+fn forward(
+    peer_ip: Option<IpAddr>,
+    downstream: &RequestHeader,
+    scheme: &str,
+    host: Option<&str>,
+    trusted: &TrustedProxies,
+    upstream_request: &mut RequestHeader,
+) -> Result<()> {
+    // Every line of a header, as one list.
+    let inbound = |name: &str| {
+        let values = downstream
+            .headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>();
+        (!values.is_empty()).then(|| values.join(", "))
+    };
+
+    let peer_trusted = trusted.trusts(peer_ip);
+
+    let element = forwarded_element(peer_ip, scheme, host);
+    let existing = downstream
+        .headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+    let forwarded = if peer_trusted && !existing.is_empty() {
+        format!("{}, {element}", existing.join(", "))
+    } else {
+        element
+    };
+    upstream_request.insert_header(FORWARDED, forwarded)?;
+
+    let forwarded_for = match (inbound(X_FORWARDED_FOR), peer_ip) {
+        (Some(existing), Some(peer)) if peer_trusted => Some(format!("{existing}, {peer}")),
+        (Some(existing), None) if peer_trusted => Some(existing),
+        (_, peer) => peer.map(|peer| peer.to_string()),
+    };
+    // The client a trusted proxy names, rather than the proxy itself.
+    let real_ip = match inbound(X_REAL_IP) {
+        Some(existing) if peer_trusted => Some(existing),
+        _ => resolve_client_ip(peer_ip, downstream, trusted).map(|ip| ip.to_string()),
+    };
+    for (name, value) in [(X_FORWARDED_FOR, forwarded_for), (X_REAL_IP, real_ip)] {
+        match value {
+            Some(value) => upstream_request.insert_header(name, value)?,
+            None => {
+                upstream_request.remove_header(name);
+            }
+        }
+    }
+
+    let proto = match inbound(X_FORWARDED_PROTO) {
+        Some(existing) if peer_trusted => existing,
+        _ => scheme.to_string(),
+    };
+    upstream_request.insert_header(X_FORWARDED_PROTO, proto)?;
+
+    match inbound(X_FORWARDED_HOST) {
+        Some(existing) if peer_trusted => {
+            upstream_request.insert_header(X_FORWARDED_HOST, existing)?;
+        }
+        _ => match host {
+            Some(host) => upstream_request.insert_header(X_FORWARDED_HOST, host.to_string())?,
+            None => {
+                upstream_request.remove_header(X_FORWARDED_HOST);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> TrustedProxies {
+        TrustedProxies::parse(&["10.0.0.0/8".to_string()]).unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            request.append_header(name.to_string(), *value).unwrap();
+        }
+        request
+    }
+
+    /// The upstream request's forwarding headers for `headers` from `peer`.
+    fn forwarded(peer: Option<&str>, headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        let downstream = request(headers);
+        let mut upstream = downstream.clone();
+        let peer = peer.map(|peer| peer.parse().unwrap());
+        forward(
+            peer,
+            &downstream,
+            "https",
+            Some("example.com"),
+            &trusted().trusting_unix_peers(true),
+            &mut upstream,
+        )
+        .unwrap();
+        [
+            FORWARDED,
+            X_FORWARDED_FOR,
+            X_REAL_IP,
+            X_FORWARDED_PROTO,
+            X_FORWARDED_HOST,
+        ]
+        .iter()
+        .filter_map(|name| {
+            let value = upstream.headers.get(*name)?.to_str().unwrap().to_string();
+            Some((name.to_string(), value))
+        })
+        .collect()
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    const SPOOFED: &[(&str, &str)] = &[
+        ("Forwarded", "for=6.6.6.6"),
+        ("X-Forwarded-For", "6.6.6.6"),
+        ("X-Real-IP", "6.6.6.6"),
+        ("X-Forwarded-Proto", "http"),
+        ("X-Forwarded-Host", "evil.example"),
+    ];
+
+    #[test]
+    fn untrusted_peers_cannot_spoof() {
+        let headers = forwarded(Some("203.0.113.9"), SPOOFED);
+        assert_eq!(
+            headers,
+            [
+                (
+                    "Forwarded",
+                    "for=203.0.113.9;proto=https;host=\"example.com\""
+                ),
+                ("X-Forwarded-For", "203.0.113.9"),
+                ("X-Real-IP", "203.0.113.9"),
+                ("X-Forwarded-Proto", "https"),
+                ("X-Forwarded-Host", "example.com"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        assert_eq!(
+            resolve_client_ip(
+                Some("203.0.113.9".parse().unwrap()),
+                &request(SPOOFED),
+                &trusted()
+            ),
+            Some("203.0.113.9".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn trusted_peers_are_appended_to() {
+        let headers = forwarded(Some("10.0.0.2"), SPOOFED);
+        assert_eq!(
+            header(&headers, "Forwarded"),
+            Some("for=6.6.6.6, for=10.0.0.2;proto=https;host=\"example.com\"")
+        );
+        assert_eq!(
+            header(&headers, "X-Forwarded-For"),
+            Some("6.6.6.6, 10.0.0.2")
+        );
+        assert_eq!(header(&headers, "X-Real-IP"), Some("6.6.6.6"));
+        assert_eq!(header(&headers, "X-Forwarded-Proto"), Some("http"));
+        assert_eq!(header(&headers, "X-Forwarded-Host"), Some("evil.example"));
+    }
+
+    #[test]
+    fn every_forwarded_for_line_is_kept() {
+        let headers = forwarded(
+            Some("10.0.0.2"),
+            &[
+                ("X-Forwarded-For", "6.6.6.6, 198.51.100.4"),
+                ("X-Forwarded-For", "10.0.0.3"),
+            ],
+        );
+        assert_eq!(
+            header(&headers, "X-Forwarded-For"),
+            Some("6.6.6.6, 198.51.100.4, 10.0.0.3, 10.0.0.2")
+        );
+        // Without an X-Real-IP of its own, the client is the rightmost untrusted entry.
+        assert_eq!(header(&headers, "X-Real-IP"), Some("198.51.100.4"));
+    }
+
+    #[test]
+    fn trusted_unix_peers_name_the_client() {
+        let headers = forwarded(None, &[("X-Forwarded-For", "198.51.100.4")]);
+        assert_eq!(header(&headers, "X-Forwarded-For"), Some("198.51.100.4"));
+        assert_eq!(header(&headers, "X-Real-IP"), Some("198.51.100.4"));
+        assert_eq!(
+            header(&headers, "Forwarded"),
+            Some("for=unknown;proto=https;host=\"example.com\"")
+        );
+    }
+
+    #[test]
+    fn client_ip_skips_trusted_hops() {
+        let peer = Some("10.0.0.2".parse().unwrap());
+        let client = |headers: &[(&str, &str)], trusted: &TrustedProxies| {
+            resolve_client_ip(peer, &request(headers), trusted).map(|ip| ip.to_string())
+        };
+        let xff = trusted();
+        assert_eq!(
+            client(
+                &[("X-Forwarded-For", "6.6.6.6, 198.51.100.4, 10.1.2.3")],
+                &xff
+            )
+            .as_deref(),
+            Some("198.51.100.4")
+        );
+        // Every hop trusted: the leftmost.
+        assert_eq!(
+            client(&[("X-Forwarded-For", "10.1.2.3, 10.2.3.4")], &xff).as_deref(),
+            Some("10.1.2.3")
+        );
+        assert_eq!(client(&[], &xff).as_deref(), Some("10.0.0.2"));
+        // Only the configured header counts.
+        let forwarded = trusted().reading(ClientIpHeader::Forwarded);
+        assert_eq!(
+            client(
+                &[
+                    ("X-Forwarded-For", "6.6.6.6"),
+                    (
+                        "Forwarded",
+                        "for=6.6.6.6, for=\"[2001:db8::17]:4711\";proto=https"
+                    ),
+                    ("Forwarded", "for=10.1.2.3"),
+                ],
+                &forwarded
+            )
+            .as_deref(),
+            Some("2001:db8::17")
+        );
+        assert_eq!(
+            client(&[("X-Forwarded-For", "6.6.6.6")], &forwarded).as_deref(),
+            Some("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn forwarded_for_parameters() {
+        for (element, ip) in [
+            ("for=192.0.2.60", Some("192.0.2.60")),
+            ("For=\"192.0.2.60:4711\"", Some("192.0.2.60")),
+            (
+                "proto=https; for=\"[2001:db8::17]:4711\"",
+                Some("2001:db8::17"),
+            ),
+            ("for=\"[2001:db8::17]\";by=10.0.0.1", Some("2001:db8::17")),
+            ("for=unknown", None),
+            ("for=_hidden", None),
+            ("by=192.0.2.60", None),
+            ("for=\"[2001:db8::17\"", None),
+        ] {
+            assert_eq!(
+                forwarded_for(element),
+                ip.map(|ip| ip.parse().unwrap()),
+                "{element}"
+            );
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::net::IpAddr;
+
+use log::debug;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::mmdb::Mmdb;
+use crate::watched_file::WatchedFile;
+
+/// `[geoip]` section of the config file: the country database `allow_countries` and
+/// `deny_countries` look clients up in.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct GeoIpConfig {
+    /// MaxMind DB file with country data (GeoLite2-Country, GeoIP2-Country or -City), re-read
+    /// when it changes.
+    pub database: String,
+}
+
+/// Country lookups in a local MaxMind database.
+pub struct GeoIp {
+    database: WatchedFile<Mmdb>,
+}
+
+impl GeoIp {
+    pub fn open(config: &GeoIpConfig) -> Result<Self, String> {
+        Ok(Self {
+            database: WatchedFile::open(&config.database, Mmdb::open)?,
+        })
+    }
+
+    /// ISO 3166 code of the country `ip` is located in, or else registered to.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record = match self.database.get().lookup(ip) {
+            Ok(record) => record?,
+            Err(err) => {
+                debug!("GeoIP lookup of {ip} failed: {err}");
+                return None;
+            }
+        };
+        ["country", "registered_country"]
+            .iter()
+            .find_map(|field| record[field]["iso_code"].as_str())
+            .map(str::to_string)
+    }
+
+    pub fn refresh(&self) {
+        self.database.refresh();
+    }
+}
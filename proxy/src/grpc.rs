@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use http::StatusCode;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::{Error, ErrorType};
+
+use crate::request_id::REQUEST_ID_HEADER;
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+/// Every gRPC message is prefixed with a compression flag and a big-endian length.
+const MESSAGE_PREFIX_LEN: usize = 5;
+
+/// Whether `request` is a gRPC call, by its content type.
+pub fn is_grpc(request: &RequestHeader) -> bool {
+    request
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value == "application/grpc" || value.starts_with("application/grpc+"))
+}
+
+/// The `grpc-status` in response headers or trailers.
+pub fn status(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get(GRPC_STATUS)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// A status the proxy ends a call with in place of the upstream's, for a request it stopped
+/// forwarding partway.
+#[derive(Debug)]
+pub struct CallError {
+    code: u32,
+    message: String,
+}
+
+impl CallError {
+    /// A request message of `length` bytes, over the limit.
+    pub fn message_too_large(length: usize) -> Self {
+        Self {
+            code: 8, // RESOURCE_EXHAUSTED
+            message: format!("request message of {length} bytes is over the proxy's limit"),
+        }
+    }
+
+    /// A request body that could not be translated.
+    pub fn malformed(message: &str) -> Self {
+        Self {
+            code: 13, // INTERNAL
+            message: message.to_string(),
+        }
+    }
+
+    /// The `grpc-status` and `grpc-message` headers or trailers reporting it.
+    pub fn headers(&self) -> [(&'static str, String); 2] {
+        [
+            (GRPC_STATUS, self.code.to_string()),
+            (GRPC_MESSAGE, self.message.clone()),
+        ]
+    }
+}
+
+/// The deadline a client set with `grpc-timeout`, e.g. `"5S"` or `"250m"`.
+pub fn timeout(request: &RequestHeader) -> Option<Duration> {
+    let value = request.headers.get(GRPC_TIMEOUT)?.to_str().ok()?;
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    // The spec allows at most 8 digits.
+    let amount: u64 = amount.parse().ok().filter(|_| amount.len() <= 8)?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Follows the message framing of a gRPC request body to enforce a size limit on each message.
+pub struct MessageSizes {
+    max: usize,
+    prefix: [u8; MESSAGE_PREFIX_LEN],
+    prefix_len: usize,
+    /// Bytes left of the current message after its prefix.
+    remaining: usize,
+}
+
+impl MessageSizes {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            prefix: [0; MESSAGE_PREFIX_LEN],
+            prefix_len: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Reads the next chunk of the body, returning the length of a message over the limit.
+    /// Nothing is to be fed after that.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<(), usize> {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let taken = self.remaining.min(data.len());
+                self.remaining -= taken;
+                data = &data[taken..];
+                continue;
+            }
+            let taken = (MESSAGE_PREFIX_LEN - self.prefix_len).min(data.len());
+            self.prefix[self.prefix_len..self.prefix_len + taken].copy_from_slice(&data[..taken]);
+            self.prefix_len += taken;
+            data = &data[taken..];
+            if self.prefix_len == MESSAGE_PREFIX_LEN {
+                let [_, length @ ..] = self.prefix;
+                let length = u32::from_be_bytes(length) as usize;
+                if length > self.max {
+                    return Err(length);
+                }
+                self.prefix_len = 0;
+                self.remaining = length;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The gRPC status for a call the proxy failed with HTTP `status`, following the mapping gRPC
+/// clients apply to HTTP errors, except that deadlines and size limits keep their own codes.
+pub fn status_code(status: u16, error: &Error) -> u32 {
+    if *error.etype() == ErrorType::ReadTimedout {
+        return 4; // DEADLINE_EXCEEDED
+    }
+    match status {
+        408 | 504 => 4,  // DEADLINE_EXCEEDED
+        413 | 429 => 8,  // RESOURCE_EXHAUSTED
+        401 => 16,       // UNAUTHENTICATED
+        403 => 7,        // PERMISSION_DENIED
+        404 => 12,       // UNIMPLEMENTED
+        502 | 503 => 14, // UNAVAILABLE
+        400 | 500 => 13, // INTERNAL
+        _ => 2,          // UNKNOWN
+    }
+}
+
+/// A trailers-only gRPC response carrying `code` for a call failed with HTTP `status`, the
+/// way gRPC servers report errors.
+pub fn error_response(code: u32, status: u16, request_id: &str) -> pingora::Result<ResponseHeader> {
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Error");
+    let mut header = ResponseHeader::build(200, Some(4))?;
+    header.insert_header(CONTENT_TYPE, "application/grpc")?;
+    header.insert_header(CONTENT_LENGTH, "0")?;
+    header.insert_header(GRPC_STATUS, code.to_string())?;
+    header.insert_header(GRPC_MESSAGE, format!("{status} {reason}"))?;
+    header.insert_header(REQUEST_ID_HEADER, request_id)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("POST", b"/pkg.Service/Method", None).unwrap();
+        for (name, value) in headers {
+            request.append_header(name.to_string(), *value).unwrap();
+        }
+        request
+    }
+
+    fn message(length: usize) -> Vec<u8> {
+        let mut message = vec![0];
+        message.extend_from_slice(&(length as u32).to_be_bytes());
+        message.resize(MESSAGE_PREFIX_LEN + length, b'x');
+        message
+    }
+
+    #[test]
+    fn recognises_grpc_content_types() {
+        for (content_type, grpc) in [
+            ("application/grpc", true),
+            ("application/grpc+proto", true),
+            ("application/grpc-web", false),
+            ("application/json", false),
+        ] {
+            let request = request(&[("content-type", content_type)]);
+            assert_eq!(is_grpc(&request), grpc, "{content_type}");
+        }
+        assert!(!is_grpc(&request(&[])));
+    }
+
+    #[test]
+    fn parses_timeouts() {
+        let timeout = |value| timeout(&request(&[(GRPC_TIMEOUT, value)]));
+        assert_eq!(timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(timeout("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(
+            timeout("99999999u"),
+            Some(Duration::from_micros(99_999_999))
+        );
+        assert_eq!(timeout("1n"), Some(Duration::from_nanos(1)));
+        for invalid in ["", "S", "5", "5s", "-5S", "123456789S", "1.5S"] {
+            assert_eq!(timeout(invalid), None, "{invalid}");
+        }
+        assert_eq!(self::timeout(&request(&[])), None);
+    }
+
+    #[test]
+    fn follows_messages_across_chunks() {
+        let mut sizes = MessageSizes::new(10);
+        let mut body = message(10);
+        body.extend(message(0));
+        body.extend(message(3));
+        for chunk in body.chunks(2) {
+            assert_eq!(sizes.feed(chunk), Ok(()));
+        }
+        // A prefix split over chunks.
+        let large = message(11);
+        assert_eq!(sizes.feed(&large[..3]), Ok(()));
+        assert_eq!(sizes.feed(&large[3..]), Err(11));
+    }
+
+    #[test]
+    fn maps_http_errors_to_grpc_codes() {
+        let error = Error::new(ErrorType::HTTPStatus(502));
+        assert_eq!(status_code(502, &error), 14);
+        assert_eq!(status_code(403, &error), 7);
+        assert_eq!(status_code(413, &error), 8);
+        assert_eq!(status_code(418, &error), 2);
+        let timed_out = Error::new(ErrorType::ReadTimedout);
+        assert_eq!(status_code(502, &timed_out), 4);
+    }
+
+    #[test]
+    fn reports_errors_in_trailers_only_responses() {
+        let response = error_response(14, 503, "abc").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers[CONTENT_TYPE], "application/grpc");
+        assert_eq!(response.headers[GRPC_STATUS], "14");
+        assert_eq!(response.headers[GRPC_MESSAGE], "503 Service Unavailable");
+        assert_eq!(response.headers[REQUEST_ID_HEADER], "abc");
+
+        let mut trailers = http::HeaderMap::new();
+        for (name, value) in CallError::message_too_large(20).headers() {
+            trailers.insert(name, value.parse().unwrap());
+        }
+        assert_eq!(status(&trailers).as_deref(), Some("8"));
+        assert_eq!(status(&http::HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,148 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::{BufMut, Bytes, BytesMut};
+use http::HeaderMap;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+const GRPC_WEB: &str = "application/grpc-web";
+const TEXT: &str = "-text";
+/// Flag of the frame that carries the trailers at the end of a gRPC-Web response body.
+const TRAILERS_FLAG: u8 = 0x80;
+
+/// Translates a gRPC-Web call from a browser to native gRPC for the upstream, and the
+/// response back: trailers become the last frame of the body, and `-text` bodies are base64.
+pub struct GrpcWeb {
+    /// Content type the client sent, answered with.
+    content_type: String,
+    /// The same for the upstream, e.g. `application/grpc+proto`.
+    grpc_content_type: String,
+    text: bool,
+    /// Set once the upstream answered with gRPC, rather than e.g. an HTTP error page.
+    translating: bool,
+    /// Base64 characters of the request short of a full group, decoded with the next chunk.
+    request_rest: Vec<u8>,
+    /// Response bytes short of a full base64 group, encoded with the next chunk.
+    response_rest: Vec<u8>,
+}
+
+impl GrpcWeb {
+    /// The translation for `request`, if it is a gRPC-Web call.
+    pub fn new(request: &RequestHeader) -> Option<Self> {
+        let content_type = request.headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let rest = content_type.strip_prefix(GRPC_WEB)?;
+        let (text, suffix) = match rest.strip_prefix(TEXT) {
+            Some(suffix) => (true, suffix),
+            None => (false, rest),
+        };
+        if !suffix.is_empty() && !suffix.starts_with('+') {
+            return None;
+        }
+        Some(Self {
+            content_type: content_type.to_string(),
+            grpc_content_type: format!("application/grpc{suffix}"),
+            text,
+            translating: false,
+            request_rest: Vec::new(),
+            response_rest: Vec::new(),
+        })
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Turns the request into a gRPC one.
+    pub fn request_header(&self, request: &mut RequestHeader) -> Result<()> {
+        request.insert_header(CONTENT_TYPE, &self.grpc_content_type)?;
+        request.insert_header(http::header::TE, "trailers")?;
+        if self.text {
+            request.remove_header(&CONTENT_LENGTH);
+        }
+        Ok(())
+    }
+
+    /// Decodes a chunk of a `-text` request body, which may end in the middle of a base64
+    /// group.
+    pub fn decode_request(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> Result<(), &'static str> {
+        if !self.text {
+            return Ok(());
+        }
+        if let Some(data) = body.take() {
+            self.request_rest.extend_from_slice(&data);
+        }
+        let whole = self.request_rest.len() - self.request_rest.len() % 4;
+        if end_of_stream && whole < self.request_rest.len() {
+            return Err("a truncated base64 request body");
+        }
+        let mut decoded = Vec::with_capacity(whole / 4 * 3);
+        // Messages may be encoded one by one, so padding can come before the end.
+        for group in self.request_rest[..whole].chunks(4) {
+            STANDARD
+                .decode_vec(group, &mut decoded)
+                .map_err(|_| "an invalid base64 request body")?;
+        }
+        self.request_rest.drain(..whole);
+        *body = (!decoded.is_empty()).then(|| decoded.into());
+        Ok(())
+    }
+
+    /// Turns a gRPC response into a gRPC-Web one. Its length changes with the trailers added.
+    pub fn response_header(&mut self, response: &mut ResponseHeader) -> Result<()> {
+        self.translating = response
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/grpc"));
+        if !self.translating {
+            return Ok(());
+        }
+        response.insert_header(CONTENT_TYPE, &self.content_type)?;
+        response.remove_header(&CONTENT_LENGTH);
+        Ok(())
+    }
+
+    /// Encodes a chunk of the response body for `-text` calls.
+    pub fn encode_response(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if !self.translating || !self.text {
+            return;
+        }
+        if let Some(data) = body.take() {
+            self.response_rest.extend_from_slice(&data);
+        }
+        let whole = if end_of_stream {
+            self.response_rest.len()
+        } else {
+            self.response_rest.len() - self.response_rest.len() % 3
+        };
+        let encoded = STANDARD.encode(&self.response_rest[..whole]);
+        self.response_rest.drain(..whole);
+        *body = (!encoded.is_empty()).then(|| encoded.into());
+    }
+
+    /// The frame ending the response body in place of `trailers`.
+    pub fn trailers(&mut self, trailers: &HeaderMap) -> Option<Bytes> {
+        if !self.translating {
+            return None;
+        }
+        let mut block = Vec::new();
+        for (name, value) in trailers {
+            block.extend_from_slice(name.as_str().as_bytes());
+            block.extend_from_slice(b": ");
+            block.extend_from_slice(value.as_bytes());
+            block.extend_from_slice(b"\r\n");
+        }
+        let mut frame = BytesMut::with_capacity(5 + block.len());
+        frame.put_u8(TRAILERS_FLAG);
+        frame.put_u32(block.len() as u32);
+        frame.put_slice(&block);
+        let mut frame = Some(frame.freeze());
+        self.encode_response(&mut frame, true);
+        frame
+    }
+}
@@ -0,0 +1,172 @@
+use http::{HeaderName, HeaderValue};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A single header manipulation, e.g. `{ action = "set", name = "X-Env", value = "prod" }`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum HeaderRule {
+    /// Appends a value, keeping any existing ones.
+    Add {
+        name: String,
+        value: String,
+    },
+    /// Replaces all existing values.
+    Set {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+    /// Moves all values of `name` to `to`.
+    Rename {
+        name: String,
+        to: String,
+    },
+}
+
+/// Header rules for both directions, as found in `[headers]` and `[routes.headers]`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct HeaderRules {
+    /// Applied to requests sent to the upstream.
+    #[serde(default)]
+    pub request: Vec<HeaderRule>,
+    /// Applied to responses sent to the client.
+    #[serde(default)]
+    pub response: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    /// The invalid names and values among the rules, by their path below the section, e.g.
+    /// `request[0].value`.
+    pub fn problems(&self) -> Vec<(String, String)> {
+        let mut problems = Vec::new();
+        for (direction, rules) in [("request", &self.request), ("response", &self.response)] {
+            for (index, rule) in rules.iter().enumerate() {
+                if let Err((field, message)) = HeaderEdit::new(rule) {
+                    problems.push((format!("{direction}[{index}].{field}"), message));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// [`HeaderRules`] with their names and values parsed, ready to apply.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderEdits {
+    request: Vec<HeaderEdit>,
+    response: Vec<HeaderEdit>,
+}
+
+#[derive(Debug, Clone)]
+enum HeaderEdit {
+    Add(HeaderName, HeaderValue),
+    Set(HeaderName, HeaderValue),
+    Remove(HeaderName),
+    Rename(HeaderName, HeaderName),
+}
+
+impl HeaderEdits {
+    pub fn new(rules: &HeaderRules) -> Result<Self, String> {
+        if let Some((field, message)) = rules.problems().into_iter().next() {
+            return Err(format!("{field}: {message}"));
+        }
+        let edits = |rules: &[HeaderRule]| {
+            rules
+                .iter()
+                .filter_map(|rule| HeaderEdit::new(rule).ok())
+                .collect()
+        };
+        Ok(Self {
+            request: edits(&rules.request),
+            response: edits(&rules.response),
+        })
+    }
+
+    pub fn apply_request(&self, header: &mut RequestHeader) -> Result<()> {
+        apply_edits(&self.request, header)
+    }
+
+    pub fn apply_response(&self, header: &mut ResponseHeader) -> Result<()> {
+        apply_edits(&self.response, header)
+    }
+}
+
+impl HeaderEdit {
+    /// Parses `rule`, or names its invalid field.
+    fn new(rule: &HeaderRule) -> Result<Self, (&'static str, String)> {
+        let name = |field, name: &str| {
+            HeaderName::try_from(name)
+                .map_err(|_| (field, format!("{name:?} is not a valid header name")))
+        };
+        let value = |value: &str| {
+            HeaderValue::try_from(value)
+                .map_err(|_| ("value", format!("{value:?} is not a valid header value")))
+        };
+        Ok(match rule {
+            HeaderRule::Add { name: n, value: v } => Self::Add(name("name", n)?, value(v)?),
+            HeaderRule::Set { name: n, value: v } => Self::Set(name("name", n)?, value(v)?),
+            HeaderRule::Remove { name: n } => Self::Remove(name("name", n)?),
+            HeaderRule::Rename { name: n, to } => Self::Rename(name("name", n)?, name("to", to)?),
+        })
+    }
+}
+
+/// Common surface of pingora's request and response headers.
+pub trait HeaderTarget {
+    fn values(&self, name: &HeaderName) -> Vec<HeaderValue>;
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn remove(&mut self, name: &HeaderName);
+}
+
+macro_rules! impl_header_target {
+    ($ty:ty) => {
+        impl HeaderTarget for $ty {
+            fn values(&self, name: &HeaderName) -> Vec<HeaderValue> {
+                self.headers.get_all(name).iter().cloned().collect()
+            }
+
+            fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+                self.append_header(name, value).map(|_| ())
+            }
+
+            fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+                self.insert_header(name, value)
+            }
+
+            fn remove(&mut self, name: &HeaderName) {
+                self.remove_header(name);
+            }
+        }
+    };
+}
+
+impl_header_target!(RequestHeader);
+impl_header_target!(ResponseHeader);
+
+fn apply_edits<T: HeaderTarget>(edits: &[HeaderEdit], header: &mut T) -> Result<()> {
+    for edit in edits {
+        match edit {
+            HeaderEdit::Add(name, value) => header.append(name.clone(), value.clone())?,
+            HeaderEdit::Set(name, value) => header.insert(name.clone(), value.clone())?,
+            HeaderEdit::Remove(name) => header.remove(name),
+            HeaderEdit::Rename(name, to) => {
+                let values = header.values(name);
+                if values.is_empty() {
+                    continue;
+                }
+                header.remove(name);
+                header.remove(to);
+                for value in values {
+                    header.append(to.clone(), value)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::{Response, StatusCode};
+use log::{info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpStream;
+
+use crate::audit;
+use crate::drain::{Quiesce, UpstreamDrains};
+use crate::endpoints::local_response;
+use crate::state::SharedState;
+
+const DEFAULT_LIVENESS_PATH: &str = "/healthz";
+const DEFAULT_READINESS_PATH: &str = "/readyz";
+const DEFAULT_CHECK_INTERVAL_SECONDS: u64 = 5;
+const DEFAULT_CHECK_TIMEOUT_SECONDS: u64 = 1;
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+/// `[health]` section of the config file. The endpoints are off when the
+/// section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct HealthConfig {
+    /// Serve the endpoints on this address only; unset serves them on the proxy listeners.
+    pub listen_addr: Option<String>,
+    /// Answers 200 while the process is running.
+    pub liveness_path: Option<String>,
+    /// Answers 200 when the proxy can serve traffic, 503 otherwise.
+    pub readiness_path: Option<String>,
+    /// How often upstreams are probed with a TCP connect.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub check_interval_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub check_timeout_seconds: Option<u64>,
+}
+
+impl HealthConfig {
+    /// Fills in the default of every unset field.
+    pub fn with_defaults(self) -> Self {
+        Self {
+            liveness_path: Some(self.liveness_path().to_string()),
+            readiness_path: Some(self.readiness_path().to_string()),
+            check_interval_seconds: Some(self.check_interval().as_secs()),
+            check_timeout_seconds: Some(self.check_timeout().as_secs()),
+            listen_addr: self.listen_addr,
+        }
+    }
+
+    pub fn liveness_path(&self) -> &str {
+        self.liveness_path
+            .as_deref()
+            .unwrap_or(DEFAULT_LIVENESS_PATH)
+    }
+
+    pub fn readiness_path(&self) -> &str {
+        self.readiness_path
+            .as_deref()
+            .unwrap_or(DEFAULT_READINESS_PATH)
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.check_interval_seconds
+                .unwrap_or(DEFAULT_CHECK_INTERVAL_SECONDS),
+        )
+    }
+
+    pub fn check_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.check_timeout_seconds
+                .unwrap_or(DEFAULT_CHECK_TIMEOUT_SECONDS),
+        )
+    }
+}
+
+/// Result of the latest probe of an upstream.
+#[derive(Debug, Clone)]
+pub struct UpstreamStatus {
+    pub healthy: bool,
+    pub error: Option<String>,
+}
+
+/// Latest probe results by upstream address, shared between the checker and the endpoints.
+#[derive(Clone, Default)]
+pub struct UpstreamHealth {
+    inner: Arc<RwLock<HashMap<String, UpstreamStatus>>>,
+}
+
+impl UpstreamHealth {
+    pub fn get(&self, upstream: &str) -> Option<UpstreamStatus> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(upstream)
+            .cloned()
+    }
+
+    /// The latest probe result of each of `upstreams`, as the status page and admin API
+    /// show it.
+    pub fn report(&self, upstreams: Vec<String>) -> Vec<serde_json::Value> {
+        upstreams
+            .into_iter()
+            .map(|addr| {
+                let (health, error) = match self.get(&addr) {
+                    Some(status) if status.healthy => ("up", None),
+                    Some(status) => ("down", status.error),
+                    None => ("unknown", None),
+                };
+                json!({ "addr": addr, "health": health, "error": error })
+            })
+            .collect()
+    }
+
+    /// Stores a round of probe results, dropping upstreams that were not probed.
+    fn update(&self, results: HashMap<String, UpstreamStatus>) {
+        let mut guard = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (upstream, status) in &results {
+            let was_healthy = guard.get(upstream).map(|previous| previous.healthy);
+            match (was_healthy, status.healthy) {
+                (Some(false) | None, true) => info!("upstream {upstream} is healthy"),
+                (Some(true) | None, false) => warn!(
+                    "upstream {upstream} is unhealthy: {}",
+                    status.error.as_deref().unwrap_or("unknown error")
+                ),
+                _ => continue,
+            }
+            audit::record(
+                "upstream_health",
+                json!({
+                    "upstream": upstream,
+                    "healthy": status.healthy,
+                    "error": status.error,
+                }),
+            );
+        }
+        *guard = results;
+    }
+}
+
+/// Background service probing every upstream of the current config with a TCP connect.
+pub struct UpstreamHealthChecker {
+    state: SharedState,
+    health: UpstreamHealth,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl UpstreamHealthChecker {
+    pub fn new(config: &HealthConfig, state: SharedState, health: UpstreamHealth) -> Self {
+        Self {
+            state,
+            health,
+            interval: config.check_interval(),
+            timeout: config.check_timeout(),
+        }
+    }
+
+    async fn check_all(&self) {
+        let mut results = HashMap::new();
+        for upstream in self.state.current().upstreams() {
+            let error =
+                match tokio::time::timeout(self.timeout, TcpStream::connect(&upstream)).await {
+                    Ok(Ok(_)) => None,
+                    Ok(Err(err)) => Some(err.to_string()),
+                    Err(_) => Some(format!("connect timed out after {:?}", self.timeout)),
+                };
+            let status = UpstreamStatus {
+                healthy: error.is_none(),
+                error,
+            };
+            results.insert(upstream, status);
+        }
+        self.health.update(results);
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UpstreamHealthChecker {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!(
+            "starting upstream health checker (interval: {:?})",
+            self.interval
+        );
+        loop {
+            self.check_all().await;
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = shutdown.changed() => {
+                    info!("upstream health checker shutting down");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Answers liveness and readiness probes.
+pub struct HealthEndpoints {
+    liveness_path: String,
+    readiness_path: String,
+    state: SharedState,
+    upstreams: UpstreamHealth,
+    drains: UpstreamDrains,
+    quiesce: Quiesce,
+}
+
+impl HealthEndpoints {
+    pub fn new(
+        config: &HealthConfig,
+        state: SharedState,
+        upstreams: UpstreamHealth,
+        drains: UpstreamDrains,
+        quiesce: Quiesce,
+    ) -> Self {
+        Self {
+            liveness_path: config.liveness_path().to_string(),
+            readiness_path: config.readiness_path().to_string(),
+            state,
+            upstreams,
+            drains,
+            quiesce,
+        }
+    }
+
+    /// The response for a probe path, or `None` for any other path.
+    pub fn answer(&self, path: &str) -> Option<Response<Vec<u8>>> {
+        if path == self.liveness_path {
+            Some(local_response(StatusCode::OK, TEXT_PLAIN, b"ok\n".to_vec()))
+        } else if path == self.readiness_path {
+            let (ready, report) = self.readiness();
+            let status = if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Some(local_response(status, TEXT_PLAIN, report.into_bytes()))
+        } else {
+            None
+        }
+    }
+
+    /// Ready when the proxy is not quiescing, the config is loaded, the static root (if any)
+    /// is readable, and at least one upstream that is not draining answered the latest probe.
+    fn readiness(&self) -> (bool, String) {
+        let state = self.state.current();
+        let mut report = String::from("config: ok\n");
+        let mut ready = true;
+        if self.quiesce.since().is_some() {
+            ready = false;
+            report.push_str("quiescing\n");
+        }
+
+        if let Some(static_assets) = &state.static_assets {
+            match std::fs::read_dir(static_assets.root_path()) {
+                Ok(_) => report.push_str("static_root: ok\n"),
+                Err(err) => {
+                    ready = false;
+                    let _ = writeln!(report, "static_root: {err}");
+                }
+            }
+        }
+
+        let statuses: BTreeMap<String, Option<UpstreamStatus>> = state
+            .upstreams()
+            .into_iter()
+            .map(|upstream| {
+                let status = self.upstreams.get(&upstream);
+                (upstream, status)
+            })
+            .collect();
+        let mut any_healthy = false;
+        for (upstream, status) in &statuses {
+            let line = match status {
+                _ if self.drains.is_draining(upstream) => "draining".to_string(),
+                Some(status) if status.healthy => {
+                    any_healthy = true;
+                    "ok".to_string()
+                }
+                Some(status) => format!("down ({})", status.error.as_deref().unwrap_or("unknown")),
+                None => "not checked yet".to_string(),
+            };
+            let _ = writeln!(report, "upstream {upstream}: {line}");
+        }
+        ready &= any_healthy;
+
+        report.push_str(if ready { "ready\n" } else { "not ready\n" });
+        (ready, report)
+    }
+}
@@ -0,0 +1,99 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pingora::http::RequestHeader;
+use pingora::prelude::*;
+
+/// Callbacks following requests through the proxy, registered with
+/// [`ProxyBuilder::on_request_start`](crate::ProxyBuilder::on_request_start) and its
+/// siblings. They run on the request's task, so slow work belongs on a channel.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    request_start: Vec<RequestStartHook>,
+    upstream_selected: Vec<UpstreamSelectedHook>,
+    response_complete: Vec<ResponseCompleteHook>,
+}
+
+type RequestStartHook = Arc<dyn Fn(&RequestStart) + Send + Sync>;
+type UpstreamSelectedHook = Arc<dyn Fn(&UpstreamSelected) + Send + Sync>;
+type ResponseCompleteHook = Arc<dyn Fn(&ResponseComplete) + Send + Sync>;
+
+/// A request the proxy started handling.
+pub struct RequestStart<'a> {
+    pub request_id: &'a str,
+    pub request: &'a RequestHeader,
+    /// Client address, as told by `trusted_proxies` when the peer is one.
+    pub client_ip: Option<IpAddr>,
+}
+
+/// An upstream picked for a request; picked again, maybe the same, when connecting is retried.
+pub struct UpstreamSelected<'a> {
+    pub request_id: &'a str,
+    /// Name of the route the request took, if any.
+    pub route: Option<&'a str>,
+    /// `host:port` of the upstream.
+    pub upstream_addr: &'a str,
+    /// Address connected to instead, when the upstream is reached through `parent_proxy`.
+    pub parent_proxy: Option<&'a str>,
+}
+
+/// A request the proxy is done with, answered or not.
+pub struct ResponseComplete<'a> {
+    pub request_id: &'a str,
+    pub route: Option<&'a str>,
+    /// Status sent to the client; 0 when none was.
+    pub status: u16,
+    /// From the start of the request to the end of the response.
+    pub duration: Duration,
+    /// Upstream the request was sent to, unless the proxy answered it.
+    pub upstream_addr: Option<&'a str>,
+    /// Status the upstream answered with.
+    pub upstream_status: Option<u16>,
+    /// Until the upstream's response header arrived.
+    pub upstream_ttfb: Option<Duration>,
+    pub request_body_bytes: usize,
+    pub response_body_bytes: usize,
+    /// Key the request was accepted with, on routes with `api_keys`.
+    pub api_key: Option<&'a str>,
+    /// Why the request failed, if it did.
+    pub error: Option<&'a Error>,
+}
+
+impl Hooks {
+    pub fn add_request_start(&mut self, hook: impl Fn(&RequestStart) + Send + Sync + 'static) {
+        self.request_start.push(Arc::new(hook));
+    }
+
+    pub fn add_upstream_selected(
+        &mut self,
+        hook: impl Fn(&UpstreamSelected) + Send + Sync + 'static,
+    ) {
+        self.upstream_selected.push(Arc::new(hook));
+    }
+
+    pub fn add_response_complete(
+        &mut self,
+        hook: impl Fn(&ResponseComplete) + Send + Sync + 'static,
+    ) {
+        self.response_complete.push(Arc::new(hook));
+    }
+
+    pub fn request_start(&self, event: &RequestStart) {
+        for hook in &self.request_start {
+            hook(event);
+        }
+    }
+
+    pub fn upstream_selected(&self, event: &UpstreamSelected) {
+        for hook in &self.upstream_selected {
+            hook(event);
+        }
+    }
+
+    pub fn response_complete(&self, event: &ResponseComplete) {
+        for hook in &self.response_complete {
+            hook(event);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use http::Version;
+use http::header::{CONNECTION, VIA};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+
+/// Legacy hop-by-hop headers that are never forwarded.
+const ALWAYS_HOP_BY_HOP: &[&str] = &["Keep-Alive", "Proxy-Connection"];
+
+/// `Connection` tokens that pingora itself relies on for framing and upgrades.
+const PRESERVED_TOKENS: &[&str] = &["close", "keep-alive", "upgrade", "transfer-encoding", "te"];
+
+/// `Via` handling and hop-by-hop header stripping for both directions.
+#[derive(Clone, Debug)]
+pub struct HopHeaders {
+    via_token: Option<String>,
+}
+
+impl HopHeaders {
+    /// `via_token` identifies this proxy in `Via`; an empty token disables the header.
+    pub fn new(via_token: &str) -> Self {
+        Self {
+            via_token: (!via_token.is_empty()).then(|| via_token.to_string()),
+        }
+    }
+
+    pub fn apply_request(&self, request: &mut RequestHeader) -> Result<()> {
+        for name in hop_by_hop_names(&request.headers) {
+            request.remove_header(name.as_str());
+        }
+        if let Some(token) = &self.via_token {
+            request.append_header(VIA, format!("{} {token}", via_version(request.version)))?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_response(&self, response: &mut ResponseHeader) -> Result<()> {
+        for name in hop_by_hop_names(&response.headers) {
+            response.remove_header(name.as_str());
+        }
+        if let Some(token) = &self.via_token {
+            response.append_header(VIA, format!("{} {token}", via_version(response.version)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Headers named in `Connection` plus the legacy hop-by-hop set.
+fn hop_by_hop_names(headers: &http::HeaderMap) -> Vec<String> {
+    let mut names: Vec<String> = ALWAYS_HOP_BY_HOP.iter().map(|s| s.to_string()).collect();
+    for value in headers.get_all(CONNECTION) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        names.extend(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|token| !token.is_empty())
+                .filter(|token| {
+                    !PRESERVED_TOKENS
+                        .iter()
+                        .any(|preserved| preserved.eq_ignore_ascii_case(token))
+                })
+                .map(str::to_string),
+        );
+    }
+    names
+}
+
+fn via_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
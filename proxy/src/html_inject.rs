@@ -0,0 +1,99 @@
+use bytes::{Bytes, BytesMut};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::body_rewrite;
+
+/// `html_inject`: snippets inserted into `text/html` responses, e.g. analytics or a cookie
+/// banner for backends that can't be changed.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HtmlInjectConfig {
+    /// Inserted before the first `</head>`.
+    pub head: Option<String>,
+    /// Inserted before the first `</body>`.
+    pub body: Option<String>,
+}
+
+impl HtmlInjectConfig {
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none() && self.body.is_none()
+    }
+
+    pub fn apply_request(&self, request: &mut RequestHeader) {
+        if !self.is_empty() {
+            body_rewrite::ask_uncompressed(request);
+        }
+    }
+
+    /// The injector for `response`, with its headers adjusted for the longer body, if it is an
+    /// uncompressed HTML page.
+    pub fn start(
+        &self,
+        request: &RequestHeader,
+        response: &mut ResponseHeader,
+    ) -> Result<Option<HtmlInjector>> {
+        if self.is_empty() || !body_rewrite::start(request, response, |mime| mime == "text/html")? {
+            return Ok(None);
+        }
+        let snippets = [("</head>", &self.head), ("</body>", &self.body)]
+            .into_iter()
+            .filter_map(|(marker, snippet)| Some((marker, Bytes::from(snippet.clone()?))))
+            .collect();
+        Ok(Some(HtmlInjector {
+            snippets,
+            pending: BytesMut::new(),
+        }))
+    }
+}
+
+/// Streams a page through, inserting each snippet before its closing tag; a snippet whose tag
+/// doesn't come before a later one's is dropped. Bytes that may be the start of a tag split
+/// across chunks are held back until the next one.
+pub struct HtmlInjector {
+    /// Snippets still to insert, in page order.
+    snippets: Vec<(&'static str, Bytes)>,
+    pending: BytesMut,
+}
+
+impl HtmlInjector {
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if self.snippets.is_empty() && self.pending.is_empty() {
+            return;
+        }
+        if let Some(chunk) = body.take() {
+            self.pending.extend_from_slice(&chunk);
+        }
+        let mut out = BytesMut::with_capacity(self.pending.len());
+        while let Some((index, at)) = self
+            .snippets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (marker, _))| {
+                find_ignore_case(&self.pending, marker.as_bytes()).map(|at| (index, at))
+            })
+            .min_by_key(|(_, at)| *at)
+        {
+            out.extend_from_slice(&self.pending.split_to(at));
+            out.extend_from_slice(&self.snippets[index].1);
+            self.snippets.drain(..=index);
+        }
+        let keep = match self.snippets.iter().map(|(marker, _)| marker.len()).max() {
+            Some(len) if !end_of_stream => (len - 1).min(self.pending.len()),
+            _ => 0,
+        };
+        let ready = self.pending.len() - keep;
+        out.extend_from_slice(&self.pending.split_to(ready));
+        if !out.is_empty() || end_of_stream {
+            *body = Some(out.freeze());
+        }
+    }
+}
+
+/// Position of the first ASCII case-insensitive occurrence of `needle` in `haystack`.
+fn find_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
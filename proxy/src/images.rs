@@ -0,0 +1,460 @@
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageReader};
+use log::warn;
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::body_rewrite;
+use crate::memory_cache::{CacheEntry, MemoryCache, MemoryCacheConfig};
+
+const DEFAULT_FORMATS: [ImageFormat; 2] = [ImageFormat::Avif, ImageFormat::Webp];
+const DEFAULT_QUALITY: u8 = 75;
+const DEFAULT_MAX_DIMENSION: u32 = 4096;
+const DEFAULT_MAX_SOURCE_KB: usize = 20 * 1024;
+const DEFAULT_CACHE_MB: usize = 64;
+/// rav1e speed for AVIF, 1 (slowest, smallest) to 10: quick enough to encode on request.
+const AVIF_SPEED: u8 = 8;
+
+/// A format images are converted to.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Avif,
+    Webp,
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    fn mime(self) -> &'static str {
+        match self {
+            Self::Avif => "image/avif",
+            Self::Webp => "image/webp",
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "avif" => Some(Self::Avif),
+            "webp" => Some(Self::Webp),
+            "jpeg" | "jpg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            _ => None,
+        }
+    }
+
+    /// The format of a source image of type `mime`, if it can be converted.
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "image/jpeg" | "image/pjpeg" => Some(Self::Jpeg),
+            "image/png" => Some(Self::Png),
+            "image/webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+}
+
+/// `[images]` section of the config file: resizes and converts JPEG, PNG and WebP images,
+/// static and proxied, for requests with `w`, `h`, `q` or `format` query parameters, e.g.
+/// `/img/hero.jpg?w=400`. Off when absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImagesConfig {
+    /// Only images under these path prefixes; every path by default.
+    pub path_prefixes: Option<Vec<String>>,
+    /// Formats offered to clients listing them in `Accept`, in order of preference (avif,
+    /// webp by default); others get the image's own format.
+    pub formats: Option<Vec<ImageFormat>>,
+    /// Quality of JPEG and AVIF output from 1 to 100 (75 by default), unless `q` asks for
+    /// another. WebP output is lossless.
+    pub quality: Option<u8>,
+    /// Largest width or height output; larger `w` and `h` are lowered to it (4096 by
+    /// default). Images are never enlarged.
+    pub max_dimension: Option<u32>,
+    /// Larger images, and proxied ones of unknown size, are sent as they are (20 MB by
+    /// default).
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_source_kb: Option<usize>,
+    /// Memory for converted images, kept while their source's ETag is unchanged (64 MB by
+    /// default, 0 to convert every time).
+    #[serde(default, deserialize_with = "crate::units::opt_megabytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub cache_mb: Option<usize>,
+}
+
+impl ImagesConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            path_prefixes: self.path_prefixes,
+            formats: Some(self.formats.unwrap_or_else(|| DEFAULT_FORMATS.to_vec())),
+            quality: Some(self.quality.unwrap_or(DEFAULT_QUALITY)),
+            max_dimension: Some(self.max_dimension.unwrap_or(DEFAULT_MAX_DIMENSION)),
+            max_source_kb: Some(self.max_source_kb.unwrap_or(DEFAULT_MAX_SOURCE_KB)),
+            cache_mb: Some(self.cache_mb.unwrap_or(DEFAULT_CACHE_MB)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(quality) = self.quality
+            && !(1..=100).contains(&quality)
+        {
+            return Err(format!("quality {quality} is not between 1 and 100"));
+        }
+        if self.max_dimension == Some(0) {
+            return Err("max_dimension must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The `[images]` converter, with its cache of converted images.
+pub struct Images {
+    config: ImagesConfig,
+    cache: Option<MemoryCache>,
+}
+
+/// What a request asks of an image.
+#[derive(Debug, Clone)]
+pub struct ImageTransform {
+    width: Option<u32>,
+    height: Option<u32>,
+    quality: u8,
+    format: ImageFormat,
+    /// Whether the format was picked from `Accept`, so responses vary by it.
+    negotiated: bool,
+}
+
+impl Images {
+    pub fn new(config: ImagesConfig) -> Self {
+        let cache_bytes = config.cache_mb.unwrap_or(DEFAULT_CACHE_MB) * 1024 * 1024;
+        let cache = (cache_bytes > 0).then(|| {
+            MemoryCache::new(MemoryCacheConfig {
+                capacity_bytes: cache_bytes,
+                max_object_bytes: cache_bytes,
+                promote_hits: 1,
+            })
+        });
+        Self { config, cache }
+    }
+
+    pub fn config(&self) -> &ImagesConfig {
+        &self.config
+    }
+
+    pub fn max_source_bytes(&self) -> usize {
+        self.config.max_source_kb.unwrap_or(DEFAULT_MAX_SOURCE_KB) * 1024
+    }
+
+    /// What `request` asks of its image of type `mime`, if it asks for a conversion.
+    pub fn transform_for(&self, request: &RequestHeader, mime: &str) -> Option<ImageTransform> {
+        if request.method != http::Method::GET {
+            return None;
+        }
+        let source = ImageFormat::from_mime(mime)?;
+        if let Some(prefixes) = &self.config.path_prefixes
+            && !prefixes
+                .iter()
+                .any(|prefix| request.uri.path().starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+        let max_dimension = self.config.max_dimension.unwrap_or(DEFAULT_MAX_DIMENSION);
+        let mut asked = false;
+        let mut transform = ImageTransform {
+            width: None,
+            height: None,
+            quality: self.config.quality.unwrap_or(DEFAULT_QUALITY),
+            format: source,
+            negotiated: true,
+        };
+        let query = request.uri.query().unwrap_or("");
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            let dimension = || {
+                value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|value| *value > 0)
+                    .map(|value| value.min(max_dimension))
+            };
+            match name.as_ref() {
+                "w" => transform.width = dimension(),
+                "h" => transform.height = dimension(),
+                "q" => {
+                    if let Ok(quality) = value.parse::<u8>() {
+                        transform.quality = quality.clamp(1, 100);
+                    }
+                }
+                "format" => {
+                    if let Some(format) = ImageFormat::from_name(&value) {
+                        transform.format = format;
+                        transform.negotiated = false;
+                    }
+                }
+                _ => continue,
+            }
+            asked = true;
+        }
+        if !asked {
+            return None;
+        }
+        if transform.negotiated {
+            let accept = request
+                .headers
+                .get(ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+            let accepted = self
+                .config
+                .formats
+                .as_deref()
+                .unwrap_or(&DEFAULT_FORMATS)
+                .iter()
+                .find(|format| accept.contains(format.mime()));
+            if let Some(format) = accepted {
+                transform.format = *format;
+            }
+        }
+        Some(transform)
+    }
+
+    /// The converted images cached, keyed by source and query, then `#` and their type.
+    pub async fn cache_entries(&self) -> Vec<CacheEntry> {
+        match &self.cache {
+            Some(cache) => cache.entries().await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Drops every converted image cached; returns how many there were.
+    pub async fn clear_cache(&self) -> usize {
+        match &self.cache {
+            Some(cache) => cache.clear().await,
+            None => 0,
+        }
+    }
+
+    /// `source` converted as `transform` asks, from the cache when it holds the conversion of
+    /// the same source, told by its `etag`, under `key`.
+    pub async fn convert(
+        &self,
+        key: &str,
+        etag: Option<&str>,
+        source: Bytes,
+        transform: &ImageTransform,
+    ) -> Result<Bytes, String> {
+        let key = transform.cache_key(key);
+        if let (Some(cache), Some(etag)) = (&self.cache, etag)
+            && let Some(converted) = cache.get(Path::new(&key), etag).await
+        {
+            return Ok(converted);
+        }
+        let converting = transform.clone();
+        let converted = tokio::task::spawn_blocking(move || converting.apply(&source))
+            .await
+            .map_err(|err| err.to_string())??;
+        if let (Some(cache), Some(etag)) = (&self.cache, etag) {
+            cache
+                .insert(Path::new(&key), etag.to_string(), converted.clone())
+                .await;
+        }
+        Ok(converted)
+    }
+
+    /// Adjusts the headers of a static image response for its converted body of `len` bytes.
+    pub fn static_response(
+        &self,
+        response: &mut ResponseHeader,
+        transform: &ImageTransform,
+        etag: &str,
+        len: usize,
+    ) -> Result<()> {
+        response.insert_header(CONTENT_TYPE, transform.format.mime())?;
+        response.insert_header(CONTENT_LENGTH, len.to_string())?;
+        response.insert_header(ETAG, transform.etag(etag))?;
+        if transform.negotiated {
+            response.append_header(VARY, "Accept")?;
+        }
+        Ok(())
+    }
+
+    /// The converter for a proxied image `response`, with its headers adjusted for the
+    /// converted body, if `request` asks for a conversion the image is small enough for.
+    pub async fn start(
+        self: &Arc<Self>,
+        request: &RequestHeader,
+        response: &mut ResponseHeader,
+        key: String,
+    ) -> Result<Option<ImageConverter>> {
+        let header = |name| {
+            response
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let mime = header(CONTENT_TYPE)
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime| mime.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let len = header(CONTENT_LENGTH).and_then(|len| len.parse::<usize>().ok());
+        let Some(transform) = self.transform_for(request, &mime) else {
+            return Ok(None);
+        };
+        if response.status != http::StatusCode::OK
+            || len.is_none_or(|len| len > self.max_source_bytes())
+        {
+            return Ok(None);
+        }
+        let etag = header(ETAG).map(str::to_string);
+        if !body_rewrite::start(request, response, |_| true)? {
+            return Ok(None);
+        }
+        response.insert_header(CONTENT_TYPE, transform.format.mime())?;
+        if let Some(etag) = &etag {
+            response.insert_header(ETAG, transform.etag(etag))?;
+        }
+        if transform.negotiated {
+            response.append_header(VARY, "Accept")?;
+        }
+        let cached = match (&self.cache, &etag) {
+            (Some(cache), Some(etag)) => {
+                cache.get(Path::new(&transform.cache_key(&key)), etag).await
+            }
+            _ => None,
+        };
+        Ok(Some(ImageConverter {
+            images: self.clone(),
+            transform,
+            key,
+            etag,
+            cached,
+            source: BytesMut::new(),
+        }))
+    }
+}
+
+impl std::fmt::Debug for Images {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Images")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl ImageTransform {
+    fn cache_key(&self, key: &str) -> String {
+        format!("{key}#{}", self.format.mime())
+    }
+
+    /// The ETag of the conversion of an image with `etag`.
+    fn etag(&self, etag: &str) -> String {
+        let extension = match self.format {
+            ImageFormat::Avif => "avif",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+        };
+        match etag.strip_suffix('"') {
+            Some(opaque) => format!("{opaque}-{extension}\""),
+            None => etag.to_string(),
+        }
+    }
+
+    fn apply(&self, source: &[u8]) -> Result<Bytes, String> {
+        let mut image = ImageReader::new(Cursor::new(source))
+            .with_guessed_format()
+            .map_err(|err| err.to_string())?
+            .decode()
+            .map_err(|err| err.to_string())?;
+        let width = self.width.unwrap_or(u32::MAX).min(image.width());
+        let height = self.height.unwrap_or(u32::MAX).min(image.height());
+        if width < image.width() || height < image.height() {
+            image = image.resize(width, height, FilterType::Lanczos3);
+        }
+        let image = if image.color().has_alpha() && self.format != ImageFormat::Jpeg {
+            DynamicImage::ImageRgba8(image.to_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        };
+        let mut out = Vec::new();
+        let written = match self.format {
+            ImageFormat::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut out,
+                AVIF_SPEED,
+                self.quality,
+            )),
+            ImageFormat::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut out)),
+            ImageFormat::Jpeg => {
+                image.write_with_encoder(JpegEncoder::new_with_quality(&mut out, self.quality))
+            }
+            ImageFormat::Png => image.write_with_encoder(PngEncoder::new(&mut out)),
+        };
+        written.map_err(|err| err.to_string())?;
+        Ok(Bytes::from(out))
+    }
+}
+
+/// Collects a proxied image and sends its conversion once it is complete; the original if it
+/// can't be converted.
+pub struct ImageConverter {
+    images: Arc<Images>,
+    transform: ImageTransform,
+    key: String,
+    etag: Option<String>,
+    /// The conversion, when the cache had it.
+    cached: Option<Bytes>,
+    source: BytesMut,
+}
+
+impl ImageConverter {
+    pub fn response_body(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        request_id: &str,
+    ) {
+        if let Some(chunk) = body.take() {
+            // The cached conversion stands in for the whole upstream body.
+            if self.cached.is_none() {
+                self.source.extend_from_slice(&chunk);
+            }
+        }
+        if !end_of_stream {
+            return;
+        }
+        if let Some(cached) = self.cached.take() {
+            *body = Some(cached);
+            return;
+        }
+        let source = self.source.split().freeze();
+        // Converting takes a while, so let the runtime move other tasks off this thread.
+        let converted = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(self.images.convert(
+                &self.key,
+                self.etag.as_deref(),
+                source.clone(),
+                &self.transform,
+            ))
+        });
+        *body = Some(converted.unwrap_or_else(|err| {
+            warn!(
+                "request {request_id}: can't convert image {}: {err}",
+                self.key
+            );
+            source
+        }));
+    }
+}
@@ -0,0 +1,38 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Commented config listing every knob with its default value.
+const STARTER_CONFIG: &str = include_str!("starter_config.toml");
+
+/// Writes the starter config to `path`, or to stdout when no path is given.
+///
+/// An existing file is only replaced when `force` is set.
+pub fn write_starter_config(path: Option<&Path>, force: bool) -> Result<(), String> {
+    let Some(path) = path else {
+        return io::stdout()
+            .write_all(STARTER_CONFIG.as_bytes())
+            .map_err(|err| format!("failed to write starter config: {err}"));
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options.open(path).map_err(|err| match err.kind() {
+        io::ErrorKind::AlreadyExists => {
+            format!(
+                "{} already exists, pass --force to overwrite it",
+                path.display()
+            )
+        }
+        _ => format!("failed to create {}: {err}", path.display()),
+    })?;
+    file.write_all(STARTER_CONFIG.as_bytes())
+        .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+    eprintln!("wrote starter config to {}", path.display());
+    Ok(())
+}
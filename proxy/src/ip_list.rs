@@ -0,0 +1,32 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// A set of IP addresses and CIDR blocks.
+#[derive(Clone, Debug, Default)]
+pub struct IpList {
+    networks: Vec<IpNet>,
+}
+
+impl IpList {
+    /// Parses a list of IP addresses or CIDR blocks.
+    pub fn parse(entries: &[String]) -> Result<Self, String> {
+        let networks = entries
+            .iter()
+            .map(|entry| parse_network(entry))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { networks })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Parses an IP address, as a single-address block, or a CIDR block.
+pub fn parse_network(entry: &str) -> Result<IpNet, String> {
+    entry
+        .parse::<IpNet>()
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid IP address or CIDR block '{entry}'"))
+}
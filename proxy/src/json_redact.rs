@@ -0,0 +1,441 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::header::{IF_RANGE, RANGE};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::body_rewrite;
+use crate::errors::ProxyError;
+
+const DEFAULT_MASK: &str = "***";
+/// Longest object key looked at, unless a field could be escaped to a longer one; longer
+/// keys are passed on as they are.
+const MAX_KEY_LEN: usize = 1024;
+
+/// `json_redact` of a route: fields removed or masked in its JSON responses, at any depth,
+/// e.g. tokens or identity numbers that clients must never see.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct JsonRedactConfig {
+    /// Fields left out, with their values.
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Fields whose values are replaced with `mask_with`.
+    #[serde(default)]
+    pub mask: Vec<String>,
+    /// The string masked values are replaced with.
+    pub mask_with: Option<String>,
+}
+
+/// A compiled `json_redact`.
+#[derive(Debug)]
+pub struct JsonRedact {
+    remove: HashSet<String>,
+    mask: HashSet<String>,
+    /// `mask_with` as a JSON string.
+    mask_with: Vec<u8>,
+    /// Longest key, as it appears in the body, that can match a field.
+    max_key_len: usize,
+}
+
+impl JsonRedact {
+    pub fn new(config: &JsonRedactConfig) -> Result<Self, String> {
+        if config.remove.is_empty() && config.mask.is_empty() {
+            return Err("needs fields to remove or mask".to_string());
+        }
+        if let Some(field) = config
+            .remove
+            .iter()
+            .find(|field| config.mask.contains(field))
+        {
+            return Err(format!("field {field:?} is both removed and masked"));
+        }
+        // Each byte of a field is at most 6 in the body, as a \u escape.
+        let longest = config.remove.iter().chain(&config.mask).map(String::len);
+        Ok(Self {
+            remove: config.remove.iter().cloned().collect(),
+            mask: config.mask.iter().cloned().collect(),
+            mask_with: serde_json::to_vec(config.mask_with.as_deref().unwrap_or(DEFAULT_MASK))
+                .unwrap_or_default(),
+            max_key_len: longest
+                .max()
+                .unwrap_or(0)
+                .saturating_mul(6)
+                .max(MAX_KEY_LEN),
+        })
+    }
+
+    /// Asks for the whole body, uncompressed, as only that can be redacted.
+    pub fn apply_request(&self, request: &mut RequestHeader) {
+        body_rewrite::ask_uncompressed(request);
+        request.remove_header(&RANGE);
+        request.remove_header(&IF_RANGE);
+    }
+
+    /// The redactor for `response`, with its headers adjusted for a shorter body, if it is
+    /// JSON. JSON that can't be redacted, compressed despite the request or partial, is
+    /// refused with 502 rather than passed on.
+    pub fn start(
+        self: &Arc<Self>,
+        request: &RequestHeader,
+        response: &mut ResponseHeader,
+    ) -> Result<Option<JsonRedactor>> {
+        let json = |mime: &str| mime == "application/json" || mime.ends_with("+json");
+        if body_rewrite::start(request, response, json)? {
+            return Ok(Some(JsonRedactor::new(self.clone())));
+        }
+        if request.method != http::Method::HEAD
+            && body_rewrite::media_type(response).is_some_and(|mime| json(&mime))
+        {
+            return ProxyError::BadUpstreamResponse
+                .fail("JSON response to redact is compressed or partial");
+        }
+        Ok(None)
+    }
+
+    fn action(&self, key: &[u8]) -> Action {
+        // Keys are compared unescaped, so `"to\u006ben"` is `token` too.
+        let unescaped = if key.contains(&b'\\') {
+            let mut quoted = Vec::with_capacity(key.len() + 2);
+            quoted.push(b'"');
+            quoted.extend_from_slice(key);
+            quoted.push(b'"');
+            serde_json::from_slice::<String>(&quoted).ok()
+        } else {
+            String::from_utf8(key.to_vec()).ok()
+        };
+        let Some(key) = unescaped else {
+            return Action::Keep;
+        };
+        if self.remove.contains(&key) {
+            Action::Remove
+        } else if self.mask.contains(&key) {
+            Action::Mask
+        } else {
+            Action::Keep
+        }
+    }
+}
+
+/// An open object or array.
+enum Frame {
+    /// `key` is set while a key is expected, `emitted` once a member was written out, which
+    /// the next one is separated from with a comma.
+    Object {
+        key: bool,
+        emitted: bool,
+    },
+    Array,
+}
+
+enum Mode {
+    Normal,
+    /// Inside a string that is passed on.
+    String {
+        escape: bool,
+    },
+    /// Inside a key, held back until it is known whether the member is kept.
+    Key {
+        key: Vec<u8>,
+        escape: bool,
+    },
+    /// After a key, before its colon.
+    Colon {
+        key: Vec<u8>,
+        action: Action,
+    },
+    /// Dropping a value; scalars end at the first byte that isn't theirs.
+    Skip {
+        depth: usize,
+        string: bool,
+        escape: bool,
+        started: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Keep,
+    Remove,
+    Mask,
+}
+
+/// Streams a JSON body through, dropping or masking the configured members. The output is
+/// valid JSON whenever the input is, without the whitespace around object members.
+pub struct JsonRedactor {
+    redact: Arc<JsonRedact>,
+    frames: Vec<Frame>,
+    mode: Mode,
+}
+
+impl JsonRedactor {
+    fn new(redact: Arc<JsonRedact>) -> Self {
+        Self {
+            redact,
+            frames: Vec::new(),
+            mode: Mode::Normal,
+        }
+    }
+
+    pub fn response_body(&mut self, body: &mut Option<Bytes>) {
+        let Some(chunk) = body.take() else {
+            return;
+        };
+        let mut out = Vec::with_capacity(chunk.len());
+        for &byte in chunk.iter() {
+            self.push(byte, &mut out);
+        }
+        *body = Some(Bytes::from(out));
+    }
+
+    fn push(&mut self, byte: u8, out: &mut Vec<u8>) {
+        match &mut self.mode {
+            Mode::String { escape } => {
+                out.push(byte);
+                if *escape {
+                    *escape = false;
+                } else if byte == b'\\' {
+                    *escape = true;
+                } else if byte == b'"' {
+                    self.mode = Mode::Normal;
+                }
+            }
+            Mode::Key { key, escape } => {
+                if *escape {
+                    *escape = false;
+                } else if byte == b'\\' {
+                    *escape = true;
+                } else if byte == b'"' {
+                    let key = std::mem::take(key);
+                    let action = self.redact.action(&key);
+                    self.mode = Mode::Colon { key, action };
+                    return;
+                }
+                key.push(byte);
+                if key.len() > self.redact.max_key_len {
+                    let key = std::mem::take(key);
+                    let escape = *escape;
+                    self.begin_member(&key, out);
+                    self.mode = Mode::String { escape };
+                }
+            }
+            Mode::Colon { key, action } => {
+                if byte != b':' {
+                    return;
+                }
+                let key = std::mem::take(key);
+                let action = *action;
+                if action != Action::Remove {
+                    self.begin_member(&key, out);
+                    out.extend_from_slice(b"\":");
+                }
+                if action == Action::Mask {
+                    out.extend_from_slice(&self.redact.mask_with);
+                }
+                self.mode = match action {
+                    Action::Keep => Mode::Normal,
+                    Action::Remove | Action::Mask => Mode::Skip {
+                        depth: 0,
+                        string: false,
+                        escape: false,
+                        started: false,
+                    },
+                };
+            }
+            Mode::Skip {
+                depth,
+                string,
+                escape,
+                started,
+            } => {
+                if *string {
+                    if *escape {
+                        *escape = false;
+                    } else if byte == b'\\' {
+                        *escape = true;
+                    } else if byte == b'"' {
+                        *string = false;
+                        if *depth == 0 {
+                            self.mode = Mode::Normal;
+                        }
+                    }
+                    return;
+                }
+                match byte {
+                    b' ' | b'\t' | b'\r' | b'\n' if !*started => {}
+                    b'"' => {
+                        *started = true;
+                        *string = true;
+                    }
+                    b'{' | b'[' => {
+                        *started = true;
+                        *depth += 1;
+                    }
+                    b'}' | b']' if *depth > 0 => {
+                        *depth -= 1;
+                        if *depth == 0 {
+                            self.mode = Mode::Normal;
+                        }
+                    }
+                    b',' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n' if *depth == 0 => {
+                        // The end of a number or literal belongs to what follows it.
+                        self.mode = Mode::Normal;
+                        self.push(byte, out);
+                    }
+                    _ => *started = true,
+                }
+            }
+            Mode::Normal => self.push_normal(byte, out),
+        }
+    }
+
+    fn push_normal(&mut self, byte: u8, out: &mut Vec<u8>) {
+        if let Some(Frame::Object { key: true, .. }) = self.frames.last() {
+            match byte {
+                b'"' => {
+                    self.mode = Mode::Key {
+                        key: Vec::new(),
+                        escape: false,
+                    }
+                }
+                b'}' => {
+                    self.frames.pop();
+                    out.push(byte);
+                }
+                // Commas are written before the next member that is kept.
+                _ => {}
+            }
+            return;
+        }
+        match byte {
+            b'"' => {
+                out.push(byte);
+                self.mode = Mode::String { escape: false };
+            }
+            b'{' => {
+                out.push(byte);
+                self.frames.push(Frame::Object {
+                    key: true,
+                    emitted: false,
+                });
+            }
+            b'[' => {
+                out.push(byte);
+                self.frames.push(Frame::Array);
+            }
+            b'}' | b']' => {
+                out.push(byte);
+                self.frames.pop();
+            }
+            b',' => match self.frames.last_mut() {
+                Some(Frame::Object { key, .. }) => *key = true,
+                _ => out.push(byte),
+            },
+            b' ' | b'\t' | b'\r' | b'\n'
+                if matches!(self.frames.last(), Some(Frame::Object { .. })) => {}
+            _ => out.push(byte),
+        }
+    }
+
+    /// Writes the separator and opening of a kept member, up to the end of its key.
+    fn begin_member(&mut self, key: &[u8], out: &mut Vec<u8>) {
+        if let Some(Frame::Object {
+            key: expecting,
+            emitted,
+        }) = self.frames.last_mut()
+        {
+            if *emitted {
+                out.push(b',');
+            }
+            *emitted = true;
+            *expecting = false;
+        }
+        out.push(b'"');
+        out.extend_from_slice(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor() -> JsonRedactor {
+        let config = JsonRedactConfig {
+            remove: vec!["token".to_string()],
+            mask: vec!["ssn".to_string()],
+            mask_with: None,
+        };
+        JsonRedactor::new(Arc::new(JsonRedact::new(&config).unwrap()))
+    }
+
+    fn redact<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> String {
+        let mut redactor = redactor();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            let mut body = Some(Bytes::copy_from_slice(chunk));
+            redactor.response_body(&mut body);
+            out.extend_from_slice(&body.unwrap());
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    const NESTED: &str = r#"{"a":1,"token":"x","n":{"token":{"deep":[1,{"b":2}]},"ssn":"123","k":[{"ssn":5},"ssn"]},"z":null}"#;
+    const NESTED_REDACTED: &str = r#"{"a":1,"n":{"ssn":"***","k":[{"ssn":"***"},"ssn"]},"z":null}"#;
+
+    #[test]
+    fn redacts_nested_objects_and_arrays() {
+        assert_eq!(redact([NESTED.as_bytes()]), NESTED_REDACTED);
+        assert_eq!(
+            redact([r#"[{"token":1},{"b":2,"token":[3]},[{"ssn":{}}]]"#.as_bytes()]),
+            r#"[{},{"b":2},[{"ssn":"***"}]]"#
+        );
+    }
+
+    #[test]
+    fn keys_split_across_chunks() {
+        assert_eq!(redact(NESTED.as_bytes().chunks(1)), NESTED_REDACTED);
+        assert_eq!(redact(NESTED.as_bytes().chunks(3)), NESTED_REDACTED);
+    }
+
+    #[test]
+    fn escaped_keys_are_compared_unescaped() {
+        let body = r#"{"to\u006ben":"x","\u0073sn":1,"t\"q":"to\"ken","token\\":2}"#;
+        assert_eq!(
+            redact([body.as_bytes()]),
+            r#"{"\u0073sn":"***","t\"q":"to\"ken","token\\":2}"#
+        );
+    }
+
+    #[test]
+    fn whitespace_around_members_is_dropped() {
+        let body = "{ \"token\" : 1 ,\n \"b\" : [ 2, 3 ] , \"ssn\" : true }";
+        assert_eq!(redact([body.as_bytes()]), r#"{"b":[ 2, 3 ],"ssn":"***"}"#);
+    }
+
+    #[test]
+    fn unreadable_json_is_refused() {
+        let redact = Arc::new(
+            JsonRedact::new(&JsonRedactConfig {
+                remove: vec!["token".to_string()],
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let request = RequestHeader::build("GET", b"/", None).unwrap();
+        let mut gzipped = ResponseHeader::build(200, None).unwrap();
+        gzipped
+            .insert_header("content-type", "application/json")
+            .unwrap();
+        gzipped.insert_header("content-encoding", "gzip").unwrap();
+        let err = redact.start(&request, &mut gzipped).err().unwrap();
+        assert_eq!(ProxyError::of(&err), Some(ProxyError::BadUpstreamResponse));
+
+        let mut html = ResponseHeader::build(200, None).unwrap();
+        html.insert_header("content-type", "text/html").unwrap();
+        html.insert_header("content-encoding", "gzip").unwrap();
+        assert!(redact.start(&request, &mut html).unwrap().is_none());
+    }
+}
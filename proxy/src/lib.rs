@@ -0,0 +1,93 @@
+//! Reverse proxy and static server for the Tar stack, built on pingora.
+//!
+//! The `proxy` binary runs it from a config file; other services can embed it, and tests can
+//! start it, through [`ProxyBuilder`].
+
+mod access_control;
+mod access_log;
+mod admin;
+mod admin_tls;
+mod alerts;
+mod api_keys;
+mod audit;
+mod basic_auth;
+mod bcrypt;
+mod body_rewrite;
+mod bots;
+mod build_info;
+mod builder;
+mod capture;
+mod clients;
+mod compression;
+mod concurrency;
+pub mod config;
+mod cookies;
+mod cors;
+mod csrf;
+mod doh;
+mod drain;
+pub mod dump;
+mod endpoints;
+mod error_reporting;
+mod errors;
+mod esi;
+mod fastcgi;
+mod filters;
+mod forward_proxy;
+mod forwarded;
+mod geoip;
+mod grpc;
+mod grpc_web;
+mod headers;
+mod health;
+mod hex;
+mod hooks;
+mod hop_headers;
+mod html_inject;
+mod images;
+mod ip_list;
+mod json_redact;
+mod limits;
+mod listeners;
+pub mod log_control;
+mod maintenance;
+mod markdown;
+mod memory_cache;
+mod metrics;
+mod mmdb;
+mod oidc;
+mod parent_proxy;
+mod proxy;
+mod proxy_protocol;
+mod rate_limit;
+mod redirects;
+mod redis;
+mod reload;
+mod request_id;
+mod response_policy;
+mod rewrites;
+pub mod routes;
+mod security_headers;
+mod signed_urls;
+mod state;
+mod static_assets;
+mod statsd;
+mod status;
+mod substitutions;
+pub mod syslog;
+mod systemd;
+mod tcp_proxy;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod units;
+mod waf;
+mod wasm;
+mod watched_file;
+mod websocket;
+
+pub use api_keys::ApiKey;
+pub use builder::ProxyBuilder;
+pub use config::{Config, ConfigError, ConfigSource};
+pub use filters::{Filter, FilterCtx};
+pub use hooks::{RequestStart, ResponseComplete, UpstreamSelected};
+pub use routes::RouteConfig;
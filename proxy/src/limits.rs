@@ -0,0 +1,454 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::header::CONTENT_LENGTH;
+use log::warn;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use pingora::proxy::Session;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ProxyError;
+use crate::routes::Route;
+
+/// Pingora's own timeout for each read from a client.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time a request body is given before `min_client_rate_kb` applies to it.
+const MIN_RATE_GRACE: Duration = Duration::from_secs(5);
+const DEFAULT_QUEUE_TIMEOUT_MS: u64 = 1000;
+/// Header telling the client a response was cut short, with the length it would have had.
+const TRUNCATED_HEADER: &str = "x-response-truncated";
+
+/// What happens to a response body larger than `max_response_body_kb`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseOverflow {
+    /// The client gets 502, or the connection is cut once the body is under way.
+    #[default]
+    Error,
+    /// The client gets the first `max_response_body_kb`.
+    Truncate,
+}
+
+/// `[limits]` section of the config file: how much clients may send.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct LimitsConfig {
+    /// Largest request body accepted; bigger ones are refused with 413. Unset for no limit.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_request_body_kb: Option<usize>,
+    /// Largest response body passed on to clients; see `response_body_overflow`. Unset for
+    /// no limit.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_response_body_kb: Option<usize>,
+    /// What happens to larger response bodies ("error" by default).
+    pub response_body_overflow: Option<ResponseOverflow>,
+    /// Longest request target (path and query) accepted; longer ones get 414.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_uri_kb: Option<usize>,
+    /// Largest single header line (name and value) accepted; larger ones get 431.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_header_kb: Option<usize>,
+    /// Largest total of all header lines accepted; larger ones get 431.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_headers_kb: Option<usize>,
+    /// Most headers accepted in a request; more get 431.
+    pub max_header_count: Option<usize>,
+    /// How long a kept-alive connection may sit idle waiting for the next request, and the
+    /// longest pause while its headers arrive (Pingora's default is 60 seconds).
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub client_idle_timeout_seconds: Option<u64>,
+    /// Longest pause while reading a request body (Pingora's default is 60 seconds).
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub client_body_timeout_seconds: Option<u64>,
+    /// Longest pause while a client takes a response.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub client_send_timeout_seconds: Option<u64>,
+    /// Slowest rate, in KB per second, at which a client may send a request body or take a
+    /// response.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub min_client_rate_kb: Option<usize>,
+    /// Time a client has to send the whole request body once its headers arrived.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub request_timeout_seconds: Option<u64>,
+    /// Most requests handled at once; more are queued or refused with 503. Routes may set
+    /// their own cap on top of it.
+    pub max_concurrent_requests: Option<usize>,
+    /// Requests allowed to wait for a free slot when a cap is reached, per cap (default 0).
+    pub max_queued_requests: Option<usize>,
+    /// How long a queued request waits for a slot before it is refused.
+    pub queue_timeout_ms: Option<u64>,
+    /// Most requests a single client IP may have in flight, over all its connections and
+    /// HTTP/2 streams; more get 429 and the connection is closed.
+    pub max_concurrent_requests_per_client: Option<usize>,
+}
+
+impl LimitsConfig {
+    /// Checks the request line and headers, returning the status to refuse the request with
+    /// and why.
+    pub fn check_head(&self, request: &RequestHeader) -> Option<(u16, String)> {
+        if let Some(max) = self.max_uri_kb.map(kilobytes) {
+            let length = request
+                .uri
+                .path_and_query()
+                .map_or(0, |target| target.as_str().len());
+            if length > max {
+                return Some((414, format!("request target of {length} bytes")));
+            }
+        }
+        if let Some(max) = self.max_header_count
+            && request.headers.len() > max
+        {
+            return Some((431, format!("{} headers", request.headers.len())));
+        }
+        let mut total = 0;
+        for (name, value) in &request.headers {
+            // As sent: `name: value` and CRLF.
+            let line = name.as_str().len() + value.len() + 4;
+            if let Some(max) = self.max_header_kb.map(kilobytes)
+                && line > max
+            {
+                return Some((431, format!("{name} header of {line} bytes")));
+            }
+            total += line;
+        }
+        match self.max_headers_kb.map(kilobytes) {
+            Some(max) if total > max => Some((431, format!("{total} bytes of headers"))),
+            _ => None,
+        }
+    }
+
+    /// Applies the client timeouts to the connection of a new request. They only take effect
+    /// on HTTP/1 connections.
+    pub fn apply_timeouts(&self, session: &mut Session) {
+        if let Some(idle) = self.client_idle_timeout_seconds
+            && session.get_keepalive().is_some()
+        {
+            session.set_keepalive(Some(idle));
+        }
+        let body_timeout = self.client_body_timeout_seconds.map(Duration::from_secs);
+        let deadline = self.request_timeout_seconds.map(Duration::from_secs);
+        if body_timeout.is_some() || deadline.is_some() {
+            let read_timeout = body_timeout.unwrap_or(DEFAULT_READ_TIMEOUT);
+            session.set_read_timeout(Some(deadline.map_or(read_timeout, |d| read_timeout.min(d))));
+        }
+        if deadline.is_some() {
+            session.set_total_drain_timeout(deadline);
+        }
+        if let Some(send_timeout) = self.client_send_timeout_seconds {
+            session.set_write_timeout(Some(Duration::from_secs(send_timeout)));
+        }
+        if let Some(rate) = self.min_client_rate_kb {
+            session.set_min_send_rate(Some(kilobytes(rate)));
+        }
+    }
+
+    /// Checks a request body still arriving after `received` bytes in `elapsed`, refusing it
+    /// with 408 when it is too slow, and shortens the next read to the time left.
+    pub fn pace_body(
+        &self,
+        session: &mut Session,
+        received: usize,
+        elapsed: Duration,
+    ) -> Result<()> {
+        if let Some(deadline) = self.request_timeout_seconds.map(Duration::from_secs) {
+            let Some(left) = deadline.checked_sub(elapsed).filter(|left| !left.is_zero()) else {
+                return ProxyError::RequestTimeout
+                    .fail(format!("request body not received within {deadline:?}"));
+            };
+            let read_timeout = self
+                .client_body_timeout_seconds
+                .map_or(DEFAULT_READ_TIMEOUT, Duration::from_secs);
+            session.set_read_timeout(Some(read_timeout.min(left)));
+        }
+        if let Some(rate) = self.min_client_rate_kb.map(kilobytes)
+            && elapsed >= MIN_RATE_GRACE
+        {
+            let actual = received as f64 / elapsed.as_secs_f64();
+            if actual < rate as f64 {
+                return ProxyError::RequestTimeout
+                    .fail(format!("request body arriving at {actual:.0} bytes/s"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn kilobytes(kb: usize) -> usize {
+    kb.saturating_mul(1024)
+}
+
+/// The request body cap in bytes for a request on `route`, if any.
+pub fn max_request_body(limits: Option<&LimitsConfig>, route: Option<&Route>) -> Option<usize> {
+    route
+        .and_then(|route| route.max_request_body_kb)
+        .or_else(|| limits.and_then(|limits| limits.max_request_body_kb))
+        .map(kilobytes)
+}
+
+/// Caps the body of a response as `max_response_body_kb` says.
+#[derive(Debug)]
+pub struct ResponseBodyCap {
+    max: usize,
+    overflow: ResponseOverflow,
+    sent: usize,
+}
+
+/// The response body cap for a request on `route`, if any.
+pub fn response_body_cap(
+    limits: Option<&LimitsConfig>,
+    route: Option<&Route>,
+) -> Option<ResponseBodyCap> {
+    let max = route
+        .and_then(|route| route.max_response_body_kb)
+        .or_else(|| limits.and_then(|limits| limits.max_response_body_kb))?;
+    let overflow = route
+        .and_then(|route| route.response_body_overflow)
+        .or_else(|| limits.and_then(|limits| limits.response_body_overflow))
+        .unwrap_or_default();
+    Some(ResponseBodyCap {
+        max: kilobytes(max),
+        overflow,
+        sent: 0,
+    })
+}
+
+impl ResponseBodyCap {
+    /// Refuses a response whose `Content-Length` is over the cap with 502, or announces the
+    /// truncated length.
+    pub fn check_response(&self, response: &mut ResponseHeader) -> Result<()> {
+        let declared = response
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .filter(|length| *length > self.max as u64);
+        let Some(declared) = declared else {
+            return Ok(());
+        };
+        match self.overflow {
+            ResponseOverflow::Error => ProxyError::BadUpstreamResponse
+                .fail(format!("upstream response body of {declared} bytes")),
+            ResponseOverflow::Truncate => {
+                response.insert_header(CONTENT_LENGTH, self.max)?;
+                response.insert_header(TRUNCATED_HEADER, declared)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Passes on what fits under the cap. Past it, a truncated body is dropped and any other
+    /// fails the response.
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, request_id: &str) -> Result<()> {
+        let Some(chunk) = body else {
+            return Ok(());
+        };
+        let room = self.max - self.sent;
+        if chunk.len() <= room {
+            self.sent += chunk.len();
+            return Ok(());
+        }
+        if self.overflow == ResponseOverflow::Error {
+            return ProxyError::BadUpstreamResponse
+                .fail(format!("upstream response body over {} bytes", self.max));
+        }
+        if room > 0 {
+            warn!(
+                "request {request_id}: response body truncated at {} bytes",
+                self.max
+            );
+        }
+        chunk.truncate(room);
+        self.sent = self.max;
+        if chunk.is_empty() {
+            *body = None;
+        }
+        Ok(())
+    }
+}
+
+/// The in-flight request caps a request on `route` is subject to, as the route name (`None`
+/// for the global cap) and limit: the route's own first, then the global one.
+pub fn concurrency_gates<'a>(
+    limits: Option<&'a LimitsConfig>,
+    route: Option<&'a Route>,
+) -> impl Iterator<Item = (Option<&'a str>, usize)> {
+    let route = route.and_then(|route| {
+        route
+            .max_concurrent_requests
+            .map(|limit| (Some(route.name.as_str()), limit))
+    });
+    let global = limits
+        .and_then(|limits| limits.max_concurrent_requests)
+        .map(|limit| (None, limit));
+    route.into_iter().chain(global)
+}
+
+/// How many requests may queue for a full concurrency gate, and for how long.
+pub fn concurrency_queue(limits: Option<&LimitsConfig>) -> (usize, Duration) {
+    let queued = limits.and_then(|limits| limits.max_queued_requests);
+    let timeout = limits.and_then(|limits| limits.queue_timeout_ms);
+    (
+        queued.unwrap_or(0),
+        Duration::from_millis(timeout.unwrap_or(DEFAULT_QUEUE_TIMEOUT_MS)),
+    )
+}
+
+/// Whether the `Content-Length` of `request` already announces more than `max` bytes.
+pub fn declares_larger_body(request: &RequestHeader, max: usize) -> bool {
+    request
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .is_some_and(|length| length > max as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(config: &str) -> LimitsConfig {
+        toml::from_str(config).unwrap()
+    }
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("GET", target.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            request.append_header(name.to_string(), *value).unwrap();
+        }
+        request
+    }
+
+    fn response(length: Option<usize>) -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        if let Some(length) = length {
+            response.insert_header(CONTENT_LENGTH, length).unwrap();
+        }
+        response
+    }
+
+    #[test]
+    fn checks_the_request_target() {
+        let limits = limits("max_uri_kb = 1");
+        let fits = format!("/{}", "a".repeat(1023));
+        assert_eq!(limits.check_head(&request(&fits, &[])), None);
+        let (status, reason) = limits
+            .check_head(&request(&format!("{fits}b"), &[]))
+            .unwrap();
+        assert_eq!(
+            (status, reason.as_str()),
+            (414, "request target of 1025 bytes")
+        );
+        // The query counts too.
+        assert!(
+            limits
+                .check_head(&request(&format!("{fits}?q"), &[]))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn checks_header_lines_and_their_total() {
+        let limits = limits("max_header_kb = 1\nmax_headers_kb = 2\nmax_header_count = 3");
+        // `x-a: ` and CRLF take 7 bytes of the line.
+        let value = "v".repeat(1024 - 7);
+        assert_eq!(limits.check_head(&request("/", &[("x-a", &value)])), None);
+        let long = format!("{value}v");
+        let (status, reason) = limits.check_head(&request("/", &[("x-a", &long)])).unwrap();
+        assert_eq!((status, reason.as_str()), (431, "x-a header of 1025 bytes"));
+
+        let three = [("x-a", value.as_str()), ("x-b", &value), ("x-c", "v")];
+        let (status, reason) = limits.check_head(&request("/", &three)).unwrap();
+        assert_eq!((status, reason.as_str()), (431, "2056 bytes of headers"));
+
+        let four = [("x-a", "v"), ("x-b", "v"), ("x-c", "v"), ("x-d", "v")];
+        let (status, reason) = limits.check_head(&request("/", &four)).unwrap();
+        assert_eq!((status, reason.as_str()), (431, "4 headers"));
+    }
+
+    #[test]
+    fn unset_limits_accept_anything() {
+        let limits = LimitsConfig::default();
+        let value = "v".repeat(64 * 1024);
+        let target = format!("/{value}");
+        assert_eq!(
+            limits.check_head(&request(&target, &[("x-a", &value)])),
+            None
+        );
+        assert_eq!(max_request_body(Some(&limits), None), None);
+        assert!(response_body_cap(Some(&limits), None).is_none());
+        assert!(response_body_cap(None, None).is_none());
+    }
+
+    #[test]
+    fn compares_declared_body_lengths() {
+        let limits = limits("max_request_body_kb = 2");
+        assert_eq!(max_request_body(Some(&limits), None), Some(2048));
+        let declared = |length: &str| request("/", &[("content-length", length)]);
+        assert!(!declares_larger_body(&declared("2048"), 2048));
+        assert!(declares_larger_body(&declared(" 2049 "), 2048));
+        assert!(!declares_larger_body(&declared("lots"), 2048));
+        assert!(!declares_larger_body(&request("/", &[]), 2048));
+    }
+
+    #[test]
+    fn refuses_responses_over_the_cap() {
+        let limits = limits("max_response_body_kb = 1");
+        let mut cap = response_body_cap(Some(&limits), None).unwrap();
+        assert!(cap.check_response(&mut response(Some(1024))).is_ok());
+        assert!(cap.check_response(&mut response(None)).is_ok());
+        assert!(cap.check_response(&mut response(Some(1025))).is_err());
+
+        let mut body = Some(Bytes::from(vec![b'a'; 1000]));
+        cap.response_body(&mut body, "1").unwrap();
+        assert_eq!(body.map(|body| body.len()), Some(1000));
+        let mut body = Some(Bytes::from(vec![b'a'; 25]));
+        assert!(cap.response_body(&mut body, "1").is_err());
+    }
+
+    #[test]
+    fn truncates_responses_over_the_cap() {
+        let limits = limits("max_response_body_kb = 1\nresponse_body_overflow = \"truncate\"");
+        let mut cap = response_body_cap(Some(&limits), None).unwrap();
+        let mut header = response(Some(4096));
+        cap.check_response(&mut header).unwrap();
+        assert_eq!(header.headers[CONTENT_LENGTH], "1024");
+        assert_eq!(header.headers[TRUNCATED_HEADER], "4096");
+
+        let mut body = Some(Bytes::from(vec![b'a'; 1000]));
+        cap.response_body(&mut body, "1").unwrap();
+        let mut body = Some(Bytes::from(vec![b'b'; 100]));
+        cap.response_body(&mut body, "1").unwrap();
+        assert_eq!(body.as_deref(), Some(&[b'b'; 24][..]));
+        let mut body = Some(Bytes::from_static(b"more"));
+        cap.response_body(&mut body, "1").unwrap();
+        assert_eq!(body, None);
+        // The end of the body passes.
+        let mut body = None;
+        cap.response_body(&mut body, "1").unwrap();
+    }
+
+    #[test]
+    fn orders_concurrency_gates() {
+        let limits = limits("max_concurrent_requests = 100\nmax_queued_requests = 5");
+        let gates: Vec<_> = concurrency_gates(Some(&limits), None).collect();
+        assert_eq!(gates, [(None, 100)]);
+        assert_eq!(concurrency_gates(None, None).count(), 0);
+        assert_eq!(
+            concurrency_queue(Some(&limits)),
+            (5, Duration::from_millis(DEFAULT_QUEUE_TIMEOUT_MS))
+        );
+        assert_eq!(concurrency_queue(None), (0, Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs::Permissions;
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+
+use log::info;
+use pingora::apps::HttpServerOptions;
+use pingora::listeners::TcpSocketOptions;
+use pingora::protocols::TcpKeepalive;
+use pingora::protocols::http::v2::server::H2Options;
+use pingora::services::listening::Service;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::systemd::{InheritedSocket, ListenAddr};
+
+/// Largest HTTP/2 flow control window, 2^31 - 1 bytes.
+const MAX_H2_WINDOW: u32 = (1 << 31) - 1;
+
+/// `listen_addr`: a single address or a list of them, e.g. `["0.0.0.0:8080", "[::]:8080"]`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum ListenAddrs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ListenAddrs {
+    pub fn addrs(&self) -> &[String] {
+        match self {
+            ListenAddrs::One(addr) => std::slice::from_ref(addr),
+            ListenAddrs::Many(addrs) => addrs,
+        }
+    }
+}
+
+/// `[listener]` section: socket options for every TCP listener.
+///
+/// Pingora always enables `TCP_NODELAY` on accepted connections and listens
+/// with a backlog of 65535; neither can be changed from here.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct ListenerConfig {
+    /// Sets `SO_REUSEPORT` so several processes can share a port.
+    pub reuseport: Option<bool>,
+    /// Enables TCP Fast Open with this queue length.
+    pub tcp_fastopen: Option<usize>,
+    /// TCP keepalive probes on accepted connections.
+    pub tcp_keepalive: Option<TcpKeepaliveConfig>,
+    /// Accepts HTTP/2 without TLS from clients that start with its preface ("prior
+    /// knowledge"), next to HTTP/1 on the same listeners.
+    pub h2c: Option<bool>,
+    /// Streams a client may have open at once on one HTTP/2 connection.
+    pub h2_max_concurrent_streams: Option<u32>,
+    /// HTTP/2 flow control window of each stream.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub h2_stream_window_kb: Option<usize>,
+    /// HTTP/2 flow control window of each connection, shared by its streams.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub h2_connection_window_kb: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct TcpKeepaliveConfig {
+    #[serde(deserialize_with = "crate::units::seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub idle_seconds: u64,
+    #[serde(deserialize_with = "crate::units::seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub interval_seconds: u64,
+    pub count: usize,
+}
+
+impl ListenerConfig {
+    fn tcp_options(&self) -> TcpSocketOptions {
+        let mut options = TcpSocketOptions::default();
+        options.so_reuseport = self.reuseport;
+        options.tcp_fastopen = self.tcp_fastopen;
+        options.tcp_keepalive = self.tcp_keepalive.as_ref().map(|ka| TcpKeepalive {
+            idle: Duration::from_secs(ka.idle_seconds),
+            interval: Duration::from_secs(ka.interval_seconds),
+            count: ka.count,
+            #[cfg(target_os = "linux")]
+            user_timeout: Duration::ZERO,
+        });
+        options
+    }
+
+    /// Pingora's server options, turning on h2c when asked for.
+    pub fn server_options(&self) -> Option<HttpServerOptions> {
+        self.h2c.unwrap_or(false).then(|| {
+            let mut options = HttpServerOptions::default();
+            options.h2c = true;
+            options
+        })
+    }
+
+    /// HTTP/2 settings sent to clients; `None` keeps the h2 crate's defaults.
+    pub fn h2_options(&self) -> Option<H2Options> {
+        if self.h2_max_concurrent_streams.is_none()
+            && self.h2_stream_window_kb.is_none()
+            && self.h2_connection_window_kb.is_none()
+        {
+            return None;
+        }
+        let mut options = H2Options::new();
+        if let Some(streams) = self.h2_max_concurrent_streams {
+            options.max_concurrent_streams(streams);
+        }
+        if let Some(window) = self.h2_stream_window_kb {
+            options.initial_window_size(window_size(window));
+        }
+        if let Some(window) = self.h2_connection_window_kb {
+            options.initial_connection_window_size(window_size(window));
+        }
+        Some(options)
+    }
+}
+
+/// A window of `kb` in bytes, capped at HTTP/2's largest.
+fn window_size(kb: usize) -> u32 {
+    kb.saturating_mul(1024).min(MAX_H2_WINDOW as usize) as u32
+}
+
+/// Parses a Unix socket mode such as `"660"`, `"0660"` or `"0o660"`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("{mode:?} is not an octal file mode"))
+}
+
+/// Adds the configured TCP and Unix socket listeners to `service`, plus any
+/// systemd-passed socket that none of them matches.
+///
+/// With more than one TCP address, IPv6 listeners are bound IPv6-only so that
+/// `0.0.0.0:port` and `[::]:port` can be listed side by side.
+///
+/// Returns the listen addresses to serve from inherited descriptors; see
+/// [`SocketActivated`](crate::systemd::SocketActivated).
+pub fn add_listeners<A>(
+    service: &mut Service<A>,
+    config: &Config,
+    inherited: &[InheritedSocket],
+) -> Vec<(String, RawFd)> {
+    let mut adopted = Vec::new();
+    let mut adopt = |addr: &str| {
+        if let Some(socket) = inherited.iter().find(|socket| socket.matches(addr)) {
+            adopted.push((addr.to_string(), socket.fd()));
+            info!("Proxy listening on {} (systemd socket)", addr);
+            true
+        } else {
+            false
+        }
+    };
+
+    if let Some(path) = &config.listen_unix {
+        let mode = config
+            .listen_unix_mode
+            .as_deref()
+            .map(|mode| parse_mode(mode).expect("listen_unix_mode is checked by Config::load"));
+        service.add_uds(path, mode.map(Permissions::from_mode));
+        if !adopt(path) {
+            info!("Proxy listening on unix:{}", path);
+        }
+    }
+
+    let tcp_options = config.listener.clone().unwrap_or_default().tcp_options();
+    let addrs = config
+        .listen_addr
+        .as_ref()
+        .map(ListenAddrs::addrs)
+        .unwrap_or_default();
+    for addr in addrs {
+        let is_ipv6 = addr
+            .parse::<SocketAddr>()
+            .is_ok_and(|parsed| parsed.is_ipv6());
+        let mut options = tcp_options.clone();
+        if addrs.len() > 1 && is_ipv6 {
+            options.ipv6_only = Some(true);
+        }
+        service.add_tcp_with_settings(addr, options);
+        if !adopt(addr) {
+            info!("Proxy listening on {}", addr);
+        }
+    }
+
+    for socket in inherited {
+        if adopted.iter().any(|(_, fd)| *fd == socket.fd()) {
+            continue;
+        }
+        let addr = match socket.listen_addr() {
+            ListenAddr::Tcp(addr) => {
+                service.add_tcp_with_settings(&addr, tcp_options.clone());
+                addr
+            }
+            ListenAddr::Unix(path) => {
+                service.add_uds(&path, None);
+                path
+            }
+        };
+        info!("Proxy listening on {} (systemd socket)", addr);
+        adopted.push((addr, socket.fd()));
+    }
+    adopted
+}
@@ -0,0 +1,170 @@
+use std::sync::{OnceLock, RwLock};
+
+use async_trait::async_trait;
+use env_filter::Filter;
+use log::{Log, Metadata, Record, error, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde_json::json;
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::audit;
+use crate::state::SharedState;
+
+/// Filter switched to by `SIGUSR2` unless `debug_log_level` says otherwise.
+pub const DEFAULT_DEBUG_LOG_LEVEL: &str = "debug";
+
+static LOGGER: OnceLock<RuntimeLogger> = OnceLock::new();
+
+/// The global logger: env_logger's output behind a filter that can be swapped at runtime.
+struct RuntimeLogger {
+    output: env_logger::Logger,
+    filter: RwLock<ActiveFilter>,
+}
+
+struct ActiveFilter {
+    filter: Filter,
+    spec: String,
+    /// `log_level` from the config, restored when the debug filter is toggled off.
+    configured: String,
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.active().filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.active().filter.matches(record) {
+            self.output.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+impl RuntimeLogger {
+    fn active(&self) -> std::sync::RwLockReadGuard<'_, ActiveFilter> {
+        self.filter
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Parses a filter in env_logger syntax, e.g. `info,rose_proxy=debug`.
+pub fn parse_filter(spec: &str) -> Result<Filter, String> {
+    let mut builder = env_filter::Builder::new();
+    builder
+        .try_parse(spec)
+        .map_err(|err| format!("invalid log filter {spec:?}: {err}"))?;
+    Ok(builder.build())
+}
+
+/// Installs the global logger writing through `output` (its own filter is ignored),
+/// starting with the configured `log_level`.
+pub fn init(mut output: env_logger::Builder, log_level: &str) -> Result<(), String> {
+    let filter = parse_filter(log_level)?;
+    log::set_max_level(filter.filter());
+    let logger = LOGGER.get_or_init(|| RuntimeLogger {
+        output: output.filter_level(log::LevelFilter::Trace).build(),
+        filter: RwLock::new(ActiveFilter {
+            filter,
+            spec: log_level.to_string(),
+            configured: log_level.to_string(),
+        }),
+    });
+    log::set_logger(logger).map_err(|err| err.to_string())
+}
+
+/// Applies `spec` until it is changed again; the configured `log_level` is kept to
+/// switch back to.
+pub fn set_filter(spec: &str) -> Result<(), String> {
+    let filter = parse_filter(spec)?;
+    let logger = LOGGER.get().ok_or("logging is not initialized")?;
+    let mut active = logger
+        .filter
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    log::set_max_level(filter.filter());
+    active.filter = filter;
+    active.spec = spec.to_string();
+    Ok(())
+}
+
+/// Applies a changed `log_level` from a config reload, replacing any temporary filter.
+pub fn set_configured(log_level: &str) -> Result<(), String> {
+    set_filter(log_level)?;
+    if let Some(logger) = LOGGER.get() {
+        logger
+            .filter
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .configured = log_level.to_string();
+    }
+    Ok(())
+}
+
+/// Switches to `debug_filter`, or back to the configured `log_level` when it is active.
+/// Returns the filter now applied.
+pub fn toggle_debug(debug_filter: &str) -> Result<String, String> {
+    let logger = LOGGER.get().ok_or("logging is not initialized")?;
+    let (spec, configured) = {
+        let active = logger.active();
+        (active.spec.clone(), active.configured.clone())
+    };
+    let next = if spec == configured {
+        debug_filter
+    } else {
+        configured.as_str()
+    };
+    set_filter(next)?;
+    Ok(next.to_string())
+}
+
+/// Background service that toggles debug logging whenever the process gets `SIGUSR2`.
+pub struct DebugLogToggleService {
+    state: SharedState,
+}
+
+impl DebugLogToggleService {
+    pub fn new(state: SharedState) -> Self {
+        Self { state }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for DebugLogToggleService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut signals = match signal(SignalKind::user_defined2()) {
+            Ok(signals) => signals,
+            Err(err) => {
+                error!("failed to install SIGUSR2 handler, debug log toggle disabled: {err}");
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = signals.recv() => {
+                    let config = &self.state.current().config;
+                    let debug_filter = config
+                        .debug_log_level
+                        .as_deref()
+                        .unwrap_or(DEFAULT_DEBUG_LOG_LEVEL);
+                    match toggle_debug(debug_filter) {
+                        Ok(spec) => {
+                            warn!("SIGUSR2 received, log filter is now {spec:?}");
+                            audit::record(
+                                "log_filter_changed",
+                                json!({ "filter": spec, "trigger": "sigusr2" }),
+                            );
+                        }
+                        Err(err) => error!("failed to toggle the log filter: {err}"),
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+}
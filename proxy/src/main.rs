@@ -1,248 +1,89 @@
-mod static_assets;
+mod cli;
+mod init;
 
-use async_trait::async_trait;
-use http::header::{
-    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    ACCESS_CONTROL_MAX_AGE, ORIGIN, VARY,
-};
+use clap::Parser;
 use log::info;
-use pingora::http::{Method, ResponseHeader};
-use pingora::prelude::*;
-use pingora::proxy::http_proxy_service;
-use pingora::server::configuration::{Opt, ServerConf};
-use serde::Deserialize;
-use std::fs;
-use std::path::PathBuf;
+use rose_proxy::config::{self, Config, DEFAULT_LOG_LEVEL};
+use rose_proxy::syslog::{SyslogFormat, SyslogWriter};
+use rose_proxy::{ProxyBuilder, dump, log_control};
 
-use static_assets::{StaticAssetConfig, StaticAssets};
+use cli::{Cli, Command};
 
-const DEFAULT_STATIC_MOUNT: &str = "/";
-const DEFAULT_STATIC_INDEX: &str = "index.html";
-const DEFAULT_STATIC_CACHE_SECONDS: u64 = 60;
-const DEFAULT_STATIC_IMMUTABLE_CACHE_SECONDS: u64 = 60 * 60 * 24 * 365; // 1 year
-const DEFAULT_STATIC_KEEPALIVE_SECONDS: u64 = 60;
-const DEFAULT_STATIC_MANIFEST_POLL_SECONDS: u64 = 5;
-
-#[derive(Deserialize, Debug, Clone)]
-struct Config {
-    upstream_addr: String,
-    listen_addr: Option<String>,
-    log_level: Option<String>,
-    grace_period_seconds: Option<u64>,
-    graceful_shutdown_timeout_seconds: Option<u64>,
-    static_root: Option<String>,
-    static_mount: Option<String>,
-    static_index_file: Option<String>,
-    static_manifest: Option<String>,
-    static_default_cache_seconds: Option<u64>,
-    static_immutable_cache_seconds: Option<u64>,
-    static_keepalive_seconds: Option<u64>,
-    static_manifest_poll_seconds: Option<u64>,
-}
-
-#[derive(Clone)]
-pub struct RoseProxy {
-    upstream_addr: String,
-    static_assets: Option<StaticAssets>,
-}
-
-#[async_trait]
-impl ProxyHttp for RoseProxy {
-    type CTX = ();
-    fn new_ctx(&self) -> Self::CTX {}
-
-    async fn upstream_peer(
-        &self,
-        _session: &mut Session,
-        _ctx: &mut Self::CTX,
-    ) -> Result<Box<HttpPeer>> {
-        let peer = Box::new(HttpPeer::new(&self.upstream_addr, false, "".to_string()));
-        Ok(peer)
-    }
-
-    async fn upstream_request_filter(
-        &self,
-        _session: &mut Session,
-        upstream_request: &mut RequestHeader,
-        _ctx: &mut Self::CTX,
-    ) -> Result<()> {
-        let host = self
-            .upstream_addr
-            .split(':')
-            .next()
-            .unwrap_or(&self.upstream_addr);
-        upstream_request.insert_header("Host", host)?;
-        Ok(())
-    }
-
-    async fn response_filter(
-        &self,
-        session: &mut Session,
-        response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
-    ) -> Result<()> {
-        if let Some(origin_value) = session.req_header().headers.get(ORIGIN) {
-            response.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, origin_value)?;
-
-            response.append_header(VARY, "Origin")?;
-
-            response.insert_header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
-
-            response.insert_header(
-                ACCESS_CONTROL_ALLOW_METHODS,
-                "GET, POST, PUT, DELETE, OPTIONS, PATCH",
-            )?
+fn main() {
+    let cli = Cli::parse();
+    match &cli.command {
+        Some(Command::Init { path, force }) => {
+            if let Err(err) = init::write_starter_config(path.as_deref(), *force) {
+                exit_with_error(&err);
+            }
+            return;
         }
-
-        Ok(())
-    }
-
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool> {
-        if let Some(static_assets) = &self.static_assets
-            && static_assets.try_serve(session).await?
-        {
-            return Ok(true);
+        Some(Command::Schema) => {
+            print!("{}", config::json_schema());
+            return;
         }
+        None => {}
+    }
 
-        if session.req_header().method == Method::OPTIONS {
-            if let Some(origin_value) = session.req_header().headers.get(ORIGIN) {
-                let mut resp = ResponseHeader::build(204, None)?;
-
-                resp.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, origin_value)?;
-
-                resp.insert_header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
-
-                resp.insert_header(
-                    ACCESS_CONTROL_ALLOW_METHODS,
-                    "GET, POST, PUT, DELETE, OPTIONS, PATCH",
-                )?;
-
-                resp.insert_header(ACCESS_CONTROL_MAX_AGE, "86400")?; // 1 day
-
-                session.write_response_header(Box::new(resp), true).await?;
-                session.finish_body().await?;
-                return Ok(true);
-            } else {
-                session.respond_error(200).await?;
-                return Ok(true);
-            }
+    let config_source = cli.config_source();
+    let config = Config::load(&config_source).unwrap_or_else(|err| {
+        exit_with_error(&format!(
+            "invalid configuration in {}:\n{err}",
+            cli.config.display()
+        ))
+    });
+
+    if let Some(format) = cli.dump_config {
+        match dump::dump_config(&config, format) {
+            Ok(dump) => print!("{dump}"),
+            Err(err) => exit_with_error(&err),
         }
-        Ok(false)
+        return;
     }
-}
-
-fn main() {
-    let config_path = "/proxy/config.toml";
-    let config_str = fs::read_to_string(config_path)
-        .unwrap_or_else(|_| panic!("Failed to read config file: {}", config_path));
-
-    let config: Config = toml::from_str(&config_str)
-        .unwrap_or_else(|_| panic!("Failed to parse config file: {}", config_path));
 
     let log_level_filter = config
         .log_level
         .clone()
-        .unwrap_or_else(|| "info".to_string());
-
-    env_logger::Builder::new()
-        .parse_filters(&log_level_filter)
-        .init();
-
-    info!("Loaded configuration: {:?}", config);
-
-    let listen_addr = config
-        .listen_addr
-        .clone()
-        .expect("listen_addr must be set in the config file");
-
-    let opt = Opt::parse_args();
-
-    let default_conf = ServerConf::default();
-    let server_conf = ServerConf {
-        grace_period_seconds: config
-            .grace_period_seconds
-            .or(default_conf.grace_period_seconds),
-        graceful_shutdown_timeout_seconds: config
-            .graceful_shutdown_timeout_seconds
-            .or(default_conf.graceful_shutdown_timeout_seconds),
-        ..default_conf
-    };
-    info!("Using ServerConf: {:?}", server_conf);
-
-    let mut my_server = Server::new_with_opt_and_conf(opt, server_conf);
-
-    my_server.bootstrap();
-
-    let static_assets = config
-        .static_root
-        .as_ref()
-        .map(|root| build_static_assets(&config, root));
-
-    if let Some(ref assets) = static_assets {
-        info!(
-            "Static assets enabled: mount '{}' -> {:?}",
-            assets.mount_path(),
-            assets.root_path()
-        );
+        .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string());
+
+    let mut logger = env_logger::Builder::new();
+    if let Some(syslog_config) = &config.syslog {
+        let writer = SyslogWriter::connect(syslog_config).unwrap_or_else(|err| {
+            exit_with_error(&format!(
+                "failed to connect to syslog at {}: {err}",
+                syslog_config.addr
+            ))
+        });
+        let format = SyslogFormat::new(syslog_config);
+        logger
+            .format(move |out, record| format.format(out, record))
+            .target(env_logger::Target::Pipe(Box::new(writer)));
     }
+    log_control::init(logger, &log_level_filter).unwrap_or_else(|err| exit_with_error(&err));
 
-    let manifest_poll = config
-        .static_manifest_poll_seconds
-        .unwrap_or(DEFAULT_STATIC_MANIFEST_POLL_SECONDS);
+    // Kept for the life of the process; dropping it stops reporting.
+    let _sentry = config.sentry.as_ref().map(|sentry_config| {
+        let guard = sentry_config
+            .init()
+            .unwrap_or_else(|err| exit_with_error(&err));
+        info!("Reporting panics and upstream failures to Sentry");
+        guard
+    });
 
-    if let Some(ref assets) = static_assets
-        && let Some(manifest_service) = assets.manifest_background(manifest_poll)
-    {
-        my_server.add_service(manifest_service);
-    }
+    info!("Loaded configuration from {}", cli.config.display());
 
-    let proxy_config = RoseProxy {
-        upstream_addr: config.upstream_addr.clone(),
-        static_assets: static_assets.clone(),
-    };
-
-    let mut proxy_service = http_proxy_service(&my_server.configuration, proxy_config);
-
-    proxy_service.add_tcp(&listen_addr);
-    info!("Proxy listening on {}", listen_addr);
-
-    my_server.add_service(proxy_service);
+    let server = ProxyBuilder::from_config(config)
+        .reload_from(config_source)
+        .server_opt(cli.server_opt())
+        .build()
+        .unwrap_or_else(|err| exit_with_error(&err));
 
     info!("Starting server...");
-    my_server.run_forever();
+    server.run_forever();
 }
 
-fn build_static_assets(config: &Config, root: &str) -> StaticAssets {
-    let asset_root = PathBuf::from(root);
-    let mount_path = config
-        .static_mount
-        .as_deref()
-        .unwrap_or(DEFAULT_STATIC_MOUNT);
-    let index_file = config
-        .static_index_file
-        .as_deref()
-        .unwrap_or(DEFAULT_STATIC_INDEX);
-    let manifest_path = config.static_manifest.as_ref().map(PathBuf::from);
-    let immutable_cache_seconds = config
-        .static_immutable_cache_seconds
-        .unwrap_or(DEFAULT_STATIC_IMMUTABLE_CACHE_SECONDS);
-    let default_cache_seconds = config
-        .static_default_cache_seconds
-        .unwrap_or(DEFAULT_STATIC_CACHE_SECONDS);
-    let keepalive_seconds = config
-        .static_keepalive_seconds
-        .unwrap_or(DEFAULT_STATIC_KEEPALIVE_SECONDS);
-
-    let asset_config = StaticAssetConfig {
-        mount_path: mount_path.to_string(),
-        root: asset_root,
-        index_file: index_file.to_string(),
-        manifest_path,
-        immutable_cache_seconds,
-        default_cache_seconds,
-        keepalive_seconds,
-    };
-
-    StaticAssets::new(asset_config)
-        .unwrap_or_else(|err| panic!("Failed to initialise static assets with root {root}: {err}"))
+/// Reports a startup error and exits with a non-zero status.
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("error: {message}");
+    std::process::exit(1);
 }
@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use http::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use pingora::http::ResponseHeader;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::units::timestamp;
+
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+    <title>Down for maintenance</title></head><body><h1>Down for maintenance</h1>\
+    <p>We'll be back shortly.</p></body></html>\n";
+
+/// `[maintenance]` section of the config file: the page served while maintenance mode,
+/// switched on through the admin API, is on.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct MaintenanceConfig {
+    /// HTML file served with the 503; a short built-in page by default.
+    pub page: Option<String>,
+    /// `Retry-After` sent while maintenance has no end set; none by default.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub retry_after_seconds: Option<u64>,
+}
+
+/// The response of requests refused for maintenance.
+#[derive(Debug)]
+pub struct MaintenancePage {
+    body: Bytes,
+    retry_after: Option<u64>,
+}
+
+impl MaintenancePage {
+    pub fn new(config: Option<&MaintenanceConfig>) -> Result<Self, String> {
+        let body = match config.and_then(|config| config.page.as_deref()) {
+            Some(path) => std::fs::read(path)
+                .map_err(|err| format!("can't read page {path}: {err}"))?
+                .into(),
+            None => Bytes::from_static(DEFAULT_PAGE.as_bytes()),
+        };
+        Ok(Self {
+            body,
+            retry_after: config.and_then(|config| config.retry_after_seconds),
+        })
+    }
+
+    /// The 503 for a request under maintenance that ends at `until`, if set.
+    pub fn response(
+        &self,
+        until: Option<SystemTime>,
+        request_id: &str,
+    ) -> pingora::Result<(ResponseHeader, Bytes)> {
+        let retry_after = until
+            .map(|until| {
+                let left = until.duration_since(SystemTime::now()).unwrap_or_default();
+                left.as_secs().max(1)
+            })
+            .or(self.retry_after);
+        let mut header = ResponseHeader::build(503, Some(5))?;
+        header.insert_header(CONTENT_TYPE, "text/html; charset=utf-8")?;
+        header.insert_header(CONTENT_LENGTH, self.body.len())?;
+        header.insert_header(CACHE_CONTROL, "no-store")?;
+        if let Some(seconds) = retry_after {
+            header.insert_header(RETRY_AFTER, seconds)?;
+        }
+        header.insert_header(REQUEST_ID_HEADER, request_id)?;
+        Ok((header, self.body.clone()))
+    }
+}
+
+/// Maintenance mode switched on through the admin API, for everything or for single routes,
+/// kept across config reloads. Each switch may end by itself at a set time.
+#[derive(Clone, Default)]
+pub struct Maintenance {
+    inner: Arc<RwLock<Switches>>,
+}
+
+#[derive(Default)]
+struct Switches {
+    /// When global maintenance ends, if it is on; `Some(None)` lasts until switched off.
+    global: Option<Option<SystemTime>>,
+    /// The same, by route name.
+    routes: BTreeMap<String, Option<SystemTime>>,
+}
+
+fn running(until: Option<SystemTime>, now: SystemTime) -> bool {
+    until.is_none_or(|until| until > now)
+}
+
+impl Maintenance {
+    /// Whether requests to `route` are under maintenance, and until when; the global switch
+    /// comes first.
+    pub fn active(&self, route: Option<&str>) -> Option<Option<SystemTime>> {
+        let switches = self
+            .inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = SystemTime::now();
+        switches
+            .global
+            .filter(|until| running(*until, now))
+            .or_else(|| {
+                let until = *switches.routes.get(route?)?;
+                running(until, now).then_some(until)
+            })
+    }
+
+    /// Switches maintenance on for `route`, or everything, for `duration` or until switched
+    /// off.
+    pub fn enable(&self, route: Option<&str>, duration: Option<Duration>) -> Option<SystemTime> {
+        let until = duration.map(|duration| SystemTime::now() + duration);
+        let mut switches = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match route {
+            Some(route) => {
+                switches.routes.insert(route.to_string(), until);
+            }
+            None => switches.global = Some(until),
+        }
+        until
+    }
+
+    /// Switches maintenance off for `route`, or globally; returns whether it was on.
+    pub fn disable(&self, route: Option<&str>) -> bool {
+        let mut switches = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = SystemTime::now();
+        let was = match route {
+            Some(route) => switches.routes.remove(route),
+            None => switches.global.take(),
+        };
+        was.is_some_and(|until| running(until, now))
+    }
+
+    /// The switches that are on, for the admin API; expired ones are forgotten.
+    pub fn report(&self) -> serde_json::Value {
+        let mut switches = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = SystemTime::now();
+        switches.global = switches.global.filter(|until| running(*until, now));
+        switches.routes.retain(|_, until| running(*until, now));
+        let routes: BTreeMap<&String, _> = switches
+            .routes
+            .iter()
+            .map(|(route, until)| (route, json!({ "until": until.map(timestamp) })))
+            .collect();
+        json!({
+            "global": switches.global.map(|until| json!({ "until": until.map(timestamp) })),
+            "routes": routes,
+        })
+    }
+}
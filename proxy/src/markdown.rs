@@ -0,0 +1,109 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+use crate::status::escape_html;
+
+/// Page rendered Markdown goes into, unless `static_markdown_template` names another.
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{title}}</title>
+<style>
+body { max-width: 50rem; margin: 2rem auto; padding: 0 1rem; font: 16px/1.6 system-ui, sans-serif; }
+pre { overflow-x: auto; padding: 0.75rem; background: #f5f5f5; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.25rem 0.5rem; }
+</style>
+</head>
+<body>
+{{content}}
+</body>
+</html>
+"#;
+const TITLE_PLACEHOLDER: &str = "{{title}}";
+const CONTENT_PLACEHOLDER: &str = "{{content}}";
+
+/// Renders `.md` files under the static mount to HTML pages, for `static_markdown`.
+#[derive(Debug)]
+pub struct MarkdownPages {
+    /// The template around `{{content}}`.
+    before: String,
+    after: String,
+    /// Tells apart the ETags of pages rendered with different templates.
+    template_tag: String,
+}
+
+impl MarkdownPages {
+    /// Uses the template file at `template`, or the built-in page.
+    pub fn new(template: Option<&str>) -> Result<Self, String> {
+        let text = match template {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|err| format!("can't read template {path}: {err}"))?,
+            None => DEFAULT_TEMPLATE.to_string(),
+        };
+        let Some((before, after)) = text.split_once(CONTENT_PLACEHOLDER) else {
+            return Err(format!("template has no {CONTENT_PLACEHOLDER}"));
+        };
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        Ok(Self {
+            before: before.to_string(),
+            after: after.to_string(),
+            template_tag: format!("{:x}", hasher.finish() as u32),
+        })
+    }
+
+    /// Whether the file at `path` is rendered.
+    pub fn handles(path: &str) -> bool {
+        let path = path.to_ascii_lowercase();
+        path.ends_with(".md") || path.ends_with(".markdown")
+    }
+
+    /// The ETag of the page rendered from a file with `etag`.
+    pub fn etag(&self, etag: &str) -> String {
+        match etag.strip_suffix('"') {
+            Some(opaque) => format!("{opaque}-md{}\"", self.template_tag),
+            None => etag.to_string(),
+        }
+    }
+
+    /// The page for Markdown `source`, titled by its first heading, else `fallback_title`.
+    pub fn render(&self, source: &str, fallback_title: &str) -> String {
+        let options = Options::ENABLE_TABLES
+            | Options::ENABLE_FOOTNOTES
+            | Options::ENABLE_STRIKETHROUGH
+            | Options::ENABLE_TASKLISTS
+            | Options::ENABLE_HEADING_ATTRIBUTES;
+        let events: Vec<Event> = Parser::new_ext(source, options).collect();
+        let title = first_heading(&events).unwrap_or_else(|| fallback_title.to_string());
+        let title = escape_html(&title);
+        let mut content = String::with_capacity(source.len() * 3 / 2);
+        pulldown_cmark::html::push_html(&mut content, events.into_iter());
+        [
+            self.before.replace(TITLE_PLACEHOLDER, &title),
+            content,
+            self.after.replace(TITLE_PLACEHOLDER, &title),
+        ]
+        .concat()
+    }
+}
+
+/// The text of the first heading.
+fn first_heading(events: &[Event]) -> Option<String> {
+    let start = events
+        .iter()
+        .position(|event| matches!(event, Event::Start(Tag::Heading { .. })))?;
+    let mut title = String::new();
+    for event in &events[start + 1..] {
+        match event {
+            Event::End(TagEnd::Heading(_)) => break,
+            Event::Text(text) | Event::Code(text) => title.push_str(text),
+            _ => {}
+        }
+    }
+    let title = title.trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
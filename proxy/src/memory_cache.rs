@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use log::debug;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+/// Upper bound on tracked-but-not-cached paths before the hit counters reset.
+const MAX_TRACKED_CANDIDATES: usize = 4096;
+
+/// Configuration for the in-memory hot-object tier.
+#[derive(Clone, Debug)]
+pub struct MemoryCacheConfig {
+    pub capacity_bytes: usize,
+    pub max_object_bytes: usize,
+    pub promote_hits: u32,
+}
+
+#[derive(Debug)]
+struct CachedObject {
+    body: Bytes,
+    etag: String,
+    last_used: u64,
+    cached_at: Instant,
+    hits: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    objects: HashMap<PathBuf, CachedObject>,
+    candidates: HashMap<PathBuf, u32>,
+    used_bytes: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+/// Counters shown on the status page.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryCacheStats {
+    pub objects: usize,
+    pub used_bytes: usize,
+    pub capacity_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// One cached object, for the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub bytes: usize,
+    /// ETag of the file the object is a copy of; it is dropped once the file's changes.
+    pub etag: String,
+    /// Requests answered from the object.
+    pub hits: u64,
+    pub age_seconds: u64,
+}
+
+/// Small LRU cache that keeps the hottest static files in memory.
+///
+/// Objects are only admitted after `promote_hits` requests so that one-off
+/// downloads don't churn the cache. Entries are keyed by path and validated
+/// against the current ETag, so a changed file on disk is never served stale.
+#[derive(Clone)]
+pub struct MemoryCache {
+    config: MemoryCacheConfig,
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl MemoryCache {
+    pub fn new(config: MemoryCacheConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(CacheState::default())),
+        }
+    }
+
+    /// Whether an object of `len` bytes may ever live in the cache.
+    pub fn admits(&self, len: u64) -> bool {
+        len <= self.config.max_object_bytes as u64 && len as usize <= self.config.capacity_bytes
+    }
+
+    /// Returns the cached body for `path` if it is present and still matches `etag`.
+    pub async fn get(&self, path: &Path, etag: &str) -> Option<Bytes> {
+        let mut guard = self.state.lock().await;
+        guard.clock += 1;
+        let clock = guard.clock;
+
+        let stale = match guard.objects.get_mut(path) {
+            Some(object) if object.etag == etag => {
+                object.last_used = clock;
+                object.hits += 1;
+                let body = object.body.clone();
+                guard.hits += 1;
+                return Some(body);
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        guard.misses += 1;
+        if stale && let Some(object) = guard.objects.remove(path) {
+            guard.used_bytes -= object.body.len();
+            debug!("dropped stale in-memory copy of {:?}", path);
+        }
+        None
+    }
+
+    /// Records a miss for `path` and reports whether it is now hot enough to promote.
+    pub async fn record_miss(&self, path: &Path) -> bool {
+        let mut guard = self.state.lock().await;
+        if guard.candidates.len() >= MAX_TRACKED_CANDIDATES && !guard.candidates.contains_key(path)
+        {
+            guard.candidates.clear();
+        }
+        let hits = guard.candidates.entry(path.to_path_buf()).or_insert(0);
+        *hits += 1;
+        *hits >= self.config.promote_hits
+    }
+
+    pub async fn stats(&self) -> MemoryCacheStats {
+        let guard = self.state.lock().await;
+        MemoryCacheStats {
+            objects: guard.objects.len(),
+            used_bytes: guard.used_bytes,
+            capacity_bytes: self.config.capacity_bytes,
+            hits: guard.hits,
+            misses: guard.misses,
+            evictions: guard.evictions,
+        }
+    }
+
+    /// The objects held, least recently used first.
+    pub async fn entries(&self) -> Vec<CacheEntry> {
+        let guard = self.state.lock().await;
+        let mut objects: Vec<_> = guard.objects.iter().collect();
+        objects.sort_by_key(|(_, object)| object.last_used);
+        objects
+            .into_iter()
+            .map(|(key, object)| CacheEntry {
+                key: key.display().to_string(),
+                bytes: object.body.len(),
+                etag: object.etag.clone(),
+                hits: object.hits,
+                age_seconds: object.cached_at.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Drops every object, and the request counts of those not promoted yet; returns how many
+    /// objects were dropped.
+    pub async fn clear(&self) -> usize {
+        let mut guard = self.state.lock().await;
+        let dropped = guard.objects.len();
+        guard.objects.clear();
+        guard.candidates.clear();
+        guard.used_bytes = 0;
+        dropped
+    }
+
+    /// Stores `body` for `path`, evicting least recently used objects as needed.
+    pub async fn insert(&self, path: &Path, etag: String, body: Bytes) {
+        if !self.admits(body.len() as u64) {
+            return;
+        }
+
+        let mut guard = self.state.lock().await;
+        guard.candidates.remove(path);
+        if let Some(previous) = guard.objects.remove(path) {
+            guard.used_bytes -= previous.body.len();
+        }
+
+        while guard.used_bytes + body.len() > self.config.capacity_bytes {
+            let Some(victim) = guard
+                .objects
+                .iter()
+                .min_by_key(|(_, object)| object.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(object) = guard.objects.remove(&victim) {
+                guard.used_bytes -= object.body.len();
+                guard.evictions += 1;
+                debug!("evicted {:?} from in-memory static cache", victim);
+            }
+        }
+
+        guard.clock += 1;
+        let last_used = guard.clock;
+        guard.used_bytes += body.len();
+        guard.objects.insert(
+            path.to_path_buf(),
+            CachedObject {
+                body,
+                etag,
+                last_used,
+                cached_at: Instant::now(),
+                hits: 0,
+            },
+        );
+        debug!(
+            "promoted {:?} to in-memory static cache ({} bytes in use)",
+            path, guard.used_bytes
+        );
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use http::{Response, StatusCode};
+use pingora::http::RequestHeader;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::alerts::AlertKind;
+use crate::clients::ClientTraffic;
+use crate::endpoints::local_response;
+use crate::static_assets::StaticServed;
+
+const DEFAULT_METRICS_PATH: &str = "/metrics";
+/// Histogram buckets in seconds, from fast cache hits to slow reports.
+const DEFAULT_LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+/// Client connections remembered to tell new from reused ones; forgotten all at once beyond this.
+const MAX_TRACKED_CONNECTIONS: usize = 65536;
+
+/// `[metrics]` section of the config file. Metrics are off when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct MetricsConfig {
+    /// Serve the metrics on this address only; unset serves them on the proxy listeners.
+    pub listen_addr: Option<String>,
+    /// Path of the Prometheus text endpoint.
+    pub path: Option<String>,
+    /// Upper bounds (seconds) of the upstream latency histogram buckets.
+    pub latency_buckets: Option<Vec<f64>>,
+}
+
+impl MetricsConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            path: Some(self.path().to_string()),
+            latency_buckets: Some(self.latency_buckets().to_vec()),
+            listen_addr: self.listen_addr,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or(DEFAULT_METRICS_PATH)
+    }
+
+    pub fn latency_buckets(&self) -> &[f64] {
+        self.latency_buckets
+            .as_deref()
+            .unwrap_or(DEFAULT_LATENCY_BUCKETS)
+    }
+
+    /// Checks the buckets are positive and strictly increasing.
+    pub fn validate_buckets(&self) -> Result<(), String> {
+        let buckets = self.latency_buckets();
+        if buckets.is_empty() {
+            return Err("must list at least one bucket".to_string());
+        }
+        if buckets
+            .iter()
+            .any(|bucket| !bucket.is_finite() || *bucket <= 0.0)
+        {
+            return Err("buckets must be positive numbers of seconds".to_string());
+        }
+        if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("buckets must be strictly increasing".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Prometheus metrics of proxied traffic, labelled by route, and of static
+/// assets, labelled by mount path.
+pub struct ProxyMetrics {
+    path: String,
+    registry: Registry,
+    upstream_ttfb: HistogramVec,
+    upstream_duration: HistogramVec,
+    upstream_responses: IntCounterVec,
+    static_responses: IntCounterVec,
+    static_bytes: IntCounterVec,
+    static_memory_cache: IntCounterVec,
+    downstream_requests: IntCounterVec,
+    upstream_connections: IntCounterVec,
+    upstream_connections_in_use: IntGauge,
+    websocket_connections: IntGaugeVec,
+    websocket_closed: IntCounterVec,
+    tcp_connections: IntGaugeVec,
+    tcp_bytes: IntCounterVec,
+    alerts: IntCounterVec,
+    rate_limited: IntCounter,
+    shed: IntCounter,
+    /// Top talkers of `[clients]`, refreshed on every scrape.
+    clients: Option<Arc<ClientTraffic>>,
+    client_requests: IntGaugeVec,
+    client_bytes: IntGaugeVec,
+    client_in_flight: IntGaugeVec,
+    /// When each client address last opened its connection.
+    client_connections: Mutex<HashMap<SocketAddr, SystemTime>>,
+}
+
+/// What is recorded about one request sent upstream.
+pub struct UpstreamTiming<'a> {
+    pub route: &'a str,
+    /// Until the upstream response header arrived; unset when none did.
+    pub ttfb: Option<Duration>,
+    /// Until the upstream response body ended, or the request was given up on.
+    pub total: Duration,
+    /// Status the upstream answered with; unset when it failed to answer.
+    pub status: Option<u16>,
+}
+
+impl ProxyMetrics {
+    pub fn new(
+        config: &MetricsConfig,
+        clients: Option<Arc<ClientTraffic>>,
+    ) -> Result<Self, String> {
+        let buckets = config.latency_buckets().to_vec();
+        let registry = Registry::new();
+        let upstream_ttfb = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_upstream_ttfb_seconds",
+                "Time from picking an upstream to receiving its response header",
+            )
+            .buckets(buckets.clone()),
+            &["route"],
+        )
+        .map_err(|err| err.to_string())?;
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_upstream_duration_seconds",
+                "Time from picking an upstream to the end of its response body",
+            )
+            .buckets(buckets),
+            &["route"],
+        )
+        .map_err(|err| err.to_string())?;
+        let upstream_responses = IntCounterVec::new(
+            Opts::new(
+                "proxy_upstream_responses_total",
+                "Upstream responses by status class; \"error\" when the upstream did not answer",
+            ),
+            &["route", "status"],
+        )
+        .map_err(|err| err.to_string())?;
+        let static_responses = IntCounterVec::new(
+            Opts::new(
+                "proxy_static_responses_total",
+                "Static asset responses by status and whether the path was resolved through the manifest",
+            ),
+            &["mount", "status", "source"],
+        )
+        .map_err(|err| err.to_string())?;
+        let static_bytes = IntCounterVec::new(
+            Opts::new(
+                "proxy_static_body_bytes_total",
+                "Body bytes of static asset responses",
+            ),
+            &["mount"],
+        )
+        .map_err(|err| err.to_string())?;
+        let static_memory_cache = IntCounterVec::new(
+            Opts::new(
+                "proxy_static_memory_cache_lookups_total",
+                "In-memory cache lookups for static asset bodies by result",
+            ),
+            &["mount", "result"],
+        )
+        .map_err(|err| err.to_string())?;
+        let downstream_requests = IntCounterVec::new(
+            Opts::new(
+                "proxy_downstream_requests_total",
+                "Client requests by whether they opened a TCP connection or reused a kept-alive one",
+            ),
+            &["connection"],
+        )
+        .map_err(|err| err.to_string())?;
+        let upstream_connections = IntCounterVec::new(
+            Opts::new(
+                "proxy_upstream_connections_total",
+                "Upstream connections used, by whether they were new or taken from the keepalive pool",
+            ),
+            &["connection"],
+        )
+        .map_err(|err| err.to_string())?;
+        let upstream_connections_in_use = IntGauge::new(
+            "proxy_upstream_connections_in_use",
+            "Upstream connections currently serving a request",
+        )
+        .map_err(|err| err.to_string())?;
+        let websocket_connections = IntGaugeVec::new(
+            Opts::new(
+                "proxy_websocket_connections",
+                "Upgraded (WebSocket) connections currently open",
+            ),
+            &["route"],
+        )
+        .map_err(|err| err.to_string())?;
+        let websocket_closed = IntCounterVec::new(
+            Opts::new(
+                "proxy_websocket_closed_total",
+                "Upgraded connections ended, by reason: \"closed\" by either side, \"idle\", \"lifetime\", \"shutdown\" or \"error\"",
+            ),
+            &["route", "reason"],
+        )
+        .map_err(|err| err.to_string())?;
+        let tcp_connections = IntGaugeVec::new(
+            Opts::new(
+                "proxy_tcp_connections",
+                "Connections currently relayed by [[tcp_routes]]",
+            ),
+            &["route"],
+        )
+        .map_err(|err| err.to_string())?;
+        let tcp_bytes = IntCounterVec::new(
+            Opts::new(
+                "proxy_tcp_bytes_total",
+                "Bytes relayed by [[tcp_routes]], \"sent\" to the upstream or \"received\" from it, counted as connections close",
+            ),
+            &["route", "direction"],
+        )
+        .map_err(|err| err.to_string())?;
+        let alerts = IntCounterVec::new(
+            Opts::new(
+                "proxy_alerts_total",
+                "Windows in which a route crossed an [alerts] threshold",
+            ),
+            &["route", "alert"],
+        )
+        .map_err(|err| err.to_string())?;
+        let rate_limited = IntCounter::new(
+            "proxy_rate_limited_requests_total",
+            "Requests answered 429 by the per-client [rate_limit]",
+        )
+        .map_err(|err| err.to_string())?;
+        let shed = IntCounter::new(
+            "proxy_shed_requests_total",
+            "Requests answered 503 because [limits] max_concurrent_requests was reached",
+        )
+        .map_err(|err| err.to_string())?;
+        let client_requests = IntGaugeVec::new(
+            Opts::new(
+                "proxy_client_requests",
+                "Requests of the busiest client IPs within the [clients] window",
+            ),
+            &["client"],
+        )
+        .map_err(|err| err.to_string())?;
+        let client_bytes = IntGaugeVec::new(
+            Opts::new(
+                "proxy_client_bytes",
+                "Body bytes received from and sent to the busiest client IPs within the [clients] window",
+            ),
+            &["client", "direction"],
+        )
+        .map_err(|err| err.to_string())?;
+        let client_in_flight = IntGaugeVec::new(
+            Opts::new(
+                "proxy_client_requests_in_flight",
+                "Requests currently handled for the busiest client IPs",
+            ),
+            &["client"],
+        )
+        .map_err(|err| err.to_string())?;
+
+        for collector in [
+            Box::new(upstream_ttfb.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(upstream_duration.clone()),
+            Box::new(upstream_responses.clone()),
+            Box::new(static_responses.clone()),
+            Box::new(static_bytes.clone()),
+            Box::new(static_memory_cache.clone()),
+            Box::new(downstream_requests.clone()),
+            Box::new(upstream_connections.clone()),
+            Box::new(upstream_connections_in_use.clone()),
+            Box::new(websocket_connections.clone()),
+            Box::new(websocket_closed.clone()),
+            Box::new(tcp_connections.clone()),
+            Box::new(tcp_bytes.clone()),
+            Box::new(alerts.clone()),
+            Box::new(rate_limited.clone()),
+            Box::new(shed.clone()),
+            Box::new(client_requests.clone()),
+            Box::new(client_bytes.clone()),
+            Box::new(client_in_flight.clone()),
+        ] {
+            registry
+                .register(collector)
+                .map_err(|err| err.to_string())?;
+        }
+
+        Ok(Self {
+            path: config.path().to_string(),
+            registry,
+            upstream_ttfb,
+            upstream_duration,
+            upstream_responses,
+            static_responses,
+            static_bytes,
+            static_memory_cache,
+            downstream_requests,
+            upstream_connections,
+            upstream_connections_in_use,
+            websocket_connections,
+            websocket_closed,
+            tcp_connections,
+            tcp_bytes,
+            alerts,
+            rate_limited,
+            shed,
+            clients,
+            client_requests,
+            client_bytes,
+            client_in_flight,
+            client_connections: Mutex::default(),
+        })
+    }
+
+    pub fn observe_upstream(&self, timing: &UpstreamTiming) {
+        let route = [timing.route];
+        if let Some(ttfb) = timing.ttfb {
+            self.upstream_ttfb
+                .with_label_values(&route)
+                .observe(ttfb.as_secs_f64());
+        }
+        self.upstream_duration
+            .with_label_values(&route)
+            .observe(timing.total.as_secs_f64());
+        let status = match timing.status {
+            Some(status) => format!("{}xx", status / 100),
+            None => "error".to_string(),
+        };
+        self.upstream_responses
+            .with_label_values(&[timing.route, &status])
+            .inc();
+    }
+
+    pub fn observe_static(&self, mount: &str, served: &StaticServed) {
+        let source = if served.from_manifest {
+            "manifest"
+        } else {
+            "direct"
+        };
+        self.static_responses
+            .with_label_values(&[mount, &served.status.to_string(), source])
+            .inc();
+        self.static_bytes
+            .with_label_values(&[mount])
+            .inc_by(served.body_bytes);
+        if let Some(hit) = served.memory_cache_hit {
+            self.static_memory_cache
+                .with_label_values(&[mount, if hit { "hit" } else { "miss" }])
+                .inc();
+        }
+    }
+
+    /// Counts a client request, telling connections apart by client address and the time
+    /// they were established. Requests over unix sockets are not counted.
+    pub fn observe_downstream(&self, client: Option<SocketAddr>, established: Option<SystemTime>) {
+        let (Some(client), Some(established)) = (client, established) else {
+            return;
+        };
+        let reused = {
+            let mut connections = self
+                .client_connections
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if connections.len() >= MAX_TRACKED_CONNECTIONS && !connections.contains_key(&client) {
+                connections.clear();
+            }
+            connections.insert(client, established) == Some(established)
+        };
+        self.downstream_requests
+            .with_label_values(&[if reused { "reused" } else { "new" }])
+            .inc();
+    }
+
+    /// Counts an upstream connection taken into use for a request.
+    pub fn upstream_connected(&self, reused: bool) {
+        self.upstream_connections
+            .with_label_values(&[if reused { "reused" } else { "new" }])
+            .inc();
+        self.upstream_connections_in_use.inc();
+    }
+
+    /// Counts `connections` upstream connections of a finished request as released.
+    pub fn upstream_released(&self, connections: u32) {
+        self.upstream_connections_in_use.sub(connections.into());
+    }
+
+    /// Counts a connection upgraded on `route` as open.
+    pub fn websocket_opened(&self, route: &str) {
+        self.websocket_connections.with_label_values(&[route]).inc();
+    }
+
+    pub fn websocket_closed(&self, route: &str, reason: &str) {
+        self.websocket_connections.with_label_values(&[route]).dec();
+        self.websocket_closed
+            .with_label_values(&[route, reason])
+            .inc();
+    }
+
+    /// Counts a connection relayed by the TCP route `route` as open.
+    pub fn tcp_opened(&self, route: &str) {
+        self.tcp_connections.with_label_values(&[route]).inc();
+    }
+
+    pub fn tcp_closed(&self, route: &str, sent: u64, received: u64) {
+        self.tcp_connections.with_label_values(&[route]).dec();
+        self.tcp_bytes
+            .with_label_values(&[route, "sent"])
+            .inc_by(sent);
+        self.tcp_bytes
+            .with_label_values(&[route, "received"])
+            .inc_by(received);
+    }
+
+    pub fn alert_raised(&self, route: &str, kind: AlertKind) {
+        self.alerts.with_label_values(&[route, kind.as_str()]).inc();
+    }
+
+    pub fn rate_limited(&self) {
+        self.rate_limited.inc();
+    }
+
+    pub fn shed(&self) {
+        self.shed.inc();
+    }
+
+    /// Replaces the client gauges with the current top talkers, so clients that dropped
+    /// out of the top are not reported with stale values.
+    fn refresh_clients(&self) {
+        let Some(clients) = &self.clients else {
+            return;
+        };
+        self.client_requests.reset();
+        self.client_bytes.reset();
+        self.client_in_flight.reset();
+        for usage in clients.top_talkers() {
+            let client = usage.client.as_str();
+            self.client_requests
+                .with_label_values(&[client])
+                .set(usage.requests as i64);
+            self.client_bytes
+                .with_label_values(&[client, "received"])
+                .set(usage.bytes_received as i64);
+            self.client_bytes
+                .with_label_values(&[client, "sent"])
+                .set(usage.bytes_sent as i64);
+            self.client_in_flight
+                .with_label_values(&[client])
+                .set(usage.in_flight as i64);
+        }
+    }
+
+    /// The Prometheus text exposition for requests to the metrics path.
+    pub fn answer(&self, request: &RequestHeader) -> Option<Response<Vec<u8>>> {
+        if request.uri.path() != self.path {
+            return None;
+        }
+        self.refresh_clients();
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        if let Err(err) = encoder.encode(&self.registry.gather(), &mut body) {
+            return Some(local_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain; charset=utf-8",
+                format!("failed to encode metrics: {err}\n").into_bytes(),
+            ));
+        }
+        Some(local_response(StatusCode::OK, encoder.format_type(), body))
+    }
+}
@@ -0,0 +1,395 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde_json::{Map, Value, json};
+
+/// Marks the start of the metadata at the end of a database file.
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+/// The metadata is within this many bytes of the end of the file.
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+/// Zero bytes between the search tree and the data section.
+const DATA_SECTION_SEPARATOR: usize = 16;
+/// Nesting of maps and arrays decoded before a record is considered corrupt.
+const MAX_DEPTH: usize = 32;
+
+/// Reader for MaxMind DB files (GeoLite2, GeoIP2 and compatible databases), holding the
+/// whole file in memory and decoding records into JSON values.
+pub struct Mmdb {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// Start of the data section, which pointers are relative to.
+    data_start: usize,
+    /// Node IPv4 lookups start from: the one for `::/96` in IPv6 databases.
+    ipv4_start: usize,
+}
+
+impl Mmdb {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        Self::from_bytes(data).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        let search_from = data.len().saturating_sub(METADATA_MAX_SIZE);
+        let metadata_start = data[search_from..]
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .map(|position| search_from + position + METADATA_MARKER.len())
+            .ok_or("not a MaxMind DB file: metadata not found")?;
+        let (metadata, _) = decode(&data, metadata_start, metadata_start, 0)?;
+        let field = |name: &str| {
+            metadata[name]
+                .as_u64()
+                .ok_or_else(|| format!("metadata has no {name}"))
+        };
+        let node_count =
+            usize::try_from(field("node_count")?).map_err(|_| "node_count is too large")?;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("unsupported record size {record_size}"));
+        }
+        if ![4, 6].contains(&ip_version) {
+            return Err(format!("unsupported IP version {ip_version}"));
+        }
+        // Checked, as the metadata may claim any node count.
+        let data_start = node_count
+            .checked_mul(record_size / 4)
+            .and_then(|tree_size| tree_size.checked_add(DATA_SECTION_SEPARATOR))
+            .filter(|data_start| *data_start <= metadata_start)
+            .ok_or("search tree is larger than the file")?;
+        let mut mmdb = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = mmdb.record(node, 0);
+            }
+            mmdb.ipv4_start = node;
+        }
+        Ok(mmdb)
+    }
+
+    /// Left (`bit` 0) or right record of search tree `node`.
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let node_size = self.record_size / 4;
+        let bytes = &self.data[node * node_size..(node + 1) * node_size];
+        let record = match (self.record_size, bit) {
+            (28, 0) => (bytes[3] as u128 & 0xF0) << 20 | be(&bytes[..3]),
+            (28, _) => (bytes[3] as u128 & 0x0F) << 24 | be(&bytes[4..]),
+            (_, 0) => be(&bytes[..node_size / 2]),
+            (_, _) => be(&bytes[node_size / 2..]),
+        };
+        record as usize
+    }
+
+    /// The record for the network containing `ip`, if any.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, String> {
+        let (bytes, mut node) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(ip) => (ip.octets().to_vec(), 0),
+        };
+        for bit in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bytes[bit / 8] >> (7 - bit % 8)) & 1);
+        }
+        if node == self.node_count {
+            return Ok(None);
+        }
+        let offset = node
+            .checked_sub(self.node_count + DATA_SECTION_SEPARATOR)
+            .ok_or("search tree is deeper than the address")?;
+        let (record, _) = decode(&self.data, self.data_start, self.data_start + offset, 0)?;
+        Ok(Some(record))
+    }
+}
+
+fn bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| format!("record at {offset} runs past the end of the file"))
+}
+
+fn be(bytes: &[u8]) -> u128 {
+    bytes
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as u128)
+}
+
+/// Decodes the value at `offset`, with pointers relative to `base`, returning it with the
+/// offset that follows it.
+fn decode(data: &[u8], base: usize, offset: usize, depth: usize) -> Result<(Value, usize), String> {
+    if depth > MAX_DEPTH {
+        return Err("records are nested too deeply".to_string());
+    }
+    let control = bytes(data, offset, 1)?[0];
+    let mut offset = offset + 1;
+    let mut kind = control >> 5;
+    if kind == 0 {
+        kind = 7 + bytes(data, offset, 1)?[0];
+        offset += 1;
+    }
+    if kind == 1 {
+        let size = ((control >> 3) & 0x3) as usize + 1;
+        let high = (control & 0x7) as usize;
+        let raw = be(bytes(data, offset, size)?) as usize;
+        let target = match size {
+            1 => high << 8 | raw,
+            2 => (high << 16 | raw) + 2048,
+            3 => (high << 24 | raw) + 526_336,
+            _ => raw,
+        };
+        let (value, _) = decode(data, base, base + target, depth + 1)?;
+        return Ok((value, offset + size));
+    }
+    let (size, mut offset) = match (control & 0x1F) as usize {
+        29 => (29 + be(bytes(data, offset, 1)?) as usize, offset + 1),
+        30 => (285 + be(bytes(data, offset, 2)?) as usize, offset + 2),
+        31 => (65_821 + be(bytes(data, offset, 3)?) as usize, offset + 3),
+        size => (size, offset),
+    };
+    let value = match kind {
+        2 => json!(String::from_utf8_lossy(bytes(data, offset, size)?)),
+        3 => json!(f64::from_bits(be(bytes(data, offset, size.min(8))?) as u64)),
+        4 => json!(bytes(data, offset, size)?),
+        5 | 6 | 9 | 10 => {
+            let value = be(bytes(data, offset, size.min(16))?);
+            u64::try_from(value).map_or_else(|_| json!(value.to_string()), |value| json!(value))
+        }
+        8 => json!(be(bytes(data, offset, size.min(4))?) as u32 as i32),
+        14 => return Ok((json!(size != 0), offset)),
+        15 => json!(f32::from_bits(be(bytes(data, offset, size.min(4))?) as u32)),
+        7 => {
+            let mut map = Map::new();
+            for _ in 0..size {
+                let (key, next) = decode(data, base, offset, depth + 1)?;
+                let (value, next) = decode(data, base, next, depth + 1)?;
+                let Value::String(key) = key else {
+                    return Err(format!("map key at {offset} is not a string"));
+                };
+                map.insert(key, value);
+                offset = next;
+            }
+            return Ok((Value::Object(map), offset));
+        }
+        11 => {
+            let mut array = Vec::with_capacity(size.min(1024));
+            for _ in 0..size {
+                let (value, next) = decode(data, base, offset, depth + 1)?;
+                array.push(value);
+                offset = next;
+            }
+            return Ok((Value::Array(array), offset));
+        }
+        _ => return Err(format!("unsupported data type {kind} at {offset}")),
+    };
+    Ok((value, offset + size))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum Slot {
+        Node(usize),
+        Data(usize),
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut encoded = vec![2 << 5 | value.len() as u8];
+        encoded.extend(value.as_bytes());
+        encoded
+    }
+
+    /// An unsigned integer of data type `kind`: 5 (16 bits), 6 (32 bits) or 9 (64 bits).
+    fn uint(kind: u8, value: u64) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let bytes = &bytes[value.leading_zeros() as usize / 8..];
+        let mut encoded = match kind {
+            9 => vec![bytes.len() as u8, kind - 7],
+            _ => vec![kind << 5 | bytes.len() as u8],
+        };
+        encoded.extend(bytes);
+        encoded
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut encoded = vec![7 << 5 | entries.len() as u8];
+        for (key, value) in entries {
+            encoded.extend(string(key));
+            encoded.extend(value);
+        }
+        encoded
+    }
+
+    fn metadata(node_count: Vec<u8>, record_size: usize, ip_version: u64) -> Vec<u8> {
+        let mut encoded = METADATA_MARKER.to_vec();
+        encoded.extend(map(&[
+            ("node_count", node_count),
+            ("record_size", uint(5, record_size as u64)),
+            ("ip_version", uint(5, ip_version)),
+        ]));
+        encoded
+    }
+
+    /// A database mapping each network, given as address bytes and prefix length, to a
+    /// record with its `country`.
+    fn database(record_size: usize, ip_version: u64, networks: &[(&[u8], usize, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut nodes: Vec<[Option<Slot>; 2]> = vec![[None; 2]];
+        for (address, prefix, country) in networks {
+            let offset = data.len();
+            data.extend(map(&[("country", string(country))]));
+            let mut node = 0;
+            for bit in 0..*prefix {
+                let side = ((address[bit / 8] >> (7 - bit % 8)) & 1) as usize;
+                if bit + 1 == *prefix {
+                    nodes[node][side] = Some(Slot::Data(offset));
+                    break;
+                }
+                node = match nodes[node][side] {
+                    Some(Slot::Node(next)) => next,
+                    _ => {
+                        nodes.push([None; 2]);
+                        nodes[node][side] = Some(Slot::Node(nodes.len() - 1));
+                        nodes.len() - 1
+                    }
+                };
+            }
+        }
+        let node_count = nodes.len();
+        let mut file = Vec::new();
+        for node in nodes {
+            let [left, right] = node.map(|slot| match slot {
+                None => node_count as u32,
+                Some(Slot::Node(next)) => next as u32,
+                Some(Slot::Data(offset)) => (node_count + DATA_SECTION_SEPARATOR + offset) as u32,
+            });
+            match record_size {
+                24 => {
+                    file.extend(&left.to_be_bytes()[1..]);
+                    file.extend(&right.to_be_bytes()[1..]);
+                }
+                28 => {
+                    file.extend(&left.to_be_bytes()[1..]);
+                    file.push((left >> 24) as u8 & 0x0F << 4 | (right >> 24) as u8 & 0x0F);
+                    file.extend(&right.to_be_bytes()[1..]);
+                }
+                _ => {
+                    file.extend(left.to_be_bytes());
+                    file.extend(right.to_be_bytes());
+                }
+            }
+        }
+        file.extend([0; DATA_SECTION_SEPARATOR]);
+        file.extend(data);
+        file.extend(metadata(
+            uint(6, node_count as u64),
+            record_size,
+            ip_version,
+        ));
+        file
+    }
+
+    fn country(mmdb: &Mmdb, ip: impl Into<IpAddr>) -> Option<String> {
+        mmdb.lookup(ip.into())
+            .unwrap()
+            .map(|record| record["country"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn ipv4_lookups_for_each_record_size() {
+        for record_size in [24, 28, 32] {
+            let mmdb = Mmdb::from_bytes(database(
+                record_size,
+                4,
+                &[(&[1, 2, 3, 0], 24, "NZ"), (&[10, 0, 0, 0], 8, "AU")],
+            ))
+            .unwrap();
+            assert_eq!(
+                country(&mmdb, Ipv4Addr::new(1, 2, 3, 4)).as_deref(),
+                Some("NZ")
+            );
+            assert_eq!(
+                country(&mmdb, Ipv4Addr::new(10, 200, 1, 1)).as_deref(),
+                Some("AU")
+            );
+            assert_eq!(country(&mmdb, Ipv4Addr::new(1, 2, 4, 1)), None);
+            assert_eq!(country(&mmdb, Ipv6Addr::LOCALHOST), None);
+        }
+    }
+
+    #[test]
+    fn ipv4_lookups_in_ipv6_database() {
+        let ipv4_network = [&[0; 12][..], &[1, 2, 3, 0]].concat();
+        let ipv6_network = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0).octets();
+        for record_size in [24, 28, 32] {
+            let mmdb = Mmdb::from_bytes(database(
+                record_size,
+                6,
+                &[(&ipv4_network, 120, "NZ"), (&ipv6_network, 32, "DE")],
+            ))
+            .unwrap();
+            assert_eq!(
+                country(&mmdb, Ipv4Addr::new(1, 2, 3, 4)).as_deref(),
+                Some("NZ")
+            );
+            assert_eq!(
+                country(&mmdb, Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped()).as_deref(),
+                Some("NZ")
+            );
+            assert_eq!(
+                country(&mmdb, Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)).as_deref(),
+                Some("DE")
+            );
+            assert_eq!(country(&mmdb, Ipv4Addr::new(5, 6, 7, 8)), None);
+        }
+    }
+
+    #[test]
+    fn records_of_28_bits_split_the_middle_byte() {
+        let mmdb = Mmdb {
+            data: vec![0x12, 0x34, 0x56, 0xAB, 0x78, 0x9A, 0xBC],
+            node_count: 1,
+            record_size: 28,
+            ip_version: 4,
+            data_start: 7 + DATA_SECTION_SEPARATOR,
+            ipv4_start: 0,
+        };
+        assert_eq!(mmdb.record(0, 0), 0xA12_3456);
+        assert_eq!(mmdb.record(0, 1), 0xB78_9ABC);
+    }
+
+    #[test]
+    fn oversized_node_counts_are_refused() {
+        for node_count in [u64::MAX, u64::MAX / 4, 1 << 40] {
+            let file = metadata(uint(9, node_count), 32, 4);
+            assert!(Mmdb::from_bytes(file).is_err(), "{node_count}");
+        }
+    }
+
+    #[test]
+    fn files_without_metadata_are_refused() {
+        assert!(Mmdb::from_bytes(Vec::new()).is_err());
+        assert!(Mmdb::from_bytes(vec![0; 64]).is_err());
+    }
+}
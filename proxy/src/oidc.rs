@@ -0,0 +1,617 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use http::header::{ACCEPT, ALLOW, CACHE_CONTROL, CONTENT_LENGTH, LOCATION, SET_COOKIE};
+use http::{Method, Uri};
+use log::{info, warn};
+use pingora::http::{RequestHeader, ResponseHeader};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+use crate::cookies::request_cookie;
+use crate::csrf::CsrfConfig;
+use crate::errors::refusal_response;
+
+const DEFAULT_SIGN_OUT_PATH: &str = "/oauth2/sign_out";
+const DEFAULT_COOKIE_NAME: &str = "_proxy_session";
+const DEFAULT_SESSION_LIFETIME_SECONDS: u64 = 12 * 60 * 60;
+const DEFAULT_SCOPES: &[&str] = &["openid", "email", "profile"];
+/// Suffix of the cookie holding a pending login's state, nonce and return path.
+const LOGIN_COOKIE_SUFFIX: &str = "_login";
+/// How long a user has to complete a login at the provider.
+const LOGIN_TIMEOUT_SECONDS: u64 = 10 * 60;
+const MIN_COOKIE_SECRET_LEN: usize = 32;
+/// Timeout of discovery and token requests to the provider.
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Identity headers sent upstream for logged in users, removed from client requests.
+const USER_HEADER: &str = "X-Forwarded-User";
+const EMAIL_HEADER: &str = "X-Forwarded-Email";
+
+/// `[oidc]` section of the config file: log browser users in with an OpenID Connect
+/// provider (authorization code flow) before routes with `oidc = true` are served.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct OidcConfig {
+    /// Issuer URL; endpoints are discovered from `<issuer>/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Public URL the provider sends users back to; the proxy answers its path.
+    pub redirect_url: String,
+    /// Path that clears the session.
+    pub sign_out_path: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    pub cookie_name: Option<String>,
+    /// Key session cookies are encrypted with, at least 32 characters. Changing it logs
+    /// everyone out.
+    pub cookie_secret: String,
+    /// How long a login lasts.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub session_lifetime_seconds: Option<u64>,
+    /// Only users with a verified email address in one of these domains may log in.
+    pub email_domains: Option<Vec<String>>,
+}
+
+impl OidcConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            sign_out_path: Some(self.sign_out_path().to_string()),
+            scopes: Some(self.scopes()),
+            cookie_name: Some(self.cookie_name().to_string()),
+            session_lifetime_seconds: Some(self.session_lifetime_seconds()),
+            ..self
+        }
+    }
+
+    fn sign_out_path(&self) -> &str {
+        self.sign_out_path
+            .as_deref()
+            .unwrap_or(DEFAULT_SIGN_OUT_PATH)
+    }
+
+    fn scopes(&self) -> Vec<String> {
+        self.scopes.clone().unwrap_or_else(|| {
+            DEFAULT_SCOPES
+                .iter()
+                .map(|scope| scope.to_string())
+                .collect()
+        })
+    }
+
+    fn cookie_name(&self) -> &str {
+        self.cookie_name.as_deref().unwrap_or(DEFAULT_COOKIE_NAME)
+    }
+
+    fn session_lifetime_seconds(&self) -> u64 {
+        self.session_lifetime_seconds
+            .unwrap_or(DEFAULT_SESSION_LIFETIME_SECONDS)
+    }
+}
+
+/// Endpoints read from the provider's discovery document.
+#[derive(Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// A logged in user, as stored in the session cookie.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OidcUser {
+    pub subject: String,
+    pub email: Option<String>,
+    expires: u64,
+}
+
+/// A login in progress, as stored in the login cookie.
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    return_to: String,
+    expires: u64,
+}
+
+/// OIDC login flow and the sessions it creates.
+pub struct Oidc {
+    config: OidcConfig,
+    callback_path: String,
+    key: LessSafeKey,
+    random: SystemRandom,
+    agent: ureq::Agent,
+    provider: OnceCell<Arc<Provider>>,
+}
+
+impl Oidc {
+    pub fn new(config: &OidcConfig) -> Result<Self, String> {
+        let redirect: Uri = config
+            .redirect_url
+            .parse()
+            .map_err(|err| format!("invalid redirect_url: {err}"))?;
+        if redirect.scheme().is_none() || redirect.host().is_none() {
+            return Err("redirect_url must be an absolute http(s) URL".to_string());
+        }
+        if config.cookie_secret.len() < MIN_COOKIE_SECRET_LEN {
+            return Err(format!(
+                "cookie_secret must be at least {MIN_COOKIE_SECRET_LEN} characters"
+            ));
+        }
+        let config = config.clone().with_defaults();
+        if !config.sign_out_path().starts_with('/') {
+            return Err("sign_out_path must start with '/'".to_string());
+        }
+        let key = UnboundKey::new(
+            &AES_256_GCM,
+            digest(&SHA256, config.cookie_secret.as_bytes()).as_ref(),
+        )
+        .map_err(|_| "failed to derive the cookie key".to_string())?;
+        Ok(Self {
+            callback_path: redirect.path().to_string(),
+            key: LessSafeKey::new(key),
+            random: SystemRandom::new(),
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(PROVIDER_TIMEOUT))
+                .http_status_as_error(false)
+                .build()
+                .into(),
+            provider: OnceCell::new(),
+            config,
+        })
+    }
+
+    /// Answers requests to the callback and sign out paths; `host` is the one the request
+    /// was addressed to.
+    pub async fn answer(
+        &self,
+        request: &RequestHeader,
+        host: Option<&str>,
+        request_id: &str,
+    ) -> pingora::Result<Option<(ResponseHeader, Bytes)>> {
+        let path = request.uri.path();
+        if path == self.config.sign_out_path() {
+            // Only a same-origin POST signs out, so other sites can't log users out.
+            if request.method != Method::POST {
+                let (mut header, body) = refusal_response(405, request_id)?;
+                header.insert_header(ALLOW, "POST")?;
+                return Ok(Some((header, body)));
+            }
+            if let Some(reason) = CsrfConfig::default().check(request, host) {
+                warn!("OIDC sign out refused for request {request_id}: cross-site ({reason})");
+                return refusal_response(403, request_id).map(Some);
+            }
+            let mut header = redirect("/")?;
+            header.append_header(SET_COOKIE, self.cookie(self.config.cookie_name(), "", 0))?;
+            return Ok(Some((header, Bytes::new())));
+        }
+        if path != self.callback_path {
+            return Ok(None);
+        }
+        match self.finish_login(request).await {
+            Ok((user, return_to)) => {
+                info!(
+                    "OIDC login of {} ({})",
+                    user.subject,
+                    user.email.as_deref().unwrap_or("no email")
+                );
+                let lifetime = self.config.session_lifetime_seconds();
+                let session = self.seal(self.config.cookie_name(), &user);
+                let mut header = redirect(local_path(&return_to))?;
+                header.append_header(
+                    SET_COOKIE,
+                    self.cookie(self.config.cookie_name(), &session, lifetime),
+                )?;
+                header.append_header(SET_COOKIE, self.cookie(&self.login_cookie_name(), "", 0))?;
+                Ok(Some((header, Bytes::new())))
+            }
+            Err((status, reason)) => {
+                warn!("OIDC login failed for request {request_id}: {reason}");
+                refusal_response(status, request_id).map(Some)
+            }
+        }
+    }
+
+    /// The logged in user of a request, if its session cookie is valid.
+    pub fn user(&self, request: &RequestHeader) -> Option<OidcUser> {
+        let cookie = request_cookie(request, self.config.cookie_name())?;
+        let user: OidcUser = self.open(self.config.cookie_name(), cookie)?;
+        (user.expires > now()).then_some(user)
+    }
+
+    /// Response to a request that needs a login: browsers are sent to the provider,
+    /// anything else gets 401.
+    pub async fn login_response(
+        &self,
+        request: &RequestHeader,
+        request_id: &str,
+    ) -> pingora::Result<(ResponseHeader, Bytes)> {
+        let wants_html = request
+            .headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+        if !wants_html || !matches!(request.method, Method::GET | Method::HEAD) {
+            return refusal_response(401, request_id);
+        }
+        let provider = match self.provider().await {
+            Ok(provider) => provider,
+            Err(err) => {
+                warn!("OIDC discovery failed for request {request_id}: {err}");
+                return refusal_response(502, request_id);
+            }
+        };
+        let login = PendingLogin {
+            state: self.random_token(),
+            nonce: self.random_token(),
+            return_to: local_path(
+                request
+                    .uri
+                    .path_and_query()
+                    .map_or("/", |path| path.as_str()),
+            )
+            .to_string(),
+            expires: now() + LOGIN_TIMEOUT_SECONDS,
+        };
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes().join(" "))
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce)
+            .finish();
+        let separator = if provider.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let mut header = redirect(&format!(
+            "{}{separator}{query}",
+            provider.authorization_endpoint
+        ))?;
+        let login_cookie = self.login_cookie_name();
+        header.append_header(
+            SET_COOKIE,
+            self.cookie(
+                &login_cookie,
+                &self.seal(&login_cookie, &login),
+                LOGIN_TIMEOUT_SECONDS,
+            ),
+        )?;
+        Ok((header, Bytes::new()))
+    }
+
+    /// Replaces the identity headers of a proxied request with those of `user`.
+    pub fn apply_request(
+        &self,
+        upstream_request: &mut RequestHeader,
+        user: Option<&OidcUser>,
+    ) -> pingora::Result<()> {
+        upstream_request.remove_header(USER_HEADER);
+        upstream_request.remove_header(EMAIL_HEADER);
+        if let Some(user) = user {
+            upstream_request.insert_header(USER_HEADER, user.subject.as_str())?;
+            if let Some(email) = &user.email {
+                upstream_request.insert_header(EMAIL_HEADER, email.as_str())?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish_login(
+        &self,
+        request: &RequestHeader,
+    ) -> Result<(OidcUser, String), (u16, String)> {
+        let param = |name: &str| {
+            request.uri.query().and_then(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+            })
+        };
+        if let Some(error) = param("error") {
+            return Err((403, format!("provider answered {error}")));
+        }
+        let login: PendingLogin = request_cookie(request, &self.login_cookie_name())
+            .and_then(|cookie| self.open(&self.login_cookie_name(), cookie))
+            .filter(|login: &PendingLogin| login.expires > now())
+            .ok_or((400, "no login in progress".to_string()))?;
+        if param("state").as_deref() != Some(login.state.as_str()) {
+            return Err((400, "state does not match".to_string()));
+        }
+        let code = param("code").ok_or((400, "no code".to_string()))?;
+        let provider = self.provider().await.map_err(|err| (502, err))?;
+        let claims = self
+            .exchange_code(&provider, code)
+            .await
+            .map_err(|err| (502, err))?;
+        let user = self
+            .check_claims(&provider, &claims, &login.nonce)
+            .map_err(|err| (403, err))?;
+        Ok((user, login.return_to))
+    }
+
+    async fn provider(&self) -> Result<Arc<Provider>, String> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let agent = self.agent.clone();
+                let provider: Provider =
+                    tokio::task::spawn_blocking(move || fetch_json(agent.get(&url).call()))
+                        .await
+                        .map_err(|err| err.to_string())??;
+                if provider.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/')
+                {
+                    return Err(format!("discovery names issuer {}", provider.issuer));
+                }
+                Ok(Arc::new(provider))
+            })
+            .await
+            .cloned()
+    }
+
+    /// Redeems an authorization code and returns the claims of the ID token. The token
+    /// comes straight from the provider over TLS, so its signature is not checked again.
+    async fn exchange_code(&self, provider: &Provider, code: String) -> Result<Value, String> {
+        let agent = self.agent.clone();
+        let token_endpoint = provider.token_endpoint.clone();
+        let redirect_uri = self.config.redirect_url.clone();
+        let credentials = STANDARD.encode(format!(
+            "{}:{}",
+            form_urlencoded::byte_serialize(self.config.client_id.as_bytes()).collect::<String>(),
+            form_urlencoded::byte_serialize(self.config.client_secret.as_bytes())
+                .collect::<String>()
+        ));
+        let tokens: Value = tokio::task::spawn_blocking(move || {
+            fetch_json(
+                agent
+                    .post(&token_endpoint)
+                    .header("Authorization", format!("Basic {credentials}"))
+                    .send_form([
+                        ("grant_type", "authorization_code"),
+                        ("code", code.as_str()),
+                        ("redirect_uri", redirect_uri.as_str()),
+                    ]),
+            )
+        })
+        .await
+        .map_err(|err| err.to_string())??;
+        let id_token = tokens["id_token"]
+            .as_str()
+            .ok_or("token response has no id_token")?;
+        let payload = id_token
+            .split('.')
+            .nth(1)
+            .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+            .ok_or("malformed id_token")?;
+        serde_json::from_slice(&payload).map_err(|err| format!("malformed id_token: {err}"))
+    }
+
+    fn check_claims(
+        &self,
+        provider: &Provider,
+        claims: &Value,
+        nonce: &str,
+    ) -> Result<OidcUser, String> {
+        if claims["iss"].as_str() != Some(provider.issuer.as_str()) {
+            return Err(format!("id_token issued by {}", claims["iss"]));
+        }
+        let audience_ok = match &claims["aud"] {
+            Value::String(audience) => *audience == self.config.client_id,
+            Value::Array(audiences) => audiences
+                .iter()
+                .any(|audience| audience.as_str() == Some(self.config.client_id.as_str())),
+            _ => false,
+        };
+        if !audience_ok {
+            return Err("id_token is for another client".to_string());
+        }
+        if claims["exp"]
+            .as_u64()
+            .is_none_or(|expires| expires <= now())
+        {
+            return Err("id_token expired".to_string());
+        }
+        if claims["nonce"].as_str() != Some(nonce) {
+            return Err("nonce does not match".to_string());
+        }
+        let subject = claims["sub"].as_str().ok_or("id_token has no sub")?;
+        let email = claims["email"].as_str().map(str::to_string);
+        if let Some(domains) = &self.config.email_domains {
+            let verified = claims["email_verified"].as_bool().unwrap_or(false);
+            let domain = email
+                .as_deref()
+                .and_then(|email| email.rsplit_once('@'))
+                .map(|(_, domain)| domain);
+            if !verified
+                || !domain.is_some_and(|domain| {
+                    domains
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+                })
+            {
+                return Err(format!(
+                    "{} is not a verified address in email_domains",
+                    email.as_deref().unwrap_or("no email")
+                ));
+            }
+        }
+        Ok(OidcUser {
+            subject: subject.to_string(),
+            email,
+            expires: now() + self.config.session_lifetime_seconds(),
+        })
+    }
+
+    fn login_cookie_name(&self) -> String {
+        format!("{}{LOGIN_COOKIE_SUFFIX}", self.config.cookie_name())
+    }
+
+    fn cookie(&self, name: &str, value: &str, max_age: u64) -> String {
+        let secure = if self.config.redirect_url.starts_with("https:") {
+            "; Secure"
+        } else {
+            ""
+        };
+        format!("{name}={value}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}")
+    }
+
+    fn random_token(&self) -> String {
+        let mut bytes = [0; 16];
+        self.random
+            .fill(&mut bytes)
+            .expect("system random source failed");
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Encrypts `value` for the cookie `name`, which it can only be opened from.
+    fn seal<T: Serialize>(&self, name: &str, value: &T) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        self.random
+            .fill(&mut nonce)
+            .expect("system random source failed");
+        let mut data = serde_json::to_vec(value).unwrap_or_default();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .expect("sealing a cookie cannot fail");
+        URL_SAFE_NO_PAD.encode([nonce.as_slice(), &data].concat())
+    }
+
+    fn open<T: DeserializeOwned>(&self, name: &str, cookie: &str) -> Option<T> {
+        let mut data = URL_SAFE_NO_PAD.decode(cookie).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let mut sealed = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).ok()?;
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut sealed)
+            .ok()?;
+        serde_json::from_slice(plain).ok()
+    }
+}
+
+impl std::fmt::Debug for Oidc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Oidc")
+            .field("issuer", &self.config.issuer)
+            .field("callback_path", &self.callback_path)
+            .finish()
+    }
+}
+
+fn redirect(location: &str) -> pingora::Result<ResponseHeader> {
+    let mut header = ResponseHeader::build(302, Some(4))?;
+    header.insert_header(LOCATION, location)?;
+    header.insert_header(CONTENT_LENGTH, 0)?;
+    header.insert_header(CACHE_CONTROL, "no-store")?;
+    Ok(header)
+}
+
+/// `path` if it stays on the host, `/` otherwise: browsers take `//evil.example/` and
+/// `/\evil.example/` to be other hosts.
+fn local_path(path: &str) -> &str {
+    if path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\") {
+        path
+    } else {
+        "/"
+    }
+}
+
+fn fetch_json<T: DeserializeOwned>(
+    response: Result<http::Response<ureq::Body>, ureq::Error>,
+) -> Result<T, String> {
+    let mut response = response.map_err(|err| err.to_string())?;
+    let status = response.status();
+    let body = response
+        .body_mut()
+        .read_to_string()
+        .map_err(|err| err.to_string())?;
+    if !status.is_success() {
+        return Err(format!("provider answered {status}: {}", body.trim()));
+    }
+    serde_json::from_str(&body).map_err(|err| format!("unexpected provider response: {err}"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oidc() -> Oidc {
+        Oidc::new(&OidcConfig {
+            issuer: "https://accounts.example.com".to_string(),
+            client_id: "proxy".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: "https://app.example.com/oauth2/callback".to_string(),
+            sign_out_path: None,
+            scopes: None,
+            cookie_name: None,
+            cookie_secret: "x".repeat(MIN_COOKIE_SECRET_LEN),
+            session_lifetime_seconds: None,
+            email_domains: None,
+        })
+        .unwrap()
+    }
+
+    fn sign_out(method: &str, origin: Option<&str>) -> ResponseHeader {
+        let mut request =
+            RequestHeader::build(method, DEFAULT_SIGN_OUT_PATH.as_bytes(), None).unwrap();
+        if let Some(origin) = origin {
+            request.insert_header("origin", origin).unwrap();
+        }
+        let oidc = oidc();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(oidc.answer(&request, Some("app.example.com"), "test"))
+            .unwrap()
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn return_paths_stay_on_the_host() {
+        assert_eq!(local_path("/app/page?x=1"), "/app/page?x=1");
+        assert_eq!(local_path("/"), "/");
+        assert_eq!(local_path("//evil.example/path"), "/");
+        assert_eq!(local_path("/\\evil.example/path"), "/");
+        assert_eq!(local_path("https://evil.example/"), "/");
+        assert_eq!(local_path(""), "/");
+    }
+
+    #[test]
+    fn sign_out_takes_same_origin_posts_only() {
+        assert_eq!(sign_out("GET", None).status, 405);
+        assert_eq!(sign_out("POST", Some("https://evil.example")).status, 403);
+        let signed_out = sign_out("POST", Some("https://app.example.com"));
+        assert_eq!(signed_out.status, 302);
+        let cookie = signed_out.headers[SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with("_proxy_session=;"), "{cookie}");
+        assert!(cookie.contains("Max-Age=0"), "{cookie}");
+    }
+}
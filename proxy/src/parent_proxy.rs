@@ -0,0 +1,162 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::debug;
+use pingora::connectors::L4Connect;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::l4::stream::Stream;
+use pingora::{Error, ErrorType, OrErr, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request line passed on.
+const MAX_REQUEST_LINE_LEN: usize = 16 * 1024;
+
+/// `parent_proxy`: an HTTP proxy that upstream requests go through, for hosts without direct
+/// egress.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct ParentProxyConfig {
+    /// The parent proxy, `host:port`.
+    pub addr: String,
+    /// Sent with `password` as `Proxy-Authorization: Basic` credentials.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// A compiled `parent_proxy`: requests are sent to it in absolute form, one per connection,
+/// and it connects to the upstream.
+pub struct ParentProxy {
+    addr: String,
+    authorization: Option<String>,
+}
+
+impl fmt::Debug for ParentProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParentProxy")
+            .field("addr", &self.addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ParentProxy {
+    pub fn new(config: &ParentProxyConfig) -> Result<Self, String> {
+        if !config
+            .addr
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        {
+            return Err(format!("addr {:?} is not a host:port address", config.addr));
+        }
+        let authorization = match (&config.username, &config.password) {
+            (Some(username), password) => Some(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{username}:{}", password.as_deref().unwrap_or("")))
+            )),
+            (None, Some(_)) => return Err("password needs a username".to_string()),
+            (None, None) => None,
+        };
+        Ok(Self {
+            addr: config.addr.clone(),
+            authorization,
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Connector for requests to `upstream_addr`, handed to Pingora in place of its own.
+    pub fn connector(&self, upstream_addr: &str) -> Arc<ParentConnect> {
+        Arc::new(ParentConnect {
+            origin: format!("http://{upstream_addr}"),
+            authorization: self.authorization.clone(),
+        })
+    }
+}
+
+/// Connects to the parent proxy through a relay that rewrites the request line.
+///
+/// Pingora writes request targets in origin form only, so its end of the connection is a
+/// socket pair whose other end passes the request on with the upstream's origin prepended.
+#[derive(Debug)]
+pub struct ParentConnect {
+    origin: String,
+    authorization: Option<String>,
+}
+
+#[async_trait]
+impl L4Connect for ParentConnect {
+    async fn connect(&self, addr: &SocketAddr) -> Result<Stream> {
+        let Some(inet) = addr.as_inet() else {
+            return Error::e_explain(ErrorType::ConnectError, "parent proxy is not a TCP address");
+        };
+        let parent = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(inet))
+            .await
+            .or_err_with(ErrorType::ConnectTimedout, || {
+                format!("connecting to parent proxy {inet}")
+            })?
+            .or_err_with(ErrorType::ConnectError, || {
+                format!("connecting to parent proxy {inet}")
+            })?;
+        let (pingora_end, relay_end) =
+            UnixStream::pair().or_err(ErrorType::ConnectError, "creating a socket pair")?;
+        let origin = self.origin.clone();
+        let authorization = self.authorization.clone();
+        tokio::spawn(async move {
+            if let Err(err) = relay(relay_end, parent, &origin, authorization.as_deref()).await {
+                debug!("relay to parent proxy for {origin} ended: {err}");
+            }
+        });
+        Ok(pingora_end.into())
+    }
+}
+
+/// Passes the request on in absolute form, with the credentials, then copies both ways.
+async fn relay(
+    mut pingora: UnixStream,
+    mut parent: TcpStream,
+    origin: &str,
+    authorization: Option<&str>,
+) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let end = loop {
+        if let Some(end) = head.windows(2).position(|window| window == b"\r\n") {
+            break end;
+        }
+        if head.len() > MAX_REQUEST_LINE_LEN {
+            return Err(std::io::Error::other("request line too long"));
+        }
+        let mut chunk = [0; 4096];
+        match pingora.read(&mut chunk).await? {
+            0 => return Ok(()),
+            read => head.extend_from_slice(&chunk[..read]),
+        }
+    };
+    let line = &head[..end];
+    let mut rewritten = Vec::with_capacity(head.len() + origin.len() + 64);
+    match line.iter().position(|byte| *byte == b' ') {
+        Some(space) if line.get(space + 1) == Some(&b'/') => {
+            rewritten.extend_from_slice(&line[..=space]);
+            rewritten.extend_from_slice(origin.as_bytes());
+            rewritten.extend_from_slice(&line[space + 1..]);
+        }
+        _ => rewritten.extend_from_slice(line),
+    }
+    rewritten.extend_from_slice(b"\r\n");
+    if let Some(authorization) = authorization {
+        rewritten.extend_from_slice(b"Proxy-Authorization: ");
+        rewritten.extend_from_slice(authorization.as_bytes());
+        rewritten.extend_from_slice(b"\r\n");
+    }
+    rewritten.extend_from_slice(&head[end + 2..]);
+    parent.write_all(&rewritten).await?;
+    tokio::io::copy_bidirectional(&mut pingora, &mut parent).await?;
+    Ok(())
+}
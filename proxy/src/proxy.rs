@@ -0,0 +1,1311 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{debug, error};
+use pingora::http::{Method, ResponseHeader};
+use pingora::prelude::*;
+use pingora::protocols::Digest;
+use pingora::protocols::http::HttpTask;
+use pingora::proxy::FailToProxy;
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::access_log::{LoggedRequest, body_bytes_sent};
+use crate::admin::RuntimeControls;
+use crate::alerts::AlertMonitor;
+use crate::bots::BotVerdict;
+use crate::capture::{DebugCapture, PendingCapture};
+use crate::clients::ClientTraffic;
+use crate::concurrency::{ClientPermit, ConcurrencyLimiter};
+use crate::drain::UpstreamPermit;
+use crate::endpoints::LocalEndpoints;
+use crate::error_reporting::{UpstreamFailure, UpstreamFailureReporter};
+use crate::errors::{ProxyError, error_response, error_status, refusal_response};
+use crate::esi::EsiProcessor;
+use crate::fastcgi::CgiRequest;
+use crate::filters::{FilterChain, FilterCtx};
+use crate::forwarded::{apply_forwarded_headers, client_ip, downstream_host, downstream_scheme};
+use crate::grpc::{self, CallError, MessageSizes};
+use crate::grpc_web::GrpcWeb;
+use crate::hooks::{Hooks, RequestStart, ResponseComplete, UpstreamSelected};
+use crate::html_inject::{HtmlInjectConfig, HtmlInjector};
+use crate::images::ImageConverter;
+use crate::json_redact::JsonRedactor;
+use crate::limits::{self, ResponseBodyCap};
+use crate::metrics::{ProxyMetrics, UpstreamTiming};
+use crate::oidc::OidcUser;
+use crate::parent_proxy::ParentProxy;
+use crate::proxy_protocol::{self, ProxyProtocol};
+use crate::redirects::{PublicOrigin, rewrite_location};
+use crate::request_id::{REQUEST_ID_HEADER, request_id};
+use crate::rewrites::Rewritten;
+use crate::routes::Route;
+use crate::state::{ProxyState, SharedState};
+use crate::static_assets::StaticServed;
+use crate::statsd::StatsdExporter;
+use crate::status::{DEFAULT_ROUTE_NAME, RequestStats};
+use crate::substitutions::{Substituter, Substitutions};
+use crate::waf::WafVerdict;
+use crate::websocket::WebSocket;
+
+/// The pingora proxy application: everything done to requests on the proxy listeners.
+#[derive(Clone)]
+pub struct RoseProxy {
+    pub state: SharedState,
+    /// Health and status endpoints answered on the proxy listeners.
+    pub endpoints: LocalEndpoints,
+    pub stats: Arc<RequestStats>,
+    pub alerts: Arc<AlertMonitor>,
+    pub concurrency: Arc<ConcurrencyLimiter>,
+    pub metrics: Option<Arc<ProxyMetrics>>,
+    pub capture: Option<Arc<DebugCapture>>,
+    pub clients: Option<Arc<ClientTraffic>>,
+    pub statsd: Option<Arc<StatsdExporter>>,
+    pub upstream_failures: Option<Arc<UpstreamFailureReporter>>,
+    /// Drains, maintenance, quiesce and bans set through `[admin]`, and the requests in flight
+    /// per upstream; only checked and tracked with the admin API on.
+    pub controls: Option<RuntimeControls>,
+    /// Lifecycle callbacks registered by the program embedding the proxy.
+    pub hooks: Hooks,
+}
+
+/// Per-request state carried through the proxy phases.
+pub struct RequestCtx {
+    /// Config snapshot the whole request is handled with, even across reloads.
+    state: Arc<ProxyState>,
+    route: Option<Arc<Route>>,
+    started: Instant,
+    /// Correlates the access log, error log and error responses; sent upstream as `X-Request-Id`.
+    request_id: String,
+    /// Request target as the client sent it, when `[[rewrites]]` changed it.
+    original_uri: Option<http::Uri>,
+    /// Set once the request is sent upstream rather than answered by the proxy.
+    proxied: bool,
+    /// Body bytes of the proxied response passed on to the client so far.
+    upstream_body_bytes: Option<usize>,
+    /// When the first upstream was picked for the request.
+    upstream_started: Option<Instant>,
+    upstream_ttfb: Option<Duration>,
+    upstream_finished: Option<Instant>,
+    /// PROXY protocol header sent first on new upstream connections.
+    upstream_proxy_header: Option<Vec<u8>>,
+    upstream_status: Option<u16>,
+    /// Set when the request was answered from the static root.
+    static_served: Option<StaticServed>,
+    /// Upstream connections taken into use, more than one when connecting was retried.
+    upstream_connections: u32,
+    /// Set when the request matched the debug capture filter.
+    capture: Option<PendingCapture>,
+    /// Client whose `[clients]` traffic accounting is waiting for the request to finish.
+    tracked_client: Option<SocketAddr>,
+    /// User logged in through `[oidc]`, on routes that require it.
+    oidc_user: Option<OidcUser>,
+    /// The filter chain of the request's route, and what its filters know of the request;
+    /// set once the request reaches the filters.
+    filters: Option<(FilterChain, FilterCtx)>,
+    /// Request body bytes allowed by `[limits]` or the route.
+    max_request_body: Option<usize>,
+    /// Request body bytes received so far.
+    request_body_bytes: usize,
+    /// Tags added by `[waf]` rules the request matched.
+    waf_tags: Vec<String>,
+    /// `[bots] throttle` pattern the user agent matched.
+    bot: Option<String>,
+    /// Slots held in the `max_concurrent_requests` caps until the request is done.
+    concurrency_permits: Vec<OwnedSemaphorePermit>,
+    /// Counts the request against `max_concurrent_requests_per_client` until it is done.
+    client_permit: Option<ClientPermit>,
+    /// Counts the request as in flight to its upstream until it is done.
+    upstream_permit: Option<UpstreamPermit>,
+    /// Set for gRPC calls on routes with `grpc`.
+    grpc: bool,
+    /// Checks request messages against the route's `grpc_max_message_kb`.
+    grpc_messages: Option<MessageSizes>,
+    /// Replaces the upstream's status once the proxy stopped forwarding the request body.
+    grpc_error: Option<CallError>,
+    /// Translates calls on routes with `grpc_web` from and to gRPC-Web.
+    grpc_web: Option<GrpcWeb>,
+    grpc_status: Option<String>,
+    /// Set once the upstream switched protocols, e.g. to WebSocket.
+    websocket: Option<WebSocket>,
+    /// Removes and masks the route's `json_redact` fields in the response body.
+    json_redactor: Option<JsonRedactor>,
+    /// Converts an image as the request's query asks.
+    image: Option<ImageConverter>,
+    /// Enforces `max_response_body_kb` on what the client gets.
+    response_body_cap: Option<ResponseBodyCap>,
+    /// Replaces the route's `<esi:include>` tags with their fragments.
+    esi: Option<EsiProcessor>,
+    /// Applies the `substitutions` rules to the response body.
+    substituter: Option<Substituter>,
+    /// Inserts the `html_inject` snippets into an HTML response.
+    html_injector: Option<HtmlInjector>,
+}
+
+impl RequestCtx {
+    fn upstream_addr(&self) -> &str {
+        self.route
+            .as_ref()
+            .map(|route| route.upstream_addr.as_str())
+            .unwrap_or(&self.state.upstream_addr)
+    }
+
+    fn substitutions(&self) -> Option<&Arc<Substitutions>> {
+        self.route
+            .as_ref()
+            .and_then(|route| route.substitutions.as_ref())
+            .or(self.state.substitutions.as_ref())
+    }
+
+    fn html_inject(&self) -> Option<&HtmlInjectConfig> {
+        self.route
+            .as_ref()
+            .and_then(|route| route.html_inject.as_ref())
+            .or(self.state.config.html_inject.as_ref())
+    }
+
+    fn parent_proxy(&self) -> Option<&ParentProxy> {
+        self.route
+            .as_ref()
+            .and_then(|route| route.parent_proxy.as_ref())
+            .or(self.state.parent_proxy.as_ref())
+    }
+
+    fn upstream_proxy_protocol(&self) -> ProxyProtocol {
+        self.route
+            .as_ref()
+            .and_then(|route| route.upstream_proxy_protocol)
+            .or(self.state.config.upstream_proxy_protocol)
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl ProxyHttp for RoseProxy {
+    type CTX = RequestCtx;
+    fn new_ctx(&self) -> Self::CTX {
+        self.stats.request_started();
+        RequestCtx {
+            state: self.state.current(),
+            route: None,
+            started: Instant::now(),
+            request_id: String::new(),
+            original_uri: None,
+            proxied: false,
+            upstream_body_bytes: None,
+            upstream_started: None,
+            upstream_ttfb: None,
+            upstream_finished: None,
+            upstream_proxy_header: None,
+            upstream_status: None,
+            static_served: None,
+            upstream_connections: 0,
+            capture: None,
+            tracked_client: None,
+            oidc_user: None,
+            filters: None,
+            max_request_body: None,
+            request_body_bytes: 0,
+            waf_tags: Vec::new(),
+            bot: None,
+            concurrency_permits: Vec::new(),
+            client_permit: None,
+            upstream_permit: None,
+            grpc: false,
+            grpc_messages: None,
+            grpc_error: None,
+            grpc_web: None,
+            grpc_status: None,
+            websocket: None,
+            json_redactor: None,
+            image: None,
+            response_body_cap: None,
+            esi: None,
+            substituter: None,
+            html_injector: None,
+        }
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        ctx.proxied = true;
+        ctx.upstream_started.get_or_insert_with(Instant::now);
+        let mut peer = match ctx.parent_proxy() {
+            Some(parent_proxy) => {
+                let mut peer = HttpPeer::new(parent_proxy.addr(), false, "".to_string());
+                peer.options.custom_l4 = Some(parent_proxy.connector(ctx.upstream_addr()));
+                // Connections to the parent are pooled by its address alone otherwise.
+                let mut hasher = DefaultHasher::new();
+                ctx.upstream_addr().hash(&mut hasher);
+                peer.group_key = hasher.finish();
+                Box::new(peer)
+            }
+            None => Box::new(HttpPeer::new(ctx.upstream_addr(), false, "".to_string())),
+        };
+        self.hooks.upstream_selected(&UpstreamSelected {
+            request_id: &ctx.request_id,
+            route: ctx.route.as_ref().map(|route| route.name.as_str()),
+            upstream_addr: ctx.upstream_addr(),
+            parent_proxy: ctx.parent_proxy().map(ParentProxy::addr),
+        });
+        if ctx
+            .route
+            .as_ref()
+            .is_some_and(|route| route.grpc || route.grpc_web)
+        {
+            // Without TLS there is no ALPN, so the upstream is taken to speak h2c.
+            peer.options.set_http_version(2, 2);
+        }
+        if let Some(idle) = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.stream_idle_timeout())
+        {
+            peer.options.read_timeout = Some(idle);
+        }
+        let peer_addr =
+            proxy_protocol::client_addr(session).and_then(|addr| addr.as_inet().copied());
+        let client = client_ip(session, &ctx.state.trusted_proxies).map(|ip| match peer_addr {
+            Some(peer) if peer.ip() == ip => peer,
+            _ => SocketAddr::new(ip, 0),
+        });
+        let server = session
+            .server_addr()
+            .and_then(|addr| addr.as_inet())
+            .copied();
+        if let Some(header) = ctx.upstream_proxy_protocol().header(client, server) {
+            // A connection speaks for one client, so it is only reused for the same header.
+            let mut hasher = DefaultHasher::new();
+            header.hash(&mut hasher);
+            peer.group_key = hasher.finish();
+            ctx.upstream_proxy_header = Some(header);
+        }
+        if session.is_upgrade_req()
+            && let Some(websocket) = &ctx.state.config.websocket
+        {
+            // Once upgraded, Pingora restarts it whenever data passes either way.
+            peer.options.read_timeout = Some(websocket.idle_timeout());
+        }
+        if ctx.grpc
+            && let Some(deadline) = grpc::timeout(session.req_header())
+        {
+            let left = deadline.saturating_sub(ctx.started.elapsed());
+            peer.options.read_timeout = Some(left.max(Duration::from_millis(1)));
+        }
+        Ok(peer)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.state.hop_headers.apply_request(upstream_request)?;
+        apply_forwarded_headers(session, &ctx.state.trusted_proxies, upstream_request)?;
+
+        let upstream_addr = ctx.upstream_addr();
+        let host = upstream_addr.split(':').next().unwrap_or(upstream_addr);
+        upstream_request.insert_header("Host", host)?;
+
+        if let Some(route) = &ctx.route
+            && let Some(path_and_query) = upstream_request.uri.path_and_query()
+            && let Some(rewritten) = route.upstream_path(path_and_query.as_str())
+        {
+            let uri = rewritten.parse().or_else(|err| {
+                ProxyError::Internal.because(
+                    format!("invalid path after stripping route prefix: {rewritten}"),
+                    err,
+                )
+            })?;
+            upstream_request.set_uri(uri);
+        }
+        if let Some(web) = &ctx.grpc_web {
+            web.request_header(upstream_request)?;
+        }
+        if let Some(redact) = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.json_redact.as_ref())
+        {
+            redact.apply_request(upstream_request);
+        }
+        if let Some(esi) = ctx.route.as_ref().and_then(|route| route.esi.as_ref()) {
+            esi.apply_request(upstream_request);
+        }
+        if let Some(substitutions) = ctx.substitutions() {
+            substitutions.apply_request(upstream_request);
+        }
+        if let Some(inject) = ctx.html_inject() {
+            inject.apply_request(upstream_request);
+        }
+
+        upstream_request.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+        if ctx.parent_proxy().is_some() && !session.is_upgrade_req() {
+            // Only the first request on a connection to the parent proxy goes in absolute form.
+            upstream_request.insert_header(http::header::CONNECTION, "close")?;
+        }
+
+        if let Some(oidc) = &ctx.state.oidc {
+            oidc.apply_request(upstream_request, ctx.oidc_user.as_ref())?;
+        }
+        if let Some(waf) = &ctx.state.waf {
+            waf.apply_request(upstream_request, &ctx.waf_tags)?;
+        }
+
+        if let Some((chain, filter_ctx)) = &mut ctx.filters {
+            chain.upstream_request_filter(upstream_request, filter_ctx)?;
+        }
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.state.hop_headers.apply_response(response)?;
+        let streaming = ctx.route.as_ref().is_some_and(|route| route.streaming);
+        if let Some(images) = ctx.state.images.as_ref().filter(|_| !streaming) {
+            let request = session.req_header();
+            let key = format!(
+                "{}{}",
+                downstream_host(session).unwrap_or_default(),
+                request
+                    .uri
+                    .path_and_query()
+                    .map_or("", |path| path.as_str())
+            );
+            ctx.image = images.start(request, response, key).await?;
+        }
+        if let Some(redact) = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.json_redact.as_ref())
+        {
+            ctx.json_redactor = redact.start(session.req_header(), response)?;
+        }
+        if let Some(esi) = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.esi.as_ref())
+            .filter(|_| !streaming)
+        {
+            ctx.esi = esi.start(session.req_header(), response, &ctx.request_id)?;
+        }
+        if let Some(substitutions) = ctx.substitutions().filter(|_| !streaming) {
+            ctx.substituter = substitutions.start(session.req_header(), response)?;
+        }
+        if let Some(inject) = ctx.html_inject() {
+            ctx.html_injector = inject.start(session.req_header(), response)?;
+        }
+        if session.req_header().method != http::Method::HEAD
+            && response.status != http::StatusCode::SWITCHING_PROTOCOLS
+        {
+            ctx.response_body_cap =
+                limits::response_body_cap(ctx.state.config.limits.as_ref(), ctx.route.as_deref());
+        }
+        if let Some(cap) = &ctx.response_body_cap {
+            cap.check_response(response)?;
+        }
+        if let Some(compression) = &ctx.state.config.compression {
+            compression.check_response(session, response, streaming);
+        }
+        if response.status == http::StatusCode::SWITCHING_PROTOCOLS
+            && session.is_upgrade_req()
+            && let Some(config) = &ctx.state.config.websocket
+        {
+            let websocket = WebSocket::new(config);
+            session.set_read_timeout(Some(websocket.read_timeout()));
+            session.set_min_send_rate(None);
+            if let Some(metrics) = &self.metrics {
+                metrics.websocket_opened(
+                    ctx.route
+                        .as_ref()
+                        .map_or(DEFAULT_ROUTE_NAME, |route| &route.name),
+                );
+            }
+            ctx.websocket = Some(websocket);
+        }
+        if let Some(web) = &mut ctx.grpc_web {
+            web.response_header(response)?;
+        }
+        if streaming
+            && session.req_header().method != Method::HEAD
+            && !matches!(response.status.as_u16(), 100..=199 | 204 | 304)
+        {
+            // Pingora holds back bodies of known length until its buffer fills; chunked ones
+            // are flushed chunk by chunk.
+            response.remove_header(&http::header::CONTENT_LENGTH);
+            if session.req_header().version == http::Version::HTTP_11 {
+                response.insert_header(http::header::TRANSFER_ENCODING, "chunked")?;
+            }
+            // Asks proxies in front, e.g. nginx, not to buffer it either.
+            response.insert_header("X-Accel-Buffering", "no")?;
+        }
+        ctx.state.response_policy.apply_security(response)?;
+
+        let cookies = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.cookies.as_ref())
+            .unwrap_or(&ctx.state.cookies);
+        cookies.apply(response)?;
+
+        let rewrite = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.rewrite_location)
+            .unwrap_or(ctx.state.rewrite_location);
+        if rewrite && let Some(host) = downstream_host(session) {
+            let public = PublicOrigin {
+                scheme: downstream_scheme(session),
+                host,
+                stripped_prefix: ctx.route.as_ref().and_then(|route| route.stripped_prefix()),
+            };
+            rewrite_location(response, ctx.upstream_addr(), &public)?;
+        }
+
+        if let Some((chain, filter_ctx)) = &mut ctx.filters {
+            chain.response_filter(session.req_header(), response, filter_ctx)?;
+        }
+        Ok(())
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.upstream_status = Some(upstream_response.status.as_u16());
+        ctx.upstream_ttfb = ctx.upstream_started.map(|started| started.elapsed());
+        if ctx.grpc {
+            // A trailers-only response carries the status in its headers.
+            ctx.grpc_status = grpc::status(&upstream_response.headers);
+            if ctx.grpc_status.is_some()
+                && let Some(error) = &ctx.grpc_error
+            {
+                for (name, value) in error.headers() {
+                    upstream_response.insert_header(name, value)?;
+                }
+                ctx.grpc_status = grpc::status(&upstream_response.headers);
+            }
+        }
+        Ok(())
+    }
+
+    fn upstream_response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if ctx.grpc {
+            if let Some(error) = &ctx.grpc_error {
+                for (name, value) in error.headers() {
+                    upstream_trailers.insert(
+                        name,
+                        value.parse().or_else(|err| {
+                            ProxyError::Internal.because("invalid grpc-message", err)
+                        })?,
+                    );
+                }
+            }
+            ctx.grpc_status = grpc::status(upstream_trailers);
+        }
+        Ok(())
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if end_of_stream {
+            ctx.upstream_finished = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        if let Some(websocket) = &mut ctx.websocket {
+            websocket.response_body(body);
+            session.set_read_timeout(Some(websocket.read_timeout()));
+        }
+        if let Some(image) = &mut ctx.image {
+            image.response_body(body, end_of_stream, &ctx.request_id);
+        }
+        if let Some(redactor) = &mut ctx.json_redactor {
+            redactor.response_body(body);
+        }
+        if let Some(esi) = &mut ctx.esi {
+            esi.response_body(body, end_of_stream);
+        }
+        if let Some(substituter) = &mut ctx.substituter {
+            substituter.response_body(body, end_of_stream);
+        }
+        if let Some(injector) = &mut ctx.html_injector {
+            injector.response_body(body, end_of_stream);
+        }
+        if let Some((chain, filter_ctx)) = &mut ctx.filters {
+            chain.response_body_filter(body, end_of_stream, filter_ctx)?;
+        }
+        if let Some(cap) = &mut ctx.response_body_cap {
+            cap.response_body(body, &ctx.request_id)?;
+        }
+        let sent = ctx.upstream_body_bytes.get_or_insert(0);
+        *sent += body.as_ref().map_or(0, |body| body.len());
+        if let Some(capture) = &mut ctx.capture
+            && let Some(body) = body
+        {
+            capture.response_body(body);
+        }
+        if let Some(web) = &mut ctx.grpc_web {
+            web.encode_response(body, end_of_stream);
+        }
+        Ok(None)
+    }
+
+    async fn response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Bytes>> {
+        // gRPC-Web carries trailers in the body, which also gets them through HTTP/1.1.
+        Ok(ctx.grpc_web.as_mut().and_then(|web| web.trailers(trailers)))
+    }
+
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(websocket) = &mut ctx.websocket {
+            // Frames rather than a request body, which the body limits don't apply to.
+            websocket.request_body(body);
+            session.set_read_timeout(Some(websocket.read_timeout()));
+            return Ok(());
+        }
+        if let Some(body) = body {
+            ctx.request_body_bytes += body.len();
+            if let Some(max) = ctx.max_request_body
+                && ctx.request_body_bytes > max
+            {
+                session.set_keepalive(None);
+                return ProxyError::PayloadTooLarge
+                    .fail(format!("request body is larger than {max} bytes"));
+            }
+        }
+        if let Some((chain, filter_ctx)) = &mut ctx.filters {
+            chain.request_body_filter(body, end_of_stream, filter_ctx)?;
+        }
+        if ctx.grpc_error.is_none()
+            && let Some(web) = &mut ctx.grpc_web
+            && let Err(message) = web.decode_request(body, end_of_stream)
+        {
+            debug!("request {} sent {message}", ctx.request_id);
+            ctx.grpc_error = Some(CallError::malformed(message));
+        }
+        if ctx.grpc_error.is_none()
+            && let Some(messages) = &mut ctx.grpc_messages
+            && let Some(data) = body
+            && let Err(length) = messages.feed(data)
+        {
+            debug!(
+                "request {} sent a gRPC message of {length} bytes",
+                ctx.request_id
+            );
+            ctx.grpc_error = Some(CallError::message_too_large(length));
+        }
+        if ctx.grpc_error.is_some() {
+            // Errors from here don't end calls to HTTP/2 upstreams, so the rest of the body is
+            // dropped instead and the call's status replaced once the upstream answers.
+            *body = None;
+        }
+        if !end_of_stream && let Some(limits) = &ctx.state.config.limits {
+            limits.pace_body(session, ctx.request_body_bytes, ctx.started.elapsed())?;
+        }
+        if let Some(capture) = &mut ctx.capture
+            && let Some(body) = body
+        {
+            capture.request_body(body);
+        }
+        Ok(())
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.request_id = request_id(session.req_header());
+        self.hooks.request_start(&RequestStart {
+            request_id: &ctx.request_id,
+            request: session.req_header(),
+            client_ip: client_ip(session, &ctx.state.trusted_proxies),
+        });
+        if let Some(clients) = &self.clients
+            && let Some(client) =
+                proxy_protocol::client_addr(session).and_then(|addr| addr.as_inet().copied())
+            && clients.request_started(client)
+        {
+            ctx.tracked_client = Some(client);
+        }
+
+        if let Some(limits) = &ctx.state.config.limits {
+            limits.apply_timeouts(session);
+        }
+        if let Some(controls) = &self.controls
+            && controls.quiesce.since().is_some()
+        {
+            session.set_keepalive(None);
+        }
+        if let Some(limits) = &ctx.state.config.limits
+            && let Some((status, reason)) = limits.check_head(session.req_header())
+        {
+            debug!("request {} refused with {status}: {reason}", ctx.request_id);
+            session.set_keepalive(None);
+            let (header, body) = refusal_response(status, &ctx.request_id)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+
+        if !self.endpoints.is_empty() && self.endpoints.try_serve(session).await? {
+            return Ok(true);
+        }
+        if let Some(capture) = &self.capture {
+            let client = proxy_protocol::client_addr(session).map(|addr| addr.to_string());
+            ctx.capture = capture.start(session.req_header(), &ctx.request_id, client);
+        }
+
+        if let Some(oidc) = &ctx.state.oidc
+            && let Some((header, body)) = oidc
+                .answer(
+                    session.req_header(),
+                    downstream_host(session),
+                    &ctx.request_id,
+                )
+                .await?
+        {
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+
+        if !ctx.state.rewrites.is_empty()
+            && let Some(rewritten) = ctx
+                .state
+                .rewrites
+                .apply(session.req_header(), downstream_host(session))
+        {
+            match rewritten {
+                Rewritten::Request(path_and_query) => {
+                    debug!("request {} rewritten to {path_and_query}", ctx.request_id);
+                    let uri = path_and_query.parse().or_else(|err| {
+                        ProxyError::BadRequest.because(
+                            format!("invalid path after rewriting: {path_and_query}"),
+                            err,
+                        )
+                    })?;
+                    ctx.original_uri = Some(session.req_header().uri.clone());
+                    session.req_header_mut().set_uri(uri);
+                }
+                Rewritten::Redirect(status, location) => {
+                    debug!("request {} redirected to {location}", ctx.request_id);
+                    let mut header = ResponseHeader::build(status, Some(4))?;
+                    header.insert_header(http::header::LOCATION, location)?;
+                    header.insert_header(http::header::CONTENT_LENGTH, 0)?;
+                    header.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+                    session
+                        .write_response_header(Box::new(header), true)
+                        .await?;
+                    return Ok(true);
+                }
+            }
+        }
+        ctx.route = ctx.state.router.match_path(session.req_header().uri.path());
+        if let Some(controls) = &self.controls
+            && let Some(until) = controls
+                .maintenance
+                .active(ctx.route.as_ref().map(|route| route.name.as_str()))
+        {
+            debug!("request {} refused: under maintenance", ctx.request_id);
+            let (header, body) = ctx
+                .state
+                .maintenance_page
+                .response(until, &ctx.request_id)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+        if let Some(idle) = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.stream_idle_timeout())
+        {
+            // Streams may trickle, so the client's rate and send timeout give way to it.
+            session.set_min_send_rate(None);
+            session.set_write_timeout(Some(idle));
+        }
+        if let Some(route) = &ctx.route
+            && route.grpc_web
+        {
+            ctx.grpc_web = GrpcWeb::new(session.req_header());
+        }
+        if let Some(route) = &ctx.route
+            && (ctx.grpc_web.is_some() || route.grpc && grpc::is_grpc(session.req_header()))
+        {
+            ctx.grpc = true;
+            ctx.grpc_messages = route
+                .grpc_max_message_kb
+                .map(|kb| MessageSizes::new(kb.saturating_mul(1024)));
+        }
+        let client = client_ip(session, &ctx.state.trusted_proxies);
+
+        if let Some(controls) = &self.controls
+            && let Some(ip) = client
+            && controls.bans.is_banned(&ip)
+        {
+            debug!("request {} from {ip} refused: banned", ctx.request_id);
+            let (header, body) = refusal_response(403, &ctx.request_id)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+        let access_control = match &ctx.route {
+            Some(route) if route.access_control.is_some() => route.access_control.as_ref(),
+            _ => ctx.state.access_control.as_ref(),
+        };
+        if let Some(access_control) = access_control
+            && !access_control.allows(client)
+        {
+            debug!(
+                "request {} from {client:?} refused by access control",
+                ctx.request_id
+            );
+            let (header, body) = refusal_response(403, &ctx.request_id)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+
+        if let Some(limit) = ctx
+            .state
+            .config
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.max_concurrent_requests_per_client)
+            && let Some(client) = client
+        {
+            ctx.client_permit = self.concurrency.enter_client(client, limit);
+            if ctx.client_permit.is_none() {
+                debug!(
+                    "request {} refused: {client} has {limit} requests in flight",
+                    ctx.request_id
+                );
+                session.set_keepalive(None);
+                let (header, body) = refusal_response(429, &ctx.request_id)?;
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session.write_response_body(Some(body), true).await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(waf) = &ctx.state.waf {
+            match waf.inspect(session.req_header(), &ctx.request_id) {
+                WafVerdict::Allow { tags } => ctx.waf_tags = tags,
+                WafVerdict::Block => {
+                    let (header, body) = refusal_response(403, &ctx.request_id)?;
+                    session
+                        .write_response_header(Box::new(header), false)
+                        .await?;
+                    session.write_response_body(Some(body), true).await?;
+                    return Ok(true);
+                }
+            }
+        }
+
+        if let Some(bots) = &ctx.state.bots {
+            let refusal = match bots.classify(session.req_header(), client).await {
+                BotVerdict::Pass { throttle } => {
+                    ctx.bot = throttle;
+                    None
+                }
+                BotVerdict::Deny => {
+                    debug!("request {} refused by its user agent", ctx.request_id);
+                    Some(refusal_response(403, &ctx.request_id)?)
+                }
+                BotVerdict::Challenge => Some(bots.challenge_response(client, &ctx.request_id)?),
+            };
+            if let Some((header, body)) = refusal {
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session.write_response_body(Some(body), true).await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(csrf) = &ctx.state.config.csrf
+            && ctx.route.as_ref().is_none_or(|route| route.csrf)
+            && let Some(reason) = csrf.check(session.req_header(), downstream_host(session))
+        {
+            debug!(
+                "request {} refused as cross-site ({reason})",
+                ctx.request_id
+            );
+            let (header, body) = refusal_response(403, &ctx.request_id)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+
+        ctx.max_request_body =
+            limits::max_request_body(ctx.state.config.limits.as_ref(), ctx.route.as_deref());
+        if let Some(max) = ctx.max_request_body
+            && limits::declares_larger_body(session.req_header(), max)
+        {
+            debug!(
+                "request {} announces a body larger than {max} bytes",
+                ctx.request_id
+            );
+            session.set_keepalive(None);
+            let (header, body) = refusal_response(413, &ctx.request_id)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = &ctx.route
+            && let Some(signed_urls) = &route.signed_urls
+            // Links are signed for the URL clients are given, not what it is rewritten to.
+            && let Err(err) = signed_urls.verify(
+                ctx.original_uri
+                    .as_ref()
+                    .unwrap_or(&session.req_header().uri),
+            )
+        {
+            debug!(
+                "request {} to route '{}' has a {} signature",
+                ctx.request_id,
+                route.name,
+                err.as_str()
+            );
+            let (header, body) = refusal_response(403, &ctx.request_id)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+
+        let chain = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.filters.clone())
+            .unwrap_or_else(|| ctx.state.filter_chain.clone());
+        let mut filter_ctx = FilterCtx::new(
+            ctx.request_id.clone(),
+            ctx.state.clone(),
+            ctx.route.clone(),
+            client,
+            ctx.bot.clone(),
+        );
+        let answer = chain.request_filter(session, &mut filter_ctx).await?;
+        ctx.filters = Some((chain, filter_ctx));
+        if let Some((header, body)) = answer {
+            let end = body.is_empty();
+            session.write_response_header(Box::new(header), end).await?;
+            if !end {
+                session.write_response_body(Some(body), true).await?;
+            }
+            return Ok(true);
+        }
+
+        if let Some(route) = &ctx.route
+            && route.oidc
+            && let Some(oidc) = &ctx.state.oidc
+        {
+            ctx.oidc_user = oidc.user(session.req_header());
+            if ctx.oidc_user.is_none() {
+                let (header, body) = oidc
+                    .login_response(session.req_header(), &ctx.request_id)
+                    .await?;
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session.write_response_body(Some(body), true).await?;
+                return Ok(true);
+            }
+        }
+
+        let limits = ctx.state.config.limits.as_ref();
+        let (queue, queue_timeout) = limits::concurrency_queue(limits);
+        for (route, limit) in limits::concurrency_gates(limits, ctx.route.as_deref()) {
+            let Some(permit) = self
+                .concurrency
+                .acquire(route, limit, queue, queue_timeout)
+                .await
+            else {
+                debug!(
+                    "request {} shed: {limit} requests in flight{}",
+                    ctx.request_id,
+                    route.map_or(String::new(), |route| format!(" on route '{route}'"))
+                );
+                if let Some(metrics) = &self.metrics {
+                    metrics.shed();
+                }
+                let (header, body) = refusal_response(503, &ctx.request_id)?;
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session.write_response_body(Some(body), true).await?;
+                return Ok(true);
+            };
+            ctx.concurrency_permits.push(permit);
+        }
+
+        if let Some(doh) = &ctx.state.doh
+            && doh.try_serve(session, &ctx.request_id).await?
+        {
+            return Ok(true);
+        }
+        if let Some(static_assets) = &ctx.state.static_assets
+            && let Some(served) = static_assets.try_serve(session).await?
+        {
+            ctx.static_served = Some(served);
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route.clone()
+            && let Some(fastcgi) = &route.fastcgi
+        {
+            let prefix = route.stripped_prefix().unwrap_or_default();
+            let path = session.req_header().uri.path();
+            let Some(script) = fastcgi.script(path.strip_prefix(prefix).unwrap_or(path)) else {
+                debug!(
+                    "request {} to route '{}' names no FastCGI script",
+                    ctx.request_id, route.name
+                );
+                let (header, body) = refusal_response(404, &ctx.request_id)?;
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session.write_response_body(Some(body), true).await?;
+                return Ok(true);
+            };
+            let peer_addr =
+                proxy_protocol::client_addr(session).and_then(|addr| addr.as_inet().copied());
+            let client = client_ip(session, &ctx.state.trusted_proxies).map(|ip| match peer_addr {
+                Some(peer) if peer.ip() == ip => peer,
+                _ => SocketAddr::new(ip, 0),
+            });
+            let host = downstream_host(session).map(str::to_string);
+            let request = CgiRequest {
+                prefix,
+                client,
+                server: session
+                    .server_addr()
+                    .and_then(|addr| addr.as_inet())
+                    .copied(),
+                host: host.as_deref(),
+                https: downstream_scheme(session) == "https",
+                max_body: ctx.max_request_body,
+            };
+            let state = ctx.state.clone();
+            let request_header = session.req_header().clone();
+            fastcgi
+                .serve(session, &script, request, |response| {
+                    state.hop_headers.apply_response(response)?;
+                    state.response_policy.apply(&request_header, response)?;
+                    route
+                        .cookies
+                        .as_ref()
+                        .unwrap_or(&state.cookies)
+                        .apply(response)?;
+                    route.headers.apply_response(response)
+                })
+                .await?;
+            return Ok(true);
+        }
+        if let Some(controls) = &self.controls {
+            let Some(permit) = controls.drains.admit(ctx.upstream_addr()) else {
+                debug!(
+                    "request {} refused: upstream {} is draining",
+                    ctx.request_id,
+                    ctx.upstream_addr()
+                );
+                let (header, body) = refusal_response(503, &ctx.request_id)?;
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session.write_response_body(Some(body), true).await?;
+                return Ok(true);
+            };
+            ctx.upstream_permit = Some(permit);
+        }
+        if let Some(compression) = &ctx.state.config.compression {
+            compression.enable(session);
+        }
+        Ok(false)
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        _peer: &HttpPeer,
+        #[cfg(unix)] fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if !reused && let Some(header) = &ctx.upstream_proxy_header {
+            proxy_protocol::send(fd, header)?;
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.upstream_connected(reused);
+            ctx.upstream_connections += 1;
+        }
+        Ok(())
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        let code = error_status(e);
+        if let Some(websocket) = &ctx.websocket {
+            // Nothing more can be answered on a connection speaking another protocol.
+            debug!(
+                "upgraded connection of request {} ended ({}): {e}",
+                ctx.request_id,
+                websocket.close_reason(Some(e))
+            );
+            return FailToProxy {
+                error_code: code,
+                can_reuse_downstream: false,
+            };
+        }
+        error!(
+            "request {} failed with {code}: {e} ({})",
+            ctx.request_id,
+            session.request_summary()
+        );
+        if let Some(reporter) = &self.upstream_failures
+            && ctx.proxied
+            && e.esource() == &ErrorSource::Upstream
+        {
+            reporter.record(&UpstreamFailure {
+                upstream: ctx.upstream_addr(),
+                route: ctx
+                    .route
+                    .as_ref()
+                    .map_or(DEFAULT_ROUTE_NAME, |route| &route.name),
+                request_id: &ctx.request_id,
+                method: session.req_header().method.as_str(),
+                url: format!(
+                    "{}://{}{}",
+                    downstream_scheme(session),
+                    downstream_host(session).unwrap_or("localhost"),
+                    session.req_header().uri
+                ),
+                status: code,
+                error: e.to_string(),
+            });
+        }
+        if code > 0 && ctx.grpc {
+            let status = grpc::status_code(code, e);
+            let header =
+                grpc::error_response(status, code, &ctx.request_id).and_then(|mut header| {
+                    // Upstreams may reject a body the proxy cut short.
+                    if let Some(error) = &ctx.grpc_error {
+                        for (name, value) in error.headers() {
+                            header.insert_header(name, value)?;
+                        }
+                    }
+                    if let Some(web) = &ctx.grpc_web {
+                        header.insert_header(http::header::CONTENT_TYPE, web.content_type())?;
+                    }
+                    Ok(header)
+                });
+            ctx.grpc_status = match &header {
+                Ok(header) => grpc::status(&header.headers),
+                Err(_) => Some(status.to_string()),
+            };
+            let sent = match header {
+                // Unlike `write_response_header`, a task keeps the end of stream on HTTP/2.
+                Ok(header) => session
+                    .write_response_tasks(vec![HttpTask::Header(Box::new(header), true)])
+                    .await
+                    .map(|_| ()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = sent {
+                error!("failed to send error response to downstream: {err}");
+            }
+        } else if code > 0 {
+            let detail = ctx.state.config.error_detail.unwrap_or_default();
+            let sent = match error_response(
+                code,
+                session.req_header(),
+                &ctx.request_id,
+                e,
+                detail,
+                &ctx.state.error_pages,
+            ) {
+                Ok((header, body)) => {
+                    match session.write_response_header(Box::new(header), false).await {
+                        Ok(()) => session.write_response_body(Some(body), true).await,
+                        Err(err) => Err(err),
+                    }
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = sent {
+                error!("failed to send error response to downstream: {err}");
+            }
+        }
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        let status = session
+            .response_written()
+            .map_or(0, |response| response.status.as_u16());
+        let route = ctx
+            .route
+            .as_ref()
+            .map_or(DEFAULT_ROUTE_NAME, |route| &route.name);
+        self.stats.request_finished(route, status);
+        let upstream_timing = ctx.upstream_started.map(|started| UpstreamTiming {
+            route,
+            ttfb: ctx.upstream_ttfb,
+            total: ctx
+                .upstream_finished
+                .unwrap_or_else(Instant::now)
+                .duration_since(started),
+            status: ctx.upstream_status,
+        });
+        if let Some(metrics) = &self.metrics
+            && let Some(timing) = &upstream_timing
+        {
+            metrics.observe_upstream(timing);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_downstream(
+                proxy_protocol::client_addr(session).and_then(|addr| addr.as_inet().copied()),
+                session
+                    .digest()
+                    .and_then(|digest| digest.timing_digest.first().cloned().flatten())
+                    .map(|timing| timing.established_ts),
+            );
+            metrics.upstream_released(ctx.upstream_connections);
+            if let Some(websocket) = &ctx.websocket {
+                metrics.websocket_closed(route, websocket.close_reason(e));
+            }
+        }
+        if let Some(metrics) = &self.metrics
+            && let Some(served) = &ctx.static_served
+            && let Some(static_assets) = &ctx.state.static_assets
+        {
+            metrics.observe_static(static_assets.mount_path(), served);
+        }
+        if let Some(alert_config) = &ctx.state.config.alerts {
+            let raised = self
+                .alerts
+                .record(alert_config, route, status, ctx.started.elapsed());
+            if let Some(metrics) = &self.metrics {
+                for kind in raised {
+                    metrics.alert_raised(route, kind);
+                }
+            }
+        }
+        if let Some(clients) = &self.clients
+            && let Some(client) = ctx.tracked_client
+        {
+            clients.request_finished(client, session.body_bytes_read(), session.body_bytes_sent());
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.observe_request(route, status, upstream_timing.as_ref());
+        }
+
+        if let Some(capture) = &self.capture
+            && let Some(pending) = ctx.capture.take()
+        {
+            capture.finish(pending, session.response_written(), ctx.started.elapsed());
+        }
+
+        let api_key = ctx
+            .filters
+            .as_ref()
+            .and_then(|(_, filter_ctx)| filter_ctx.api_key.as_ref())
+            .map(|key| key.id.as_str());
+        self.hooks.response_complete(&ResponseComplete {
+            request_id: &ctx.request_id,
+            route: ctx.route.as_ref().map(|route| route.name.as_str()),
+            status,
+            duration: ctx.started.elapsed(),
+            upstream_addr: ctx.proxied.then(|| ctx.upstream_addr()),
+            upstream_status: ctx.upstream_status,
+            upstream_ttfb: ctx.upstream_ttfb,
+            request_body_bytes: session.body_bytes_read(),
+            response_body_bytes: body_bytes_sent(session, ctx.upstream_body_bytes),
+            api_key,
+            error: e,
+        });
+
+        if let Some(access_log) = &ctx.state.access_log {
+            access_log.log(&LoggedRequest {
+                session,
+                started: ctx.started,
+                logged_at: SystemTime::now(),
+                request_id: &ctx.request_id,
+                client_ip: client_ip(session, &ctx.state.trusted_proxies),
+                upstream_body_bytes: ctx.upstream_body_bytes,
+                upstream_addr: ctx.proxied.then(|| ctx.upstream_addr()),
+                route: ctx.route.as_ref().map(|route| route.name.as_str()),
+                api_key,
+                waf_tags: &ctx.waf_tags,
+                grpc_status: ctx.grpc_status.as_deref(),
+            });
+        }
+    }
+}
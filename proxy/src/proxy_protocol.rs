@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::os::fd::{IntoRawFd, RawFd};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::protocols::l4::socket::SocketAddr as PeerAddr;
+use pingora::proxy::Session;
+use pingora::server::ShutdownWatch;
+use pingora::{Error, ErrorType, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpSocket;
+
+use crate::ip_list::IpList;
+use crate::state::SharedState;
+use crate::tcp_proxy::relay;
+
+/// Opens every version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+/// Version 2 signature, version and command, family and length.
+const V2_HEADER_LEN: usize = 16;
+/// Time a load balancer has to send the header of a new connection.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a relayed connection may carry nothing either way; the proxy's own timeouts
+/// close idle ones well before.
+const BRIDGE_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `[proxy_protocol]`: listeners for load balancers, e.g. an AWS NLB, that open each
+/// connection with a PROXY protocol header (version 1 or 2) naming the client. Requests on
+/// them are served as on `listen_addr`, with the client the header names in place of the
+/// balancer.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct ProxyProtocolConfig {
+    /// Address it listens on, e.g. `"0.0.0.0:8081"`; only changes on restart.
+    pub listen_addr: String,
+    /// IP addresses and CIDR blocks of the load balancers. Connections from anywhere else are
+    /// closed, since their header could name any client.
+    pub trusted_networks: Vec<String>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.trusted_networks.is_empty() {
+            return Err("trusted_networks is empty".to_string());
+        }
+        IpList::parse(&self.trusted_networks).map(drop)
+    }
+}
+
+/// `upstream_proxy_protocol`: a PROXY protocol header sent first on new upstream connections,
+/// for upstreams that want the client address at the TCP level.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    #[default]
+    Off,
+    /// The text version.
+    V1,
+    /// The binary version.
+    V2,
+}
+
+impl ProxyProtocol {
+    /// The header for a connection from `client` to `server`, or `None` when off. Without
+    /// both addresses, e.g. on a Unix socket listener, the source is given as unknown.
+    pub fn header(self, client: Option<SocketAddr>, server: Option<SocketAddr>) -> Option<Vec<u8>> {
+        // Both addresses must be of one family, so IPv4 ones go as IPv4-mapped IPv6 otherwise.
+        let addrs = client
+            .zip(server)
+            .map(|(client, server)| match (client.ip(), server.ip()) {
+                (IpAddr::V4(_), IpAddr::V4(_)) => (client, server),
+                _ => (mapped(client), mapped(server)),
+            });
+        match self {
+            Self::Off => None,
+            Self::V1 => Some(match addrs {
+                Some((client, server)) => format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    if client.is_ipv4() { "TCP4" } else { "TCP6" },
+                    client.ip(),
+                    server.ip(),
+                    client.port(),
+                    server.port()
+                )
+                .into_bytes(),
+                None => b"PROXY UNKNOWN\r\n".to_vec(),
+            }),
+            Self::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                let Some((client, server)) = addrs else {
+                    // LOCAL command, unspecified family, no addresses.
+                    header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+                    return Some(header);
+                };
+                let (family, addresses) = match (client.ip(), server.ip()) {
+                    (IpAddr::V4(client), IpAddr::V4(server)) => {
+                        (0x11, [client.octets(), server.octets()].concat())
+                    }
+                    (client, server) => (
+                        0x21,
+                        [ipv6(client).octets(), ipv6(server).octets()].concat(),
+                    ),
+                };
+                let ports = [client.port().to_be_bytes(), server.port().to_be_bytes()].concat();
+                // PROXY command over TCP.
+                header.extend_from_slice(&[0x21, family]);
+                header.extend_from_slice(&((addresses.len() + ports.len()) as u16).to_be_bytes());
+                header.extend_from_slice(&addresses);
+                header.extend_from_slice(&ports);
+                Some(header)
+            }
+        }
+    }
+}
+
+/// Writes `header` on a new upstream connection, before the request.
+pub fn send(fd: RawFd, header: &[u8]) -> Result<()> {
+    // A new connection's send buffer is empty, so the few bytes go out in one call.
+    let sent = unsafe { libc::send(fd, header.as_ptr().cast(), header.len(), libc::MSG_NOSIGNAL) };
+    if sent == header.len() as isize {
+        return Ok(());
+    }
+    let reason = if sent < 0 {
+        std::io::Error::last_os_error().to_string()
+    } else {
+        format!("{sent} of {} bytes written", header.len())
+    };
+    Err(Error::explain(
+        ErrorType::WriteError,
+        format!("sending PROXY protocol header: {reason}"),
+    )
+    .into_up())
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(ipv6(addr.ip())), addr.port())
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Parses the PROXY protocol header at the start of `buf`: `None` while it may still be
+/// incomplete, otherwise the client address it conveys, `None` for health checks of the
+/// balancer itself (`LOCAL`, `UNKNOWN`) or other families, and the header's length.
+pub fn parse_header(buf: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, String> {
+    if buf.len() < V2_SIGNATURE.len()
+        && (V2_SIGNATURE.starts_with(buf) || b"PROXY ".starts_with(buf))
+    {
+        return Ok(None);
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(b"PROXY ") {
+        parse_v1(buf)
+    } else {
+        Err("no PROXY protocol header".to_string())
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, String> {
+    let Some(end) = buf.windows(2).position(|pair| pair == b"\r\n") else {
+        return if buf.len() < V1_MAX_LEN {
+            Ok(None)
+        } else {
+            Err("version 1 header is too long".to_string())
+        };
+    };
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        return Err("version 1 header is too long".to_string());
+    }
+    let line =
+        std::str::from_utf8(&buf[..end]).map_err(|_| "version 1 header is not text".to_string())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let client = match fields[1..] {
+        ["UNKNOWN", ..] => None,
+        [
+            family @ ("TCP4" | "TCP6"),
+            source,
+            destination,
+            source_port,
+            destination_port,
+        ] => {
+            let ip = |text: &str| {
+                text.parse::<IpAddr>()
+                    .ok()
+                    .filter(|ip| ip.is_ipv4() == (family == "TCP4"))
+                    .ok_or_else(|| format!("invalid {family} address {text:?}"))
+            };
+            let port = |text: &str| {
+                text.parse::<u16>()
+                    .ok()
+                    .filter(|_| !text.starts_with('0') || text == "0")
+                    .ok_or_else(|| format!("invalid port {text:?}"))
+            };
+            ip(destination)?;
+            port(destination_port)?;
+            Some(SocketAddr::new(ip(source)?, port(source_port)?))
+        }
+        _ => return Err(format!("invalid version 1 header {line:?}")),
+    };
+    Ok(Some((client, len)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, String> {
+    let Some(header) = buf.get(..V2_HEADER_LEN) else {
+        return Ok(None);
+    };
+    let (version_command, family) = (header[12], header[13]);
+    let len = V2_HEADER_LEN + usize::from(u16::from_be_bytes([header[14], header[15]]));
+    if version_command >> 4 != 2 {
+        return Err(format!("unknown version {}", version_command >> 4));
+    }
+    let Some(body) = buf.get(V2_HEADER_LEN..len) else {
+        return Ok(None);
+    };
+    let client = match version_command & 0x0f {
+        // LOCAL: the balancer's own connection.
+        0x0 => None,
+        // PROXY: TCP or UDP over IPv4 or IPv6; the rest, e.g. Unix sockets, is unknown.
+        0x1 => match family >> 4 {
+            0x1 => {
+                let addrs = body
+                    .get(..12)
+                    .ok_or("IPv4 addresses are truncated".to_string())?;
+                let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+                let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+                Some(SocketAddr::new(IpAddr::V4(ip), port))
+            }
+            0x2 => {
+                let addrs = body
+                    .get(..36)
+                    .ok_or("IPv6 addresses are truncated".to_string())?;
+                let ip: [u8; 16] = addrs[..16].try_into().expect("16 bytes");
+                let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+                Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+            }
+            _ => None,
+        },
+        command => return Err(format!("unknown command {command}")),
+    };
+    Ok(Some((client, len)))
+}
+
+/// Reads the PROXY protocol header a connection opens with, returning the client it conveys
+/// and the bytes read past it.
+async fn read_header(stream: &mut Stream) -> Result<(Option<SocketAddr>, Vec<u8>), String> {
+    let mut buf = Vec::with_capacity(V1_MAX_LEN);
+    let read = async {
+        loop {
+            if let Some((client, len)) = parse_header(&buf)? {
+                return Ok((client, buf.split_off(len)));
+            }
+            let mut chunk = [0; 512];
+            match stream.read(&mut chunk).await {
+                Ok(0) => return Err("closed before the header ended".to_string()),
+                Ok(read) => buf.extend_from_slice(&chunk[..read]),
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    };
+    tokio::time::timeout(HEADER_TIMEOUT, read)
+        .await
+        .map_err(|_| "timed out waiting for the header".to_string())?
+}
+
+/// The loopback listener of the proxy that `[proxy_protocol]` connections are relayed to, and
+/// the clients of those being relayed, by the local port they come from.
+struct Bridge {
+    addr: SocketAddr,
+    clients: Mutex<HashMap<u16, SocketAddr>>,
+}
+
+static BRIDGE: OnceLock<Bridge> = OnceLock::new();
+
+/// Binds the loopback listener `[proxy_protocol]` connections are relayed to, returning its
+/// address and socket for the proxy service to adopt.
+pub fn bind_bridge() -> Result<(String, RawFd), String> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .map_err(|err| format!("can't bind the proxy_protocol relay listener: {err}"))?;
+    let addr = listener.local_addr().map_err(|err| err.to_string())?;
+    let bridge = Bridge {
+        addr,
+        clients: Mutex::default(),
+    };
+    BRIDGE
+        .set(bridge)
+        .map_err(|_| "proxy_protocol is already set up in this process".to_string())?;
+    Ok((addr.to_string(), listener.into_raw_fd()))
+}
+
+/// Address of the client of a request: the one named by its load balancer on `[proxy_protocol]`
+/// listeners, the peer of the connection otherwise. Client IPs, logs, rate limits and
+/// `X-Forwarded-For` all start from this.
+pub fn client_addr(session: &Session) -> Option<PeerAddr> {
+    let peer = session.client_addr()?;
+    if let Some(bridge) = BRIDGE.get()
+        && session.server_addr().and_then(PeerAddr::as_inet) == Some(&bridge.addr)
+        && let Some(relayed) = peer.as_inet()
+        && relayed.ip() == bridge.addr.ip()
+        && let Some(client) = bridge.clients.lock().unwrap().get(&relayed.port())
+    {
+        return Some(PeerAddr::Inet(*client));
+    }
+    Some(peer.clone())
+}
+
+/// Keeps the client of a relayed connection registered while it lasts.
+struct Registered {
+    port: u16,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        if let Some(bridge) = BRIDGE.get() {
+            bridge.clients.lock().unwrap().remove(&self.port);
+        }
+    }
+}
+
+/// Listening service of `[proxy_protocol]`: takes the header of connections from trusted load
+/// balancers and relays the rest to the proxy's loopback listener.
+pub struct ProxyProtocolService {
+    state: SharedState,
+}
+
+impl ProxyProtocolService {
+    pub fn new(state: SharedState) -> Self {
+        Self { state }
+    }
+
+    async fn serve(&self, mut stream: Stream) {
+        let Some(bridge) = BRIDGE.get() else {
+            return;
+        };
+        let balancer = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied());
+        let state = self.state.current();
+        let Some(balancer) = balancer.filter(|balancer| {
+            state
+                .proxy_protocol
+                .as_ref()
+                .is_some_and(|trusted| trusted.contains(&balancer.ip()))
+        }) else {
+            debug!("proxy_protocol: {balancer:?} is not a trusted load balancer");
+            return;
+        };
+        let (client, rest) = match read_header(&mut stream).await {
+            Ok(read) => read,
+            Err(err) => {
+                debug!("proxy_protocol: bad header from {balancer}: {err}");
+                return;
+            }
+        };
+        let client = client.unwrap_or(balancer);
+        let connect = async {
+            let socket = TcpSocket::new_v4()?;
+            socket.bind(SocketAddr::new(bridge.addr.ip(), 0))?;
+            let port = socket.local_addr()?.port();
+            bridge.clients.lock().unwrap().insert(port, client);
+            let registered = Registered { port };
+            let mut proxy = socket.connect(bridge.addr).await?;
+            proxy.write_all(&rest).await?;
+            std::io::Result::Ok((proxy, registered))
+        };
+        let (proxy, _registered) = match connect.await {
+            Ok(connected) => connected,
+            Err(err) => {
+                warn!("proxy_protocol: relaying {client} to the proxy failed: {err}");
+                return;
+            }
+        };
+        let (_, _, ended) = relay(stream, proxy, BRIDGE_IDLE_TIMEOUT).await;
+        debug!("proxy_protocol: {client} via {balancer} {ended}");
+    }
+}
+
+#[async_trait]
+impl ServerApp for ProxyProtocolService {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        self.serve(stream).await;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_the_headers_it_sends() {
+        for (client, server) in [
+            ("192.0.2.7:51000", "10.0.0.1:80"),
+            ("[2001:db8::7]:51000", "[2001:db8::1]:443"),
+        ] {
+            for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+                let mut header = version
+                    .header(Some(addr(client)), Some(addr(server)))
+                    .unwrap();
+                let len = header.len();
+                header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                assert_eq!(
+                    parse_header(&header),
+                    Ok(Some((Some(addr(client)), len))),
+                    "{version:?} {client}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn balancer_connections_convey_no_client() {
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            let header = version.header(None, None).unwrap();
+            assert_eq!(parse_header(&header), Ok(Some((None, header.len()))));
+        }
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_header() {
+        let v1 = b"PROXY TCP4 192.0.2.7 10.0.0.1 51000 80\r\n";
+        let v2 = ProxyProtocol::V2
+            .header(Some(addr("192.0.2.7:51000")), Some(addr("10.0.0.1:80")))
+            .unwrap();
+        for header in [&v1[..], &v2] {
+            for len in 0..header.len() {
+                assert_eq!(parse_header(&header[..len]), Ok(None), "{len} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_what_is_not_a_header() {
+        for buf in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 2001:db8::7 10.0.0.1 51000 80\r\n",
+            b"PROXY TCP4 192.0.2.7 10.0.0.1 51000\r\n",
+            b"PROXY TCP4 192.0.2.7 10.0.0.1 051000 80\r\n",
+            b"PROXY TCP5 192.0.2.7 10.0.0.1 51000 80\r\n",
+            &[b"PROXY ".as_slice(), &[b'x'; V1_MAX_LEN]].concat(),
+            // Version 1 of the binary header, and an unknown command.
+            b"\r\n\r\n\0\r\nQUIT\n\x11\x11\0\0",
+            b"\r\n\r\n\0\r\nQUIT\n\x22\x11\0\0",
+            // IPv4 with its addresses cut short.
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x11\0\x04\xc0\0\x02\x07",
+        ] {
+            assert!(
+                parse_header(buf).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(buf)
+            );
+        }
+    }
+
+    #[test]
+    fn skips_tlvs_and_unknown_families() {
+        let mut header = ProxyProtocol::V2
+            .header(Some(addr("192.0.2.7:51000")), Some(addr("10.0.0.1:80")))
+            .unwrap();
+        // An AWS VPC endpoint ID TLV after the addresses.
+        let tlv = b"\xea\0\x06\x01vpce1";
+        header[15] += tlv.len() as u8;
+        header.extend_from_slice(tlv);
+        assert_eq!(
+            parse_header(&header),
+            Ok(Some((Some(addr("192.0.2.7:51000")), header.len())))
+        );
+
+        // Unix sockets.
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x31, 0, 216]);
+        header.extend_from_slice(&[0; 216]);
+        assert_eq!(parse_header(&header), Ok(Some((None, header.len()))));
+    }
+}
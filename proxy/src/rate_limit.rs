@@ -0,0 +1,933 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::Bytes;
+use http::header::RETRY_AFTER;
+use log::{info, warn};
+use pingora::http::{RequestHeader, ResponseHeader};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api_keys::ApiKey;
+use crate::cookies::request_cookie;
+use crate::errors::refusal_response;
+use crate::ip_list::IpList;
+use crate::redis::RedisClient;
+use crate::units::timestamp;
+
+const DEFAULT_MAX_TRACKED: usize = 100_000;
+/// How often a full set of local buckets is swept for those that refilled.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_REDIS_KEY_PREFIX: &str = "proxy:rate_limit:";
+const DEFAULT_REDIS_TIMEOUT_MS: u64 = 50;
+const DEFAULT_REDIS_POOL_SIZE: usize = 4;
+const DEFAULT_REDIS_RETRY_SECONDS: u64 = 5;
+
+/// `[rate_limit]` section of the config file: token buckets checked before static files and
+/// upstreams. Requests are not limited when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    /// Sustained requests per second allowed per client IP, for every request.
+    pub requests_per_second: Option<f64>,
+    /// Requests a client may make at once after being idle; defaults to one second's worth.
+    pub burst: Option<u32>,
+    /// Keys tracked at once; beyond this, those whose bucket refilled are forgotten, then
+    /// those seen least recently.
+    pub max_tracked: Option<usize>,
+    /// Client IPs and CIDR blocks never limited, e.g. health checkers and internal ranges.
+    pub exempt: Option<Vec<String>>,
+    /// Further limits, each with its own buckets. A request counts against every rule it
+    /// matches and is refused when any of them is exhausted.
+    #[serde(default)]
+    pub rules: Vec<RateLimitRule>,
+    /// Limits per API key, for the keys of routes' `api_keys` that name the tier.
+    #[serde(default)]
+    pub tiers: BTreeMap<String, RateLimitTier>,
+    /// Share the buckets between replicas through Redis.
+    pub redis: Option<RedisBackendConfig>,
+}
+
+/// `[rate_limit.redis]`: buckets shared by every replica using the same server. While it
+/// cannot be reached, each replica falls back to its own in-memory buckets.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct RedisBackendConfig {
+    /// `host:port` of the server.
+    pub addr: String,
+    /// May be given as `password_file` to read it from a file.
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    /// Prepended to the bucket keys.
+    pub key_prefix: Option<String>,
+    /// Longest wait for a reply before the request is limited locally.
+    pub timeout_ms: Option<u64>,
+    /// Connections kept open to the server.
+    pub pool_size: Option<usize>,
+    /// How long to limit locally after the server failed before trying it again.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub retry_seconds: Option<u64>,
+}
+
+impl RedisBackendConfig {
+    fn with_defaults(self) -> Self {
+        Self {
+            key_prefix: Some(self.key_prefix().to_string()),
+            timeout_ms: Some(self.timeout().as_millis() as u64),
+            pool_size: Some(self.pool_size()),
+            retry_seconds: Some(self.retry().as_secs()),
+            ..self
+        }
+    }
+
+    fn key_prefix(&self) -> &str {
+        self.key_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_REDIS_KEY_PREFIX)
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.unwrap_or(DEFAULT_REDIS_TIMEOUT_MS))
+    }
+
+    fn pool_size(&self) -> usize {
+        self.pool_size.unwrap_or(DEFAULT_REDIS_POOL_SIZE)
+    }
+
+    fn retry(&self) -> Duration {
+        Duration::from_secs(self.retry_seconds.unwrap_or(DEFAULT_REDIS_RETRY_SECONDS))
+    }
+}
+
+/// A `[[rate_limit.rules]]` entry.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct RateLimitRule {
+    /// Names the rule in the admin API and its buckets in Redis: letters, digits, `-`, `_` and
+    /// `.`, other than `rule` and `tier`. Defaults to `rule:` and the rule's position, which
+    /// changes when rules are added before it.
+    pub id: Option<String>,
+    /// Only requests on the route with this name; unset matches every request.
+    pub route: Option<String>,
+    /// What requests are counted by: `ip` (the default), `api_key`, `header:<name>` or
+    /// `cookie:<name>`. Requests without the key, header or cookie are not counted by the rule.
+    pub key: Option<String>,
+    pub requests_per_second: f64,
+    pub burst: Option<u32>,
+}
+
+/// A `[rate_limit.tiers.<name>]` entry: a bucket per API key of the tier.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct RateLimitTier {
+    pub requests_per_second: f64,
+    pub burst: Option<u32>,
+}
+
+impl RateLimitConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            burst: self
+                .requests_per_second
+                .map(|rate| self.burst.unwrap_or_else(|| default_burst(rate))),
+            max_tracked: Some(self.max_tracked.unwrap_or(DEFAULT_MAX_TRACKED)),
+            rules: self
+                .rules
+                .into_iter()
+                .map(|rule| RateLimitRule {
+                    key: Some(rule.key.unwrap_or_else(|| "ip".to_string())),
+                    burst: Some(
+                        rule.burst
+                            .unwrap_or_else(|| default_burst(rule.requests_per_second)),
+                    ),
+                    ..rule
+                })
+                .collect(),
+            tiers: self
+                .tiers
+                .into_iter()
+                .map(|(name, tier)| {
+                    let burst = tier
+                        .burst
+                        .unwrap_or_else(|| default_burst(tier.requests_per_second));
+                    (
+                        name,
+                        RateLimitTier {
+                            burst: Some(burst),
+                            ..tier
+                        },
+                    )
+                })
+                .collect(),
+            redis: self.redis.map(RedisBackendConfig::with_defaults),
+            ..self
+        }
+    }
+}
+
+fn default_burst(rate: f64) -> u32 {
+    rate.ceil().max(1.0) as u32
+}
+
+/// What a rule counts requests by.
+#[derive(Debug, Clone)]
+enum RuleKey {
+    Ip,
+    ApiKey,
+    /// The API key, for keys of the named tier only.
+    Tier(String),
+    /// The `[bots] throttle` pattern the user agent matched.
+    Bot,
+    Header(String),
+    Cookie(String),
+}
+
+impl RuleKey {
+    fn parse(key: &str) -> Result<Self, String> {
+        match key.split_once(':') {
+            None if key == "ip" => Ok(Self::Ip),
+            None if key == "api_key" => Ok(Self::ApiKey),
+            None if key == "bot" => Ok(Self::Bot),
+            Some(("header", name))
+                if http::HeaderName::from_bytes(name.trim().as_bytes()).is_ok() =>
+            {
+                Ok(Self::Header(name.trim().to_ascii_lowercase()))
+            }
+            Some(("cookie", name)) if !name.trim().is_empty() => {
+                Ok(Self::Cookie(name.trim().to_string()))
+            }
+            _ => Err(format!(
+                "invalid key {key:?}: expected \"ip\", \"api_key\", \"bot\", \"header:<name>\" or \"cookie:<name>\""
+            )),
+        }
+    }
+
+    /// The value `request` is counted under, if it has one.
+    fn value(
+        &self,
+        request: &RequestHeader,
+        client: Option<IpAddr>,
+        api_key: Option<&ApiKey>,
+        bot: Option<&str>,
+    ) -> Option<String> {
+        match self {
+            Self::Ip => client.map(|ip| ip.to_string()),
+            Self::ApiKey => api_key.map(|key| key.id.clone()),
+            Self::Tier(tier) => api_key
+                .filter(|key| key.tier.as_ref() == Some(tier))
+                .map(|key| key.id.clone()),
+            Self::Bot => bot.map(str::to_string),
+            Self::Header(name) => request
+                .headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            Self::Cookie(name) => request_cookie(request, name).map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Names the rule's buckets in Redis, the same on every replica.
+    id: String,
+    route: Option<String>,
+    key: RuleKey,
+    /// `key` as configured, for the admin API.
+    key_name: String,
+    rate: f64,
+    burst: f64,
+}
+
+/// The `[rate_limit]` section, compiled.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    rules: Vec<Rule>,
+    tiers: Vec<String>,
+    exempt: IpList,
+    max_tracked: usize,
+    redis: Option<RedisBackendConfig>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        if let Some(rate) = config.requests_per_second {
+            rules.push(Rule {
+                id: "*:ip".to_string(),
+                route: None,
+                key: RuleKey::Ip,
+                key_name: "ip".to_string(),
+                rate,
+                burst: config.burst.unwrap_or_else(|| default_burst(rate)).into(),
+            });
+        }
+        for (index, rule) in config.rules.iter().enumerate() {
+            let key = rule.key.as_deref().unwrap_or("ip");
+            let id = match &rule.id {
+                Some(id) if id.is_empty() => return Err(format!("rules[{index}]: id is empty")),
+                Some(id)
+                    if !id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) =>
+                {
+                    return Err(format!(
+                        "rules[{index}]: id {id:?} may only have letters, digits, '-', '_' and '.'"
+                    ));
+                }
+                // Would share Redis keys with `rule:<n>` and `tier:<name>` buckets.
+                Some(id) if id == "rule" || id == "tier" => {
+                    return Err(format!("rules[{index}]: id {id:?} is reserved"));
+                }
+                Some(id) => id.clone(),
+                None => format!("rule:{index}"),
+            };
+            if rules.iter().any(|other: &Rule| other.id == id) {
+                return Err(format!(
+                    "rules[{index}]: id {id:?} is taken by another rule"
+                ));
+            }
+            rules.push(Rule {
+                id,
+                route: rule.route.clone(),
+                key: RuleKey::parse(key).map_err(|err| format!("rules[{index}]: {err}"))?,
+                key_name: key.to_string(),
+                rate: rule.requests_per_second,
+                burst: rule
+                    .burst
+                    .unwrap_or_else(|| default_burst(rule.requests_per_second))
+                    .into(),
+            });
+        }
+        for (name, tier) in &config.tiers {
+            rules.push(Rule {
+                id: format!("tier:{name}"),
+                route: None,
+                key: RuleKey::Tier(name.clone()),
+                key_name: "api_key".to_string(),
+                rate: tier.requests_per_second,
+                burst: tier
+                    .burst
+                    .unwrap_or_else(|| default_burst(tier.requests_per_second))
+                    .into(),
+            });
+        }
+        if rules.is_empty() {
+            return Err("set requests_per_second or add rules or tiers".to_string());
+        }
+        for rule in &rules {
+            if !(rule.rate.is_finite() && rule.rate > 0.0) {
+                return Err("requests_per_second must be a positive number".to_string());
+            }
+            if rule.burst < 1.0 {
+                return Err("burst must be at least 1".to_string());
+            }
+        }
+        Ok(Self {
+            rules,
+            tiers: config.tiers.keys().cloned().collect(),
+            exempt: IpList::parse(config.exempt.as_deref().unwrap_or_default())
+                .map_err(|err| format!("exempt: {err}"))?,
+            max_tracked: config.max_tracked.unwrap_or(DEFAULT_MAX_TRACKED),
+            redis: config.redis.clone(),
+        })
+    }
+
+    pub fn has_tier(&self, name: &str) -> bool {
+        self.tiers.iter().any(|tier| tier == name)
+    }
+
+    /// Whether a rule has the id `id`, as the admin API names rules.
+    pub fn has_rule(&self, id: &str) -> bool {
+        self.rules.iter().any(|rule| rule.id == id)
+    }
+
+    /// Whether a rule limits throttled bots.
+    pub fn limits_bots(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule.key, RuleKey::Bot))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    /// Position in `LocalBuckets::by_age`.
+    age: u64,
+}
+
+/// The in-memory buckets, with their keys in the order they were last used so the oldest
+/// can be evicted once `max_tracked` are held.
+#[derive(Default)]
+struct LocalBuckets {
+    buckets: HashMap<(usize, String), Bucket>,
+    by_age: BTreeMap<u64, (usize, String)>,
+    next_age: u64,
+    swept: Option<Instant>,
+}
+
+impl LocalBuckets {
+    /// Makes room for a new key: drops the buckets that refilled, at most once per
+    /// `SWEEP_INTERVAL`, then the least recently used while still full.
+    fn make_room(&mut self, rates: &[(f64, f64)], max_tracked: usize, now: Instant) {
+        if self
+            .swept
+            .is_none_or(|swept| now.duration_since(swept) >= SWEEP_INTERVAL)
+        {
+            self.swept = Some(now);
+            // A bucket that refilled is the same as a new one.
+            self.buckets.retain(|(index, _), bucket| {
+                rates.get(*index).is_some_and(|(rate, burst)| {
+                    bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate
+                        < *burst
+                })
+            });
+            let buckets = &self.buckets;
+            self.by_age.retain(|_, key| buckets.contains_key(key));
+        }
+        while self.buckets.len() >= max_tracked.max(1) {
+            let Some((_, key)) = self.by_age.pop_first() else {
+                break;
+            };
+            self.buckets.remove(&key);
+        }
+    }
+
+    /// The bucket of `key`, full when new, marked as the most recently used.
+    fn touch(&mut self, key: &(usize, String), burst: f64, now: Instant) -> &mut Bucket {
+        let age = self.next_age;
+        self.next_age += 1;
+        self.by_age.insert(age, key.clone());
+        let bucket = self.buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+            age,
+        });
+        if bucket.age != age {
+            self.by_age.remove(&bucket.age);
+            bucket.age = age;
+        }
+        bucket
+    }
+}
+
+/// Token buckets kept in Redis, so every replica draws from the same ones: takes a token
+/// from the bucket in each of `KEYS`, with the rate and burst of the i-th in `ARGV[2i - 1]`
+/// and `ARGV[2i]`. Uses the server's clock; returns the seconds until each bucket has a
+/// token, 0 for those one was taken from, separated by spaces.
+const REDIS_TOKEN_BUCKET: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local waits = {}
+for i, key in ipairs(KEYS) do
+  local rate = tonumber(ARGV[2 * i - 1])
+  local burst = tonumber(ARGV[2 * i])
+  local bucket = redis.call('HMGET', key, 'tokens', 'refilled')
+  local tokens = tonumber(bucket[1]) or burst
+  local refilled = tonumber(bucket[2]) or now
+  tokens = math.min(burst, tokens + math.max(0, now - refilled) * rate)
+  local wait = 0
+  if tokens >= 1 then
+    tokens = tokens - 1
+  else
+    wait = (1 - tokens) / rate
+  end
+  redis.call('HSET', key, 'tokens', tostring(tokens), 'refilled', tostring(now))
+  redis.call('PEXPIRE', key, math.ceil(burst / rate * 1000) + 1000)
+  waits[i] = tostring(wait)
+end
+return table.concat(waits, ' ')
+"#;
+
+/// The shared backend of `[rate_limit.redis]`, with the time until it is tried again
+/// after a failure.
+struct RedisBackend {
+    config: RedisBackendConfig,
+    client: RedisClient,
+    /// SHA1 of `REDIS_TOKEN_BUCKET` once the server has it.
+    script: Mutex<Option<String>>,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl RedisBackend {
+    fn new(config: &RedisBackendConfig) -> Self {
+        Self {
+            client: RedisClient::new(
+                &config.addr,
+                config.password.as_deref(),
+                config.db,
+                config.timeout(),
+                config.pool_size(),
+            ),
+            config: config.clone(),
+            script: Mutex::default(),
+            down_until: Mutex::default(),
+        }
+    }
+
+    fn available(&self) -> bool {
+        self.down_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    /// Takes a token from the shared bucket of every matched rule, in one call; returns the
+    /// position in `matched` of those without one, with the time until they have one.
+    async fn take(
+        &self,
+        limits: &RateLimits,
+        rates: &[(f64, f64)],
+        matched: &[(usize, String)],
+    ) -> Result<Vec<(usize, Duration)>, String> {
+        let mut keys = Vec::new();
+        let mut rate_args = Vec::new();
+        for (index, value) in matched {
+            let (rate, burst) = rates[*index];
+            keys.push(format!(
+                "{}{}:{value}",
+                self.config.key_prefix(),
+                limits.rules[*index].id
+            ));
+            rate_args.extend([rate.to_string(), burst.to_string()]);
+        }
+        let count = keys.len().to_string();
+        let mut args = vec![count.as_bytes()];
+        args.extend(keys.iter().map(|key| key.as_bytes()));
+        args.extend(rate_args.iter().map(|arg| arg.as_bytes()));
+        let reply = self.run_script(&args).await?;
+        let parsed: Option<Vec<f64>> = reply
+            .as_deref()
+            .and_then(|reply| std::str::from_utf8(reply).ok())
+            .and_then(|reply| reply.split(' ').map(|wait| wait.parse().ok()).collect());
+        let waits = parsed
+            .filter(|waits| waits.len() == matched.len())
+            .ok_or_else(|| format!("unexpected reply {reply:?}"))?;
+        let waits = waits
+            .into_iter()
+            .enumerate()
+            .filter(|(_, wait)| *wait > 0.0)
+            .map(|(position, wait)| (position, Duration::from_secs_f64(wait)))
+            .collect();
+        let mut down_until = self
+            .down_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if down_until.take().is_some() {
+            info!(
+                "rate limit backend {} is reachable again, sharing limits",
+                self.config.addr
+            );
+        }
+        Ok(waits)
+    }
+
+    /// Runs `REDIS_TOKEN_BUCKET` with `args` by its SHA1, loading it first when the server
+    /// doesn't have it, e.g. after a restart.
+    async fn run_script(&self, args: &[&[u8]]) -> Result<Option<Vec<u8>>, String> {
+        let loaded = self
+            .script
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        let sha = match loaded {
+            Some(sha) => sha,
+            None => self.load_script().await?,
+        };
+        let mut command = vec![b"EVALSHA".as_slice(), sha.as_bytes()];
+        command.extend_from_slice(args);
+        match self.client.command(&command).await {
+            Err(err) if err.contains("NOSCRIPT") => {
+                let sha = self.load_script().await?;
+                command[1] = sha.as_bytes();
+                self.client.command(&command).await
+            }
+            reply => reply,
+        }
+    }
+
+    async fn load_script(&self) -> Result<String, String> {
+        let reply = self
+            .client
+            .command(&[b"SCRIPT", b"LOAD", REDIS_TOKEN_BUCKET.as_bytes()])
+            .await?;
+        let sha = reply
+            .and_then(|sha| String::from_utf8(sha).ok())
+            .ok_or("SCRIPT LOAD returned no SHA1")?;
+        *self
+            .script
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(sha.clone());
+        Ok(sha)
+    }
+
+    fn failed(&self, err: &str) {
+        let mut down_until = self
+            .down_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if down_until.is_none() {
+            warn!(
+                "rate limit backend {} failed, limiting locally: {err}",
+                self.config.addr
+            );
+        }
+        *down_until = Some(Instant::now() + self.config.retry());
+    }
+}
+
+/// Token buckets per rule and key, kept in memory or, with `[rate_limit.redis]`, in Redis
+/// with the in-memory buckets as fallback.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<LocalBuckets>,
+    redis: Mutex<Option<Arc<RedisBackend>>>,
+    /// Limits set through the admin API in place of the configured ones, by rule id; kept
+    /// across config reloads.
+    overrides: Mutex<HashMap<String, RateOverride>>,
+    /// Requests refused, by rule id and key, for the admin API's top offenders.
+    refused: Mutex<HashMap<(String, String), u64>>,
+}
+
+/// A rule's limit set through the admin API, until a set time or until cleared.
+#[derive(Debug, Clone, Copy)]
+struct RateOverride {
+    rate: f64,
+    burst: f64,
+    until: Option<SystemTime>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the bucket of every rule `request` matches, or returns how long
+    /// until all of them have one available.
+    pub async fn check(
+        &self,
+        limits: &RateLimits,
+        request: &RequestHeader,
+        client: Option<IpAddr>,
+        route: Option<&str>,
+        api_key: Option<&ApiKey>,
+        bot: Option<&str>,
+    ) -> Result<(), Duration> {
+        if client.is_some_and(|ip| limits.exempt.contains(&ip)) {
+            return Ok(());
+        }
+        let matched: Vec<(usize, String)> = limits
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.route.as_deref().is_none_or(|name| Some(name) == route))
+            .filter_map(|(index, rule)| {
+                Some((index, rule.key.value(request, client, api_key, bot)?))
+            })
+            .collect();
+        if matched.is_empty() {
+            return Ok(());
+        }
+
+        let rates = self.rates(limits);
+        let mut waits = None;
+        if let Some(backend) = self.redis_backend(limits)
+            && backend.available()
+        {
+            match backend.take(limits, &rates, &matched).await {
+                Ok(shared) => waits = Some(shared),
+                Err(err) => backend.failed(&err),
+            }
+        }
+        let waits = match waits {
+            Some(waits) => waits,
+            None => self.take_local(&rates, limits.max_tracked, &matched),
+        };
+        let Some(retry_after) = waits.iter().map(|(_, wait)| *wait).max() else {
+            return Ok(());
+        };
+        self.count_refused(
+            limits,
+            waits.iter().map(|(position, _)| &matched[*position]),
+        );
+        Err(retry_after)
+    }
+
+    /// The rate and burst of each rule of `limits`, overrides applied; expired overrides are
+    /// dropped.
+    fn rates(&self, limits: &RateLimits) -> Vec<(f64, f64)> {
+        let mut overrides = self
+            .overrides
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !overrides.is_empty() {
+            let now = SystemTime::now();
+            overrides.retain(|_, limit| limit.until.is_none_or(|until| until > now));
+        }
+        limits
+            .rules
+            .iter()
+            .map(|rule| match overrides.get(&rule.id) {
+                Some(limit) => (limit.rate, limit.burst),
+                None => (rule.rate, rule.burst),
+            })
+            .collect()
+    }
+
+    fn count_refused<'a>(
+        &self,
+        limits: &RateLimits,
+        refused: impl Iterator<Item = &'a (usize, String)>,
+    ) {
+        let mut counts = self
+            .refused
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (index, value) in refused {
+            let key = (limits.rules[*index].id.clone(), value.clone());
+            if counts.len() >= limits.max_tracked && !counts.contains_key(&key) {
+                // Counting starts over rather than growing without bound.
+                counts.clear();
+            }
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+
+    /// Sets the limit of the rule `id` to `rate` and `burst` (one second's worth by default)
+    /// for `duration`, or until cleared; returns when it ends.
+    pub fn set_override(
+        &self,
+        id: &str,
+        rate: f64,
+        burst: Option<u32>,
+        duration: Option<Duration>,
+    ) -> Result<Option<SystemTime>, String> {
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err("requests_per_second must be a positive number".to_string());
+        }
+        let burst = burst.unwrap_or_else(|| default_burst(rate));
+        if burst < 1 {
+            return Err("burst must be at least 1".to_string());
+        }
+        let until = duration.map(|duration| SystemTime::now() + duration);
+        self.overrides
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(
+                id.to_string(),
+                RateOverride {
+                    rate,
+                    burst: burst.into(),
+                    until,
+                },
+            );
+        Ok(until)
+    }
+
+    /// Puts the rule `id` back to its configured limit; returns whether it was overridden.
+    pub fn clear_override(&self, id: &str) -> bool {
+        let now = SystemTime::now();
+        self.overrides
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(id)
+            .is_some_and(|limit| limit.until.is_none_or(|until| until > now))
+    }
+
+    /// The rules with their limits, and the `top` keys refused most with the tokens left in
+    /// their local bucket, for the admin API.
+    pub fn report(&self, limits: &RateLimits, top: usize) -> serde_json::Value {
+        let rates = self.rates(limits);
+        let overrides = self
+            .overrides
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        let rules: Vec<_> = limits
+            .rules
+            .iter()
+            .map(|rule| {
+                json!({
+                    "id": rule.id,
+                    "route": rule.route,
+                    "key": rule.key_name,
+                    "requests_per_second": rule.rate,
+                    "burst": rule.burst,
+                    "override": overrides.get(&rule.id).map(|limit| json!({
+                        "requests_per_second": limit.rate,
+                        "burst": limit.burst,
+                        "until": limit.until.map(timestamp),
+                    })),
+                })
+            })
+            .collect();
+
+        let mut offenders: Vec<((String, String), u64)> = self
+            .refused
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect();
+        offenders.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        offenders.truncate(top);
+        let now = Instant::now();
+        let buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let offenders: Vec<_> = offenders
+            .into_iter()
+            .map(|((id, value), refused)| {
+                let tokens = limits
+                    .rules
+                    .iter()
+                    .position(|rule| rule.id == id)
+                    .and_then(|index| {
+                        let (rate, burst) = rates[index];
+                        let bucket = buckets.buckets.get(&(index, value.clone()))?;
+                        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+                        Some((bucket.tokens + elapsed * rate).min(burst))
+                    });
+                json!({ "rule": id, "key": value, "refused": refused, "tokens": tokens })
+            })
+            .collect();
+
+        let backend = match self
+            .redis
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+        {
+            Some(backend) if backend.available() => "redis",
+            Some(_) => "memory (redis unreachable)",
+            None => "memory",
+        };
+        json!({
+            "backend": backend,
+            "rules": rules,
+            "tracked_buckets": buckets.buckets.len(),
+            "top_offenders": offenders,
+        })
+    }
+
+    /// The Redis backend for `limits`, reconnecting when its settings were reloaded.
+    fn redis_backend(&self, limits: &RateLimits) -> Option<Arc<RedisBackend>> {
+        let mut redis = self
+            .redis
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match (&limits.redis, redis.as_ref()) {
+            (None, _) => {
+                *redis = None;
+                None
+            }
+            (Some(config), Some(backend)) if backend.config == *config => Some(backend.clone()),
+            (Some(config), _) => {
+                let backend = Arc::new(RedisBackend::new(config));
+                *redis = Some(backend.clone());
+                Some(backend)
+            }
+        }
+    }
+
+    /// Takes a token from the local bucket of every matched rule; returns the position in
+    /// `matched` of those without one, with the time until they have one.
+    fn take_local(
+        &self,
+        rates: &[(f64, f64)],
+        max_tracked: usize,
+        matched: &[(usize, String)],
+    ) -> Vec<(usize, Duration)> {
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut waits = Vec::new();
+        for (position, key) in matched.iter().enumerate() {
+            let (rate, burst) = rates[key.0];
+            if buckets.buckets.len() >= max_tracked && !buckets.buckets.contains_key(key) {
+                buckets.make_room(rates, max_tracked, now);
+            }
+            let bucket = buckets.touch(key, burst, now);
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.refilled).as_secs_f64() * rate)
+                .min(burst);
+            bucket.refilled = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+            } else {
+                waits.push((
+                    position,
+                    Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+                ));
+            }
+        }
+        waits
+    }
+}
+
+/// The `429 Too Many Requests` response telling the client when to retry.
+pub fn too_many_requests(
+    retry_after: Duration,
+    request_id: &str,
+) -> pingora::Result<(ResponseHeader, Bytes)> {
+    let (mut header, body) = refusal_response(429, request_id)?;
+    header.insert_header(
+        RETRY_AFTER,
+        retry_after.as_secs_f64().ceil().max(1.0) as u64,
+    )?;
+    Ok((header, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(rules: &str) -> Result<RateLimits, String> {
+        let config: RateLimitConfig =
+            toml::from_str(&format!("requests_per_second = 10\n{rules}")).unwrap();
+        RateLimits::new(&config)
+    }
+
+    #[test]
+    fn rules_get_ids_of_their_own() {
+        // Same route and key, which used to share one id and its buckets.
+        let limits = limits(
+            "[[rules]]\nroute = \"api\"\nrequests_per_second = 1\n\
+             [[rules]]\nroute = \"api\"\nrequests_per_second = 100\n\
+             [[rules]]\nid = \"api-by-key\"\nroute = \"api\"\nkey = \"api_key\"\n\
+             requests_per_second = 5\n",
+        )
+        .unwrap();
+        let ids: Vec<_> = limits.rules.iter().map(|rule| rule.id.as_str()).collect();
+        assert_eq!(ids, ["*:ip", "rule:0", "rule:1", "api-by-key"]);
+        assert!(limits.has_rule("rule:1"));
+        assert!(!limits.has_rule("api:ip"));
+    }
+
+    #[test]
+    fn refuses_ids_that_clash() {
+        for (rules, error) in [
+            (
+                "[[rules]]\nid = \"a\"\nrequests_per_second = 1\n\
+                 [[rules]]\nid = \"a\"\nrequests_per_second = 2\n",
+                "rules[1]: id \"a\" is taken",
+            ),
+            (
+                "[[rules]]\nrequests_per_second = 1\n\
+                 [[rules]]\nid = \"rule:0\"\nrequests_per_second = 2\n",
+                "rules[1]: id \"rule:0\" may only have",
+            ),
+            (
+                "[[rules]]\nid = \"tier\"\nrequests_per_second = 1\n",
+                "is reserved",
+            ),
+            (
+                "[[rules]]\nid = \"\"\nrequests_per_second = 1\n",
+                "id is empty",
+            ),
+        ] {
+            let err = limits(rules).err().unwrap();
+            assert!(err.contains(error), "{err}");
+        }
+    }
+}
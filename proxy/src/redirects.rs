@@ -0,0 +1,68 @@
+use http::Uri;
+use http::header::LOCATION;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+
+/// Public coordinates of a request, used to map upstream URLs back for the client.
+pub struct PublicOrigin<'a> {
+    pub scheme: &'a str,
+    pub host: &'a str,
+    /// Route prefix removed before proxying, restored on the way back.
+    pub stripped_prefix: Option<&'a str>,
+}
+
+/// Rewrites a `Location` header that points at the upstream's internal address.
+///
+/// Absolute URLs whose authority matches `upstream_addr` (with or without the
+/// port) get the public scheme and host; when a route prefix was stripped it
+/// is put back in front of the path, for absolute and root-relative targets.
+pub fn rewrite_location(
+    response: &mut ResponseHeader,
+    upstream_addr: &str,
+    public: &PublicOrigin<'_>,
+) -> Result<()> {
+    let Some(location) = response
+        .headers
+        .get(LOCATION)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Ok(());
+    };
+
+    let rewritten = if location.starts_with('/') && !location.starts_with("//") {
+        public
+            .stripped_prefix
+            .map(|prefix| format!("{prefix}{location}"))
+    } else {
+        location.parse::<Uri>().ok().and_then(|uri| {
+            let authority = uri.authority()?;
+            if !points_at_upstream(authority.as_str(), upstream_addr) {
+                return None;
+            }
+            let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+            Some(format!(
+                "{}://{}{}{}",
+                public.scheme,
+                public.host,
+                public.stripped_prefix.unwrap_or_default(),
+                path_and_query
+            ))
+        })
+    };
+
+    if let Some(rewritten) = rewritten {
+        response.insert_header(LOCATION, rewritten)?;
+    }
+    Ok(())
+}
+
+fn points_at_upstream(authority: &str, upstream_addr: &str) -> bool {
+    if authority.eq_ignore_ascii_case(upstream_addr) {
+        return true;
+    }
+    let upstream_host = upstream_addr
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(upstream_addr);
+    authority.eq_ignore_ascii_case(upstream_host)
+}
@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// Minimal Redis client speaking RESP over a small pool of TCP connections, for the few
+/// commands the proxy needs: replies are single values, arrays are not supported. A
+/// connection that fails is dropped and reopened on next use.
+pub struct RedisClient {
+    addr: String,
+    password: Option<String>,
+    db: Option<u32>,
+    timeout: Duration,
+    connections: Vec<Mutex<Option<BufStream<TcpStream>>>>,
+    next: AtomicUsize,
+}
+
+impl RedisClient {
+    pub fn new(
+        addr: &str,
+        password: Option<&str>,
+        db: Option<u32>,
+        timeout: Duration,
+        pool_size: usize,
+    ) -> Self {
+        Self {
+            addr: addr.to_string(),
+            password: password.map(str::to_string),
+            db,
+            timeout,
+            connections: (0..pool_size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sends one command and waits for its reply, failing after the timeout. Status and
+    /// integer replies are returned as their text; `None` is a nil reply.
+    pub async fn command(&self, args: &[&[u8]]) -> Result<Option<Vec<u8>>, String> {
+        let slot =
+            &self.connections[self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len()];
+        let mut connection = slot.lock().await;
+        let result = tokio::time::timeout(self.timeout, async {
+            if connection.is_none() {
+                *connection = Some(self.connect().await?);
+            }
+            let stream = connection.as_mut().expect("connection was just opened");
+            round_trip(stream, args).await
+        })
+        .await
+        .unwrap_or_else(|_| Err(format!("no reply within {:?}", self.timeout)));
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>, String> {
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(|err| format!("failed to connect to {}: {err}", self.addr))?;
+        stream.set_nodelay(true).map_err(|err| err.to_string())?;
+        let mut stream = BufStream::new(stream);
+        if let Some(password) = &self.password {
+            round_trip(&mut stream, &[b"AUTH", password.as_bytes()]).await?;
+        }
+        if let Some(db) = self.db {
+            round_trip(&mut stream, &[b"SELECT", db.to_string().as_bytes()]).await?;
+        }
+        Ok(stream)
+    }
+}
+
+async fn round_trip(
+    stream: &mut BufStream<TcpStream>,
+    args: &[&[u8]],
+) -> Result<Option<Vec<u8>>, String> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|err| err.to_string())?;
+    stream.flush().await.map_err(|err| err.to_string())?;
+    read_reply(stream).await
+}
+
+async fn read_reply(stream: &mut BufStream<TcpStream>) -> Result<Option<Vec<u8>>, String> {
+    let mut line = String::new();
+    let read = stream
+        .read_line(&mut line)
+        .await
+        .map_err(|err| err.to_string())?;
+    if read == 0 {
+        return Err("connection closed".to_string());
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (kind, rest) = line.split_at_checked(1).ok_or("empty reply line")?;
+    match kind {
+        "+" | ":" => Ok(Some(rest.as_bytes().to_vec())),
+        "-" => Err(format!("redis error: {rest}")),
+        "$" => {
+            let Ok(len) = rest.parse::<usize>() else {
+                // `$-1` is a nil reply.
+                return Ok(None);
+            };
+            let mut data = vec![0; len + 2];
+            stream
+                .read_exact(&mut data)
+                .await
+                .map_err(|err| err.to_string())?;
+            data.truncate(len);
+            Ok(Some(data))
+        }
+        _ => Err(format!("unsupported reply {line:?}")),
+    }
+}
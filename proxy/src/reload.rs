@@ -0,0 +1,254 @@
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde_json::json;
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::audit;
+use crate::build_info;
+use crate::config::{Config, ConfigSource, DEFAULT_LOG_LEVEL};
+use crate::log_control;
+use crate::state::{ProxyState, SharedState};
+
+/// Compares the `Debug` rendering of config fields, yielding a label per changed group.
+macro_rules! changed {
+    ($old:expr, $new:expr, $($label:literal => [$($field:ident),+]),+ $(,)?) => {{
+        let mut changed = Vec::new();
+        $(
+            if format!("{:?}", ($(&$old.$field,)+)) != format!("{:?}", ($(&$new.$field,)+)) {
+                changed.push($label);
+            }
+        )+
+        changed
+    }};
+}
+
+/// Re-reads the config file and swaps the reloadable parts of the proxy state.
+#[derive(Clone)]
+pub struct ConfigReloader {
+    /// `None` for a config built in code, which has no file to reload.
+    source: Option<ConfigSource>,
+    state: SharedState,
+}
+
+impl ConfigReloader {
+    pub fn new(source: Option<ConfigSource>, state: SharedState) -> Self {
+        Self { source, state }
+    }
+
+    /// Loads the config and installs it, keeping the current state on any error.
+    ///
+    /// Requests already in flight finish with the state they started with.
+    pub fn reload(&self) -> Result<(), String> {
+        let Some(source) = &self.source else {
+            return Err("the config was built in code, not loaded from a file".to_string());
+        };
+        let mut config = Config::load(source).map_err(|err| err.to_string())?;
+        let current = self.state.current();
+        config
+            .keep_managed_routes(&current.config)
+            .map_err(|err| err.to_string())?;
+        let next = ProxyState::build(config, Some(&current), current.filters.clone())?;
+
+        let old = &current.config;
+        let new = &next.config;
+        let reloaded = changed!(old, new,
+            "upstream_addr" => [upstream_addr],
+            "upstream_proxy_protocol" => [upstream_proxy_protocol],
+            "parent_proxy" => [parent_proxy],
+            "rewrites" => [rewrites],
+            "routes" => [routes],
+            "cors" => [cors],
+            "headers" => [headers],
+            "filters" => [filters],
+            "wasm_plugins" => [wasm_plugins],
+            "static assets" => [
+                static_root,
+                static_mount,
+                static_index_file,
+                static_manifest,
+                static_default_cache_seconds,
+                static_immutable_cache_seconds,
+                static_keepalive_seconds,
+                static_memory_cache_mb,
+                static_memory_cache_max_object_kb,
+                static_memory_cache_promote_hits,
+                static_markdown,
+                static_markdown_template
+            ],
+            "trusted_proxies" => [trusted_proxies, client_ip_header, trust_unix_peers],
+            "security_headers" => [security_headers, security_header_overrides],
+            "server_header" => [server_header],
+            "cookies" => [cookies],
+            "rewrite_location" => [rewrite_location],
+            "via_token" => [via_token],
+            "access_log" => [access_log],
+            "error_detail" => [error_detail, error_pages],
+            "alerts" => [alerts],
+            "rate_limit" => [rate_limit],
+            "access_control" => [access_control, geoip],
+            "oidc" => [oidc],
+            "limits" => [limits],
+            "waf" => [waf],
+            "bots" => [bots],
+            "csrf" => [csrf],
+            "websocket" => [websocket],
+            "compression" => [compression],
+            "html_inject" => [html_inject],
+            "substitutions" => [substitutions],
+            "images" => [images],
+            "forward_proxy" => [forward_proxy],
+            "proxy_protocol" => [proxy_protocol],
+            "admin" => [admin],
+            "maintenance" => [maintenance],
+            "doh" => [doh],
+            "tcp_routes" => [tcp_routes],
+            "log_level" => [log_level, debug_log_level],
+        );
+        let mut restart_only = changed!(old, new,
+            "listen_addr" => [listen_addr],
+            "listen_unix" => [listen_unix, listen_unix_mode],
+            "listener" => [listener],
+            "threads" => [threads],
+            "health" => [health],
+            "status" => [status],
+            "metrics" => [metrics],
+            "capture" => [capture],
+            "audit_log" => [audit_log],
+            "clients" => [clients],
+            "statsd" => [statsd],
+            "syslog" => [syslog],
+            "sentry" => [sentry],
+            "grace_period_seconds" => [grace_period_seconds],
+            "graceful_shutdown_timeout_seconds" => [graceful_shutdown_timeout_seconds],
+            "static_manifest_poll_seconds" => [static_manifest_poll_seconds],
+            "static_self_test" => [static_self_test],
+        );
+        // Listeners are bound at startup; the rest of these sections reloads.
+        let forward_proxy_addr = |config: &Config| {
+            config
+                .forward_proxy
+                .as_ref()
+                .map(|forward_proxy| forward_proxy.listen_addr.clone())
+        };
+        if forward_proxy_addr(old) != forward_proxy_addr(new) {
+            restart_only.push("forward_proxy.listen_addr");
+        }
+        let proxy_protocol_addr = |config: &Config| {
+            config
+                .proxy_protocol
+                .as_ref()
+                .map(|proxy_protocol| proxy_protocol.listen_addr.clone())
+        };
+        if proxy_protocol_addr(old) != proxy_protocol_addr(new) {
+            restart_only.push("proxy_protocol.listen_addr");
+        }
+        let admin_addr =
+            |config: &Config| config.admin.as_ref().map(|admin| admin.listen_addr.clone());
+        if admin_addr(old) != admin_addr(new) {
+            restart_only.push("admin.listen_addr");
+        }
+        let admin_tls = |config: &Config| {
+            config
+                .admin
+                .as_ref()
+                .is_some_and(|admin| admin.tls.is_some())
+        };
+        if admin_tls(old) != admin_tls(new) {
+            restart_only.push("admin.tls");
+        }
+        if !current
+            .tcp_routes
+            .listen_addrs()
+            .eq(next.tcp_routes.listen_addrs())
+        {
+            restart_only.push("tcp_routes.listen_addr");
+        }
+
+        let config_hash = build_info::config_hash(new);
+        let log_level = (old.log_level != new.log_level).then(|| new.log_level.clone());
+        self.state.replace(next);
+        if let Some(log_level) = log_level {
+            log_control::set_configured(log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL))?;
+        }
+
+        audit::record(
+            "config_reload",
+            json!({
+                "path": source.path.display().to_string(),
+                "changed": reloaded,
+                "restart_required": restart_only,
+                "config_hash": config_hash,
+            }),
+        );
+        if reloaded.is_empty() {
+            info!(
+                "config reloaded from {}: no changes (hash {config_hash})",
+                source.path.display()
+            );
+        } else {
+            info!(
+                "config reloaded from {}: changed {} (hash {config_hash})",
+                source.path.display(),
+                reloaded.join(", ")
+            );
+        }
+        if !restart_only.is_empty() {
+            warn!(
+                "config changes to {} take effect only after a restart",
+                restart_only.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Background service that reloads the config whenever the process gets `SIGHUP`.
+pub struct SighupReloadService {
+    reloader: ConfigReloader,
+}
+
+impl SighupReloadService {
+    pub fn new(reloader: ConfigReloader) -> Self {
+        Self { reloader }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for SighupReloadService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(source) = &self.reloader.source else {
+            return;
+        };
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                error!("failed to install SIGHUP handler, config reload disabled: {err}");
+                return;
+            }
+        };
+        info!("send SIGHUP to reload {}", source.path.display());
+        loop {
+            tokio::select! {
+                _ = hangups.recv() => {
+                    info!("SIGHUP received, reloading config");
+                    if let Err(err) = self.reloader.reload() {
+                        error!("config reload failed, keeping the current config: {err}");
+                        audit::record(
+                            "config_reload_failed",
+                            json!({
+                                "path": source.path.display().to_string(),
+                                "error": err,
+                            }),
+                        );
+                    }
+                }
+                _ = shutdown.changed() => {
+                    info!("config reload watcher shutting down");
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pingora::http::RequestHeader;
+
+/// Header carrying the request ID to the upstream and back to the client.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest client-supplied request ID that is kept rather than replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+/// The client's `X-Request-Id` when it is a plain token, otherwise a new ID.
+pub fn request_id(request: &RequestHeader) -> String {
+    request
+        .headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_token(value))
+        .map_or_else(generate, str::to_string)
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// A process-unique ID: a per-process prefix followed by a request counter.
+fn generate() -> String {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        nanos ^ std::process::id().rotate_left(16)
+    });
+    let count = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+    format!("{prefix:08x}-{count:010x}")
+}
@@ -0,0 +1,66 @@
+use http::header::SERVER;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+
+use crate::cors::CorsPolicy;
+use crate::headers::HeaderEdits;
+use crate::security_headers::SecurityHeaders;
+
+/// What to do with the `Server` header on outgoing responses.
+#[derive(Clone, Debug, Default)]
+pub enum ServerHeader {
+    /// Leave whatever the upstream sent.
+    #[default]
+    Passthrough,
+    Remove,
+    Override(String),
+}
+
+impl ServerHeader {
+    /// Maps the `server_header` config value: unset passes through, `""` removes.
+    pub fn from_config(value: Option<&str>) -> Self {
+        match value {
+            None => ServerHeader::Passthrough,
+            Some("") => ServerHeader::Remove,
+            Some(value) => ServerHeader::Override(value.to_string()),
+        }
+    }
+}
+
+/// Response-wide policies applied to both proxied and static responses.
+#[derive(Clone, Debug)]
+pub struct ResponsePolicy {
+    pub cors: CorsPolicy,
+    pub security_headers: SecurityHeaders,
+    pub headers: HeaderEdits,
+    pub server_header: ServerHeader,
+}
+
+impl ResponsePolicy {
+    pub fn apply(&self, request: &RequestHeader, response: &mut ResponseHeader) -> Result<()> {
+        self.cors.apply(request, response)?;
+        self.security_headers.apply(response)?;
+        self.headers.apply_response(response)?;
+        self.apply_server_header(response)
+    }
+
+    /// The security headers and `Server` header alone, for proxied responses, whose CORS and
+    /// header rules are left to their filter chain.
+    pub fn apply_security(&self, response: &mut ResponseHeader) -> Result<()> {
+        self.security_headers.apply(response)?;
+        self.apply_server_header(response)
+    }
+
+    fn apply_server_header(&self, response: &mut ResponseHeader) -> Result<()> {
+        match &self.server_header {
+            ServerHeader::Passthrough => {}
+            ServerHeader::Remove => {
+                response.remove_header(&SERVER);
+            }
+            ServerHeader::Override(value) => {
+                response.insert_header(SERVER, value.as_str())?;
+            }
+        }
+        Ok(())
+    }
+}
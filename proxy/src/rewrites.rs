@@ -0,0 +1,440 @@
+use std::collections::BTreeMap;
+
+use http::Method;
+use pingora::http::RequestHeader;
+use regex::{Captures, Regex};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A `[[rewrites]]` rule: changes the path and query of matching requests before they are
+/// routed, or redirects them.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct RewriteConfig {
+    /// Regular expression the path must match; unset matches every path. Its captures are
+    /// available to `to` and `set_query` as `$1` or `$name`.
+    pub pattern: Option<String>,
+    /// The new path, optionally with a query that goes before the request's own parameters.
+    /// With `redirect` it may be an absolute URL.
+    pub to: Option<String>,
+    /// Only requests for this host, without the port.
+    pub host: Option<String>,
+    /// Only requests with one of these methods.
+    pub methods: Option<Vec<String>>,
+    /// Only requests with these headers, their values matching the regular expressions.
+    #[serde(default)]
+    pub if_headers: BTreeMap<String, String>,
+    /// Only requests with these query parameters, their values matching the regular
+    /// expressions.
+    #[serde(default)]
+    pub if_query: BTreeMap<String, String>,
+    /// Query parameters set, replacing any the request has.
+    #[serde(default)]
+    pub set_query: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove_query: Vec<String>,
+    /// Query parameters renamed, from the key to the value.
+    #[serde(default)]
+    pub rename_query: BTreeMap<String, String>,
+    /// Answers with a redirect to the rewritten URL, with this status (301, 302, 303, 307
+    /// or 308), instead of proxying it.
+    pub redirect: Option<u16>,
+    /// Stops at this rule when it applies; later rules see the rewritten request otherwise.
+    #[serde(default)]
+    pub last: bool,
+}
+
+/// The compiled `[[rewrites]]` rules.
+#[derive(Debug, Default)]
+pub struct Rewrites {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Option<Regex>,
+    to: Option<String>,
+    host: Option<String>,
+    methods: Option<Vec<Method>>,
+    if_headers: Vec<(String, Regex)>,
+    if_query: Vec<(String, Regex)>,
+    set_query: Vec<(String, String)>,
+    remove_query: Vec<String>,
+    rename_query: Vec<(String, String)>,
+    redirect: Option<u16>,
+    last: bool,
+}
+
+/// What the rules made of a request.
+#[derive(Debug)]
+pub enum Rewritten {
+    /// Proxy it with this path and query.
+    Request(String),
+    /// Answer with a redirect to this location.
+    Redirect(u16, String),
+}
+
+impl RewriteConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        Rule::new(self).map(drop)
+    }
+}
+
+impl Rewrites {
+    pub fn new(configs: &[RewriteConfig]) -> Result<Self, String> {
+        let rules = configs
+            .iter()
+            .enumerate()
+            .map(|(index, config)| {
+                Rule::new(config).map_err(|message| format!("rewrites[{index}]: {message}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Applies the rules to `request`, addressed to `host`; `None` if none applied.
+    pub fn apply(&self, request: &RequestHeader, host: Option<&str>) -> Option<Rewritten> {
+        let host = host.map(|host| {
+            host.rsplit_once(':')
+                .filter(|(_, port)| port.bytes().all(|byte| byte.is_ascii_digit()))
+                .map_or(host, |(name, _)| name)
+        });
+        let mut path = request.uri.path().to_string();
+        let mut query = request.uri.query().map(str::to_string);
+        let mut applied = false;
+        for rule in &self.rules {
+            let Some(captures) = rule.matches(request, host, &path, query.as_deref()) else {
+                continue;
+            };
+            applied = true;
+            let (new_path, new_query) = rule.rewrite(&captures, &path, query.as_deref());
+            drop(captures);
+            path = new_path;
+            query = new_query;
+            if let Some(status) = rule.redirect {
+                return Some(Rewritten::Redirect(status, join(&path, query.as_deref())));
+            }
+            if rule.last {
+                break;
+            }
+        }
+        applied.then(|| Rewritten::Request(join(&path, query.as_deref())))
+    }
+}
+
+impl Rule {
+    fn new(config: &RewriteConfig) -> Result<Self, String> {
+        let regex = |field: &str, pattern: &str| {
+            Regex::new(pattern).map_err(|err| format!("{field} is not a valid regex: {err}"))
+        };
+        let pattern = config
+            .pattern
+            .as_deref()
+            .map(|pattern| regex("pattern", pattern))
+            .transpose()?;
+        let absolute = config
+            .to
+            .as_deref()
+            .is_some_and(|to| to.starts_with("http://") || to.starts_with("https://"));
+        match (&config.to, config.redirect) {
+            (Some(to), None) if !to.starts_with('/') => {
+                return Err(format!("to {to:?} must be a path starting with '/'"));
+            }
+            (Some(to), Some(_)) if !to.starts_with('/') && !absolute => {
+                return Err(format!("to {to:?} must be a path or an absolute URL"));
+            }
+            (_, Some(status)) if ![301, 302, 303, 307, 308].contains(&status) => {
+                return Err(format!("redirect {status} is not a redirect status"));
+            }
+            _ => {}
+        }
+        let methods = config
+            .methods
+            .as_ref()
+            .map(|methods| {
+                methods
+                    .iter()
+                    .map(|method| {
+                        Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                            .map_err(|_| format!("method {method:?} is not valid"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+        let if_headers = config
+            .if_headers
+            .iter()
+            .map(|(name, pattern)| Ok((name.to_ascii_lowercase(), regex("if_headers", pattern)?)))
+            .collect::<Result<_, String>>()?;
+        let if_query = config
+            .if_query
+            .iter()
+            .map(|(name, pattern)| Ok((name.clone(), regex("if_query", pattern)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            pattern,
+            to: config.to.clone(),
+            host: config.host.as_ref().map(|host| host.to_ascii_lowercase()),
+            methods,
+            if_headers,
+            if_query,
+            set_query: config.set_query.clone().into_iter().collect(),
+            remove_query: config.remove_query.clone(),
+            rename_query: config.rename_query.clone().into_iter().collect(),
+            redirect: config.redirect,
+            last: config.last,
+        })
+    }
+
+    /// The captures of `pattern` in `path` if the rule applies to the request.
+    fn matches<'p>(
+        &self,
+        request: &RequestHeader,
+        host: Option<&str>,
+        path: &'p str,
+        query: Option<&str>,
+    ) -> Option<Option<Captures<'p>>> {
+        if let Some(expected) = &self.host
+            && !host.is_some_and(|host| host.eq_ignore_ascii_case(expected))
+        {
+            return None;
+        }
+        if let Some(methods) = &self.methods
+            && !methods.contains(&request.method)
+        {
+            return None;
+        }
+        for (name, pattern) in &self.if_headers {
+            let value = request
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())?;
+            if !pattern.is_match(value) {
+                return None;
+            }
+        }
+        if !self.if_query.is_empty() {
+            let params = parse_query(query);
+            for (name, pattern) in &self.if_query {
+                let (_, value) = params.iter().find(|(param, _)| param == name)?;
+                if !pattern.is_match(value) {
+                    return None;
+                }
+            }
+        }
+        match &self.pattern {
+            Some(pattern) => pattern.captures(path).map(Some),
+            None => Some(None),
+        }
+    }
+
+    fn rewrite(
+        &self,
+        captures: &Option<Captures<'_>>,
+        path: &str,
+        query: Option<&str>,
+    ) -> (String, Option<String>) {
+        let expand = |template: &str| match captures {
+            Some(captures) => {
+                let mut expanded = String::new();
+                captures.expand(template, &mut expanded);
+                expanded
+            }
+            None => template.to_string(),
+        };
+        let (new_path, mut params) = match &self.to {
+            Some(to) => {
+                let to = expand(to);
+                match to.split_once('?') {
+                    Some((to_path, to_query)) => {
+                        let mut params = parse_query(Some(to_query));
+                        params.extend(parse_query(query));
+                        (to_path.to_string(), Some(params))
+                    }
+                    None => (to, None),
+                }
+            }
+            None => (path.to_string(), None),
+        };
+        let edits_query = !self.set_query.is_empty()
+            || !self.remove_query.is_empty()
+            || !self.rename_query.is_empty();
+        if params.is_none() && !edits_query {
+            return (new_path, query.map(str::to_string));
+        }
+        let mut params = params.take().unwrap_or_else(|| parse_query(query));
+        params.retain(|(name, _)| !self.remove_query.contains(name));
+        for (from, to) in &self.rename_query {
+            for (name, _) in params.iter_mut().filter(|(name, _)| name == from) {
+                *name = to.clone();
+            }
+        }
+        for (name, value) in &self.set_query {
+            params.retain(|(param, _)| param != name);
+            params.push((name.clone(), expand(value)));
+        }
+        let query = (!params.is_empty()).then(|| {
+            form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&params)
+                .finish()
+        });
+        (new_path, query)
+    }
+}
+
+fn parse_query(query: Option<&str>) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn join(path: &str, query: Option<&str>) -> String {
+    match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrites(rules: &str) -> Result<Rewrites, String> {
+        let mut file: BTreeMap<String, Vec<RewriteConfig>> = toml::from_str(rules).unwrap();
+        Rewrites::new(&file.remove("rewrites").unwrap())
+    }
+
+    /// What `rewrites` make of a `method` request for `target`, as the new target or the
+    /// redirect status and location.
+    fn apply(
+        rewrites: &Rewrites,
+        method: &str,
+        target: &str,
+        host: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> Option<String> {
+        let mut request = RequestHeader::build(method, target.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            request.append_header(name.to_string(), *value).unwrap();
+        }
+        rewrites
+            .apply(&request, host)
+            .map(|rewritten| match rewritten {
+                Rewritten::Request(target) => target,
+                Rewritten::Redirect(status, location) => format!("{status} {location}"),
+            })
+    }
+
+    fn get(rewrites: &Rewrites, target: &str) -> Option<String> {
+        apply(rewrites, "GET", target, None, &[])
+    }
+
+    #[test]
+    fn rewrites_paths_with_captures() {
+        let rewrites = rewrites(
+            "[[rewrites]]\npattern = '^/blog/(?P<year>\\d+)/(.+)$'\n\
+             to = \"/posts/$2?year=$year\"",
+        )
+        .unwrap();
+        assert_eq!(
+            get(&rewrites, "/blog/2024/hello?ref=rss").as_deref(),
+            Some("/posts/hello?year=2024&ref=rss")
+        );
+        assert_eq!(get(&rewrites, "/blog/latest"), None);
+        assert!(Rewrites::default().is_empty());
+    }
+
+    #[test]
+    fn chains_rules_until_the_last() {
+        let rewrites = rewrites(
+            "[[rewrites]]\npattern = '^/old/'\nto = \"/new/\"\n\
+             [[rewrites]]\npattern = '^/new/$'\nto = \"/index\"\nlast = true\n\
+             [[rewrites]]\npattern = '^/index$'\nto = \"/unreached\"",
+        )
+        .unwrap();
+        assert_eq!(get(&rewrites, "/old/").as_deref(), Some("/index"));
+        assert_eq!(get(&rewrites, "/new/").as_deref(), Some("/index"));
+        assert_eq!(get(&rewrites, "/index").as_deref(), Some("/unreached"));
+    }
+
+    #[test]
+    fn redirects() {
+        let rewrites = rewrites(
+            "[[rewrites]]\nhost = \"Old.Example\"\npattern = '^/(.*)$'\n\
+             to = \"https://new.example/$1\"\nredirect = 301\n\
+             [[rewrites]]\npattern = '^/docs$'\nto = \"/docs/\"\nredirect = 308",
+        )
+        .unwrap();
+        assert_eq!(
+            apply(&rewrites, "GET", "/a?b=c", Some("old.example:8080"), &[]).as_deref(),
+            Some("301 https://new.example/a?b=c")
+        );
+        assert_eq!(
+            apply(&rewrites, "GET", "/docs", Some("new.example"), &[]).as_deref(),
+            Some("308 /docs/")
+        );
+        assert_eq!(get(&rewrites, "/docs/"), None);
+    }
+
+    #[test]
+    fn matches_methods_headers_and_query() {
+        let rewrites = rewrites(
+            "[[rewrites]]\nto = \"/mobile\"\nmethods = [\"get\", \"HEAD\"]\n\
+             if_headers = { User-Agent = 'Mobile' }\nif_query = { view = '^(m|mobile)$' }",
+        )
+        .unwrap();
+        let mobile = [("user-agent", "Mobile Safari")];
+        assert_eq!(
+            apply(&rewrites, "HEAD", "/?view=m", None, &mobile).as_deref(),
+            Some("/mobile?view=m")
+        );
+        assert_eq!(apply(&rewrites, "POST", "/?view=m", None, &mobile), None);
+        assert_eq!(apply(&rewrites, "GET", "/?view=m", None, &[]), None);
+        assert_eq!(
+            apply(&rewrites, "GET", "/?view=desktop", None, &mobile),
+            None
+        );
+        assert_eq!(apply(&rewrites, "GET", "/", None, &mobile), None);
+    }
+
+    #[test]
+    fn edits_the_query() {
+        let rewrites = rewrites(
+            "[[rewrites]]\npattern = '^/item/(\\d+)$'\nremove_query = [\"utm_source\"]\n\
+             rename_query = { q = \"search\" }\nset_query = { id = \"$1\", page = \"1\" }",
+        )
+        .unwrap();
+        assert_eq!(
+            get(&rewrites, "/item/7?utm_source=x&q=a+b&page=3").as_deref(),
+            Some("/item/7?search=a+b&id=7&page=1")
+        );
+        let cleared = self::rewrites("[[rewrites]]\nremove_query = [\"a\"]").unwrap();
+        assert_eq!(get(&cleared, "/x?a=1").as_deref(), Some("/x"));
+    }
+
+    #[test]
+    fn refuses_invalid_rules() {
+        for (rules, error) in [
+            ("to = \"posts\"", "rewrites[0]: to \"posts\" must be a path"),
+            (
+                "to = \"ftp://x\"\nredirect = 302",
+                "must be a path or an absolute URL",
+            ),
+            (
+                "to = \"/x\"\nredirect = 200",
+                "redirect 200 is not a redirect status",
+            ),
+            ("pattern = '('", "pattern is not a valid regex"),
+            ("methods = [\"G T\"]", "method \"G T\" is not valid"),
+            ("if_query = { a = '[' }", "if_query is not a valid regex"),
+        ] {
+            let err = self::rewrites(&format!("[[rewrites]]\n{rules}"))
+                .err()
+                .unwrap();
+            assert!(err.contains(error), "{err}");
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::access_control::{AccessControl, AccessControlConfig};
+use crate::api_keys::{ApiKeys, ApiKeysConfig};
+use crate::basic_auth::{BasicAuth, BasicAuthConfig};
+use crate::cookies::CookieRules;
+use crate::esi::{Esi, EsiConfig};
+use crate::fastcgi::{FastCgi, FastCgiConfig};
+use crate::filters::{FilterChain, Filters};
+use crate::geoip::GeoIp;
+use crate::headers::{HeaderEdits, HeaderRules};
+use crate::html_inject::HtmlInjectConfig;
+use crate::json_redact::{JsonRedact, JsonRedactConfig};
+use crate::limits::ResponseOverflow;
+use crate::listeners::ListenAddrs;
+use crate::parent_proxy::{ParentProxy, ParentProxyConfig};
+use crate::proxy_protocol::ProxyProtocol;
+use crate::signed_urls::{SignedUrls, SignedUrlsConfig};
+use crate::substitutions::{Substitutions, SubstitutionsConfig};
+
+const DEFAULT_STREAM_IDLE_TIMEOUT_SECONDS: u64 = 60 * 60;
+
+/// A `[[routes]]` entry in the config file.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct RouteConfig {
+    pub name: Option<String>,
+    /// Requests whose path starts with this prefix use the route.
+    pub path_prefix: String,
+    /// Upstream for this route; defaults to the global `upstream_addr`.
+    pub upstream_addr: Option<String>,
+    /// Removes `path_prefix` from the path before proxying.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Overrides the global `rewrite_location` setting for this route.
+    pub rewrite_location: Option<bool>,
+    /// Overrides the global `upstream_proxy_protocol` setting for this route's upstream.
+    pub upstream_proxy_protocol: Option<ProxyProtocol>,
+    /// Overrides the global `parent_proxy` for this route's upstream.
+    pub parent_proxy: Option<ParentProxyConfig>,
+    /// Overrides the global `html_inject` snippets for this route; empty to inject none.
+    pub html_inject: Option<HtmlInjectConfig>,
+    /// Overrides the global `substitutions` for this route; without rules to rewrite nothing.
+    pub substitutions: Option<SubstitutionsConfig>,
+    /// Fields removed or masked in the route's JSON responses.
+    pub json_redact: Option<JsonRedactConfig>,
+    /// Replaces `<esi:include>` tags in the route's HTML responses with their fragments.
+    pub esi: Option<EsiConfig>,
+    #[serde(default)]
+    pub headers: HeaderRules,
+    /// Overrides the global `[cookies]` rules for this route.
+    pub cookies: Option<CookieRules>,
+    /// Replaces the global `[access_control]` lists for this route.
+    pub access_control: Option<AccessControlConfig>,
+    /// Asks clients for a user and password from an htpasswd file.
+    pub basic_auth: Option<BasicAuthConfig>,
+    /// Requires one of these API keys.
+    pub api_keys: Option<ApiKeysConfig>,
+    /// Replaces the global `filters` for this route.
+    pub filters: Option<Vec<String>>,
+    /// Requires URLs signed with a shared secret, e.g. time-limited download links.
+    pub signed_urls: Option<SignedUrlsConfig>,
+    /// Requires a login through the `[oidc]` provider.
+    #[serde(default)]
+    pub oidc: bool,
+    /// `false` exempts the route from `[csrf]`, e.g. for webhooks posted by other sites.
+    pub csrf: Option<bool>,
+    /// Proxies gRPC: HTTP/2 to the upstream without TLS, and gRPC status codes for errors.
+    #[serde(default)]
+    pub grpc: bool,
+    /// Translates gRPC-Web calls from browsers, over HTTP/1.1 too, to gRPC for the upstream.
+    #[serde(default)]
+    pub grpc_web: bool,
+    /// Largest gRPC request message accepted; bigger ones end the call with
+    /// RESOURCE_EXHAUSTED.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub grpc_max_message_kb: Option<usize>,
+    /// Overrides `limits.max_request_body_kb` for this route.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_request_body_kb: Option<usize>,
+    /// Overrides `limits.max_response_body_kb` for this route.
+    #[serde(default, deserialize_with = "crate::units::opt_kilobytes")]
+    #[schemars(schema_with = "crate::units::size_schema")]
+    pub max_response_body_kb: Option<usize>,
+    /// Overrides `limits.response_body_overflow` for this route.
+    pub response_body_overflow: Option<ResponseOverflow>,
+    /// Most requests to this route handled at once, on top of `limits.max_concurrent_requests`.
+    pub max_concurrent_requests: Option<usize>,
+    /// Passes responses on as they arrive, e.g. server-sent events: each chunk is flushed to
+    /// the client, and only `stream_idle_timeout_seconds` limits pauses. Compression, `esi`,
+    /// `substitutions` and image conversion, which hold chunks back, are skipped.
+    #[serde(default)]
+    pub streaming: bool,
+    /// Longest pause on a streaming route, waiting for the upstream or the client (one hour by
+    /// default).
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub stream_idle_timeout_seconds: Option<u64>,
+    /// Runs the route on a FastCGI application server, e.g. PHP-FPM, instead of proxying it.
+    pub fastcgi: Option<FastCgiConfig>,
+}
+
+/// A resolved route, shared with in-flight requests.
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    pub path_prefix: String,
+    pub upstream_addr: String,
+    pub strip_prefix: bool,
+    pub rewrite_location: Option<bool>,
+    pub upstream_proxy_protocol: Option<ProxyProtocol>,
+    pub parent_proxy: Option<ParentProxy>,
+    pub html_inject: Option<HtmlInjectConfig>,
+    pub substitutions: Option<Arc<Substitutions>>,
+    pub json_redact: Option<Arc<JsonRedact>>,
+    pub esi: Option<Arc<Esi>>,
+    pub headers: HeaderEdits,
+    pub cookies: Option<CookieRules>,
+    pub access_control: Option<Arc<AccessControl>>,
+    pub basic_auth: Option<BasicAuth>,
+    pub api_keys: Option<ApiKeys>,
+    /// The route's own filter chain, when it sets `filters`.
+    pub filters: Option<FilterChain>,
+    pub signed_urls: Option<SignedUrls>,
+    pub oidc: bool,
+    pub csrf: bool,
+    pub grpc: bool,
+    pub grpc_web: bool,
+    pub grpc_max_message_kb: Option<usize>,
+    pub max_request_body_kb: Option<usize>,
+    pub max_response_body_kb: Option<usize>,
+    pub response_body_overflow: Option<ResponseOverflow>,
+    pub max_concurrent_requests: Option<usize>,
+    pub streaming: bool,
+    pub stream_idle_timeout_seconds: Option<u64>,
+    pub fastcgi: Option<FastCgi>,
+}
+
+impl Route {
+    /// How long a streaming route may sit idle; `None` for other routes.
+    pub fn stream_idle_timeout(&self) -> Option<Duration> {
+        self.streaming.then(|| {
+            Duration::from_secs(
+                self.stream_idle_timeout_seconds
+                    .unwrap_or(DEFAULT_STREAM_IDLE_TIMEOUT_SECONDS),
+            )
+        })
+    }
+
+    /// The prefix removed from proxied paths, without its trailing slash.
+    pub fn stripped_prefix(&self) -> Option<&str> {
+        self.strip_prefix
+            .then(|| self.path_prefix.trim_end_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+    }
+
+    /// Maps a public path and query to the one sent upstream.
+    pub fn upstream_path(&self, path_and_query: &str) -> Option<String> {
+        let prefix = self.stripped_prefix()?;
+        let rest = path_and_query.strip_prefix(prefix)?;
+        if rest.starts_with('/') {
+            Some(rest.to_string())
+        } else {
+            Some(format!("/{rest}"))
+        }
+    }
+}
+
+/// Prefix router that picks the longest matching route for a request path.
+#[derive(Clone, Debug, Default)]
+pub struct Router {
+    routes: Vec<Arc<Route>>,
+}
+
+impl Router {
+    pub fn new(
+        configs: &[RouteConfig],
+        default_upstream: &str,
+        listen_addr: Option<&ListenAddrs>,
+        geoip: Option<&Arc<GeoIp>>,
+        filters: &Filters,
+        default_filters: Option<&[String]>,
+    ) -> Result<Self, String> {
+        let mut routes: Vec<Arc<Route>> = configs
+            .iter()
+            .map(|config| {
+                let name = config
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| config.path_prefix.clone());
+                let access_control = config
+                    .access_control
+                    .as_ref()
+                    .map(|access_control| AccessControl::new(access_control, geoip).map(Arc::new))
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' access_control: {err}"))?;
+                let basic_auth = config
+                    .basic_auth
+                    .as_ref()
+                    .map(BasicAuth::new)
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' basic_auth: {err}"))?;
+                let api_keys = config
+                    .api_keys
+                    .as_ref()
+                    .map(ApiKeys::new)
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' api_keys: {err}"))?;
+                let filters = config
+                    .filters
+                    .as_deref()
+                    .map(|names| filters.chain(Some(names)))
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' filters: {err}"))?;
+                // Leaving an auth filter out of the chain must not leave the route open.
+                let names = config.filters.as_deref().or(default_filters);
+                for (configured, filter) in [
+                    (config.basic_auth.is_some(), "basic_auth"),
+                    (config.api_keys.is_some(), "api_keys"),
+                ] {
+                    if configured && names.is_some_and(|names| !names.iter().any(|n| n == filter)) {
+                        return Err(format!(
+                            "route '{name}' sets {filter} but its filters leave out '{filter}'"
+                        ));
+                    }
+                }
+                let signed_urls = config
+                    .signed_urls
+                    .as_ref()
+                    .map(SignedUrls::new)
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' signed_urls: {err}"))?;
+                let parent_proxy = config
+                    .parent_proxy
+                    .as_ref()
+                    .map(ParentProxy::new)
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' parent_proxy: {err}"))?;
+                let substitutions = config
+                    .substitutions
+                    .as_ref()
+                    .map(|substitutions| Substitutions::new(substitutions).map(Arc::new))
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' substitutions: {err}"))?;
+                let json_redact = config
+                    .json_redact
+                    .as_ref()
+                    .map(|redact| JsonRedact::new(redact).map(Arc::new))
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' json_redact: {err}"))?;
+                let esi = config
+                    .esi
+                    .as_ref()
+                    .map(|esi| Esi::new(esi, listen_addr).map(Arc::new))
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' esi: {err}"))?;
+                let headers = HeaderEdits::new(&config.headers)
+                    .map_err(|err| format!("route '{name}' headers: {err}"))?;
+                let fastcgi = config
+                    .fastcgi
+                    .as_ref()
+                    .map(FastCgi::new)
+                    .transpose()
+                    .map_err(|err| format!("route '{name}' fastcgi: {err}"))?;
+                Ok(Arc::new(Route {
+                    name,
+                    path_prefix: config.path_prefix.clone(),
+                    upstream_addr: config
+                        .upstream_addr
+                        .clone()
+                        .unwrap_or_else(|| default_upstream.to_string()),
+                    strip_prefix: config.strip_prefix,
+                    rewrite_location: config.rewrite_location,
+                    upstream_proxy_protocol: config.upstream_proxy_protocol,
+                    parent_proxy,
+                    html_inject: config.html_inject.clone(),
+                    substitutions,
+                    json_redact,
+                    esi,
+                    headers,
+                    cookies: config.cookies.clone(),
+                    access_control,
+                    basic_auth,
+                    api_keys,
+                    filters,
+                    signed_urls,
+                    oidc: config.oidc,
+                    csrf: config.csrf.unwrap_or(true),
+                    grpc: config.grpc,
+                    grpc_web: config.grpc_web,
+                    grpc_max_message_kb: config.grpc_max_message_kb,
+                    max_request_body_kb: config.max_request_body_kb,
+                    max_response_body_kb: config.max_response_body_kb,
+                    response_body_overflow: config.response_body_overflow,
+                    max_concurrent_requests: config.max_concurrent_requests,
+                    streaming: config.streaming,
+                    stream_idle_timeout_seconds: config.stream_idle_timeout_seconds,
+                    fastcgi,
+                }))
+            })
+            .collect::<Result<_, String>>()?;
+        routes.sort_by_key(|route| std::cmp::Reverse(route.path_prefix.len()));
+        for route in &routes {
+            info!(
+                "route '{}': {} -> {}",
+                route.name, route.path_prefix, route.upstream_addr
+            );
+        }
+        Ok(Self { routes })
+    }
+
+    pub fn match_path(&self, path: &str) -> Option<Arc<Route>> {
+        self.routes
+            .iter()
+            .find(|route| path.starts_with(&route.path_prefix))
+            .cloned()
+    }
+
+    pub fn routes(&self) -> &[Arc<Route>] {
+        &self.routes
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Value of the `security_headers` config key.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityPreset {
+    Strict,
+    Basic,
+    #[default]
+    Off,
+}
+
+const BASIC_HEADERS: &[(&str, &str)] = &[
+    ("Strict-Transport-Security", "max-age=31536000"),
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "SAMEORIGIN"),
+    ("Referrer-Policy", "strict-origin-when-cross-origin"),
+];
+
+const STRICT_HEADERS: &[(&str, &str)] = &[
+    (
+        "Strict-Transport-Security",
+        "max-age=63072000; includeSubDomains; preload",
+    ),
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "no-referrer"),
+    ("Cross-Origin-Opener-Policy", "same-origin"),
+    ("Cross-Origin-Embedder-Policy", "require-corp"),
+];
+
+/// Security headers added to every response that doesn't already carry them.
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl SecurityHeaders {
+    /// Resolves a preset, then applies `overrides`; an empty override value drops that header.
+    pub fn new(preset: SecurityPreset, overrides: &BTreeMap<String, String>) -> Self {
+        let base = match preset {
+            SecurityPreset::Strict => STRICT_HEADERS,
+            SecurityPreset::Basic => BASIC_HEADERS,
+            SecurityPreset::Off => &[],
+        };
+
+        let mut headers: Vec<(String, String)> = base
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        for (name, value) in overrides {
+            headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+            if !value.is_empty() {
+                headers.push((name.clone(), value.clone()));
+            }
+        }
+
+        Self { headers }
+    }
+
+    pub fn apply(&self, response: &mut ResponseHeader) -> Result<()> {
+        for (name, value) in &self.headers {
+            if !response.headers.contains_key(name.as_str()) {
+                response.insert_header(name.clone(), value.as_str())?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::Uri;
+use ring::hmac;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::hex;
+
+const DEFAULT_EXPIRES_PARAM: &str = "expires";
+const DEFAULT_SIGNATURE_PARAM: &str = "signature";
+
+/// A route's `signed_urls` table: requests must carry an unexpired HMAC-SHA256 signature of
+/// their path and query as the client sent them, before `[[rewrites]]`, made with the shared
+/// secret.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct SignedUrlsConfig {
+    pub secret: String,
+    /// Also accepted while links signed before a secret change are still out there.
+    pub previous_secret: Option<String>,
+    /// Query parameter holding the Unix time the URL expires at.
+    pub expires_param: Option<String>,
+    /// Query parameter holding the hex signature.
+    pub signature_param: Option<String>,
+}
+
+/// Why a request's signature was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    Expired,
+    Invalid,
+}
+
+impl SignatureError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Missing => "missing",
+            Self::Expired => "expired",
+            Self::Invalid => "invalid",
+        }
+    }
+}
+
+/// Compiled `signed_urls` of a route.
+pub struct SignedUrls {
+    keys: Vec<hmac::Key>,
+    expires_param: String,
+    signature_param: String,
+}
+
+impl SignedUrls {
+    pub fn new(config: &SignedUrlsConfig) -> Result<Self, String> {
+        let secrets = std::iter::once(&config.secret).chain(&config.previous_secret);
+        if secrets.clone().any(|secret| secret.len() < 16) {
+            return Err("secrets must be at least 16 bytes".to_string());
+        }
+        Ok(Self {
+            keys: secrets
+                .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+                .collect(),
+            expires_param: config
+                .expires_param
+                .clone()
+                .unwrap_or_else(|| DEFAULT_EXPIRES_PARAM.to_string()),
+            signature_param: config
+                .signature_param
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SIGNATURE_PARAM.to_string()),
+        })
+    }
+
+    /// Checks the signature of a request for `uri`. It signs the path and the query as sent,
+    /// without the signature parameter, e.g. `/files/a.zip?expires=1700000000`.
+    pub fn verify(&self, uri: &Uri) -> Result<(), SignatureError> {
+        let path = uri.path();
+        let query = uri.query().unwrap_or_default();
+        let mut signature = None;
+        let mut expires = None;
+        let mut signed = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if name == self.signature_param {
+                signature = Some(value);
+                continue;
+            }
+            if name == self.expires_param {
+                expires = Some(value);
+            }
+            signed.push(pair);
+        }
+        let (Some(signature), Some(expires)) = (signature, expires) else {
+            return Err(SignatureError::Missing);
+        };
+        let signature = hex::decode(signature).ok_or(SignatureError::Invalid)?;
+        let message = if signed.is_empty() {
+            path.to_string()
+        } else {
+            format!("{path}?{}", signed.join("&"))
+        };
+        if !self
+            .keys
+            .iter()
+            .any(|key| hmac::verify(key, message.as_bytes(), &signature).is_ok())
+        {
+            return Err(SignatureError::Invalid);
+        }
+        let expires = expires
+            .parse::<u64>()
+            .map_err(|_| SignatureError::Invalid)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        if expires <= now {
+            return Err(SignatureError::Expired);
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for SignedUrls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignedUrls")
+            .field("secrets", &self.keys.len())
+            .field("expires_param", &self.expires_param)
+            .field("signature_param", &self.signature_param)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+    const OLD_SECRET: &str = "fedcba9876543210fedcba9876543210";
+
+    fn signed_urls(previous_secret: Option<&str>) -> SignedUrls {
+        SignedUrls::new(&SignedUrlsConfig {
+            secret: SECRET.to_string(),
+            previous_secret: previous_secret.map(str::to_string),
+            expires_param: None,
+            signature_param: None,
+        })
+        .unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// `path_and_query` with its signature made with `secret` appended.
+    fn sign(secret: &str, path_and_query: &str) -> Uri {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signature = hex::encode(hmac::sign(&key, path_and_query.as_bytes()).as_ref());
+        format!("{path_and_query}&signature={signature}")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn accepts_unexpired_signatures() {
+        let uri = sign(SECRET, &format!("/files/a.zip?expires={}", now() + 60));
+        assert_eq!(signed_urls(None).verify(&uri), Ok(()));
+    }
+
+    #[test]
+    fn refuses_expired_links() {
+        let uri = sign(SECRET, &format!("/files/a.zip?expires={}", now() - 1));
+        assert_eq!(signed_urls(None).verify(&uri), Err(SignatureError::Expired));
+    }
+
+    #[test]
+    fn refuses_tampered_links() {
+        let expires = now() + 60;
+        let uri = sign(SECRET, &format!("/files/a.zip?expires={expires}"));
+        let signature = uri.query().unwrap().split("signature=").nth(1).unwrap();
+        for tampered in [
+            format!("/files/b.zip?expires={expires}&signature={signature}"),
+            format!(
+                "/files/a.zip?expires={}&signature={signature}",
+                expires + 3600
+            ),
+            format!("/files/a.zip?expires={expires}&user=admin&signature={signature}"),
+            format!("/files/a.zip?expires={expires}&signature=00{signature}"),
+            format!("/files/a.zip?expires={expires}&signature=not-hex"),
+        ] {
+            assert_eq!(
+                signed_urls(None).verify(&tampered.parse().unwrap()),
+                Err(SignatureError::Invalid),
+                "{tampered}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_links_without_a_signature() {
+        let expires = now() + 60;
+        for stripped in [
+            format!("/files/a.zip?expires={expires}"),
+            "/files/a.zip".to_string(),
+            "/files/a.zip?signature=00".to_string(),
+        ] {
+            assert_eq!(
+                signed_urls(None).verify(&stripped.parse().unwrap()),
+                Err(SignatureError::Missing),
+                "{stripped}"
+            );
+        }
+    }
+
+    #[test]
+    fn previous_secret_is_accepted_while_set() {
+        let uri = sign(OLD_SECRET, &format!("/files/a.zip?expires={}", now() + 60));
+        assert_eq!(signed_urls(Some(OLD_SECRET)).verify(&uri), Ok(()));
+        assert_eq!(signed_urls(None).verify(&uri), Err(SignatureError::Invalid));
+        let current = sign(SECRET, &format!("/files/a.zip?expires={}", now() + 60));
+        assert_eq!(signed_urls(Some(OLD_SECRET)).verify(&current), Ok(()));
+    }
+}
@@ -0,0 +1,463 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use log::info;
+
+use crate::access_control::AccessControl;
+use crate::access_log::AccessLog;
+use crate::admin_tls::AdminTls;
+use crate::bots::Bots;
+use crate::config::{
+    Config, DEFAULT_STATIC_CACHE_SECONDS, DEFAULT_STATIC_IMMUTABLE_CACHE_SECONDS,
+    DEFAULT_STATIC_INDEX, DEFAULT_STATIC_KEEPALIVE_SECONDS,
+    DEFAULT_STATIC_MEMORY_CACHE_MAX_OBJECT_KB, DEFAULT_STATIC_MEMORY_CACHE_PROMOTE_HITS,
+    DEFAULT_STATIC_MOUNT, DEFAULT_VIA_TOKEN,
+};
+use crate::cookies::CookieRules;
+use crate::cors::CorsPolicy;
+use crate::doh::Doh;
+use crate::errors::ErrorPages;
+use crate::filters::{FilterChain, Filters};
+use crate::forward_proxy::ForwardProxy;
+use crate::forwarded::TrustedProxies;
+use crate::geoip::GeoIp;
+use crate::headers::HeaderEdits;
+use crate::hop_headers::HopHeaders;
+use crate::images::Images;
+use crate::ip_list::IpList;
+use crate::maintenance::MaintenancePage;
+use crate::markdown::MarkdownPages;
+use crate::memory_cache::MemoryCacheConfig;
+use crate::oidc::Oidc;
+use crate::parent_proxy::ParentProxy;
+use crate::rate_limit::RateLimits;
+use crate::response_policy::{ResponsePolicy, ServerHeader};
+use crate::rewrites::Rewrites;
+use crate::routes::Router;
+use crate::security_headers::SecurityHeaders;
+use crate::static_assets::{ManifestSource, StaticAssetConfig, StaticAssets};
+use crate::substitutions::Substitutions;
+use crate::tcp_proxy::TcpRoutes;
+use crate::waf::Waf;
+use crate::wasm::WasmPlugin;
+
+/// Everything the proxy derives from the reloadable parts of the config.
+///
+/// Each request takes a snapshot when it starts, so a reload never changes the
+/// behavior of a request that is already in flight.
+pub struct ProxyState {
+    pub config: Config,
+    pub upstream_addr: String,
+    pub parent_proxy: Option<ParentProxy>,
+    pub substitutions: Option<Arc<Substitutions>>,
+    pub images: Option<Arc<Images>>,
+    pub static_assets: Option<StaticAssets>,
+    pub trusted_proxies: TrustedProxies,
+    pub headers: HeaderEdits,
+    pub response_policy: ResponsePolicy,
+    pub cookies: CookieRules,
+    pub rewrite_location: bool,
+    pub hop_headers: HopHeaders,
+    pub router: Router,
+    /// The filters chains can name, kept across reloads.
+    pub filters: Arc<Filters>,
+    /// The chain of routes without `filters` of their own.
+    pub filter_chain: FilterChain,
+    pub wasm_plugins: Vec<Arc<WasmPlugin>>,
+    pub access_log: Option<AccessLog>,
+    pub rate_limits: Option<RateLimits>,
+    pub access_control: Option<Arc<AccessControl>>,
+    pub geoip: Option<Arc<GeoIp>>,
+    pub oidc: Option<Oidc>,
+    pub waf: Option<Waf>,
+    pub bots: Option<Bots>,
+    pub forward_proxy: Option<ForwardProxy>,
+    /// Load balancers `[proxy_protocol]` listeners take headers from.
+    pub proxy_protocol: Option<IpList>,
+    pub doh: Option<Doh>,
+    pub tcp_routes: TcpRoutes,
+    pub rewrites: Rewrites,
+    pub maintenance_page: MaintenancePage,
+    pub error_pages: ErrorPages,
+    pub admin_tls: Option<AdminTls>,
+}
+
+impl ProxyState {
+    /// Builds the state for `config` with the filters `filters`, reusing the static asset
+    /// caches of `previous` when the static settings did not change.
+    pub fn build(
+        config: Config,
+        previous: Option<&ProxyState>,
+        filters: Arc<Filters>,
+    ) -> Result<Self, String> {
+        let headers = HeaderEdits::new(&config.headers.clone().unwrap_or_default())
+            .map_err(|err| format!("invalid headers: {err}"))?;
+        let response_policy = ResponsePolicy {
+            cors: CorsPolicy::new(&config.cors.clone().unwrap_or_default()),
+            security_headers: SecurityHeaders::new(
+                config.security_headers.unwrap_or_default(),
+                &config.security_header_overrides,
+            ),
+            headers: headers.clone(),
+            server_header: ServerHeader::from_config(config.server_header.as_deref()),
+        };
+
+        let trusted_proxies =
+            TrustedProxies::parse(config.trusted_proxies.as_deref().unwrap_or_default())
+                .map_err(|err| format!("invalid trusted_proxies: {err}"))?
+                .reading(config.client_ip_header.unwrap_or_default())
+                .trusting_unix_peers(config.trust_unix_peers.unwrap_or(false));
+
+        let access_log = config
+            .access_log
+            .as_ref()
+            .map(AccessLog::new)
+            .transpose()
+            .map_err(|err| format!("invalid access_log: {err}"))?;
+
+        let rate_limits = config
+            .rate_limit
+            .as_ref()
+            .map(RateLimits::new)
+            .transpose()
+            .map_err(|err| format!("invalid rate_limit: {err}"))?;
+
+        let geoip = config
+            .geoip
+            .as_ref()
+            .map(|geoip| GeoIp::open(geoip).map(Arc::new))
+            .transpose()
+            .map_err(|err| format!("invalid geoip: {err}"))?;
+        let access_control = config
+            .access_control
+            .as_ref()
+            .map(|access_control| AccessControl::new(access_control, geoip.as_ref()).map(Arc::new))
+            .transpose()
+            .map_err(|err| format!("invalid access_control: {err}"))?;
+        let oidc = config
+            .oidc
+            .as_ref()
+            .map(Oidc::new)
+            .transpose()
+            .map_err(|err| format!("invalid oidc: {err}"))?;
+        let waf = config
+            .waf
+            .as_ref()
+            .map(Waf::new)
+            .transpose()
+            .map_err(|err| format!("invalid waf: {err}"))?;
+        let bots = config
+            .bots
+            .as_ref()
+            .map(Bots::new)
+            .transpose()
+            .map_err(|err| format!("invalid bots: {err}"))?;
+        let forward_proxy = config
+            .forward_proxy
+            .as_ref()
+            .map(ForwardProxy::new)
+            .transpose()
+            .map_err(|err| format!("invalid forward_proxy: {err}"))?;
+        let proxy_protocol = config
+            .proxy_protocol
+            .as_ref()
+            .map(|proxy_protocol| IpList::parse(&proxy_protocol.trusted_networks))
+            .transpose()
+            .map_err(|err| format!("invalid proxy_protocol: {err}"))?;
+        let doh = config
+            .doh
+            .as_ref()
+            .map(Doh::new)
+            .transpose()
+            .map_err(|err| format!("invalid doh: {err}"))?;
+        let parent_proxy = config
+            .parent_proxy
+            .as_ref()
+            .map(ParentProxy::new)
+            .transpose()
+            .map_err(|err| format!("invalid parent_proxy: {err}"))?;
+        let substitutions = config
+            .substitutions
+            .as_ref()
+            .map(|substitutions| Substitutions::new(substitutions).map(Arc::new))
+            .transpose()
+            .map_err(|err| format!("invalid substitutions: {err}"))?;
+        let rewrites = Rewrites::new(&config.rewrites)?;
+        let tcp_routes = TcpRoutes::new(&config.tcp_routes)
+            .map_err(|err| format!("invalid tcp_routes: {err}"))?;
+        let maintenance_page = MaintenancePage::new(config.maintenance.as_ref())
+            .map_err(|err| format!("invalid maintenance: {err}"))?;
+        let error_pages = ErrorPages::new(config.error_pages.as_ref())
+            .map_err(|err| format!("invalid error_pages: {err}"))?;
+        let admin_tls = config
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.tls.as_ref())
+            .map(AdminTls::new)
+            .transpose()
+            .map_err(|err| format!("invalid admin.tls: {err}"))?;
+        // Plugins whose settings did not change keep their running instance.
+        let wasm_plugins = config
+            .wasm_plugins
+            .iter()
+            .map(|plugin| {
+                let reusable = previous.and_then(|previous| {
+                    previous
+                        .wasm_plugins
+                        .iter()
+                        .find(|previous| previous.config() == plugin)
+                });
+                match reusable {
+                    Some(previous) => {
+                        previous.refresh();
+                        Ok(previous.clone())
+                    }
+                    None => WasmPlugin::new(plugin).map(Arc::new),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid wasm_plugins: {err}"))?;
+        let chain_filters = filters
+            .with_plugins(&wasm_plugins)
+            .map_err(|err| format!("invalid wasm_plugins: {err}"))?;
+        let router = Router::new(
+            &config.routes,
+            &config.upstream_addr,
+            config.listen_addr.as_ref(),
+            geoip.as_ref(),
+            &chain_filters,
+            config.filters.as_deref(),
+        )?;
+        let filter_chain = chain_filters
+            .chain(config.filters.as_deref())
+            .map_err(|err| format!("invalid filters: {err}"))?;
+
+        // Converted images stay cached across reloads that leave `[images]` as it is.
+        let images = config.images.as_ref().map(|images| {
+            previous
+                .and_then(|previous| previous.images.clone())
+                .filter(|previous| previous.config() == images)
+                .unwrap_or_else(|| Arc::new(Images::new(images.clone())))
+        });
+
+        let reusable_assets = previous
+            .filter(|previous| static_settings(&previous.config) == static_settings(&config))
+            .and_then(|previous| previous.static_assets.as_ref());
+        let static_assets = match (reusable_assets, &config.static_root) {
+            (Some(assets), _) => Some(assets.with_response_policy(response_policy.clone())),
+            (None, Some(root)) => Some(build_static_assets(&config, root, &response_policy)?),
+            (None, None) => None,
+        }
+        .map(|assets| assets.with_images(images.clone()));
+
+        Ok(Self {
+            upstream_addr: config.upstream_addr.clone(),
+            parent_proxy,
+            substitutions,
+            images,
+            static_assets,
+            trusted_proxies,
+            headers,
+            response_policy,
+            cookies: config.cookies.clone().unwrap_or_default(),
+            rewrite_location: config.rewrite_location.unwrap_or(true),
+            hop_headers: HopHeaders::new(config.via_token.as_deref().unwrap_or(DEFAULT_VIA_TOKEN)),
+            router,
+            filters,
+            filter_chain,
+            wasm_plugins,
+            access_log,
+            rate_limits,
+            access_control,
+            geoip,
+            oidc,
+            waf,
+            bots,
+            forward_proxy,
+            proxy_protocol,
+            doh,
+            tcp_routes,
+            rewrites,
+            maintenance_page,
+            error_pages,
+            admin_tls,
+            config,
+        })
+    }
+
+    /// Reloads the IP lists, GeoIP database, htpasswd, API key, WAF rule and WASM module files
+    /// that changed on disk.
+    pub fn refresh_watched_files(&self) {
+        for access_control in self.access_control.iter().chain(
+            self.router
+                .routes()
+                .iter()
+                .filter_map(|route| route.access_control.as_ref()),
+        ) {
+            access_control.refresh();
+        }
+        for route in self.router.routes() {
+            if let Some(basic_auth) = &route.basic_auth {
+                basic_auth.refresh();
+            }
+            if let Some(api_keys) = &route.api_keys {
+                api_keys.refresh();
+            }
+        }
+        if let Some(geoip) = &self.geoip {
+            geoip.refresh();
+        }
+        if let Some(waf) = &self.waf {
+            waf.refresh();
+        }
+        if let Some(forward_proxy) = &self.forward_proxy {
+            forward_proxy.refresh();
+        }
+        for plugin in &self.wasm_plugins {
+            plugin.refresh();
+        }
+    }
+
+    /// Every upstream address requests can be sent to, without duplicates.
+    pub fn upstreams(&self) -> Vec<String> {
+        let mut upstreams: Vec<String> = std::iter::once(&self.upstream_addr)
+            .chain(
+                self.router
+                    .routes()
+                    .iter()
+                    .map(|route| &route.upstream_addr),
+            )
+            .cloned()
+            .collect();
+        upstreams.sort();
+        upstreams.dedup();
+        upstreams
+    }
+}
+
+/// Handle to the current [`ProxyState`], swapped atomically on reload.
+#[derive(Clone)]
+pub struct SharedState {
+    inner: Arc<RwLock<Arc<ProxyState>>>,
+}
+
+impl SharedState {
+    pub fn new(state: ProxyState) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Arc::new(state))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<ProxyState> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Installs `state` and returns the one it replaced.
+    pub fn replace(&self, state: ProxyState) -> Arc<ProxyState> {
+        let mut guard = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::mem::replace(&mut *guard, Arc::new(state))
+    }
+}
+
+impl ManifestSource for SharedState {
+    fn static_assets(&self) -> Option<StaticAssets> {
+        self.current().static_assets.clone()
+    }
+}
+
+/// Settings that require rebuilding the static asset server when they change.
+fn static_settings(config: &Config) -> String {
+    format!(
+        "{:?}",
+        (
+            &config.static_root,
+            &config.static_mount,
+            &config.static_index_file,
+            &config.static_manifest,
+            config.static_default_cache_seconds,
+            config.static_immutable_cache_seconds,
+            config.static_keepalive_seconds,
+            config.static_memory_cache_mb,
+            config.static_memory_cache_max_object_kb,
+            config.static_memory_cache_promote_hits,
+            config.static_markdown,
+            &config.static_markdown_template,
+        )
+    )
+}
+
+fn build_static_assets(
+    config: &Config,
+    root: &str,
+    response_policy: &ResponsePolicy,
+) -> Result<StaticAssets, String> {
+    let asset_root = PathBuf::from(root);
+    let mount_path = config
+        .static_mount
+        .as_deref()
+        .unwrap_or(DEFAULT_STATIC_MOUNT);
+    let index_file = config
+        .static_index_file
+        .as_deref()
+        .unwrap_or(DEFAULT_STATIC_INDEX);
+    let manifest_path = config.static_manifest.as_ref().map(PathBuf::from);
+    let immutable_cache_seconds = config
+        .static_immutable_cache_seconds
+        .unwrap_or(DEFAULT_STATIC_IMMUTABLE_CACHE_SECONDS);
+    let default_cache_seconds = config
+        .static_default_cache_seconds
+        .unwrap_or(DEFAULT_STATIC_CACHE_SECONDS);
+    let keepalive_seconds = config
+        .static_keepalive_seconds
+        .unwrap_or(DEFAULT_STATIC_KEEPALIVE_SECONDS);
+
+    let memory_cache = config
+        .static_memory_cache_mb
+        .filter(|mb| *mb > 0)
+        .map(|mb| MemoryCacheConfig {
+            capacity_bytes: mb * 1024 * 1024,
+            max_object_bytes: config
+                .static_memory_cache_max_object_kb
+                .unwrap_or(DEFAULT_STATIC_MEMORY_CACHE_MAX_OBJECT_KB)
+                * 1024,
+            promote_hits: config
+                .static_memory_cache_promote_hits
+                .unwrap_or(DEFAULT_STATIC_MEMORY_CACHE_PROMOTE_HITS)
+                .max(1),
+        });
+
+    let markdown = config
+        .static_markdown
+        .unwrap_or(false)
+        .then(|| MarkdownPages::new(config.static_markdown_template.as_deref()).map(Arc::new))
+        .transpose()
+        .map_err(|err| format!("invalid static_markdown_template: {err}"))?;
+
+    let asset_config = StaticAssetConfig {
+        mount_path: mount_path.to_string(),
+        root: asset_root,
+        index_file: index_file.to_string(),
+        manifest_path,
+        immutable_cache_seconds,
+        default_cache_seconds,
+        keepalive_seconds,
+        memory_cache,
+        response_policy: response_policy.clone(),
+        markdown,
+    };
+
+    let assets = StaticAssets::new(asset_config)
+        .map_err(|err| format!("failed to initialise static assets with root {root}: {err}"))?;
+    info!(
+        "Static assets enabled: mount '{}' -> {:?}",
+        assets.mount_path(),
+        assets.root_path()
+    );
+    Ok(assets)
+}
@@ -6,12 +6,11 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use async_trait::async_trait;
 use bytes::Bytes;
 use http::header::{
-    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    ACCESS_CONTROL_MAX_AGE, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
-    IF_NONE_MATCH, LAST_MODIFIED, ORIGIN, VARY,
+    CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED,
 };
 use httpdate::fmt_http_date;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use mime_guess::MimeGuess;
 use pingora::Error;
 use pingora::ErrorType;
@@ -20,10 +19,32 @@ use pingora::prelude::*;
 use pingora::proxy::Session;
 use pingora::server::ShutdownWatch;
 use pingora::services::background::{BackgroundService, background_service};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 use tokio::sync::RwLock;
 
+use crate::images::Images;
+use crate::markdown::MarkdownPages;
+use crate::memory_cache::{CacheEntry, MemoryCache, MemoryCacheConfig, MemoryCacheStats};
+use crate::response_policy::ResponsePolicy;
+
+/// Manifest entries checked by the startup self-test; larger manifests are sampled.
+const SELF_TEST_MANIFEST_SAMPLE: usize = 64;
+
+/// Value of the `static_self_test` config key: what to do when the startup
+/// self-test of the static root and manifest finds problems.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfTestMode {
+    #[default]
+    Off,
+    Warn,
+    Fail,
+}
+
 #[derive(Clone, Debug, serde::Deserialize)]
 #[serde(untagged)]
 pub enum ManifestValue {
@@ -41,6 +62,10 @@ pub struct StaticAssetConfig {
     pub immutable_cache_seconds: u64,
     pub default_cache_seconds: u64,
     pub keepalive_seconds: u64,
+    pub memory_cache: Option<MemoryCacheConfig>,
+    pub response_policy: ResponsePolicy,
+    /// Renders `.md` files to HTML pages when set.
+    pub markdown: Option<Arc<MarkdownPages>>,
 }
 
 #[derive(Clone, Debug)]
@@ -69,45 +94,44 @@ impl ManifestHandle {
     }
 
     async fn reload_if_needed(&self) {
-        match fs::metadata(&self.path).await {
-            Ok(metadata) => {
-                let modified = metadata.modified().ok();
-                {
-                    let guard = self.state.read().await;
-                    if guard.last_modified == modified {
-                        trace!(
-                            "manifest at {:?} unchanged (mtime: {:?})",
-                            self.path, modified
-                        );
-                        return;
-                    }
-                }
+        if let Err(err) = self.reload(false).await {
+            error!("{err}");
+        }
+    }
 
-                match fs::read_to_string(&self.path).await {
-                    Ok(contents) => match parse_manifest_entries(&contents) {
-                        Ok(entries) => {
-                            info!(
-                                "reloaded static manifest {:?} with {} entries",
-                                self.path,
-                                entries.len()
-                            );
-                            let mut guard = self.state.write().await;
-                            guard.entries = entries;
-                            guard.last_modified = modified;
-                        }
-                        Err(err) => {
-                            error!("failed to parse manifest {:?}: {}", self.path, err);
-                        }
-                    },
-                    Err(err) => {
-                        error!("failed to read manifest {:?}: {}", self.path, err);
-                    }
-                }
-            }
-            Err(err) => {
-                error!("manifest {:?} metadata error: {}", self.path, err);
+    /// Re-reads the manifest if its mtime changed, or whatever it is when `force` is set;
+    /// returns how many entries it has now.
+    async fn reload(&self, force: bool) -> Result<usize, String> {
+        let metadata = fs::metadata(&self.path)
+            .await
+            .map_err(|err| format!("manifest {:?} metadata error: {err}", self.path))?;
+        let modified = metadata.modified().ok();
+        {
+            let guard = self.state.read().await;
+            if !force && guard.last_modified == modified {
+                trace!(
+                    "manifest at {:?} unchanged (mtime: {:?})",
+                    self.path, modified
+                );
+                return Ok(guard.entries.len());
             }
         }
+
+        let contents = fs::read_to_string(&self.path)
+            .await
+            .map_err(|err| format!("failed to read manifest {:?}: {err}", self.path))?;
+        let entries = parse_manifest_entries(&contents)
+            .map_err(|err| format!("failed to parse manifest {:?}: {err}", self.path))?;
+        info!(
+            "reloaded static manifest {:?} with {} entries",
+            self.path,
+            entries.len()
+        );
+        let count = entries.len();
+        let mut guard = self.state.write().await;
+        guard.entries = entries;
+        guard.last_modified = modified;
+        Ok(count)
     }
 }
 
@@ -118,6 +142,26 @@ struct ResolvedFile {
     from_manifest: bool,
 }
 
+/// What a static asset response was, for metrics.
+#[derive(Debug, Clone, Copy)]
+pub struct StaticServed {
+    pub status: u16,
+    pub body_bytes: u64,
+    /// Whether the request was resolved through the manifest.
+    pub from_manifest: bool,
+    /// Whether the body came from the in-memory tier; unset when it was not consulted.
+    pub memory_cache_hit: Option<bool>,
+}
+
+/// What [`StaticAssets::force_reload`] did.
+#[derive(Debug, Serialize)]
+pub struct StaticReload {
+    /// Entries of the re-read manifest; unset without one.
+    pub manifest_entries: Option<usize>,
+    pub memory_cache_dropped: usize,
+    pub images_dropped: usize,
+}
+
 /// Handles resolving and serving static assets from disk.
 #[derive(Clone)]
 pub struct StaticAssets {
@@ -128,6 +172,10 @@ pub struct StaticAssets {
     immutable_cache_seconds: u64,
     default_cache_seconds: u64,
     keepalive_seconds: u64,
+    memory_cache: Option<MemoryCache>,
+    response_policy: ResponsePolicy,
+    images: Option<Arc<Images>>,
+    markdown: Option<Arc<MarkdownPages>>,
 }
 
 impl StaticAssets {
@@ -147,50 +195,98 @@ impl StaticAssets {
             immutable_cache_seconds: config.immutable_cache_seconds,
             default_cache_seconds: config.default_cache_seconds,
             keepalive_seconds: config.keepalive_seconds,
+            memory_cache: config.memory_cache.map(MemoryCache::new),
+            response_policy: config.response_policy,
+            images: None,
+            markdown: config.markdown,
         })
     }
 
-    pub fn manifest_background(
-        &self,
-        poll_seconds: u64,
-    ) -> Option<pingora::services::background::GenBackgroundService<StaticManifestService>> {
-        self.manifest.as_ref().map(|handle| {
-            background_service(
-                "static manifest reload",
-                StaticManifestService {
-                    handle: handle.clone(),
-                    interval: Duration::from_secs(poll_seconds.max(1)),
-                },
-            )
+    /// A copy sharing this server's manifest and memory cache but decorating
+    /// responses with `response_policy`.
+    pub fn with_response_policy(&self, response_policy: ResponsePolicy) -> Self {
+        Self {
+            response_policy,
+            ..self.clone()
+        }
+    }
+
+    /// A copy that converts images for requests asking for it with `images`.
+    pub fn with_images(&self, images: Option<Arc<Images>>) -> Self {
+        Self {
+            images,
+            ..self.clone()
+        }
+    }
+
+    /// Re-reads the manifest if it changed on disk since the last check.
+    pub async fn reload_manifest(&self) {
+        if let Some(handle) = &self.manifest {
+            handle.reload_if_needed().await;
+        }
+    }
+
+    /// Re-reads the manifest now, whether or not it changed, and drops the files and converted
+    /// images held in memory, for deploys that can't wait for the next poll.
+    pub async fn force_reload(&self) -> Result<StaticReload, String> {
+        let manifest_entries = match &self.manifest {
+            Some(handle) => Some(handle.reload(true).await?),
+            None => None,
+        };
+        let memory_cache_dropped = match &self.memory_cache {
+            Some(cache) => cache.clear().await,
+            None => 0,
+        };
+        let images_dropped = match &self.images {
+            Some(images) => images.clear_cache().await,
+            None => 0,
+        };
+        info!(
+            "static assets under {} reloaded: dropped {memory_cache_dropped} files and \
+             {images_dropped} images from memory",
+            self.mount_path
+        );
+        Ok(StaticReload {
+            manifest_entries,
+            memory_cache_dropped,
+            images_dropped,
         })
     }
 
-    pub async fn try_serve(&self, session: &mut Session) -> Result<bool> {
+    /// Answers `session` from disk if it is for a static asset; returns what was served.
+    pub async fn try_serve(&self, session: &mut Session) -> Result<Option<StaticServed>> {
         match session.req_header().method.as_str() {
             "GET" | "HEAD" => {}
-            _ => return Ok(false),
+            _ => return Ok(None),
         }
 
         let path = session.req_header().uri.path();
         let Some(resolved) = self.resolve(path).await else {
-            return Ok(false);
+            return Ok(None);
         };
 
         match fs::metadata(&resolved.full_path).await {
             Ok(metadata) => {
                 if !metadata.is_file() {
                     debug!("static path {:?} is not a file", resolved.full_path);
-                    return self.respond_not_found(session).await;
+                    return self.respond_not_found(session).await.map(Some);
                 }
-                let etag = build_etag(metadata.len(), metadata.modified().ok());
+                let etag = self.etag_for(&resolved, &metadata);
                 let last_modified = metadata.modified().ok().map(fmt_http_date);
                 if self.is_not_modified(session, &etag, last_modified.as_deref()) {
                     return self
-                        .respond_not_modified(session, &etag, last_modified.as_deref())
-                        .await;
+                        .respond_not_modified(
+                            session,
+                            &etag,
+                            last_modified.as_deref(),
+                            resolved.from_manifest,
+                        )
+                        .await
+                        .map(Some);
                 }
                 self.respond_with_file(session, resolved, metadata.len(), etag, last_modified)
                     .await
+                    .map(Some)
             }
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::NotFound {
@@ -199,13 +295,13 @@ impl StaticAssets {
                             "route-like request {:?} not found in static files, serving SPA fallback",
                             resolved.logical_path
                         );
-                        return self.respond_with_index(session).await;
+                        return self.respond_with_index(session).await.map(Some);
                     }
                     debug!(
                         "static asset miss for {:?}, falling back to upstream",
                         resolved.full_path
                     );
-                    return Ok(false);
+                    return Ok(None);
                 }
                 error!(
                     "error accessing static asset {:?}: {}",
@@ -220,6 +316,63 @@ impl StaticAssets {
         }
     }
 
+    fn etag_for(&self, resolved: &ResolvedFile, metadata: &std::fs::Metadata) -> String {
+        let etag = build_etag(metadata.len(), metadata.modified().ok());
+        match self.markdown_for(resolved) {
+            Some(markdown) => markdown.etag(&etag),
+            None => etag,
+        }
+    }
+
+    /// The renderer for `resolved`, if it is a Markdown file served as a page.
+    fn markdown_for(&self, resolved: &ResolvedFile) -> Option<&MarkdownPages> {
+        self.markdown
+            .as_deref()
+            .filter(|_| MarkdownPages::handles(&resolved.logical_path))
+    }
+
+    async fn respond_with_markdown(
+        &self,
+        session: &mut Session,
+        resolved: ResolvedFile,
+        markdown: &MarkdownPages,
+        etag: String,
+        last_modified: Option<String>,
+    ) -> Result<StaticServed> {
+        let source = read_file(&resolved.full_path).await?;
+        let name = resolved.logical_path.rsplit('/').next().unwrap_or_default();
+        let page = Bytes::from(markdown.render(&String::from_utf8_lossy(&source), name));
+
+        let mut header = ResponseHeader::build(200, None)?;
+        header.insert_header(CONTENT_LENGTH, page.len().to_string())?;
+        header.insert_header(CONTENT_TYPE, "text/html; charset=utf-8")?;
+        header.insert_header(ETAG, etag)?;
+        if let Some(value) = &last_modified {
+            header.insert_header(LAST_MODIFIED, value.as_str())?;
+        }
+        header.insert_header(CACHE_CONTROL, "no-cache, must-revalidate")?;
+        self.decorate(session, &mut header)?;
+
+        let head_only = session.req_header().method.as_str() == "HEAD";
+        session
+            .write_response_header(Box::new(header), head_only)
+            .await?;
+        let mut served = StaticServed {
+            status: 200,
+            body_bytes: 0,
+            from_manifest: resolved.from_manifest,
+            memory_cache_hit: None,
+        };
+        if !head_only {
+            served.body_bytes = page.len() as u64;
+            session.write_response_body(Some(page), false).await?;
+        }
+        session.finish_body().await?;
+        session.set_keepalive(Some(self.keepalive_seconds));
+        info!("served rendered Markdown {}", resolved.logical_path);
+        Ok(served)
+    }
+
     async fn respond_with_file(
         &self,
         session: &mut Session,
@@ -227,11 +380,48 @@ impl StaticAssets {
         len: u64,
         etag: String,
         last_modified: Option<String>,
-    ) -> Result<bool> {
+    ) -> Result<StaticServed> {
+        if let Some(markdown) = self.markdown_for(&resolved) {
+            return self
+                .respond_with_markdown(session, resolved, markdown, etag, last_modified)
+                .await;
+        }
+        let mut served = StaticServed {
+            status: 200,
+            body_bytes: 0,
+            from_manifest: resolved.from_manifest,
+            memory_cache_hit: None,
+        };
+        let mime = content_type_for(&resolved.logical_path);
+        let image = match (&self.images, &mime) {
+            (Some(images), Some(mime)) if len as usize <= images.max_source_bytes() => images
+                .transform_for(session.req_header(), mime)
+                .map(|transform| (images, transform)),
+            _ => None,
+        };
+        let converted = match image {
+            Some((images, transform)) => {
+                let source = Bytes::from(read_file(&resolved.full_path).await?);
+                let key = format!(
+                    "{}?{}",
+                    resolved.full_path.display(),
+                    session.req_header().uri.query().unwrap_or("")
+                );
+                match images.convert(&key, Some(&etag), source, &transform).await {
+                    Ok(body) => Some((images, transform, body)),
+                    Err(err) => {
+                        warn!("can't convert image {:?}: {err}", resolved.full_path);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
         let mut header = ResponseHeader::build(200, None)?;
         header.insert_header(CONTENT_LENGTH, len.to_string())?;
 
-        if let Some(mime) = content_type_for(&resolved.logical_path) {
+        if let Some(mime) = mime {
             header.insert_header(CONTENT_TYPE, mime)?;
         }
 
@@ -254,7 +444,23 @@ impl StaticAssets {
             header.insert_header(CACHE_CONTROL, cache_header)?;
         }
 
-        apply_cors(session, &mut header)?;
+        if let Some((images, transform, body)) = &converted {
+            images.static_response(&mut header, transform, &etag, body.len())?;
+        }
+
+        self.decorate(session, &mut header)?;
+
+        if let Some((_, _, body)) = converted {
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            served.body_bytes = body.len() as u64;
+            session.write_response_body(Some(body), false).await?;
+            session.finish_body().await?;
+            session.set_keepalive(Some(self.keepalive_seconds));
+            info!("served converted image {}", resolved.logical_path);
+            return Ok(served);
+        }
 
         let head_only = session.req_header().method.as_str() == "HEAD";
         session
@@ -263,7 +469,36 @@ impl StaticAssets {
 
         if head_only {
             session.finish_body().await?;
-            return Ok(true);
+            return Ok(served);
+        }
+        served.body_bytes = len;
+
+        if let Some(cache) = &self.memory_cache
+            && cache.admits(len)
+        {
+            let cached = cache.get(&resolved.full_path, &etag).await;
+            served.memory_cache_hit = Some(cached.is_some());
+            let body = match cached {
+                Some(body) => {
+                    trace!("serving {:?} from memory", resolved.full_path);
+                    Some(body)
+                }
+                None if cache.record_miss(&resolved.full_path).await => {
+                    let body = Bytes::from(read_file(&resolved.full_path).await?);
+                    cache
+                        .insert(&resolved.full_path, etag.clone(), body.clone())
+                        .await;
+                    Some(body)
+                }
+                None => None,
+            };
+            if let Some(body) = body {
+                session.write_response_body(Some(body), false).await?;
+                session.finish_body().await?;
+                session.set_keepalive(Some(self.keepalive_seconds));
+                info!("served static asset {}", resolved.logical_path);
+                return Ok(served);
+            }
         }
 
         let mut file = fs::File::open(&resolved.full_path).await.map_err(|err| {
@@ -292,7 +527,7 @@ impl StaticAssets {
         session.finish_body().await?;
         session.set_keepalive(Some(self.keepalive_seconds));
         info!("served static asset {}", resolved.logical_path);
-        Ok(true)
+        Ok(served)
     }
 
     async fn respond_not_modified(
@@ -300,46 +535,58 @@ impl StaticAssets {
         session: &mut Session,
         etag: &str,
         last_modified: Option<&str>,
-    ) -> Result<bool> {
+        from_manifest: bool,
+    ) -> Result<StaticServed> {
         let mut header = ResponseHeader::build(304, None)?;
         header.insert_header(ETAG, etag)?;
         if let Some(value) = last_modified {
             header.insert_header(LAST_MODIFIED, value)?;
         }
-        apply_cors(session, &mut header)?;
+        self.decorate(session, &mut header)?;
         session
             .write_response_header(Box::new(header), true)
             .await?;
         session.finish_body().await?;
-        Ok(true)
+        Ok(StaticServed {
+            status: 304,
+            body_bytes: 0,
+            from_manifest,
+            memory_cache_hit: None,
+        })
     }
 
-    async fn respond_not_found(&self, session: &mut Session) -> Result<bool> {
+    async fn respond_not_found(&self, session: &mut Session) -> Result<StaticServed> {
         let mut header = ResponseHeader::build(404, None)?;
         header.insert_header(CONTENT_TYPE, "text/plain; charset=utf-8")?;
-        apply_cors(session, &mut header)?;
+        self.decorate(session, &mut header)?;
         session
             .write_response_header(Box::new(header), false)
             .await?;
         let body = Bytes::from_static(b"404 not found");
+        let body_bytes = body.len() as u64;
         session.write_response_body(Some(body), true).await?;
         session.finish_body().await?;
-        Ok(true)
+        Ok(StaticServed {
+            status: 404,
+            body_bytes,
+            from_manifest: false,
+            memory_cache_hit: None,
+        })
     }
 
-    async fn respond_with_index(&self, session: &mut Session) -> Result<bool> {
+    async fn respond_with_index(&self, session: &mut Session) -> Result<StaticServed> {
         let mut full_path = self.root.clone();
         full_path.push(&self.index_file);
 
         match fs::metadata(&full_path).await {
             Ok(metadata) => {
-                let etag = build_etag(metadata.len(), metadata.modified().ok());
-                let last_modified = metadata.modified().ok().map(fmt_http_date);
                 let resolved = ResolvedFile {
                     full_path,
                     logical_path: self.index_file.clone(),
                     from_manifest: false,
                 };
+                let etag = self.etag_for(&resolved, &metadata);
+                let last_modified = metadata.modified().ok().map(fmt_http_date);
                 self.respond_with_file(session, resolved, metadata.len(), etag, last_modified)
                     .await
             }
@@ -354,6 +601,11 @@ impl StaticAssets {
         }
     }
 
+    /// Applies the response-wide policies shared with proxied responses.
+    fn decorate(&self, session: &Session, header: &mut ResponseHeader) -> Result<()> {
+        self.response_policy.apply(session.req_header(), header)
+    }
+
     fn is_not_modified(&self, session: &Session, etag: &str, last_modified: Option<&str>) -> bool {
         if let Some(value) = session
             .req_header()
@@ -435,6 +687,121 @@ impl StaticAssets {
         })
     }
 
+    /// Checks that the root is readable, the index file exists, and a sample of
+    /// manifest entries point at real files under the root. Returns the problems found.
+    pub fn self_test(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(err) = std::fs::read_dir(&self.root) {
+            problems.push(format!(
+                "static root {:?} is not readable: {err}",
+                self.root
+            ));
+            return problems;
+        }
+        let index = self.root.join(&self.index_file);
+        if !index.is_file() {
+            problems.push(format!("index file {index:?} does not exist"));
+        }
+
+        let Some(manifest) = &self.manifest else {
+            return problems;
+        };
+        let state = match load_manifest_blocking(&manifest.path) {
+            Ok(state) => state,
+            Err(err) => {
+                problems.push(format!(
+                    "failed to load manifest {:?}: {err}",
+                    manifest.path
+                ));
+                return problems;
+            }
+        };
+        let mut entries: Vec<_> = state.entries.iter().collect();
+        entries.sort();
+        let step = entries.len().div_ceil(SELF_TEST_MANIFEST_SAMPLE).max(1);
+        for (logical, file) in entries.into_iter().step_by(step) {
+            if contains_illegal_component(file) {
+                problems.push(format!(
+                    "manifest entry {logical:?} points outside the static root: {file}"
+                ));
+            } else if !self.root.join(file).is_file() {
+                problems.push(format!(
+                    "manifest entry {logical:?} points at missing file {:?}",
+                    self.root.join(file)
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Counters of the in-memory tier, when it is enabled.
+    /// The files held in memory.
+    pub async fn cache_entries(&self) -> Vec<CacheEntry> {
+        match &self.memory_cache {
+            Some(cache) => cache.entries().await,
+            None => Vec::new(),
+        }
+    }
+
+    /// What a request for `path` gets, for the admin API: the file it resolves to, how long
+    /// clients may cache it, and its copies held in memory (the file itself, and images
+    /// converted from it). `None` when `path` is not under the mount.
+    pub async fn inspect(&self, path: &str) -> Option<serde_json::Value> {
+        let resolved = self.resolve(path).await?;
+        let metadata = fs::metadata(&resolved.full_path).await.ok();
+        let etag = metadata
+            .as_ref()
+            .map(|metadata| self.etag_for(&resolved, metadata));
+        let max_age =
+            if resolved.logical_path.ends_with(".html") || self.markdown_for(&resolved).is_some() {
+                0
+            } else if resolved.from_manifest {
+                self.immutable_cache_seconds
+            } else {
+                self.default_cache_seconds
+            };
+        let full_path = resolved.full_path.display().to_string();
+        let current = |entry: &CacheEntry| Some(&entry.etag) == etag.as_ref();
+        let memory = self
+            .cache_entries()
+            .await
+            .into_iter()
+            .find(|entry| entry.key == full_path)
+            .map(|entry| json!({ "current": current(&entry), "entry": entry }));
+        let variant_prefix = format!("{full_path}?");
+        let images: Vec<_> = match &self.images {
+            Some(images) => images
+                .cache_entries()
+                .await
+                .into_iter()
+                .filter(|entry| entry.key.starts_with(&variant_prefix))
+                .map(|entry| {
+                    let variant = entry.key[variant_prefix.len()..].to_string();
+                    json!({ "variant": variant, "current": current(&entry), "entry": entry })
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        Some(json!({
+            "path": path,
+            "file": full_path,
+            "from_manifest": resolved.from_manifest,
+            "exists": metadata.as_ref().is_some_and(|metadata| metadata.is_file()),
+            "bytes": metadata.as_ref().map(|metadata| metadata.len()),
+            "etag": etag,
+            "max_age_seconds": max_age,
+            "memory": memory,
+            "images": images,
+        }))
+    }
+
+    pub async fn memory_cache_stats(&self) -> Option<MemoryCacheStats> {
+        match &self.memory_cache {
+            Some(cache) => Some(cache.stats().await),
+            None => None,
+        }
+    }
+
     pub fn mount_path(&self) -> &str {
         &self.mount_path
     }
@@ -451,6 +818,16 @@ fn build_etag(len: u64, modified: Option<SystemTime>) -> String {
     }
 }
 
+async fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).await.map_err(|err| {
+        Error::because(
+            ErrorType::FileReadError,
+            format!("failed to read static asset {:?}", path),
+            err,
+        )
+    })
+}
+
 fn content_type_for(path: &str) -> Option<String> {
     MimeGuess::from_path(path)
         .first()
@@ -507,9 +884,30 @@ fn parse_manifest_entries(contents: &str) -> Result<HashMap<String, String>, ser
     Ok(map)
 }
 
+/// Provides the static asset server whose manifest should be watched.
+///
+/// The server can be replaced at runtime by a config reload, so the watcher
+/// asks for the current one on every tick.
+pub trait ManifestSource: Send + Sync {
+    fn static_assets(&self) -> Option<StaticAssets>;
+}
+
+pub fn manifest_background(
+    source: Arc<dyn ManifestSource>,
+    poll_seconds: u64,
+) -> pingora::services::background::GenBackgroundService<StaticManifestService> {
+    background_service(
+        "static manifest reload",
+        StaticManifestService {
+            source,
+            interval: Duration::from_secs(poll_seconds.max(1)),
+        },
+    )
+}
+
 /// Background service that refreshes the manifest on a fixed interval.
 pub struct StaticManifestService {
-    handle: ManifestHandle,
+    source: Arc<dyn ManifestSource>,
     interval: Duration,
 }
 
@@ -517,14 +915,16 @@ pub struct StaticManifestService {
 impl BackgroundService for StaticManifestService {
     async fn start(&self, mut shutdown: ShutdownWatch) {
         info!(
-            "starting static manifest watcher for {:?} (interval: {:?})",
-            self.handle.path, self.interval
+            "starting static manifest watcher (interval: {:?})",
+            self.interval
         );
         let mut ticker = tokio::time::interval(self.interval);
         loop {
             tokio::select! {
                 _ = ticker.tick() => {
-                    self.handle.reload_if_needed().await;
+                    if let Some(assets) = self.source.static_assets() {
+                        assets.reload_manifest().await;
+                    }
                 }
                 _ = shutdown.changed() => {
                     info!("static manifest watcher shutting down");
@@ -534,17 +934,3 @@ impl BackgroundService for StaticManifestService {
         }
     }
 }
-
-fn apply_cors(session: &Session, header: &mut ResponseHeader) -> Result<()> {
-    if let Some(origin_value) = session.req_header().headers.get(ORIGIN) {
-        header.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, origin_value)?;
-        header.append_header(VARY, "Origin")?;
-        header.insert_header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
-        header.insert_header(
-            ACCESS_CONTROL_ALLOW_METHODS,
-            "GET, POST, PUT, DELETE, OPTIONS, PATCH",
-        )?;
-        header.insert_header(ACCESS_CONTROL_MAX_AGE, "86400")?;
-    }
-    Ok(())
-}
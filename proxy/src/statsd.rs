@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use log::debug;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::UpstreamTiming;
+
+const DEFAULT_PREFIX: &str = "proxy";
+
+/// Wire format of emitted metrics.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFormat {
+    /// DogStatsD, with labels and `tags` sent as `|#key:value` tags.
+    #[default]
+    Dogstatsd,
+    /// Plain statsd, with label values appended to the metric name.
+    Statsd,
+}
+
+/// `[statsd]` section of the config file. Nothing is sent when the section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct StatsdConfig {
+    /// `host:port` of the statsd server or Datadog agent, e.g. `"127.0.0.1:8125"`.
+    pub addr: String,
+    /// Prepended to every metric name, separated by a dot.
+    pub prefix: Option<String>,
+    pub format: Option<StatsdFormat>,
+    /// Tags added to every metric (DogStatsD only).
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl StatsdConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            prefix: Some(self.prefix().to_string()),
+            format: Some(self.format.unwrap_or_default()),
+            ..self
+        }
+    }
+
+    fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(DEFAULT_PREFIX)
+    }
+}
+
+/// Pushes per-request counters and timers over UDP, one datagram per request.
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    prefix: String,
+    format: StatsdFormat,
+    /// Preformatted `key:value` pairs of the configured tags.
+    tags: Vec<String>,
+}
+
+impl StatsdExporter {
+    pub fn new(config: &StatsdConfig) -> Result<Self, String> {
+        let target = config
+            .addr
+            .to_socket_addrs()
+            .map_err(|err| format!("failed to resolve {}: {err}", config.addr))?
+            .next()
+            .ok_or_else(|| format!("{} did not resolve to any address", config.addr))?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)
+            .and_then(|socket| socket.connect(target).map(|()| socket))
+            .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+            .map_err(|err| format!("failed to open UDP socket to {target}: {err}"))?;
+        let prefix = config.prefix().trim_end_matches('.');
+        Ok(Self {
+            socket,
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{prefix}.")
+            },
+            format: config.format.unwrap_or_default(),
+            tags: config
+                .tags
+                .iter()
+                .map(|(key, value)| format!("{key}:{value}"))
+                .collect(),
+        })
+    }
+
+    /// Sends the metrics of a finished request. `upstream` is set for proxied requests.
+    pub fn observe_request(&self, route: &str, status: u16, upstream: Option<&UpstreamTiming>) {
+        let mut datagram = String::new();
+        let status_class = format!("{}xx", status / 100);
+        self.push(
+            &mut datagram,
+            "requests",
+            "1|c",
+            &[("route", route), ("status", &status_class)],
+        );
+        if let Some(timing) = upstream {
+            let route = [("route", timing.route)];
+            if let Some(ttfb) = timing.ttfb {
+                self.push(&mut datagram, "upstream.ttfb", &timer(ttfb), &route);
+            }
+            self.push(
+                &mut datagram,
+                "upstream.duration",
+                &timer(timing.total),
+                &route,
+            );
+            let status = match timing.status {
+                Some(status) => format!("{}xx", status / 100),
+                None => "error".to_string(),
+            };
+            self.push(
+                &mut datagram,
+                "upstream.responses",
+                "1|c",
+                &[("route", timing.route), ("status", &status)],
+            );
+        }
+        if let Err(err) = self.socket.send(datagram.as_bytes()) {
+            debug!("failed to send statsd metrics: {err}");
+        }
+    }
+
+    /// Appends one metric line, e.g. `proxy.requests:1|c|#route:api`.
+    fn push(&self, datagram: &mut String, name: &str, value: &str, labels: &[(&str, &str)]) {
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(&self.prefix);
+        datagram.push_str(name);
+        match self.format {
+            StatsdFormat::Statsd => {
+                for (_, label) in labels {
+                    datagram.push('.');
+                    datagram.push_str(&sanitize(label));
+                }
+                let _ = write!(datagram, ":{value}");
+            }
+            StatsdFormat::Dogstatsd => {
+                let _ = write!(datagram, ":{value}");
+                let mut tags = labels
+                    .iter()
+                    .map(|(key, label)| format!("{key}:{}", sanitize(label)))
+                    .chain(self.tags.iter().cloned());
+                if let Some(first) = tags.next() {
+                    let _ = write!(datagram, "|#{first}");
+                    for tag in tags {
+                        let _ = write!(datagram, ",{tag}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A timer value in milliseconds.
+fn timer(duration: Duration) -> String {
+    format!("{:.3}|ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Replaces characters with a meaning in the statsd line format.
+fn sanitize(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | ',' | '#' | '@' | '.' | '\n' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
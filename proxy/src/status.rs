@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use http::{Response, StatusCode, header};
+use pingora::http::RequestHeader;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::clients::ClientTraffic;
+use crate::endpoints::local_response;
+use crate::health::UpstreamHealth;
+use crate::state::SharedState;
+
+const DEFAULT_STATUS_PATH: &str = "/status";
+/// Name requests are counted under when no route matched.
+pub const DEFAULT_ROUTE_NAME: &str = "default";
+/// Seconds of history request rates are averaged over.
+const RATE_WINDOW_SECONDS: usize = 60;
+
+/// `[status]` section of the config file. The status page is off when the
+/// section is absent.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct StatusConfig {
+    /// Serve the page on this address only; unset serves it on the proxy listeners.
+    pub listen_addr: Option<String>,
+    /// Path of the page; append `?format=json` (or send `Accept: application/json`) for JSON.
+    pub path: Option<String>,
+}
+
+impl StatusConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            path: Some(self.path().to_string()),
+            listen_addr: self.listen_addr,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or(DEFAULT_STATUS_PATH)
+    }
+}
+
+/// Request counters collected for the status page.
+pub struct RequestStats {
+    started: Instant,
+    in_flight: AtomicU64,
+    routes: Mutex<BTreeMap<String, RouteStats>>,
+}
+
+struct RouteStats {
+    requests: u64,
+    server_errors: u64,
+    /// Requests per second over the last [`RATE_WINDOW_SECONDS`], indexed by second.
+    window: [u64; RATE_WINDOW_SECONDS],
+    /// Second (since start) the newest window slot belongs to.
+    window_second: u64,
+}
+
+impl Default for RouteStats {
+    fn default() -> Self {
+        Self {
+            requests: 0,
+            server_errors: 0,
+            window: [0; RATE_WINDOW_SECONDS],
+            window_second: 0,
+        }
+    }
+}
+
+impl RouteStats {
+    /// Clears the slots of seconds that passed without requests, up to `second`.
+    fn advance(&mut self, second: u64) {
+        let elapsed = second.saturating_sub(self.window_second);
+        for step in 1..=elapsed.min(RATE_WINDOW_SECONDS as u64) {
+            self.window[((self.window_second + step) % RATE_WINDOW_SECONDS as u64) as usize] = 0;
+        }
+        self.window_second = self.window_second.max(second);
+    }
+
+    /// Average requests per second over the window ending at `second`.
+    fn rate(&mut self, second: u64) -> f64 {
+        self.advance(second);
+        let span = (second + 1).min(RATE_WINDOW_SECONDS as u64);
+        self.window.iter().sum::<u64>() as f64 / span as f64
+    }
+}
+
+impl Default for RequestStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            in_flight: AtomicU64::new(0),
+            routes: Mutex::default(),
+        }
+    }
+}
+
+impl RequestStats {
+    /// Requests started and not yet finished.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn request_started(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a finished request under its route name.
+    pub fn request_finished(&self, route: &str, status: u16) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let second = self.started.elapsed().as_secs();
+        let mut routes = self
+            .routes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stats = match routes.get_mut(route) {
+            Some(stats) => stats,
+            None => routes.entry(route.to_string()).or_default(),
+        };
+        stats.advance(second);
+        stats.requests += 1;
+        if status >= 500 {
+            stats.server_errors += 1;
+        }
+        stats.window[(second % RATE_WINDOW_SECONDS as u64) as usize] += 1;
+    }
+}
+
+/// The runtime status page: uptime, traffic, upstream health, cache statistics and the
+/// busiest clients.
+pub struct StatusPage {
+    path: String,
+    state: SharedState,
+    stats: Arc<RequestStats>,
+    upstreams: UpstreamHealth,
+    clients: Option<Arc<ClientTraffic>>,
+}
+
+impl StatusPage {
+    pub fn new(
+        config: &StatusConfig,
+        state: SharedState,
+        stats: Arc<RequestStats>,
+        upstreams: UpstreamHealth,
+        clients: Option<Arc<ClientTraffic>>,
+    ) -> Self {
+        Self {
+            path: config.path().to_string(),
+            state,
+            stats,
+            upstreams,
+            clients,
+        }
+    }
+
+    /// The page for requests to its path, in JSON or HTML as asked for.
+    pub async fn answer(&self, request: &RequestHeader) -> Option<Response<Vec<u8>>> {
+        if request.uri.path() != self.path {
+            return None;
+        }
+        let wants_json = request
+            .uri
+            .query()
+            .is_some_and(|query| query.split('&').any(|pair| pair == "format=json"))
+            || request
+                .headers
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("application/json"));
+        let report = self.report().await;
+        Some(if wants_json {
+            local_response(
+                StatusCode::OK,
+                "application/json",
+                serde_json::to_vec_pretty(&report).unwrap_or_default(),
+            )
+        } else {
+            local_response(
+                StatusCode::OK,
+                "text/html; charset=utf-8",
+                render_html(&report).into_bytes(),
+            )
+        })
+    }
+
+    async fn report(&self) -> serde_json::Value {
+        let state = self.state.current();
+        let uptime = self.stats.started.elapsed().as_secs();
+
+        let routes: Vec<_> = {
+            let mut routes = self
+                .stats
+                .routes
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            routes
+                .iter_mut()
+                .map(|(name, stats)| {
+                    json!({
+                        "name": name,
+                        "requests": stats.requests,
+                        "server_errors": stats.server_errors,
+                        "requests_per_second": (stats.rate(uptime) * 100.0).round() / 100.0,
+                    })
+                })
+                .collect()
+        };
+        let total: u64 = routes
+            .iter()
+            .filter_map(|route| route["requests"].as_u64())
+            .sum();
+
+        let upstreams = self.upstreams.report(state.upstreams());
+
+        let memory_cache = match &state.static_assets {
+            Some(static_assets) => static_assets.memory_cache_stats().await,
+            None => None,
+        };
+
+        json!({
+            "uptime_seconds": uptime,
+            "requests": {
+                "in_flight": self.stats.in_flight.load(Ordering::Relaxed),
+                "total": total,
+            },
+            "routes": routes,
+            "upstreams": upstreams,
+            "memory_cache": memory_cache,
+            "top_clients": self.clients.as_ref().map(|clients| clients.top_talkers()),
+        })
+    }
+}
+
+fn render_html(report: &serde_json::Value) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Proxy status</title>\
+         <style>body{font-family:sans-serif}table{border-collapse:collapse;margin-bottom:1em}\
+         td,th{border:1px solid #ccc;padding:2px 8px;text-align:left}</style></head><body>\n\
+         <h1>Proxy status</h1>\n",
+    );
+    let _ = writeln!(
+        html,
+        "<p>Uptime: {}s &middot; In-flight requests: {} &middot; Total requests: {}</p>",
+        report["uptime_seconds"], report["requests"]["in_flight"], report["requests"]["total"]
+    );
+    let sections = [
+        (
+            "Routes",
+            "routes",
+            &["name", "requests", "server_errors", "requests_per_second"][..],
+        ),
+        ("Upstreams", "upstreams", &["addr", "health", "error"][..]),
+        (
+            "Top clients",
+            "top_clients",
+            &[
+                "client",
+                "in_flight",
+                "connections",
+                "requests",
+                "bytes_received",
+                "bytes_sent",
+            ][..],
+        ),
+    ];
+    for (title, key, columns) in sections {
+        if report[key].is_null() {
+            continue;
+        }
+        let _ = write!(html, "<h2>{title}</h2>\n<table><tr>");
+        for column in columns {
+            let _ = write!(html, "<th>{column}</th>");
+        }
+        html.push_str("</tr>\n");
+        for row in report[key].as_array().into_iter().flatten() {
+            html.push_str("<tr>");
+            for column in columns {
+                let _ = write!(html, "<td>{}</td>", escape_html(&cell(&row[*column])));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("<h2>Memory cache</h2>\n");
+    match report["memory_cache"].as_object() {
+        Some(cache) => {
+            html.push_str("<table>");
+            for (name, value) in cache {
+                let _ = write!(html, "<tr><th>{name}</th><td>{value}</td></tr>");
+            }
+            html.push_str("</table>\n");
+        }
+        None => html.push_str("<p>disabled</p>\n"),
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn cell(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use regex::bytes::{NoExpand, Regex};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::body_rewrite;
+
+/// Longest line held back for matching; longer ones are rewritten in pieces.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// `substitutions`: find-and-replace rules applied to response bodies, e.g. to rewrite the
+/// absolute internal URLs a backend writes into its pages.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubstitutionsConfig {
+    /// Media types whose bodies are rewritten.
+    pub content_types: Option<Vec<String>>,
+    /// Applied in order, each to the output of the one before.
+    #[serde(default)]
+    pub rules: Vec<SubstitutionRule>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct SubstitutionRule {
+    /// Text to find, or a regular expression with `regex`. Matches don't span lines.
+    pub find: String,
+    /// With `regex`, `$1` and `$name` insert captured groups.
+    pub replace: String,
+    #[serde(default)]
+    pub regex: bool,
+}
+
+impl SubstitutionsConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            content_types: Some(
+                self.content_types
+                    .unwrap_or_else(|| vec!["text/html".to_string()]),
+            ),
+            ..self
+        }
+    }
+}
+
+/// A compiled `substitutions` table.
+#[derive(Debug)]
+pub struct Substitutions {
+    content_types: Vec<String>,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    find: Regex,
+    replace: Vec<u8>,
+    expand: bool,
+}
+
+impl Substitutions {
+    pub fn new(config: &SubstitutionsConfig) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                if rule.find.is_empty() {
+                    return Err(format!("rules[{index}].find is empty"));
+                }
+                let pattern = if rule.regex {
+                    rule.find.clone()
+                } else {
+                    regex::escape(&rule.find)
+                };
+                let find = Regex::new(&pattern)
+                    .map_err(|err| format!("rules[{index}].find is not a valid regex: {err}"))?;
+                Ok(Rule {
+                    find,
+                    replace: rule.replace.clone().into_bytes(),
+                    expand: rule.regex,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let content_types = config
+            .content_types
+            .clone()
+            .unwrap_or_else(|| vec!["text/html".to_string()])
+            .into_iter()
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+            .collect();
+        Ok(Self {
+            content_types,
+            rules,
+        })
+    }
+
+    pub fn apply_request(&self, request: &mut RequestHeader) {
+        if !self.rules.is_empty() {
+            body_rewrite::ask_uncompressed(request);
+        }
+    }
+
+    /// The substituter for `response`, with its headers adjusted for a body of another
+    /// length, if it is uncompressed and of one of the content types.
+    pub fn start(
+        self: &Arc<Self>,
+        request: &RequestHeader,
+        response: &mut ResponseHeader,
+    ) -> Result<Option<Substituter>> {
+        let accepts = |mime: &str| self.content_types.iter().any(|accepted| accepted == mime);
+        if self.rules.is_empty() || !body_rewrite::start(request, response, accepts)? {
+            return Ok(None);
+        }
+        Ok(Some(Substituter {
+            substitutions: self.clone(),
+            pending: BytesMut::new(),
+        }))
+    }
+
+    fn rewrite(&self, text: &[u8]) -> Vec<u8> {
+        let mut text = text.to_vec();
+        for rule in &self.rules {
+            let replaced = if rule.expand {
+                rule.find.replace_all(&text, rule.replace.as_slice())
+            } else {
+                rule.find.replace_all(&text, NoExpand(&rule.replace))
+            };
+            text = replaced.into_owned();
+        }
+        text
+    }
+}
+
+/// Streams a body through the rules a line at a time, holding back the last, incomplete line
+/// of each chunk until the rest of it arrives.
+pub struct Substituter {
+    substitutions: Arc<Substitutions>,
+    pending: BytesMut,
+}
+
+impl Substituter {
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if let Some(chunk) = body.take() {
+            self.pending.extend_from_slice(&chunk);
+        }
+        let ready = if end_of_stream || self.pending.len() > MAX_LINE_LEN {
+            self.pending.len()
+        } else {
+            self.pending
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(0, |newline| newline + 1)
+        };
+        if ready > 0 || end_of_stream {
+            let text = self.pending.split_to(ready);
+            *body = Some(Bytes::from(self.substitutions.rewrite(&text)));
+        }
+    }
+}
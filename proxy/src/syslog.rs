@@ -0,0 +1,254 @@
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::Level;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_APP_NAME: &str = "proxy";
+
+/// Syslog facility the messages are filed under.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    User,
+    #[default]
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+/// `[syslog]` section of the config file. When present, logs go to syslog
+/// instead of stderr.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct SyslogConfig {
+    /// `udp://host:port`, `tcp://host:port` or `unix:///path/to/socket` (a datagram socket such as `/dev/log`).
+    pub addr: String,
+    pub facility: Option<Facility>,
+    /// APP-NAME field of each message.
+    pub app_name: Option<String>,
+    /// HOSTNAME field of each message; defaults to the machine's hostname.
+    pub hostname: Option<String>,
+}
+
+impl SyslogConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            facility: Some(self.facility.unwrap_or_default()),
+            app_name: Some(self.app_name().to_string()),
+            ..self
+        }
+    }
+
+    fn app_name(&self) -> &str {
+        self.app_name.as_deref().unwrap_or(DEFAULT_APP_NAME)
+    }
+
+    /// Checks the address has a supported scheme and a target.
+    pub fn validate_addr(&self) -> Result<(), String> {
+        match parse_addr(&self.addr) {
+            Some((_, "")) | None => Err(format!(
+                "{:?} must be udp://host:port, tcp://host:port or unix:///path",
+                self.addr
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Transport {
+    Udp,
+    Tcp,
+    Unix,
+}
+
+fn parse_addr(addr: &str) -> Option<(Transport, &str)> {
+    if let Some(target) = addr.strip_prefix("udp://") {
+        Some((Transport::Udp, target))
+    } else if let Some(target) = addr.strip_prefix("tcp://") {
+        Some((Transport::Tcp, target))
+    } else {
+        addr.strip_prefix("unix://")
+            .map(|target| (Transport::Unix, target))
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Unix(UnixDatagram),
+}
+
+/// Sends each log record as one RFC 5424 message.
+pub struct SyslogWriter {
+    transport: Transport,
+    target: String,
+    connection: Option<Connection>,
+}
+
+impl SyslogWriter {
+    pub fn connect(config: &SyslogConfig) -> io::Result<Self> {
+        let Some((transport, target)) = parse_addr(&config.addr) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported syslog address {:?}", config.addr),
+            ));
+        };
+        let mut writer = Self {
+            transport,
+            target: target.to_string(),
+            connection: None,
+        };
+        writer.connection = Some(writer.open()?);
+        Ok(writer)
+    }
+
+    fn open(&self) -> io::Result<Connection> {
+        Ok(match self.transport {
+            Transport::Udp => {
+                let target = self.target.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} did not resolve to any address", self.target),
+                    )
+                })?;
+                let socket = UdpSocket::bind(if target.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                })?;
+                socket.connect(target)?;
+                Connection::Udp(socket)
+            }
+            Transport::Tcp => Connection::Tcp(TcpStream::connect(&self.target)?),
+            Transport::Unix => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&self.target)?;
+                Connection::Unix(socket)
+            }
+        })
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self.connection.insert(self.open()?),
+        };
+        let result = match connection {
+            Connection::Udp(socket) => socket.send(message).map(drop),
+            Connection::Unix(socket) => socket.send(message).map(drop),
+            // Octet-counting framing (RFC 6587), so messages may contain newlines.
+            Connection::Tcp(stream) => {
+                let mut framed = format!("{} ", message.len()).into_bytes();
+                framed.extend_from_slice(message);
+                stream.write_all(&framed)
+            }
+        };
+        if result.is_err() {
+            // Reconnect on the next message, e.g. after the collector restarted.
+            self.connection = None;
+        }
+        result
+    }
+}
+
+impl Write for SyslogWriter {
+    /// Called once per formatted record by the logger.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let message = buf.strip_suffix(b"\n").unwrap_or(buf);
+        // A lost log line must not fail the caller; stderr is the only place left to report it.
+        if let Err(err) = self.send(message) {
+            eprintln!("failed to send log message to syslog: {err}");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats records as RFC 5424 messages: `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID - MSG`.
+pub struct SyslogFormat {
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl SyslogFormat {
+    pub fn new(config: &SyslogConfig) -> Self {
+        let hostname = config
+            .hostname
+            .clone()
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned());
+        Self {
+            facility: config.facility.unwrap_or_default(),
+            hostname: header_field(&hostname, 255),
+            app_name: header_field(config.app_name(), 48),
+            pid: std::process::id(),
+        }
+    }
+
+    pub fn format(&self, out: &mut impl Write, record: &log::Record) -> io::Result<()> {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let timestamp =
+            DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Micros, true);
+        writeln!(
+            out,
+            "<{}>1 {timestamp} {} {} {} {} - {}",
+            self.facility.code() * 8 + severity,
+            self.hostname,
+            self.app_name,
+            self.pid,
+            header_field(record.target(), 32),
+            record.args()
+        )
+    }
+}
+
+/// A header field as RFC 5424 allows it: printable ASCII without spaces, `-` when empty.
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
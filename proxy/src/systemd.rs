@@ -0,0 +1,188 @@
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use pingora::server::{ListenFds, ShutdownWatch};
+use pingora::services::Service;
+use pingora::services::background::BackgroundService;
+use sd_notify::NotifyState;
+
+/// A listening socket passed in by systemd socket activation (`LISTEN_FDS`).
+#[derive(Debug)]
+pub struct InheritedSocket {
+    fd: RawFd,
+    addr: InheritedAddr,
+}
+
+#[derive(Debug)]
+enum InheritedAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl InheritedSocket {
+    /// Whether this socket is bound to the configured TCP address or Unix socket path.
+    pub fn matches(&self, configured: &str) -> bool {
+        match &self.addr {
+            InheritedAddr::Tcp(addr) => configured.parse::<SocketAddr>() == Ok(*addr),
+            InheritedAddr::Unix(path) => path.as_os_str() == configured,
+        }
+    }
+
+    /// The address to register the socket under when nothing in the config matches it.
+    pub fn listen_addr(&self) -> ListenAddr {
+        match &self.addr {
+            InheritedAddr::Tcp(addr) => ListenAddr::Tcp(addr.to_string()),
+            InheritedAddr::Unix(path) => ListenAddr::Unix(path.to_string_lossy().into_owned()),
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(String),
+}
+
+/// Takes the sockets systemd passed to this process, if any.
+///
+/// Must run before any threads are spawned, as it clears `LISTEN_FDS` and `LISTEN_PID`.
+pub fn inherited_sockets() -> Vec<InheritedSocket> {
+    let fds = match sd_notify::listen_fds() {
+        Ok(fds) => fds,
+        Err(err) => {
+            warn!("ignoring invalid systemd socket activation variables: {err}");
+            return Vec::new();
+        }
+    };
+
+    fds.filter_map(|fd| {
+        // Borrow the descriptor without taking ownership; pingora adopts it later.
+        let tcp = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+        if let Err(err) = tcp.set_nonblocking(true) {
+            warn!("ignoring systemd socket fd {fd}: {err}");
+            return None;
+        }
+        let addr = match tcp.local_addr() {
+            Ok(addr) => InheritedAddr::Tcp(addr),
+            Err(_) => {
+                let unix = ManuallyDrop::new(unsafe { UnixListener::from_raw_fd(fd) });
+                match unix
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(PathBuf::from))
+                {
+                    Some(path) => InheritedAddr::Unix(path),
+                    None => {
+                        warn!("ignoring systemd socket fd {fd}: not a TCP or Unix path socket");
+                        return None;
+                    }
+                }
+            }
+        };
+        info!("inherited systemd socket fd {fd} bound to {addr:?}");
+        Some(InheritedSocket { fd, addr })
+    })
+    .collect()
+}
+
+/// Wraps a listening service so it adopts inherited sockets instead of binding its own.
+///
+/// Pingora looks up listeners by address in its fd table before binding (the same
+/// mechanism it uses for graceful upgrades), so the inherited descriptors are
+/// registered there under the addresses the service listens on.
+pub struct SocketActivated<S> {
+    inner: S,
+    sockets: Vec<(String, RawFd)>,
+}
+
+impl<S> SocketActivated<S> {
+    pub fn new(inner: S, sockets: Vec<(String, RawFd)>) -> Self {
+        Self { inner, sockets }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for SocketActivated<S> {
+    async fn start_service(
+        &mut self,
+        fds: Option<ListenFds>,
+        shutdown: ShutdownWatch,
+        listeners_per_fd: usize,
+    ) {
+        if let Some(table) = &fds {
+            let mut table = table.lock().await;
+            for (addr, fd) in &self.sockets {
+                // Sockets handed over by an upgrading predecessor take precedence.
+                if table.get(addr).is_none() {
+                    table.add(addr.clone(), *fd);
+                }
+            }
+        }
+        self.inner
+            .start_service(fds, shutdown, listeners_per_fd)
+            .await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.inner.threads()
+    }
+}
+
+/// Reports readiness, watchdog keep-alives and shutdown to systemd (`Type=notify`).
+///
+/// Does nothing when the process was not started with `NOTIFY_SOCKET`.
+pub struct SystemdNotifier;
+
+#[async_trait]
+impl BackgroundService for SystemdNotifier {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if std::env::var_os("NOTIFY_SOCKET").is_none() {
+            return;
+        }
+        if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+            warn!("failed to notify systemd of readiness: {err}");
+            return;
+        }
+        info!("notified systemd of readiness");
+
+        let mut watchdog_usec = 0;
+        let watchdog = sd_notify::watchdog_enabled(false, &mut watchdog_usec)
+            // Ping twice per period, as sd_watchdog_enabled(3) recommends.
+            .then(|| Duration::from_micros(watchdog_usec / 2).max(Duration::from_millis(100)));
+        if let Some(interval) = watchdog {
+            info!("systemd watchdog enabled, pinging every {interval:?}");
+        }
+
+        loop {
+            tokio::select! {
+                _ = async {
+                    match watchdog {
+                        Some(interval) => tokio::time::sleep(interval).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if let Err(err) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                        warn!("failed to ping systemd watchdog: {err}");
+                    }
+                }
+                _ = shutdown.changed() => {
+                    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+                    break;
+                }
+            }
+        }
+    }
+}
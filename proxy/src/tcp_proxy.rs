@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::ip_list::IpList;
+use crate::metrics::ProxyMetrics;
+use crate::proxy_protocol::ProxyProtocol;
+use crate::state::SharedState;
+
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 60 * 60;
+/// Time a client has to send its TLS ClientHello on a listener routing by server name.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// TLS record header: content type, version and length.
+const RECORD_HEADER_LEN: usize = 5;
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME: usize = 0x0000;
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// A `[[tcp_routes]]` entry: TCP streams accepted on `listen_addr` and relayed as they are to
+/// `upstream_addr`, e.g. for a database.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct TcpRouteConfig {
+    /// Name used in logs and metrics.
+    pub name: String,
+    /// Address it listens on, e.g. `"0.0.0.0:5432"`; only changes on restart. Several routes
+    /// may share one when they route by `sni`.
+    pub listen_addr: String,
+    pub upstream_addr: String,
+    /// Server names, or `*.` and a domain for its subdomains, of TLS connections this route
+    /// takes, read from the ClientHello without terminating TLS. On a shared listener the
+    /// route without `sni` takes the rest.
+    pub sni: Option<Vec<String>>,
+    /// Only clients in these IP addresses and CIDR blocks may connect.
+    pub allow_clients: Option<Vec<String>>,
+    /// PROXY protocol header sent first to the upstream, so that it sees the client address.
+    pub upstream_proxy_protocol: Option<ProxyProtocol>,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub connect_timeout_seconds: Option<u64>,
+    /// How long a connection may carry nothing either way before it is closed.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub idle_timeout_seconds: Option<u64>,
+}
+
+impl TcpRouteConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            upstream_proxy_protocol: Some(self.upstream_proxy_protocol.unwrap_or_default()),
+            connect_timeout_seconds: Some(
+                self.connect_timeout_seconds
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            ),
+            idle_timeout_seconds: Some(
+                self.idle_timeout_seconds
+                    .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+            ),
+            ..self
+        }
+    }
+}
+
+/// A server name pattern of `sni`.
+enum ServerName {
+    Exact(String),
+    /// `*.example.com`, kept as `.example.com`.
+    Subdomains(String),
+}
+
+impl ServerName {
+    fn parse(name: &str) -> Result<Self, String> {
+        let name = name.to_ascii_lowercase();
+        if let Some(domain) = name.strip_prefix("*.")
+            && !domain.is_empty()
+            && !domain.contains('*')
+        {
+            return Ok(Self::Subdomains(format!(".{domain}")));
+        }
+        if name.is_empty() || name.contains(['*', ':', '/']) {
+            return Err(format!("invalid server name '{name}'"));
+        }
+        Ok(Self::Exact(name))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Exact(exact) => name.eq_ignore_ascii_case(exact),
+            Self::Subdomains(suffix) => {
+                name.len() > suffix.len()
+                    && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            }
+        }
+    }
+}
+
+/// A compiled `[[tcp_routes]]` entry.
+pub struct TcpRoute {
+    name: String,
+    upstream_addr: String,
+    sni: Vec<ServerName>,
+    allow_clients: Option<IpList>,
+    proxy_protocol: ProxyProtocol,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+impl TcpRoute {
+    fn new(config: &TcpRouteConfig) -> Result<Self, String> {
+        Ok(Self {
+            name: config.name.clone(),
+            upstream_addr: config.upstream_addr.clone(),
+            sni: config
+                .sni
+                .iter()
+                .flatten()
+                .map(|name| ServerName::parse(name))
+                .collect::<Result<_, _>>()?,
+            allow_clients: config
+                .allow_clients
+                .as_deref()
+                .map(IpList::parse)
+                .transpose()?,
+            proxy_protocol: config.upstream_proxy_protocol.unwrap_or_default(),
+            connect_timeout: Duration::from_secs(
+                config
+                    .connect_timeout_seconds
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            ),
+            idle_timeout: Duration::from_secs(
+                config
+                    .idle_timeout_seconds
+                    .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+            ),
+        })
+    }
+}
+
+/// All `[[tcp_routes]]`, by listen address.
+#[derive(Default)]
+pub struct TcpRoutes {
+    listeners: BTreeMap<String, Vec<TcpRoute>>,
+}
+
+impl TcpRoutes {
+    /// Compiles `configs`, checking that the routes sharing a listener can be told apart.
+    pub fn new(configs: &[TcpRouteConfig]) -> Result<Self, String> {
+        let mut names = HashSet::new();
+        let mut listeners: BTreeMap<String, Vec<TcpRoute>> = BTreeMap::new();
+        for config in configs {
+            if !names.insert(config.name.as_str()) {
+                return Err(format!("route name '{}' is used twice", config.name));
+            }
+            let route =
+                TcpRoute::new(config).map_err(|err| format!("route '{}': {err}", config.name))?;
+            listeners
+                .entry(config.listen_addr.clone())
+                .or_default()
+                .push(route);
+        }
+        for (addr, routes) in &listeners {
+            if routes.len() > 1 && routes.iter().filter(|route| route.sni.is_empty()).count() > 1 {
+                return Err(format!(
+                    "routes on {addr} without sni: all but one of the routes sharing a listener need one"
+                ));
+            }
+        }
+        Ok(Self { listeners })
+    }
+
+    /// The addresses to listen on.
+    pub fn listen_addrs(&self) -> impl Iterator<Item = &str> {
+        self.listeners.keys().map(String::as_str)
+    }
+
+    /// The route taking a connection on `addr` with the TLS server name `server_name`.
+    fn route(&self, addr: &str, server_name: Option<&str>) -> Option<&TcpRoute> {
+        let routes = self.listeners.get(addr)?;
+        server_name
+            .and_then(|name| {
+                routes
+                    .iter()
+                    .find(|route| route.sni.iter().any(|pattern| pattern.matches(name)))
+            })
+            .or_else(|| routes.iter().find(|route| route.sni.is_empty()))
+    }
+
+    fn routes_by_sni(&self, addr: &str) -> bool {
+        self.listeners
+            .get(addr)
+            .is_some_and(|routes| routes.iter().any(|route| !route.sni.is_empty()))
+    }
+}
+
+/// Listening service of one `listen_addr` of `[[tcp_routes]]`, with the routes of the current
+/// config.
+pub struct TcpProxyService {
+    addr: String,
+    state: SharedState,
+    metrics: Option<Arc<ProxyMetrics>>,
+}
+
+impl TcpProxyService {
+    pub fn new(addr: String, state: SharedState, metrics: Option<Arc<ProxyMetrics>>) -> Self {
+        Self {
+            addr,
+            state,
+            metrics,
+        }
+    }
+
+    async fn serve(&self, mut stream: Stream) {
+        let state = self.state.current();
+        let digest = stream.get_socket_digest();
+        let client = digest
+            .as_ref()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied());
+        let server = digest
+            .as_ref()
+            .and_then(|digest| digest.local_addr().and_then(|addr| addr.as_inet()).copied());
+        let (server_name, hello) = if state.tcp_routes.routes_by_sni(&self.addr) {
+            match read_client_hello(&mut stream).await {
+                Some(read) => read,
+                None => return,
+            }
+        } else {
+            (None, Vec::new())
+        };
+        let Some(route) = state.tcp_routes.route(&self.addr, server_name.as_deref()) else {
+            debug!(
+                "no TCP route on {} for {client:?} (server name {server_name:?})",
+                self.addr
+            );
+            return;
+        };
+        if let Some(allow_clients) = &route.allow_clients
+            && !client.is_some_and(|client| allow_clients.contains(&client.ip()))
+        {
+            debug!("TCP route {}: client {client:?} not allowed", route.name);
+            return;
+        }
+        let connect = tokio::time::timeout(
+            route.connect_timeout,
+            TcpStream::connect(route.upstream_addr.as_str()),
+        );
+        let mut upstream = match connect.await {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(err)) => {
+                warn!(
+                    "TCP route {}: connecting to {} failed: {err}",
+                    route.name, route.upstream_addr
+                );
+                return;
+            }
+            Err(_) => {
+                warn!(
+                    "TCP route {}: connecting to {} timed out",
+                    route.name, route.upstream_addr
+                );
+                return;
+            }
+        };
+        let mut first = route
+            .proxy_protocol
+            .header(client, server)
+            .unwrap_or_default();
+        first.extend_from_slice(&hello);
+        if let Err(err) = upstream.write_all(&first).await {
+            warn!(
+                "TCP route {}: writing to upstream failed: {err}",
+                route.name
+            );
+            return;
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.tcp_opened(&route.name);
+        }
+        let started = Instant::now();
+        let (sent, received, ended) = relay(stream, upstream, route.idle_timeout).await;
+        let sent = sent + hello.len() as u64;
+        if let Some(metrics) = &self.metrics {
+            metrics.tcp_closed(&route.name, sent, received);
+        }
+        debug!(
+            "TCP route {}: {client:?} {ended} after {:?}, {sent} bytes sent, {received} received",
+            route.name,
+            started.elapsed()
+        );
+    }
+}
+
+#[async_trait]
+impl ServerApp for TcpProxyService {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        self.serve(stream).await;
+        None
+    }
+}
+
+/// Reads the first TLS record, returning the server name of the ClientHello in it, if any,
+/// with the bytes read to be passed on.
+async fn read_client_hello(stream: &mut Stream) -> Option<(Option<String>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN);
+    let read = async {
+        loop {
+            let wanted = match buf.get(..RECORD_HEADER_LEN) {
+                // Not TLS: routed as having no server name.
+                Some([first, ..]) if *first != HANDSHAKE => return Some(()),
+                Some([_, _, _, high, low]) => {
+                    RECORD_HEADER_LEN + usize::from(u16::from_be_bytes([*high, *low]))
+                }
+                _ => RECORD_HEADER_LEN,
+            };
+            if buf.len() >= wanted {
+                return Some(());
+            }
+            let mut chunk = vec![0; wanted - buf.len()];
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return None,
+                Ok(read) => buf.extend_from_slice(&chunk[..read]),
+            }
+        }
+    };
+    tokio::time::timeout(HELLO_TIMEOUT, read).await.ok()??;
+    let server_name = buf
+        .get(RECORD_HEADER_LEN..)
+        .filter(|_| buf[0] == HANDSHAKE)
+        .and_then(server_name);
+    Some((server_name, buf))
+}
+
+/// The `server_name` extension of a ClientHello handshake message.
+fn server_name(handshake: &[u8]) -> Option<String> {
+    let mut reader = Reader(handshake);
+    if reader.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = Reader(reader.bytes(3)?);
+    // Version and random.
+    hello.skip(2 + 32)?;
+    let session_id = usize::from(hello.u8()?);
+    hello.skip(session_id)?;
+    let cipher_suites = hello.u16()?;
+    hello.skip(cipher_suites)?;
+    let compression_methods = usize::from(hello.u8()?);
+    hello.skip(compression_methods)?;
+    let mut extensions = Reader(hello.bytes(2)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.bytes(2)?;
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(Reader(data).bytes(2)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.bytes(2)?;
+            // Host name.
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+            }
+        }
+    }
+    None
+}
+
+/// Reads the big-endian fields of a TLS message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        let [high, low] = self.take(2)? else {
+            return None;
+        };
+        Some(usize::from(u16::from_be_bytes([*high, *low])))
+    }
+
+    /// A vector prefixed with its length in `len_bytes` bytes.
+    fn bytes(&mut self, len_bytes: usize) -> Option<&'a [u8]> {
+        let len = self
+            .take(len_bytes)?
+            .iter()
+            .fold(0, |len, byte| len << 8 | usize::from(*byte));
+        self.take(len)
+    }
+}
+
+/// Copies bytes both ways until both sides closed or nothing passed for `idle`, returning
+/// the bytes sent upstream, those received from it, and how the connection ended.
+pub async fn relay(
+    client: Stream,
+    upstream: TcpStream,
+    idle: Duration,
+) -> (u64, u64, &'static str) {
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+    let mut to_upstream = vec![0; BUFFER_SIZE];
+    let mut to_client = vec![0; BUFFER_SIZE];
+    let (mut sent, mut received) = (0, 0);
+    let (mut client_open, mut upstream_open) = (true, true);
+    while client_open || upstream_open {
+        let event = tokio::time::timeout(idle, async {
+            tokio::select! {
+                read = client_read.read(&mut to_upstream), if client_open => (true, read),
+                read = upstream_read.read(&mut to_client), if upstream_open => (false, read),
+            }
+        })
+        .await;
+        let Ok((from_client, read)) = event else {
+            return (sent, received, "idle");
+        };
+        let written = match (from_client, read) {
+            (true, Ok(0)) => {
+                client_open = false;
+                upstream_write.shutdown().await
+            }
+            (false, Ok(0)) => {
+                upstream_open = false;
+                client_write.shutdown().await
+            }
+            (true, Ok(read)) => {
+                sent += read as u64;
+                upstream_write.write_all(&to_upstream[..read]).await
+            }
+            (false, Ok(read)) => {
+                received += read as u64;
+                match client_write.write_all(&to_client[..read]).await {
+                    Ok(()) => client_write.flush().await,
+                    Err(err) => Err(err),
+                }
+            }
+            (_, Err(err)) => Err(err),
+        };
+        if written.is_err() {
+            return (sent, received, "error");
+        }
+    }
+    (sent, received, "closed")
+}
@@ -0,0 +1,456 @@
+//! Helpers for integration tests: a mock upstream, a proxy started on a free port in the
+//! background, and assertions on its responses. Enabled by the `testing` feature.
+//!
+//! ```
+//! use rose_proxy::ProxyBuilder;
+//! use rose_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+//!
+//! let upstream = MockUpstream::start(|_| MockResponse::new(200).body("hello"));
+//! let proxy = TestProxy::start(ProxyBuilder::new(upstream.addr())).unwrap();
+//! proxy
+//!     .get("/greeting")
+//!     .assert_status(200)
+//!     .assert_body("hello");
+//! assert_eq!(upstream.requests()[0].path, "/greeting");
+//! ```
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use http::HeaderMap;
+use pingora::server::{RunArgs, ShutdownSignal, ShutdownSignalWatch};
+use tokio::sync::Notify;
+
+use crate::ProxyBuilder;
+
+/// How long [`TestProxy::start`] waits for the proxy to accept connections.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long requests of [`TestProxy`] may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request the mock upstream received.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Path and query.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// The first value of header `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// What the mock upstream answers a request with.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+type Respond = dyn Fn(&RecordedRequest) -> MockResponse + Send + Sync;
+
+/// An HTTP/1.1 upstream on a free local port, answering each request with what `respond`
+/// returns for it and recording them all. Stops when dropped.
+pub struct MockUpstream {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    stopped: Arc<AtomicBool>,
+    accepting: Option<JoinHandle<()>>,
+}
+
+impl MockUpstream {
+    pub fn start(
+        respond: impl Fn(&RecordedRequest) -> MockResponse + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("can bind a local port");
+        let addr = listener
+            .local_addr()
+            .expect("bound listeners have an address");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let respond: Arc<Respond> = Arc::new(respond);
+        let accepting = {
+            let requests = requests.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let requests = requests.clone();
+                    let respond = respond.clone();
+                    thread::spawn(move || serve_connection(stream, &requests, &*respond));
+                }
+            })
+        };
+        Self {
+            addr,
+            requests,
+            stopped,
+            accepting: Some(accepting),
+        }
+    }
+
+    /// An upstream answering 200 with the request's method and path as the body.
+    pub fn echo() -> Self {
+        Self::start(|request| {
+            MockResponse::new(200)
+                .header("content-type", "text/plain")
+                .body(format!("{} {}", request.method, request.path))
+        })
+    }
+
+    /// `host:port` to send requests to, e.g. as the proxy's `upstream_addr`.
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// The requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wakes the accept loop up to see it should stop.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(accepting) = self.accepting.take() {
+            let _ = accepting.join();
+        }
+    }
+}
+
+/// Answers the requests of a connection until the client closes it.
+fn serve_connection(stream: TcpStream, requests: &Mutex<Vec<RecordedRequest>>, respond: &Respond) {
+    let Ok(write_half) = stream.try_clone() else {
+        return;
+    };
+    let mut reader = BufReader::new(stream);
+    let mut writer = write_half;
+    while let Some(request) = read_request(&mut reader) {
+        let response = respond(&request);
+        let head = request.method == "HEAD";
+        let close = request
+            .header("connection")
+            .is_some_and(|connection| connection.eq_ignore_ascii_case("close"));
+        requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(request);
+        if write_response(&mut writer, &response, head).is_err() || close {
+            break;
+        }
+    }
+    let _ = writer.shutdown(Shutdown::Both);
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<RecordedRequest> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = RecordedRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    if request
+        .header("transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        request.body = read_chunked(reader)?;
+    } else if let Some(length) = request.header("content-length") {
+        let mut body = vec![0; length.parse().ok()?];
+        reader.read_exact(&mut body).ok()?;
+        request.body = body;
+    }
+    Some(request)
+}
+
+fn read_chunked(reader: &mut impl BufRead) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let size = line.trim().split(';').next()?;
+        let size = usize::from_str_radix(size, 16).ok()?;
+        if size == 0 {
+            // Trailers, up to the empty line ending the body.
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).ok()?;
+                if line.trim_end().is_empty() {
+                    return Some(body);
+                }
+            }
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).ok()?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).ok()?;
+    }
+}
+
+/// Writes `response`, without its body when answering a HEAD request.
+fn write_response(
+    writer: &mut impl Write,
+    response: &MockResponse,
+    head: bool,
+) -> std::io::Result<()> {
+    let reason = http::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+    let mut head_lines = format!("HTTP/1.1 {} {reason}\r\n", response.status);
+    for (name, value) in &response.headers {
+        head_lines.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head_lines.push_str(&format!("content-length: {}\r\n\r\n", response.body.len()));
+    writer.write_all(head_lines.as_bytes())?;
+    if !head {
+        writer.write_all(&response.body)?;
+    }
+    writer.flush()
+}
+
+/// Stops the server of a [`TestProxy`] when notified.
+struct StopSignal(Arc<Notify>);
+
+#[async_trait]
+impl ShutdownSignalWatch for StopSignal {
+    async fn recv(&self) -> ShutdownSignal {
+        self.0.notified().await;
+        ShutdownSignal::FastShutdown
+    }
+}
+
+/// A proxy running in the background on a free local port, in addition to the listeners of
+/// its config, with a client to send it requests. Stops when dropped.
+pub struct TestProxy {
+    addr: SocketAddr,
+    agent: ureq::Agent,
+    stop: Arc<Notify>,
+    running: Option<JoinHandle<()>>,
+}
+
+impl TestProxy {
+    /// Builds the proxy of `builder` and waits until it accepts connections.
+    pub fn start(builder: ProxyBuilder) -> Result<Self, String> {
+        let addr = free_port()?;
+        let server = builder.listen(addr.to_string()).build()?;
+        let stop = Arc::new(Notify::new());
+        let running = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                server.run(RunArgs {
+                    shutdown_signal: Box::new(StopSignal(stop)),
+                })
+            })
+        };
+        let proxy = Self {
+            addr,
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(REQUEST_TIMEOUT))
+                .http_status_as_error(false)
+                .max_redirects(0)
+                .max_redirects_will_error(false)
+                .build()
+                .into(),
+            stop,
+            running: Some(running),
+        };
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            if started.elapsed() > STARTUP_TIMEOUT {
+                return Err(format!("the proxy did not start listening on {addr}"));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(proxy)
+    }
+
+    /// `host:port` the proxy listens on.
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// URL of `path` on the proxy.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.request("GET", path, &[], Vec::new())
+    }
+
+    /// Sends a request with `headers` and `body`, redirects left unfollowed. Panics when no
+    /// response comes.
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: impl Into<Vec<u8>>,
+    ) -> TestResponse {
+        let mut request = http::Request::builder().method(method).uri(self.url(path));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .body(body.into())
+            .unwrap_or_else(|err| panic!("invalid request {method} {path}: {err}"));
+        let mut response = self
+            .agent
+            .run(request)
+            .unwrap_or_else(|err| panic!("{method} {path} failed: {err}"));
+        let body = response
+            .body_mut()
+            .with_config()
+            .limit(u64::MAX)
+            .read_to_vec()
+            .unwrap_or_else(|err| panic!("{method} {path}: can't read the body: {err}"));
+        TestResponse {
+            status: response.status().as_u16(),
+            headers: response.headers().clone(),
+            body,
+        }
+    }
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        self.stop.notify_one();
+        if let Some(running) = self.running.take() {
+            let _ = running.join();
+        }
+    }
+}
+
+/// An address on a port nothing listens on, for the proxy to bind.
+fn free_port() -> Result<SocketAddr, String> {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map_err(|err| format!("can't find a free port: {err}"))
+}
+
+/// A response of a [`TestProxy`]. The assertions panic with what was expected and what came,
+/// and return the response to chain more.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// The first value of header `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn assert_status(&self, status: u16) -> &Self {
+        assert_eq!(
+            self.status,
+            status,
+            "unexpected status, body: {}",
+            self.text()
+        );
+        self
+    }
+
+    pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(
+            self.header(name),
+            Some(value),
+            "unexpected {name} header among {:?}",
+            self.headers
+        );
+        self
+    }
+
+    pub fn assert_no_header(&self, name: &str) -> &Self {
+        assert_eq!(self.header(name), None, "unexpected {name} header");
+        self
+    }
+
+    pub fn assert_body(&self, body: impl AsRef<[u8]>) -> &Self {
+        assert!(
+            self.body == body.as_ref(),
+            "unexpected body {:?}, expected {:?}",
+            self.text(),
+            String::from_utf8_lossy(body.as_ref())
+        );
+        self
+    }
+
+    pub fn assert_body_contains(&self, text: &str) -> &Self {
+        assert!(
+            self.text().contains(text),
+            "body {:?} does not contain {text:?}",
+            self.text()
+        );
+        self
+    }
+}
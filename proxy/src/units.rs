@@ -0,0 +1,192 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use schemars::{Schema, SchemaGenerator, json_schema};
+use serde::Deserializer;
+use serde::de::{self, Visitor};
+
+/// Unit suffixes accepted by `*_seconds` fields, e.g. `"30s"`, `"5m"` or `"1h30m"`.
+const DURATION_UNITS: &[(&str, u64)] = &[("s", 1), ("m", 60), ("h", 60 * 60), ("d", 24 * 60 * 60)];
+/// Unit suffixes accepted by size fields (binary multiples), e.g. `"512kb"` or `"1gb"`.
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("b", 1),
+    ("k", 1 << 10),
+    ("kb", 1 << 10),
+    ("kib", 1 << 10),
+    ("m", 1 << 20),
+    ("mb", 1 << 20),
+    ("mib", 1 << 20),
+    ("g", 1 << 30),
+    ("gb", 1 << 30),
+    ("gib", 1 << 30),
+];
+const KB: u64 = 1 << 10;
+const MB: u64 = 1 << 20;
+
+/// Parses a duration in seconds: a bare number, or numbers with units such as `"1h30m"`.
+pub fn parse_seconds(text: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!("invalid duration {text:?}, expected e.g. 30, \"30s\", \"5m\", \"1h30m\" or \"1d\"")
+    };
+    let text = text.trim();
+    if let Ok(seconds) = text.parse() {
+        return Ok(seconds);
+    }
+    let mut rest = text;
+    let mut total: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = rest[..unit_len].trim().to_ascii_lowercase();
+        let scale = lookup(DURATION_UNITS, &unit).ok_or_else(invalid)?;
+        total = number
+            .checked_mul(scale)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        rest = rest[unit_len..].trim_start();
+    }
+    Ok(total)
+}
+
+/// Parses a size into a whole number of `unit` bytes, e.g. `"512kb"` with `unit` 1024.
+/// A bare number is taken to already be in `unit`.
+fn parse_size(text: &str, unit: u64, unit_name: &str) -> Result<u64, String> {
+    let text = text.trim();
+    if let Ok(value) = text.parse() {
+        return Ok(value);
+    }
+    let invalid =
+        || format!("invalid size {text:?}, expected e.g. 512, \"512kb\", \"64mb\" or \"1gb\"");
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .filter(|&digits| digits > 0)
+        .ok_or_else(invalid)?;
+    let number: u64 = text[..digits].parse().map_err(|_| invalid())?;
+    let scale =
+        lookup(SIZE_UNITS, &text[digits..].trim().to_ascii_lowercase()).ok_or_else(invalid)?;
+    let bytes = number.checked_mul(scale).ok_or_else(invalid)?;
+    if bytes % unit != 0 {
+        return Err(format!(
+            "size {text:?} is not a whole number of {unit_name}"
+        ));
+    }
+    Ok(bytes / unit)
+}
+
+fn lookup(units: &[(&str, u64)], unit: &str) -> Option<u64> {
+    units
+        .iter()
+        .find(|(name, _)| *name == unit)
+        .map(|(_, scale)| *scale)
+}
+
+/// Accepts an integer, or a string handed to `parse`.
+struct UnitVisitor<T> {
+    expecting: &'static str,
+    parse: fn(&str) -> Result<u64, String>,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: TryFrom<u64>> Visitor<'de> for UnitVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        T::try_from(value).map_err(|_| E::custom(format!("{value} is too large")))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        let value = u64::try_from(value).map_err(|_| E::custom("must not be negative"))?;
+        self.visit_u64(value)
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<T, E> {
+        let value = (self.parse)(text).map_err(E::custom)?;
+        self.visit_u64(value)
+    }
+}
+
+fn deserialize_unit<'de, D, T>(
+    deserializer: D,
+    expecting: &'static str,
+    parse: fn(&str) -> Result<u64, String>,
+) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserializer.deserialize_any(UnitVisitor {
+        expecting,
+        parse,
+        marker: PhantomData,
+    })
+}
+
+const EXPECTING_DURATION: &str = "a number of seconds or a duration such as \"30s\" or \"5m\"";
+const EXPECTING_SIZE: &str = "a number or a size such as \"512kb\" or \"64mb\"";
+
+/// `deserialize_with` for durations in seconds.
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    deserialize_unit(deserializer, EXPECTING_DURATION, parse_seconds)
+}
+
+/// `deserialize_with` for optional durations in seconds; pair with `#[serde(default)]`.
+pub fn opt_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    seconds(deserializer).map(Some)
+}
+
+/// `deserialize_with` for optional sizes in KB; pair with `#[serde(default)]`.
+pub fn opt_kilobytes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<usize>, D::Error> {
+    deserialize_unit(deserializer, EXPECTING_SIZE, |text| {
+        parse_size(text, KB, "KB")
+    })
+    .map(Some)
+}
+
+/// `deserialize_with` for optional sizes in MB; pair with `#[serde(default)]`.
+pub fn opt_megabytes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<usize>, D::Error> {
+    deserialize_unit(deserializer, EXPECTING_SIZE, |text| {
+        parse_size(text, MB, "MB")
+    })
+    .map(Some)
+}
+
+/// Schema of a duration field: seconds, or a string with units.
+pub fn duration_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string", "pattern": "^\\s*(\\d+|(\\d+\\s*[smhdSMHD]\\s*)+)$" },
+        ],
+    })
+}
+
+/// Schema of a size field: a number in the field's unit, or a string with units.
+pub fn size_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string", "pattern": "^\\s*\\d+\\s*([bB]|[kKmMgG]([iI]?[bB])?)?\\s*$" },
+        ],
+    })
+}
+
+/// `time` as an RFC 3339 timestamp to the second, as the admin API reports times.
+pub fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
@@ -0,0 +1,391 @@
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use http::HeaderName;
+use log::info;
+use pingora::http::RequestHeader;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::watched_file::WatchedFile;
+
+/// Header the tags of matching `tag` rules are sent upstream in.
+const TAGS_HEADER: &str = "x-waf-tags";
+const SCANNER_RULES: &str = include_str!("waf_scanner_rules.toml");
+
+/// `[waf]` section of the config file: rules that block, log or tag requests by their path,
+/// query or headers.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct WafConfig {
+    /// Blocks common scanner probes: dotfiles, WordPress and admin tool paths, path traversal,
+    /// SQL injection and scanner user agents.
+    pub scanner_rules: Option<bool>,
+    #[serde(default)]
+    pub rules: Vec<WafRuleConfig>,
+    /// TOML file with more `[[rules]]`, re-read when it changes.
+    pub rules_file: Option<String>,
+}
+
+impl WafConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            scanner_rules: Some(self.scanner_rules.unwrap_or(true)),
+            ..self
+        }
+    }
+}
+
+/// A `[[waf.rules]]` entry. A request matches when every pattern given matches.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct WafRuleConfig {
+    /// Names the rule in logs.
+    pub id: String,
+    /// Regex matched against the request path.
+    pub path: Option<String>,
+    /// Regex matched against the percent-decoded query string.
+    pub query: Option<String>,
+    /// Regexes matched against header values, by header name; a missing header never matches.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub action: WafAction,
+    /// Tag the `tag` action adds; defaults to the rule's id.
+    pub tag: Option<String>,
+}
+
+/// What happens to a request matching a rule.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WafAction {
+    /// Refuse it with 403.
+    #[default]
+    Block,
+    /// Log it and let it through.
+    Log,
+    /// Let it through with the rule's tag in `X-Waf-Tags` and `$waf_tags`.
+    Tag,
+}
+
+/// Outcome of checking a request against the rules.
+pub enum WafVerdict {
+    Allow { tags: Vec<String> },
+    Block,
+}
+
+struct Rule {
+    id: String,
+    path: Option<Regex>,
+    query: Option<Regex>,
+    headers: Vec<(HeaderName, Regex)>,
+    action: WafAction,
+    tag: String,
+}
+
+impl Rule {
+    fn compile(config: &WafRuleConfig) -> Result<Self, String> {
+        let pattern = |pattern: &str| {
+            Regex::new(pattern).map_err(|err| format!("rule '{}': {err}", config.id))
+        };
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|err| format!("rule '{}': header {name:?}: {err}", config.id))?;
+                Ok((name, pattern(value)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if config.path.is_none() && config.query.is_none() && headers.is_empty() {
+            return Err(format!(
+                "rule '{}' needs a path, query or headers pattern",
+                config.id
+            ));
+        }
+        Ok(Self {
+            id: config.id.clone(),
+            path: config.path.as_deref().map(pattern).transpose()?,
+            query: config.query.as_deref().map(pattern).transpose()?,
+            headers,
+            action: config.action,
+            tag: config.tag.clone().unwrap_or_else(|| config.id.clone()),
+        })
+    }
+
+    fn matches(&self, request: &RequestHeader, query: &OnceCell<String>) -> bool {
+        if let Some(path) = &self.path
+            && !path.is_match(request.uri.path())
+        {
+            return false;
+        }
+        if let Some(pattern) = &self.query
+            && !pattern.is_match(query.get_or_init(|| decoded_query(request)))
+        {
+            return false;
+        }
+        self.headers.iter().all(|(name, pattern)| {
+            request
+                .headers
+                .get_all(name)
+                .iter()
+                .any(|value| pattern.is_match(&String::from_utf8_lossy(value.as_bytes())))
+        })
+    }
+}
+
+/// The query string with `+` and percent escapes decoded.
+fn decoded_query(request: &RequestHeader) -> String {
+    let Some(query) = request.uri.query() else {
+        return String::new();
+    };
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| {
+            if value.is_empty() {
+                name.into_owned()
+            } else {
+                format!("{name}={value}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<WafRuleConfig>,
+}
+
+fn parse_rules(text: &str) -> Result<Vec<Rule>, String> {
+    let file: RulesFile = toml::from_str(text).map_err(|err| err.to_string())?;
+    file.rules.iter().map(Rule::compile).collect()
+}
+
+fn read_rules_file(path: &Path) -> Result<Vec<Rule>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    parse_rules(&text).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+/// Compiled `[waf]` rules: those from the config, then the rules file, then the scanner rules.
+pub struct Waf {
+    rules: Vec<Rule>,
+    file: Option<WatchedFile<Vec<Rule>>>,
+    scanner_rules: Vec<Rule>,
+}
+
+impl Waf {
+    pub fn new(config: &WafConfig) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .map(Rule::compile)
+            .collect::<Result<_, _>>()?;
+        let file = config
+            .rules_file
+            .as_deref()
+            .map(|path| WatchedFile::open(path, read_rules_file))
+            .transpose()?;
+        let scanner_rules = if config.scanner_rules.unwrap_or(true) {
+            parse_rules(SCANNER_RULES).expect("built-in scanner rules are valid")
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            rules,
+            file,
+            scanner_rules,
+        })
+    }
+
+    /// Runs the rules over `request`, logging matches of `log` rules. The first matching
+    /// `block` rule refuses it.
+    pub fn inspect(&self, request: &RequestHeader, request_id: &str) -> WafVerdict {
+        let file = self.file.as_ref().map(|file| file.get());
+        let query = OnceCell::new();
+        let mut tags = Vec::new();
+        for rule in self
+            .rules
+            .iter()
+            .chain(file.iter().flat_map(|rules| rules.iter()))
+            .chain(&self.scanner_rules)
+        {
+            if !rule.matches(request, &query) {
+                continue;
+            }
+            match rule.action {
+                WafAction::Block => {
+                    info!("request {request_id} blocked by waf rule '{}'", rule.id);
+                    return WafVerdict::Block;
+                }
+                WafAction::Log => info!("request {request_id} matched waf rule '{}'", rule.id),
+                WafAction::Tag => {
+                    if !tags.contains(&rule.tag) {
+                        tags.push(rule.tag.clone());
+                    }
+                }
+            }
+        }
+        WafVerdict::Allow { tags }
+    }
+
+    /// Sends the request's tags upstream, replacing any the client sent.
+    pub fn apply_request(
+        &self,
+        upstream_request: &mut RequestHeader,
+        tags: &[String],
+    ) -> pingora::Result<()> {
+        upstream_request.remove_header(TAGS_HEADER);
+        if !tags.is_empty() {
+            upstream_request.insert_header(TAGS_HEADER, tags.join(","))?;
+        }
+        Ok(())
+    }
+
+    pub fn refresh(&self) {
+        if let Some(file) = &self.file {
+            file.refresh();
+        }
+    }
+}
+
+impl std::fmt::Debug for Waf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Waf")
+            .field("rules", &self.rules.len())
+            .field("scanner_rules", &self.scanner_rules.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waf(config: &str) -> Result<Waf, String> {
+        Waf::new(&toml::from_str::<WafConfig>(config).unwrap())
+    }
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("GET", target.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            request.append_header(name.to_string(), *value).unwrap();
+        }
+        request
+    }
+
+    /// The tags `request` is let through with, or `None` when it is blocked.
+    fn verdict(waf: &Waf, request: &RequestHeader) -> Option<Vec<String>> {
+        match waf.inspect(request, "1") {
+            WafVerdict::Allow { tags } => Some(tags),
+            WafVerdict::Block => None,
+        }
+    }
+
+    #[test]
+    fn blocks_requests_matching_every_pattern() {
+        let waf = waf("scanner_rules = false\n\
+             [[rules]]\nid = \"admin\"\npath = '^/admin'\nquery = 'debug=1'\n\
+             headers = { x-role = '^guest$' }")
+        .unwrap();
+        let admin = |target, role| request(target, &[("x-role", role)]);
+        assert!(verdict(&waf, &admin("/admin?debug=1", "guest")).is_none());
+        assert!(verdict(&waf, &admin("/admin?debug=0", "guest")).is_some());
+        assert!(verdict(&waf, &admin("/public?debug=1", "guest")).is_some());
+        assert!(verdict(&waf, &admin("/admin?debug=1", "owner")).is_some());
+        // A missing header never matches.
+        assert!(verdict(&waf, &request("/admin?debug=1", &[])).is_some());
+        // Any of the header's values may match.
+        let both = request(
+            "/admin?debug=1",
+            &[("x-role", "owner"), ("x-role", "guest")],
+        );
+        assert!(verdict(&waf, &both).is_none());
+    }
+
+    #[test]
+    fn matches_the_decoded_query() {
+        let waf =
+            waf("scanner_rules = false\n[[rules]]\nid = \"q\"\nquery = 'name=a b&x$'").unwrap();
+        assert!(verdict(&waf, &request("/?name=a%20b&x", &[])).is_none());
+        assert!(verdict(&waf, &request("/?name=a+b&x=", &[])).is_none());
+        assert!(verdict(&waf, &request("/?name=ab&x", &[])).is_some());
+        assert!(verdict(&waf, &request("/", &[])).is_some());
+    }
+
+    #[test]
+    fn collects_tags_once() {
+        let waf = waf("scanner_rules = false\n\
+             [[rules]]\nid = \"api\"\npath = '^/api'\naction = \"tag\"\n\
+             [[rules]]\nid = \"v1\"\npath = '^/api/v1'\naction = \"tag\"\ntag = \"legacy\"\n\
+             [[rules]]\nid = \"v1-again\"\npath = '/v1/'\naction = \"tag\"\ntag = \"legacy\"\n\
+             [[rules]]\nid = \"seen\"\npath = '^/api'\naction = \"log\"")
+        .unwrap();
+        assert_eq!(
+            verdict(&waf, &request("/api/v1/users", &[])),
+            Some(vec!["api".to_string(), "legacy".to_string()])
+        );
+        assert_eq!(verdict(&waf, &request("/home", &[])), Some(Vec::new()));
+
+        let mut upstream = request("/api", &[(TAGS_HEADER, "forged")]);
+        waf.apply_request(&mut upstream, &["api".into(), "legacy".into()])
+            .unwrap();
+        assert_eq!(upstream.headers[TAGS_HEADER], "api,legacy");
+        waf.apply_request(&mut upstream, &[]).unwrap();
+        assert!(upstream.headers.get(TAGS_HEADER).is_none());
+    }
+
+    #[test]
+    fn scanner_rules_are_on_by_default() {
+        let waf = waf("").unwrap();
+        for (target, headers) in [
+            ("/.env", &[][..]),
+            ("/.git/config", &[]),
+            ("/wp-login.php", &[]),
+            ("/phpmyadmin/", &[]),
+            ("/static/../../etc/passwd", &[]),
+            ("/search?q=1%27%20or%20%271", &[]),
+            ("/search?q=1+UNION+SELECT+password", &[]),
+            ("/", &[("user-agent", "sqlmap/1.7")]),
+        ] {
+            assert!(
+                verdict(&waf, &request(target, headers)).is_none(),
+                "{target}"
+            );
+        }
+        for target in [
+            "/",
+            "/environment",
+            "/blog/git-tips",
+            "/search?q=select+a+union",
+        ] {
+            assert!(verdict(&waf, &request(target, &[])).is_some(), "{target}");
+        }
+        let off = self::waf("scanner_rules = false").unwrap();
+        assert!(verdict(&off, &request("/.env", &[])).is_some());
+    }
+
+    #[test]
+    fn refuses_rules_that_cannot_match() {
+        for (rules, error) in [
+            (
+                "[[rules]]\nid = \"a\"\naction = \"log\"",
+                "rule 'a' needs a path",
+            ),
+            (
+                "[[rules]]\nid = \"b\"\npath = '('",
+                "rule 'b': regex parse error",
+            ),
+            (
+                "[[rules]]\nid = \"c\"\nheaders = { \"bad header\" = 'x' }",
+                "rule 'c': header \"bad header\"",
+            ),
+        ] {
+            let err = waf(rules).err().unwrap();
+            assert!(err.contains(error), "{err}");
+        }
+        assert!(parse_rules("rules = 1").is_err());
+    }
+}
@@ -0,0 +1,969 @@
+#[cfg(feature = "wasm")]
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(feature = "wasm"))]
+use crate::filters::Filter;
+
+/// Fuel a hook gets when `fuel` is unset: enough for header and body work, not for a loop
+/// that never ends.
+#[cfg(feature = "wasm")]
+const DEFAULT_FUEL: u64 = 100_000_000;
+
+/// A `[[wasm_plugins]]` entry: a WebAssembly module speaking a subset of the proxy-wasm ABI,
+/// run as the filter `name`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct WasmPluginConfig {
+    /// Name `filters` lists refer to the plugin by.
+    pub name: String,
+    /// `.wasm` module (or `.wat` text), compiled again whenever the file changes.
+    pub path: String,
+    /// Handed to the module's `proxy_on_configure` as the plugin configuration.
+    pub configuration: Option<String>,
+    /// Instructions a single hook may run before it is stopped and the request fails.
+    pub fuel: Option<u64>,
+}
+
+/// A loaded `[[wasm_plugins]]` module.
+pub struct WasmPlugin {
+    config: WasmPluginConfig,
+    #[cfg(feature = "wasm")]
+    runtime: Arc<runtime::Runtime>,
+}
+
+impl WasmPlugin {
+    #[cfg(feature = "wasm")]
+    pub fn new(config: &WasmPluginConfig) -> Result<Self, String> {
+        let runtime = runtime::Runtime::new(config)
+            .map_err(|err| format!("plugin '{}': {err}", config.name))?;
+        Ok(Self {
+            config: config.clone(),
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn new(config: &WasmPluginConfig) -> Result<Self, String> {
+        Err(format!(
+            "plugin '{}': this proxy was built without the wasm feature",
+            config.name
+        ))
+    }
+
+    pub fn config(&self) -> &WasmPluginConfig {
+        &self.config
+    }
+
+    /// Compiles the module again when its file changed; requests in flight finish with the
+    /// module they started with.
+    pub fn refresh(&self) {
+        #[cfg(feature = "wasm")]
+        self.runtime.refresh();
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+impl Filter for WasmPlugin {}
+
+/// The module runtime, and the part of the proxy-wasm ABI the proxy provides.
+#[cfg(feature = "wasm")]
+mod runtime {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::SystemTime;
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use http::{HeaderMap, Uri};
+    use log::{Level, debug, log};
+    use pingora::http::{RequestHeader, ResponseHeader};
+    use pingora::prelude::*;
+    use wasmtime::{
+        Caller, Engine, Instance, Linker, Memory, Module, Store, WasmParams, WasmResults,
+    };
+
+    use super::{DEFAULT_FUEL, WasmPlugin, WasmPluginConfig};
+    use crate::errors::ProxyError;
+    use crate::filters::{Filter, FilterCtx};
+    use crate::headers::HeaderTarget;
+    use crate::request_id::REQUEST_ID_HEADER;
+    use crate::watched_file::WatchedFile;
+
+    /// What a plugin sees of the request it runs for.
+    #[derive(Default)]
+    pub struct Properties {
+        pub request_id: String,
+        pub route: Option<String>,
+        pub client_ip: Option<IpAddr>,
+    }
+
+    impl Properties {
+        fn of(ctx: &FilterCtx) -> Self {
+            Self {
+                request_id: ctx.request_id.clone(),
+                route: ctx.route.clone(),
+                client_ip: ctx.client_ip,
+            }
+        }
+    }
+
+    /// Whether the request comes without a body, so its headers end the stream.
+    fn has_no_body(request: &RequestHeader) -> bool {
+        !request
+            .headers
+            .contains_key(http::header::TRANSFER_ENCODING)
+            && request
+                .headers
+                .get(http::header::CONTENT_LENGTH)
+                .is_none_or(|length| length.as_bytes() == b"0")
+    }
+
+    #[async_trait]
+    impl Filter for WasmPlugin {
+        async fn request_filter(
+            &self,
+            session: &mut Session,
+            ctx: &mut FilterCtx,
+        ) -> Result<Option<(ResponseHeader, Bytes)>> {
+            let request = session.req_header().clone();
+            let end_of_stream = has_no_body(&request);
+            let headers = request.headers.len();
+            let Some((request, answer)) = self.runtime.run(ctx, |vm, context| {
+                vm.host().request = Some(request);
+                vm.call::<_, i32>(
+                    "proxy_on_request_headers",
+                    (context, headers as i32, end_of_stream as i32),
+                )?;
+                let host = vm.host();
+                Ok((host.request.take(), host.local_response.take()))
+            })?
+            else {
+                return Ok(None);
+            };
+            if let Some(request) = request {
+                *session.req_header_mut() = request;
+            }
+            Ok(answer)
+        }
+
+        fn request_body_filter(
+            &self,
+            body: &mut Option<Bytes>,
+            end_of_stream: bool,
+            ctx: &mut FilterCtx,
+        ) -> Result<()> {
+            let chunk = body.take();
+            let size = chunk.as_ref().map_or(0, Bytes::len);
+            let filtered = self.runtime.run(ctx, |vm, context| {
+                vm.host().body = chunk.clone();
+                vm.call::<_, i32>(
+                    "proxy_on_request_body",
+                    (context, size as i32, end_of_stream as i32),
+                )?;
+                Ok(vm.host().body.take())
+            })?;
+            *body = filtered.unwrap_or(chunk);
+            Ok(())
+        }
+
+        fn response_filter(
+            &self,
+            request: &RequestHeader,
+            response: &mut ResponseHeader,
+            ctx: &mut FilterCtx,
+        ) -> Result<()> {
+            let headers = response.headers.len();
+            let filtered = self.runtime.run(ctx, |vm, context| {
+                let host = vm.host();
+                host.request = Some(request.clone());
+                host.response = Some(response.clone());
+                vm.call::<_, i32>("proxy_on_response_headers", (context, headers as i32, 0))?;
+                let host = vm.host();
+                host.request = None;
+                if host.local_response.take().is_some() {
+                    log::debug!(
+                        "request {}: local responses can't replace an upstream response",
+                        host.properties.request_id
+                    );
+                }
+                Ok(host.response.take())
+            })?;
+            if let Some(Some(filtered)) = filtered {
+                *response = filtered;
+            }
+            Ok(())
+        }
+
+        fn response_body_filter(
+            &self,
+            body: &mut Option<Bytes>,
+            end_of_stream: bool,
+            ctx: &mut FilterCtx,
+        ) -> Result<()> {
+            let chunk = body.take();
+            let size = chunk.as_ref().map_or(0, Bytes::len);
+            let filtered = self.runtime.run(ctx, |vm, context| {
+                vm.host().body = chunk.clone();
+                vm.call::<_, i32>(
+                    "proxy_on_response_body",
+                    (context, size as i32, end_of_stream as i32),
+                )?;
+                Ok(vm.host().body.take())
+            })?;
+            *body = filtered.unwrap_or(chunk);
+            Ok(())
+        }
+    }
+
+    /// Context of the plugin as a whole, as opposed to those of single requests.
+    const ROOT_CONTEXT: i32 = 1;
+
+    // proxy-wasm status codes.
+    const OK: i32 = 0;
+    const NOT_FOUND: i32 = 1;
+    const BAD_ARGUMENT: i32 = 2;
+    const INTERNAL_FAILURE: i32 = 10;
+
+    // proxy-wasm header map and buffer types.
+    const REQUEST_HEADERS: i32 = 0;
+    const RESPONSE_HEADERS: i32 = 2;
+    const REQUEST_BODY: i32 = 0;
+    const RESPONSE_BODY: i32 = 1;
+    const VM_CONFIGURATION: i32 = 6;
+    const PLUGIN_CONFIGURATION: i32 = 7;
+
+    /// One engine for every plugin, metering the instructions hooks run.
+    fn engine() -> &'static Engine {
+        static ENGINE: OnceLock<Engine> = OnceLock::new();
+        ENGINE.get_or_init(|| {
+            let mut config = wasmtime::Config::new();
+            config.consume_fuel(true);
+            Engine::new(&config).expect("the WASM engine settings are valid")
+        })
+    }
+
+    fn compile(path: &Path) -> Result<Module, String> {
+        Module::from_file(engine(), path)
+            .map_err(|err| format!("can't compile {}: {err:#}", path.display()))
+    }
+
+    /// A plugin's module and the instance requests run in. Hooks take turns on the instance,
+    /// which is started again from the module after the file changes or a hook fails.
+    pub struct Runtime {
+        name: String,
+        configuration: Bytes,
+        fuel: u64,
+        module: WatchedFile<Module>,
+        linker: Linker<Host>,
+        vm: Mutex<Option<Vm>>,
+        generations: AtomicU64,
+        contexts: AtomicI32,
+    }
+
+    impl Runtime {
+        pub fn new(config: &WasmPluginConfig) -> Result<Self, String> {
+            let mut linker = Linker::new(engine());
+            add_host_functions(&mut linker).map_err(|err| err.to_string())?;
+            let runtime = Self {
+                name: config.name.clone(),
+                configuration: Bytes::from(config.configuration.clone().unwrap_or_default()),
+                fuel: config.fuel.unwrap_or(DEFAULT_FUEL),
+                module: WatchedFile::open(&config.path, compile)?,
+                linker,
+                vm: Mutex::new(None),
+                generations: AtomicU64::new(0),
+                contexts: AtomicI32::new(ROOT_CONTEXT + 1),
+            };
+            // Fails early on modules that don't start or refuse their configuration.
+            let vm = runtime.start(runtime.module.get())?;
+            *runtime
+                .vm
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(vm);
+            Ok(runtime)
+        }
+
+        pub fn refresh(&self) {
+            self.module.refresh();
+        }
+
+        fn start(&self, module: Arc<Module>) -> Result<Vm, String> {
+            let mut store = Store::new(
+                engine(),
+                Host {
+                    plugin: self.name.clone(),
+                    configuration: self.configuration.clone(),
+                    ..Host::default()
+                },
+            );
+            let mut linker = self.linker.clone();
+            linker
+                .define_unknown_imports_as_traps(&module)
+                .map_err(|err| err.to_string())?;
+            store.set_fuel(self.fuel).map_err(|err| err.to_string())?;
+            let instance = linker
+                .instantiate(&mut store, &module)
+                .map_err(|err| format!("can't start the module: {err:#}"))?;
+            let mut vm = Vm {
+                store,
+                instance,
+                module,
+                fuel: self.fuel,
+                generation: self.generations.fetch_add(1, Ordering::Relaxed),
+            };
+            let configuration = self.configuration.len() as i32;
+            let started = vm
+                .call::<(), ()>("_initialize", ())
+                .and_then(|_| vm.call::<_, ()>("proxy_on_context_create", (ROOT_CONTEXT, 0)))
+                .and_then(|_| vm.call::<_, i32>("proxy_on_vm_start", (ROOT_CONTEXT, 0)))
+                .and_then(|_| {
+                    vm.call::<_, i32>("proxy_on_configure", (ROOT_CONTEXT, configuration))
+                })
+                .map_err(|err| format!("failed to start: {err:#}"))?;
+            if started == Some(0) {
+                return Err("the module refused its configuration".to_string());
+            }
+            Ok(vm)
+        }
+
+        /// Runs `hook` with the request's context in the plugin, creating the context on the
+        /// request's first hook. `None` when the module changed since the request started.
+        pub fn run<R>(
+            self: &Arc<Self>,
+            ctx: &mut FilterCtx,
+            hook: impl FnOnce(&mut Vm, i32) -> wasmtime::Result<R>,
+        ) -> Result<Option<R>> {
+            let mut slot = self
+                .vm
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let module = self.module.get();
+            let current = slot.take().filter(|vm| Arc::ptr_eq(&vm.module, &module));
+            let vm = match current {
+                Some(vm) => slot.insert(vm),
+                None => match self.start(module) {
+                    Ok(vm) => slot.insert(vm),
+                    Err(err) => {
+                        return ProxyError::Internal
+                            .fail(format!("WASM plugin '{}' {err}", self.name));
+                    }
+                },
+            };
+            vm.host().properties = Properties::of(ctx);
+            let contexts = ctx.extensions.get_or_insert_default::<WasmContexts>();
+            let id = match contexts.0.get(&self.name) {
+                None => {
+                    let id = self.contexts.fetch_add(1, Ordering::Relaxed);
+                    if let Err(err) =
+                        vm.call::<_, ()>("proxy_on_context_create", (id, ROOT_CONTEXT))
+                    {
+                        *slot = None;
+                        return self.failed(err);
+                    }
+                    let context = RequestContext {
+                        runtime: self.clone(),
+                        generation: vm.generation,
+                        id,
+                    };
+                    contexts.0.insert(self.name.clone(), Arc::new(context));
+                    id
+                }
+                Some(context) if context.generation == vm.generation => context.id,
+                Some(_) => return Ok(None),
+            };
+            match hook(vm, id) {
+                Ok(result) => Ok(Some(result)),
+                Err(err) => {
+                    *slot = None;
+                    self.failed(err)
+                }
+            }
+        }
+
+        fn failed<R>(&self, err: wasmtime::Error) -> Result<R> {
+            ProxyError::Internal.fail(format!(
+                "WASM plugin '{}' failed: {}",
+                self.name,
+                err.root_cause()
+            ))
+        }
+    }
+
+    /// A request's context in a plugin instance, ended when the request is done with it.
+    struct RequestContext {
+        runtime: Arc<Runtime>,
+        generation: u64,
+        id: i32,
+    }
+
+    impl Drop for RequestContext {
+        fn drop(&mut self) {
+            let mut slot = self
+                .runtime
+                .vm
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let Some(vm) = slot.as_mut().filter(|vm| vm.generation == self.generation) else {
+                return;
+            };
+            let ended = vm
+                .call::<_, i32>("proxy_on_done", (self.id,))
+                .and_then(|_| vm.call::<_, ()>("proxy_on_delete", (self.id,)));
+            if let Err(err) = ended {
+                debug!(
+                    "WASM plugin '{}' failed to end context {}: {err:#}",
+                    self.runtime.name, self.id
+                );
+                *slot = None;
+            }
+        }
+    }
+
+    /// The plugins' contexts for a request, by plugin name, ended when the request is.
+    #[derive(Clone, Default)]
+    struct WasmContexts(HashMap<String, Arc<RequestContext>>);
+
+    /// A started instance of a plugin's module.
+    pub struct Vm {
+        store: Store<Host>,
+        instance: Instance,
+        module: Arc<Module>,
+        fuel: u64,
+        generation: u64,
+    }
+
+    impl Vm {
+        pub fn host(&mut self) -> &mut Host {
+            self.store.data_mut()
+        }
+
+        /// Calls the export `name` with a fresh allowance of fuel, if the module has it.
+        pub fn call<P: WasmParams, R: WasmResults>(
+            &mut self,
+            name: &str,
+            params: P,
+        ) -> wasmtime::Result<Option<R>> {
+            let Some(func) = self.instance.get_func(&mut self.store, name) else {
+                return Ok(None);
+            };
+            self.store.set_fuel(self.fuel)?;
+            func.typed::<P, R>(&self.store)?
+                .call(&mut self.store, params)
+                .map(Some)
+        }
+    }
+
+    /// What host functions work on during a hook.
+    #[derive(Default)]
+    pub struct Host {
+        plugin: String,
+        configuration: Bytes,
+        pub properties: Properties,
+        pub request: Option<RequestHeader>,
+        pub response: Option<ResponseHeader>,
+        /// The body chunk being filtered.
+        pub body: Option<Bytes>,
+        pub local_response: Option<(ResponseHeader, Bytes)>,
+    }
+
+    impl Host {
+        fn headers(&self, map_type: i32) -> Option<&HeaderMap> {
+            match map_type {
+                REQUEST_HEADERS => self.request.as_ref().map(|request| &request.headers),
+                RESPONSE_HEADERS => self.response.as_ref().map(|response| &response.headers),
+                _ => None,
+            }
+        }
+
+        fn headers_mut(&mut self, map_type: i32) -> Option<&mut dyn HeaderTarget> {
+            match map_type {
+                REQUEST_HEADERS => self.request.as_mut().map(|request| request as _),
+                RESPONSE_HEADERS => self.response.as_mut().map(|response| response as _),
+                _ => None,
+            }
+        }
+
+        /// The pseudo-headers of a header map, as proxy-wasm modules expect them.
+        fn pseudo_headers(&self, map_type: i32) -> Vec<(&'static str, String)> {
+            match map_type {
+                REQUEST_HEADERS => self.request.as_ref().map_or_else(Vec::new, |request| {
+                    let mut pseudo = vec![
+                        (":method", request.method.to_string()),
+                        (
+                            ":path",
+                            request
+                                .uri
+                                .path_and_query()
+                                .map_or("/", |path| path.as_str())
+                                .to_string(),
+                        ),
+                    ];
+                    let authority =
+                        request
+                            .uri
+                            .authority()
+                            .map(ToString::to_string)
+                            .or_else(|| {
+                                request
+                                    .headers
+                                    .get(http::header::HOST)
+                                    .and_then(|host| host.to_str().ok())
+                                    .map(str::to_string)
+                            });
+                    if let Some(authority) = authority {
+                        pseudo.push((":authority", authority));
+                    }
+                    pseudo.push((
+                        ":scheme",
+                        request.uri.scheme_str().unwrap_or("http").to_string(),
+                    ));
+                    pseudo
+                }),
+                RESPONSE_HEADERS => self.response.as_ref().map_or_else(Vec::new, |response| {
+                    vec![(":status", response.status.as_u16().to_string())]
+                }),
+                _ => Vec::new(),
+            }
+        }
+
+        fn header(&self, map_type: i32, name: &str) -> Option<Vec<u8>> {
+            if name.starts_with(':') {
+                return self
+                    .pseudo_headers(map_type)
+                    .into_iter()
+                    .find(|(pseudo, _)| *pseudo == name)
+                    .map(|(_, value)| value.into_bytes());
+            }
+            let values: Vec<&[u8]> = self
+                .headers(map_type)?
+                .get_all(name)
+                .iter()
+                .map(|value| value.as_bytes())
+                .collect();
+            if values.is_empty() {
+                return None;
+            }
+            Some(values.join(&b", "[..]))
+        }
+
+        /// Sets a pseudo-header: the request's path or method, or the response status.
+        fn set_pseudo_header(&mut self, map_type: i32, name: &str, value: &[u8]) -> i32 {
+            let Ok(value) = std::str::from_utf8(value) else {
+                return BAD_ARGUMENT;
+            };
+            let set = match (map_type, name, &mut self.request, &mut self.response) {
+                (REQUEST_HEADERS, ":path", Some(request), _) => value
+                    .parse::<Uri>()
+                    .ok()
+                    .map(|uri| request.set_uri(uri))
+                    .is_some(),
+                (REQUEST_HEADERS, ":method", Some(request), _) => value
+                    .parse()
+                    .ok()
+                    .map(|method| request.set_method(method))
+                    .is_some(),
+                (RESPONSE_HEADERS, ":status", _, Some(response)) => value
+                    .parse::<u16>()
+                    .ok()
+                    .and_then(|status| response.set_status(status).ok())
+                    .is_some(),
+                _ => false,
+            };
+            if set { OK } else { BAD_ARGUMENT }
+        }
+
+        fn property(&self, path: &str) -> Option<Vec<u8>> {
+            let request = self.request.as_ref();
+            let value = match path {
+                "plugin_name" => Some(self.plugin.clone()),
+                "route_name" => self.properties.route.clone(),
+                "request.id" => Some(self.properties.request_id.clone()),
+                "request.path" => request
+                    .and_then(|request| request.uri.path_and_query())
+                    .map(|path| path.to_string()),
+                "request.method" => request.map(|request| request.method.to_string()),
+                "source.address" => self.properties.client_ip.map(|ip| ip.to_string()),
+                _ => None,
+            };
+            value.map(String::into_bytes)
+        }
+
+        fn buffer(&self, buffer_type: i32) -> Option<&Bytes> {
+            match buffer_type {
+                REQUEST_BODY | RESPONSE_BODY => self.body.as_ref(),
+                PLUGIN_CONFIGURATION => Some(&self.configuration),
+                _ => None,
+            }
+        }
+    }
+
+    fn memory(caller: &mut Caller<'_, Host>) -> Option<Memory> {
+        caller.get_export("memory")?.into_memory()
+    }
+
+    /// The `len` bytes of guest memory at `ptr`.
+    fn read(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+        let memory = memory(caller)?;
+        let start = ptr as u32 as usize;
+        let end = start.checked_add(len as u32 as usize)?;
+        memory.data(&caller).get(start..end).map(<[u8]>::to_vec)
+    }
+
+    /// Copies `data` into memory the guest allocates, and tells it where through
+    /// `ret_ptr` and `ret_len`.
+    fn give(
+        caller: &mut Caller<'_, Host>,
+        data: &[u8],
+        ret_ptr: i32,
+        ret_len: i32,
+    ) -> wasmtime::Result<i32> {
+        let Some(memory) = memory(caller) else {
+            return Ok(INTERNAL_FAILURE);
+        };
+        let Some(allocate) = caller
+            .get_export("proxy_on_memory_allocate")
+            .or_else(|| caller.get_export("malloc"))
+            .and_then(|export| export.into_func())
+        else {
+            return Ok(INTERNAL_FAILURE);
+        };
+        let len = i32::try_from(data.len())?;
+        let ptr = if data.is_empty() {
+            0
+        } else {
+            allocate
+                .typed::<i32, i32>(&*caller)?
+                .call(&mut *caller, len)?
+        };
+        memory.write(&mut *caller, ptr as u32 as usize, data)?;
+        memory.write(&mut *caller, ret_ptr as u32 as usize, &ptr.to_le_bytes())?;
+        memory.write(&mut *caller, ret_len as u32 as usize, &len.to_le_bytes())?;
+        Ok(OK)
+    }
+
+    /// Header pairs in proxy-wasm's encoding: the count, the length of each name and value,
+    /// then each name and value followed by a NUL.
+    fn encode_pairs(pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+        for (name, value) in pairs {
+            encoded.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            encoded.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        }
+        for (name, value) in pairs {
+            encoded.extend_from_slice(name);
+            encoded.push(0);
+            encoded.extend_from_slice(value);
+            encoded.push(0);
+        }
+        encoded
+    }
+
+    fn decode_pairs(encoded: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        let word = |at: usize| -> Option<usize> {
+            let bytes = encoded.get(at..at + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+        };
+        if encoded.is_empty() {
+            return Some(Vec::new());
+        }
+        let count = word(0)?;
+        let mut data = 4usize.checked_add(count.checked_mul(8)?)?;
+        let mut pairs = Vec::with_capacity(count.min(64));
+        for index in 0..count {
+            let name_len = word(4 + index * 8)?;
+            let value_len = word(8 + index * 8)?;
+            let name = encoded.get(data..data + name_len)?.to_vec();
+            data += name_len + 1;
+            let value = encoded.get(data..data + value_len)?.to_vec();
+            data += value_len + 1;
+            pairs.push((name, value));
+        }
+        Some(pairs)
+    }
+
+    fn header_name(bytes: Vec<u8>) -> Option<String> {
+        String::from_utf8(bytes)
+            .ok()
+            .map(|name| name.to_ascii_lowercase())
+    }
+
+    fn add_host_functions(linker: &mut Linker<Host>) -> wasmtime::Result<()> {
+        linker.func_wrap(
+            "env",
+            "proxy_log",
+            |mut caller: Caller<'_, Host>, level: i32, ptr: i32, len: i32| -> i32 {
+                let Some(message) = read(&mut caller, ptr, len) else {
+                    return BAD_ARGUMENT;
+                };
+                let level = match level {
+                    0 => Level::Trace,
+                    1 => Level::Debug,
+                    2 => Level::Info,
+                    3 => Level::Warn,
+                    _ => Level::Error,
+                };
+                let host = caller.data();
+                let message = String::from_utf8_lossy(&message);
+                match host.properties.request_id.as_str() {
+                    "" => log!(level, "WASM plugin '{}': {message}", host.plugin),
+                    request_id => log!(
+                        level,
+                        "WASM plugin '{}', request {request_id}: {message}",
+                        host.plugin
+                    ),
+                }
+                OK
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_get_header_map_value",
+            |mut caller: Caller<'_, Host>,
+             map_type: i32,
+             name_ptr: i32,
+             name_len: i32,
+             ret_ptr: i32,
+             ret_len: i32|
+             -> wasmtime::Result<i32> {
+                let Some(name) = read(&mut caller, name_ptr, name_len).and_then(header_name) else {
+                    return Ok(BAD_ARGUMENT);
+                };
+                match caller.data().header(map_type, &name) {
+                    Some(value) => give(&mut caller, &value, ret_ptr, ret_len),
+                    None => Ok(NOT_FOUND),
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_get_header_map_pairs",
+            |mut caller: Caller<'_, Host>,
+             map_type: i32,
+             ret_ptr: i32,
+             ret_len: i32|
+             -> wasmtime::Result<i32> {
+                let host = caller.data();
+                let Some(headers) = host.headers(map_type) else {
+                    return Ok(NOT_FOUND);
+                };
+                let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = host
+                    .pseudo_headers(map_type)
+                    .into_iter()
+                    .map(|(name, value)| (name.as_bytes().to_vec(), value.into_bytes()))
+                    .collect();
+                pairs.extend(headers.iter().map(|(name, value)| {
+                    (name.as_str().as_bytes().to_vec(), value.as_bytes().to_vec())
+                }));
+                give(&mut caller, &encode_pairs(&pairs), ret_ptr, ret_len)
+            },
+        )?;
+        for (function, replace) in [
+            ("proxy_add_header_map_value", false),
+            ("proxy_replace_header_map_value", true),
+        ] {
+            linker.func_wrap(
+                "env",
+                function,
+                move |mut caller: Caller<'_, Host>,
+                      map_type: i32,
+                      name_ptr: i32,
+                      name_len: i32,
+                      value_ptr: i32,
+                      value_len: i32|
+                      -> i32 {
+                    let (Some(name), Some(value)) = (
+                        read(&mut caller, name_ptr, name_len).and_then(header_name),
+                        read(&mut caller, value_ptr, value_len),
+                    ) else {
+                        return BAD_ARGUMENT;
+                    };
+                    let host = caller.data_mut();
+                    if name.starts_with(':') {
+                        return host.set_pseudo_header(map_type, &name, &value);
+                    }
+                    let (Some(headers), Ok(name), Ok(value)) = (
+                        host.headers_mut(map_type),
+                        http::HeaderName::from_bytes(name.as_bytes()),
+                        http::HeaderValue::from_bytes(&value),
+                    ) else {
+                        return BAD_ARGUMENT;
+                    };
+                    let set = if replace {
+                        headers.insert(name, value)
+                    } else {
+                        headers.append(name, value)
+                    };
+                    if set.is_ok() { OK } else { BAD_ARGUMENT }
+                },
+            )?;
+        }
+        linker.func_wrap(
+            "env",
+            "proxy_remove_header_map_value",
+            |mut caller: Caller<'_, Host>, map_type: i32, name_ptr: i32, name_len: i32| -> i32 {
+                let Some(name) = read(&mut caller, name_ptr, name_len).and_then(header_name) else {
+                    return BAD_ARGUMENT;
+                };
+                match (
+                    caller.data_mut().headers_mut(map_type),
+                    http::HeaderName::from_bytes(name.as_bytes()),
+                ) {
+                    (Some(headers), Ok(name)) => {
+                        headers.remove(&name);
+                        OK
+                    }
+                    _ => BAD_ARGUMENT,
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_get_buffer_bytes",
+            |mut caller: Caller<'_, Host>,
+             buffer_type: i32,
+             start: i32,
+             max_size: i32,
+             ret_ptr: i32,
+             ret_len: i32|
+             -> wasmtime::Result<i32> {
+                if buffer_type == VM_CONFIGURATION {
+                    return give(&mut caller, &[], ret_ptr, ret_len);
+                }
+                let Some(buffer) = caller.data().buffer(buffer_type) else {
+                    return Ok(NOT_FOUND);
+                };
+                let start = (start as u32 as usize).min(buffer.len());
+                let end = start
+                    .saturating_add(max_size as u32 as usize)
+                    .min(buffer.len());
+                let data = buffer.slice(start..end);
+                give(&mut caller, &data, ret_ptr, ret_len)
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_set_buffer_bytes",
+            |mut caller: Caller<'_, Host>,
+             buffer_type: i32,
+             start: i32,
+             size: i32,
+             data_ptr: i32,
+             data_len: i32|
+             -> i32 {
+                if buffer_type != REQUEST_BODY && buffer_type != RESPONSE_BODY {
+                    return BAD_ARGUMENT;
+                }
+                let Some(data) = read(&mut caller, data_ptr, data_len) else {
+                    return BAD_ARGUMENT;
+                };
+                let body = caller.data_mut().body.take().unwrap_or_default();
+                let start = (start as u32 as usize).min(body.len());
+                let end = start.saturating_add(size as u32 as usize).min(body.len());
+                let mut replaced = Vec::with_capacity(body.len() - (end - start) + data.len());
+                replaced.extend_from_slice(&body[..start]);
+                replaced.extend_from_slice(&data);
+                replaced.extend_from_slice(&body[end..]);
+                caller.data_mut().body = Some(replaced.into());
+                OK
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_send_local_response",
+            |mut caller: Caller<'_, Host>,
+             status: i32,
+             _details_ptr: i32,
+             _details_len: i32,
+             body_ptr: i32,
+             body_len: i32,
+             headers_ptr: i32,
+             headers_len: i32,
+             _grpc_status: i32|
+             -> i32 {
+                let (Some(body), Some(headers)) = (
+                    read(&mut caller, body_ptr, body_len),
+                    read(&mut caller, headers_ptr, headers_len)
+                        .as_deref()
+                        .and_then(decode_pairs),
+                ) else {
+                    return BAD_ARGUMENT;
+                };
+                let host = caller.data_mut();
+                match local_response(status, headers, body, &host.properties.request_id) {
+                    Ok(response) => {
+                        host.local_response = Some(response);
+                        OK
+                    }
+                    Err(_) => BAD_ARGUMENT,
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_get_property",
+            |mut caller: Caller<'_, Host>,
+             path_ptr: i32,
+             path_len: i32,
+             ret_ptr: i32,
+             ret_len: i32|
+             -> wasmtime::Result<i32> {
+                let Some(path) = read(&mut caller, path_ptr, path_len) else {
+                    return Ok(BAD_ARGUMENT);
+                };
+                // Path segments are separated by NULs.
+                let path = String::from_utf8_lossy(&path)
+                    .trim_end_matches('\0')
+                    .replace('\0', ".");
+                match caller.data().property(&path) {
+                    Some(value) => give(&mut caller, &value, ret_ptr, ret_len),
+                    None => Ok(NOT_FOUND),
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_get_current_time_nanoseconds",
+            |mut caller: Caller<'_, Host>, ret_ptr: i32| -> wasmtime::Result<i32> {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos() as u64;
+                let Some(memory) = memory(&mut caller) else {
+                    return Ok(INTERNAL_FAILURE);
+                };
+                memory.write(&mut caller, ret_ptr as u32 as usize, &now.to_le_bytes())?;
+                Ok(OK)
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "proxy_set_effective_context",
+            |_: Caller<'_, Host>, _context: i32| -> i32 { OK },
+        )?;
+        Ok(())
+    }
+
+    fn local_response(
+        status: i32,
+        headers: Vec<(Vec<u8>, Vec<u8>)>,
+        body: Vec<u8>,
+        request_id: &str,
+    ) -> Result<(ResponseHeader, Bytes)> {
+        let status = u16::try_from(status).unwrap_or(0);
+        let mut response = ResponseHeader::build(status, Some(headers.len() + 2))?;
+        for (name, value) in headers {
+            let name = String::from_utf8(name)
+                .map_err(|_| ProxyError::Internal.error("header name is not UTF-8"))?;
+            response.append_header(name, value.as_slice())?;
+        }
+        response.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+        response.insert_header(REQUEST_ID_HEADER, request_id)?;
+        Ok((response, Bytes::from(body)))
+    }
+}
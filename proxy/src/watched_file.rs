@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+use crate::state::SharedState;
+
+/// How often watched files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Contents of a file loaded by `load`, replaced when the file's modification time
+/// changes and [`WatchedFile::refresh`] is called.
+pub struct WatchedFile<T> {
+    path: PathBuf,
+    load: fn(&Path) -> Result<T, String>,
+    modified: Mutex<Option<SystemTime>>,
+    value: RwLock<Arc<T>>,
+}
+
+impl<T> WatchedFile<T> {
+    pub fn open(path: &str, load: fn(&Path) -> Result<T, String>) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        let modified = modified(&path);
+        let value = load(&path)?;
+        Ok(Self {
+            path,
+            load,
+            modified: Mutex::new(modified),
+            value: RwLock::new(Arc::new(value)),
+        })
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.value
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Loads the file again when its modification time changed, keeping the current
+    /// contents when it cannot be loaded.
+    pub fn refresh(&self) {
+        let modified = modified(&self.path);
+        {
+            let mut known = self
+                .modified
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if *known == modified {
+                return;
+            }
+            *known = modified;
+        }
+        match (self.load)(&self.path) {
+            Ok(value) => {
+                *self
+                    .value
+                    .write()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(value);
+                info!("reloaded {}", self.path.display());
+            }
+            Err(err) => warn!(
+                "keeping the previous contents of {}: {err}",
+                self.path.display()
+            ),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Background service reloading the files the current config refers to (IP lists, the
+/// GeoIP database, htpasswd, API key and WAF rule files) when they change.
+pub struct WatchedFileService {
+    state: SharedState,
+}
+
+impl WatchedFileService {
+    pub fn new(state: SharedState) -> Self {
+        Self { state }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for WatchedFileService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.state.current().refresh_watched_files(),
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use log::info;
+use pingora::prelude::*;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use ring::rand::{SecureRandom, SystemRandom};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 10 * 60;
+/// Time the other side has to answer a close frame the proxy sent.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Close frame with status 1001 (going away), as the server sends it.
+const CLOSE_FRAME: [u8; 4] = [0x88, 0x02, 0x03, 0xE9];
+
+/// Set once the server starts shutting down.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// `[websocket]` section of the config file: limits on upgraded connections.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct WebSocketConfig {
+    /// How long an upgraded connection may carry nothing either way before it is closed.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub idle_timeout_seconds: Option<u64>,
+    /// How long an upgraded connection may stay open at all. Unset for no limit.
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    #[schemars(schema_with = "crate::units::duration_schema")]
+    pub max_lifetime_seconds: Option<u64>,
+}
+
+impl WebSocketConfig {
+    pub fn with_defaults(self) -> Self {
+        Self {
+            idle_timeout_seconds: Some(
+                self.idle_timeout_seconds
+                    .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+            ),
+            ..self
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.idle_timeout_seconds
+                .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+        )
+    }
+}
+
+/// Why the proxy closed an upgraded connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Shutdown,
+    Lifetime,
+}
+
+/// An upgraded connection: follows the frames both ways so that the proxy can end it with a
+/// close frame between two of them, when the server shuts down or its lifetime is over.
+pub struct WebSocket {
+    upgraded: Instant,
+    idle: Duration,
+    max_lifetime: Option<Duration>,
+    /// Frames from the client.
+    requests: Frames,
+    /// Frames from the upstream.
+    responses: Frames,
+    closing: Option<CloseReason>,
+    /// Set once a close frame went to the client, after which the upstream's frames are
+    /// dropped.
+    closed_client: bool,
+    /// The same toward the upstream, for the client's frames.
+    closed_upstream: bool,
+}
+
+impl WebSocket {
+    pub fn new(config: &WebSocketConfig) -> Self {
+        Self {
+            upgraded: Instant::now(),
+            idle: config.idle_timeout(),
+            max_lifetime: config.max_lifetime_seconds.map(Duration::from_secs),
+            requests: Frames::default(),
+            responses: Frames::default(),
+            closing: None,
+            closed_client: false,
+            closed_upstream: false,
+        }
+    }
+
+    /// Timeout for the next read from the client, which Pingora restarts whenever data
+    /// passes either way.
+    pub fn read_timeout(&self) -> Duration {
+        if self.closing.is_some() {
+            return CLOSE_TIMEOUT.min(self.idle);
+        }
+        match self.lifetime_left() {
+            Some(left) => self.idle.min(left).max(Duration::from_millis(1)),
+            None => self.idle,
+        }
+    }
+
+    /// Passes on a chunk from the client, ending the connection toward the upstream at a
+    /// frame boundary once it is to be closed.
+    pub fn request_body(&mut self, body: &mut Option<Bytes>) {
+        if self.closed_upstream {
+            *body = None;
+            return;
+        }
+        let Some(data) = body else {
+            return;
+        };
+        let boundary = self.requests.feed(data);
+        if self.should_close()
+            && !self.closed_client
+            && let Some(at) = boundary
+        {
+            let mut chunk = BytesMut::from(&data[..at]);
+            chunk.put_slice(&masked_close_frame());
+            *body = Some(chunk.freeze());
+            self.closed_upstream = true;
+        }
+    }
+
+    /// Passes on a chunk from the upstream, ending the connection toward the client at a
+    /// frame boundary once it is to be closed.
+    pub fn response_body(&mut self, body: &mut Option<Bytes>) {
+        if self.closed_client {
+            *body = None;
+            return;
+        }
+        let Some(data) = body else {
+            return;
+        };
+        let boundary = self.responses.feed(data);
+        if self.should_close()
+            && !self.closed_upstream
+            && let Some(at) = boundary
+        {
+            let mut chunk = BytesMut::from(&data[..at]);
+            chunk.put_slice(&CLOSE_FRAME);
+            *body = Some(chunk.freeze());
+            self.closed_client = true;
+        }
+    }
+
+    /// How the connection ended, for the metrics.
+    pub fn close_reason(&self, error: Option<&Error>) -> &'static str {
+        match self.closing {
+            Some(CloseReason::Shutdown) => "shutdown",
+            Some(CloseReason::Lifetime) => "lifetime",
+            None if self.lifetime_left().is_some_and(|left| left.is_zero()) => "lifetime",
+            None => match error.map(|error| error.etype()) {
+                Some(ErrorType::ReadTimedout) => "idle",
+                Some(_) => "error",
+                None => "closed",
+            },
+        }
+    }
+
+    fn lifetime_left(&self) -> Option<Duration> {
+        self.max_lifetime
+            .map(|lifetime| lifetime.saturating_sub(self.upgraded.elapsed()))
+    }
+
+    fn should_close(&mut self) -> bool {
+        if self.closing.is_none() {
+            if DRAINING.load(Ordering::Relaxed) {
+                self.closing = Some(CloseReason::Shutdown);
+            } else if self.lifetime_left().is_some_and(|left| left.is_zero()) {
+                self.closing = Some(CloseReason::Lifetime);
+            }
+        }
+        self.closing.is_some()
+    }
+}
+
+/// Client frames must be masked, with a key the upstream cannot predict.
+fn masked_close_frame() -> [u8; 8] {
+    let mut key = [0; 4];
+    let _ = SystemRandom::new().fill(&mut key);
+    let [code_high, code_low] = 1001u16.to_be_bytes();
+    [
+        0x88,
+        0x82,
+        key[0],
+        key[1],
+        key[2],
+        key[3],
+        code_high ^ key[0],
+        code_low ^ key[1],
+    ]
+}
+
+/// Follows the frame headers through one direction of a connection.
+#[derive(Default)]
+struct Frames {
+    header: Vec<u8>,
+    /// Payload bytes left of the current frame.
+    remaining: u64,
+}
+
+impl Frames {
+    /// Reads the next chunk, returning the first offset in it at which a frame starts (or
+    /// the previous one ends), if any.
+    fn feed(&mut self, mut data: &[u8]) -> Option<usize> {
+        let mut offset = 0;
+        let mut boundary = None;
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let taken = self.remaining.min(data.len() as u64) as usize;
+                self.remaining -= taken as u64;
+                data = &data[taken..];
+                offset += taken;
+                continue;
+            }
+            if self.header.is_empty() {
+                boundary.get_or_insert(offset);
+            }
+            self.header.push(data[0]);
+            data = &data[1..];
+            offset += 1;
+            if let Some(payload) = payload_len(&self.header) {
+                self.remaining = payload;
+                self.header.clear();
+            }
+        }
+        if self.remaining == 0 && self.header.is_empty() {
+            boundary.get_or_insert(offset);
+        }
+        boundary
+    }
+}
+
+/// The payload length of a frame, once `header` holds all of its header.
+fn payload_len(header: &[u8]) -> Option<u64> {
+    let [_, second, rest @ ..] = header else {
+        return None;
+    };
+    let mask_len = if second & 0x80 != 0 { 4 } else { 0 };
+    let (length_len, short) = match second & 0x7F {
+        126 => (2, None),
+        127 => (8, None),
+        length => (0, Some(u64::from(length))),
+    };
+    if rest.len() < length_len + mask_len {
+        return None;
+    }
+    Some(short.unwrap_or_else(|| {
+        rest[..length_len]
+            .iter()
+            .fold(0, |length, byte| length << 8 | u64::from(*byte))
+    }))
+}
+
+/// Background service that marks the server as shutting down, so that upgraded connections
+/// are closed with a close frame during the grace period rather than cut when it ends.
+pub struct WebSocketDrain;
+
+#[async_trait]
+impl BackgroundService for WebSocketDrain {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if shutdown.changed().await.is_ok() {
+            DRAINING.store(true, Ordering::Relaxed);
+            info!("closing WebSocket connections as their next frame passes");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Routes, CORS and static files end to end, through the harness of the `testing` feature.
+
+use std::fs;
+use std::path::PathBuf;
+
+use rose_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use rose_proxy::{ProxyBuilder, RouteConfig};
+
+/// A directory of its own under the system temp dir, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rose-proxy-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn routes_by_longest_prefix() {
+    let default = MockUpstream::echo();
+    let api = MockUpstream::start(|request| {
+        MockResponse::new(201)
+            .header("x-upstream", "api")
+            .body(request.body.clone())
+    });
+    let proxy = TestProxy::start(ProxyBuilder::new(default.addr()).route(RouteConfig {
+        name: Some("api".to_string()),
+        path_prefix: "/api/".to_string(),
+        upstream_addr: Some(api.addr()),
+        strip_prefix: true,
+        ..RouteConfig::default()
+    }))
+    .unwrap();
+
+    proxy
+        .request(
+            "POST",
+            "/api/items",
+            &[("content-type", "text/plain")],
+            "payload",
+        )
+        .assert_status(201)
+        .assert_header("x-upstream", "api")
+        .assert_body("payload");
+    proxy
+        .get("/other?page=2")
+        .assert_status(200)
+        .assert_no_header("x-upstream")
+        .assert_body("GET /other?page=2");
+
+    let requests = api.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/items");
+    assert!(requests[0].header("x-request-id").is_some());
+    assert_eq!(default.requests()[0].path, "/other?page=2");
+}
+
+#[test]
+fn answers_cors_preflights() {
+    let upstream = MockUpstream::echo();
+    let proxy = TestProxy::start(ProxyBuilder::new(upstream.addr())).unwrap();
+
+    let preflight = proxy.request(
+        "OPTIONS",
+        "/items",
+        &[
+            ("origin", "https://app.example"),
+            ("access-control-request-method", "PUT"),
+        ],
+        Vec::new(),
+    );
+    assert!(
+        matches!(preflight.status, 200 | 204),
+        "preflight answered with {}",
+        preflight.status
+    );
+    preflight.assert_header("access-control-allow-origin", "https://app.example");
+    assert!(
+        preflight
+            .header("access-control-allow-methods")
+            .is_some_and(|methods| methods.contains("PUT"))
+    );
+    assert!(
+        upstream.requests().is_empty(),
+        "preflight reached the upstream"
+    );
+
+    proxy
+        .request(
+            "GET",
+            "/items",
+            &[("origin", "https://app.example")],
+            Vec::new(),
+        )
+        .assert_status(200)
+        .assert_header("access-control-allow-origin", "https://app.example");
+}
+
+#[test]
+fn serves_static_files() {
+    let root = TempDir::new("static");
+    fs::write(root.0.join("app.js"), "console.log(1);\n").unwrap();
+    let upstream = MockUpstream::echo();
+    let proxy = TestProxy::start(
+        ProxyBuilder::new(upstream.addr()).static_files("/assets/", root.0.to_str().unwrap()),
+    )
+    .unwrap();
+
+    let response = proxy.get("/assets/app.js");
+    response.assert_status(200).assert_body("console.log(1);\n");
+    assert!(
+        response
+            .header("content-type")
+            .is_some_and(|content_type| content_type.contains("javascript"))
+    );
+    assert!(
+        upstream.requests().is_empty(),
+        "a static file reached the upstream"
+    );
+    // Paths under the mount without a file are left to the upstream.
+    proxy
+        .get("/assets/missing.js")
+        .assert_body("GET /assets/missing.js");
+}